@@ -7,31 +7,122 @@ of its input arguments from environment variables.
 
 use buildsys::manifest::SupportedArch;
 use buildsys::BuildType;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use pipesys::server::UidMap;
+use regex::Regex;
+use std::fmt;
+use std::io::Write;
 use std::path::PathBuf;
+use std::str::FromStr;
 use url::Url;
 
 /// A list of environment variables and the type of build that should be rerun if that environment
 /// variable changes. The build type is represented with bit flags so that we can easily list
 /// multiple build types for a single variable. See `[BuildType]` and `[rerun_for_envs]` below to
-/// see how this list is used.
-const REBUILD_VARS: [(&str, u8); 14] = [
-    ("BUILDSYS_ARCH", PACKAGE | KIT | VARIANT),
-    ("BUILDSYS_CACERTS_BUNDLE_OVERRIDE", VARIANT),
+/// see how this list is used. Every variable that buildsys reads must be listed either here or in
+/// `[NON_REBUILD_VARS]`.
+const REBUILD_VARS: [(&str, u8); 34] = [
+    ("BUILDSYS_ARCH", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_ARTIFACT_IGNORE", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_BUILD_ARG_FILE", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_CACERTS_BUNDLE_OVERRIDE", VARIANT | REPACK),
+    (
+        "BUILDSYS_CARGO_METADATA_PATH",
+        PACKAGE | KIT | VARIANT | REPACK,
+    ),
+    ("BUILDSYS_CHANGED_SINCE", PACKAGE),
+    ("BUILDSYS_CONTEXT", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_CONTEXT_TAR", PACKAGE | KIT | VARIANT | REPACK),
+    (
+        "BUILDSYS_EXTERNAL_KITS_DIR",
+        PACKAGE | KIT | VARIANT | REPACK,
+    ),
+    ("BUILDSYS_FORCE", VARIANT),
+    ("BUILDSYS_FORWARD_ENV", PACKAGE | KIT),
+    ("BUILDSYS_IMAGES_DIR", VARIANT | REPACK),
     ("BUILDSYS_KITS_DIR", KIT),
-    ("BUILDSYS_EXTERNAL_KITS_DIR", PACKAGE | KIT | VARIANT),
-    ("BUILDSYS_NAME", VARIANT),
-    ("BUILDSYS_IMAGES_DIR", VARIANT),
-    ("BUILDSYS_OUTPUT_GENERATION_ID", PACKAGE | KIT | VARIANT),
-    ("BUILDSYS_PACKAGES_DIR", PACKAGE),
+    ("BUILDSYS_LOOKASIDE_CACHE", PACKAGE),
+    ("BUILDSYS_MARKER_LAYOUT", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_NAME", VARIANT | REPACK),
+    ("BUILDSYS_OUTPUT_DIR", PACKAGE | KIT | VARIANT | REPACK),
+    (
+        "BUILDSYS_OUTPUT_GENERATION_ID",
+        PACKAGE | KIT | VARIANT | REPACK,
+    ),
+    ("BUILDSYS_PACKAGES_DIR", PACKAGE | KIT),
+    ("BUILDSYS_PACKAGE_OVA", VARIANT),
     ("BUILDSYS_PRETTY_NAME", VARIANT),
-    ("BUILDSYS_ROOT_DIR", PACKAGE | KIT | VARIANT),
-    ("BUILDSYS_STATE_DIR", PACKAGE | KIT | VARIANT),
-    ("BUILDSYS_VERSION_BUILD", KIT | VARIANT),
-    ("BUILDSYS_VERSION_IMAGE", KIT | VARIANT),
-    ("TLPRIVATE_SDK_IMAGE", PACKAGE | KIT | VARIANT),
+    ("BUILDSYS_ROOT_DIR", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_SBKEYS_PROFILE_DIR", VARIANT | REPACK),
+    ("BUILDSYS_SOURCES_DIR", PACKAGE),
+    ("BUILDSYS_STATE_DIR", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_UPSTREAM_SOURCE_FALLBACK", PACKAGE),
+    ("BUILDSYS_VERSION_BUILD", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_VERSION_BUILD_TIMESTAMP", PACKAGE),
+    ("BUILDSYS_VERSION_FULL", PACKAGE | KIT | VARIANT | REPACK),
+    ("BUILDSYS_VERSION_IMAGE", KIT | VARIANT | REPACK),
+    ("BUILDSYS_VERSION_TAG", VARIANT),
+    ("PUBLISH_REPO_ROOT_JSON", VARIANT | REPACK),
+    ("TLPRIVATE_SDK_IMAGE", PACKAGE | KIT | VARIANT | REPACK),
+    ("TWOLITER_TOOLS_DIR", PACKAGE | KIT | VARIANT | REPACK),
 ];
 
+/// Environment variables that buildsys reads, but which only change how a build is run or
+/// reported, and not what it produces. Changes to these do not cause a rebuild. The list is only
+/// used to check that no variable is left unclassified.
+#[cfg(test)]
+const NON_REBUILD_VARS: [&str; 35] = [
+    "BUILDSYS_BACKUP_OUTPUT_SOCKET",
+    "BUILDSYS_BUILD_LOG_DIR",
+    "BUILDSYS_BUILD_TIMINGS",
+    "BUILDSYS_BUILD_ULIMITS",
+    "BUILDSYS_BYPASS_RUN_FLAGS",
+    "BUILDSYS_CHECKSUM_JOBS",
+    "BUILDSYS_CICD_HACK",
+    "BUILDSYS_COMPRESS_LOGS",
+    "BUILDSYS_COPY_NOT_MOVE",
+    "BUILDSYS_DUPLICATE_BUILD_ARGS",
+    "BUILDSYS_EMIT_NAMES",
+    "BUILDSYS_FAIL_FAST",
+    "BUILDSYS_JOBS",
+    "BUILDSYS_MAX_ARTIFACTS",
+    "BUILDSYS_MAX_OUTPUT_BYTES",
+    "BUILDSYS_NO_BYPASS",
+    "BUILDSYS_OUTPUT_STALL_TIMEOUT_SECS",
+    "BUILDSYS_PIPESYS_BIN",
+    "BUILDSYS_PIPESYS_FROM_IMAGE",
+    "BUILDSYS_PROVENANCE",
+    "BUILDSYS_QUIET",
+    "BUILDSYS_REGISTRY_MIRROR",
+    "BUILDSYS_REPRO_CHECK",
+    "BUILDSYS_REPRO_MANIFEST",
+    "BUILDSYS_RESOLVED_PACKAGE_CACHE",
+    "BUILDSYS_RETRY_JITTER",
+    "BUILDSYS_RETRY_PATTERN",
+    "BUILDSYS_SNAPSHOT_DIR",
+    "BUILDSYS_SNAPSHOT_MAX_BYTES",
+    "BUILDSYS_SNAPSHOT_ROOT",
+    "BUILDSYS_SYNC_RPMS_ON_RETRY",
+    "BUILDSYS_UID_MAP",
+    "BUILDSYS_VERIFY_PACKAGES",
+    "BUILDSYS_WRITABLE_BYPASS",
+    "CARGO_MANIFEST_DIR",
+];
+
+/// The default limit on the number of artifacts a single build may produce. This is far more than
+/// any real package or variant build creates, but still low enough to protect the output directory
+/// from a runaway build.
+const DEFAULT_MAX_ARTIFACTS: usize = 100_000;
+
+/// The default cap on the size of a snapshot of the project root, in bytes. This leaves room for
+/// the RPMs and kits of a large project, but stops a root with stray large files from filling the
+/// disk, or memory if the snapshot is on a tmpfs.
+const DEFAULT_SNAPSHOT_MAX_BYTES: u64 = 16 << 30;
+
+/// The default fraction by which the delay before a retry varies.
+const DEFAULT_RETRY_JITTER: f64 = 0.5;
+
 /// A tool for building Bottlerocket images and artifacts.
 #[derive(Debug, Parser)]
 pub(crate) struct Buildsys {
@@ -41,21 +132,90 @@ pub(crate) struct Buildsys {
 
 #[derive(Subcommand, Debug)]
 pub(crate) enum Command {
+    #[command(flatten)]
+    Build(BuildCommand),
+    Diff(Box<DiffArgs>),
+    ShowArgs(ShowArgsArgs),
+    Prune(PruneArgs),
+    ImageSizes(ImageSizesArgs),
+    VerifyImage(VerifyImageArgs),
+    BuildPackages(BuildPackagesArgs),
+    Completions(CompletionsArgs),
+    Clean(CleanArgs),
+    Arches(ArchesArgs),
+}
+
+impl Command {
+    /// Returns the type of build for the command, or `None` if it does not build anything.
+    pub(crate) fn build_type(&self) -> Option<BuildType> {
+        match self {
+            Command::Build(build) => Some(build.build_type()),
+            Command::Diff(_)
+            | Command::ShowArgs(_)
+            | Command::Prune(_)
+            | Command::ImageSizes(_)
+            | Command::VerifyImage(_)
+            | Command::BuildPackages(_)
+            | Command::Completions(_)
+            | Command::Clean(_)
+            | Command::Arches(_) => None,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum BuildCommand {
     BuildPackage(Box<BuildPackageArgs>),
     BuildKit(Box<BuildKitArgs>),
     BuildVariant(Box<BuildVariantArgs>),
     RepackVariant(Box<RepackVariantArgs>),
 }
 
-impl Command {
+impl BuildCommand {
     pub(crate) fn build_type(&self) -> BuildType {
         match self {
-            Command::BuildPackage(_) => BuildType::Package,
-            Command::BuildKit(_) => BuildType::Kit,
-            Command::BuildVariant(_) => BuildType::Variant,
-            Command::RepackVariant(_) => BuildType::Repack,
+            BuildCommand::BuildPackage(_) => BuildType::Package,
+            BuildCommand::BuildKit(_) => BuildType::Kit,
+            BuildCommand::BuildVariant(_) => BuildType::Variant,
+            BuildCommand::RepackVariant(_) => BuildType::Repack,
         }
     }
+
+    pub(crate) fn common(&self) -> &Common {
+        match self {
+            BuildCommand::BuildPackage(args) => &args.common,
+            BuildCommand::BuildKit(args) => &args.common,
+            BuildCommand::BuildVariant(args) => &args.common,
+            BuildCommand::RepackVariant(args) => &args.common,
+        }
+    }
+}
+
+/// How to print the results of commands that report on builds rather than run them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// How marker directories for tracking build artifacts are arranged under the state directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum MarkerLayout {
+    /// `<state-dir>/<arch>/<packages|kits|variants>/<name>`
+    #[default]
+    Nested,
+    /// `<state-dir>/<package|kit|variant>-<arch>-<name>`
+    Flat,
+}
+
+/// What to do when a build sets the same build argument more than once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DuplicateBuildArgs {
+    /// Fail the build.
+    #[default]
+    Error,
+    /// Keep the last value, and print a warning.
+    Warn,
 }
 
 /// Arguments common to all subcommands.
@@ -70,9 +230,41 @@ pub(crate) struct Common {
     #[arg(long, env = "BUILDSYS_ROOT_DIR")]
     pub(crate) root_dir: PathBuf,
 
+    /// The directory to send to docker as the build context. Relative paths are resolved against
+    /// the root directory, which is also the default.
+    #[arg(long, env = "BUILDSYS_CONTEXT")]
+    pub(crate) context: Option<PathBuf>,
+
+    /// Send this tar archive to docker as the build context instead of a directory, or `-` to read
+    /// it from standard input. The archive takes the place of the root directory, so it must hold
+    /// the Dockerfile at the same path. Relative paths are resolved against the root directory.
+    #[arg(long, env = "BUILDSYS_CONTEXT_TAR", conflicts_with = "context")]
+    pub(crate) context_tar: Option<PathBuf>,
+
+    /// Add the `KEY=VALUE` lines in this file as build arguments, after the ones from the
+    /// manifest. Blank lines and `#` comments are ignored. Relative paths are resolved against the
+    /// root directory.
+    #[arg(long, env = "BUILDSYS_BUILD_ARG_FILE")]
+    pub(crate) build_arg_file: Option<PathBuf>,
+
     #[arg(long, env = "BUILDSYS_STATE_DIR")]
     pub(crate) state_dir: PathBuf,
 
+    /// How to arrange the directories that track build artifacts under the state directory.
+    #[arg(long, env = "BUILDSYS_MARKER_LAYOUT", value_enum, default_value_t)]
+    pub(crate) marker_layout: MarkerLayout,
+
+    /// What to do when the same build argument is set more than once, such as by buildsys and by
+    /// a later addition to the build arguments. Docker would otherwise use the last value without
+    /// saying so.
+    #[arg(
+        long,
+        env = "BUILDSYS_DUPLICATE_BUILD_ARGS",
+        value_enum,
+        default_value_t
+    )]
+    pub(crate) duplicate_build_args: DuplicateBuildArgs,
+
     #[arg(long, env = "BUILDSYS_VERSION_FULL")]
     pub(crate) version_full: String,
 
@@ -82,6 +274,12 @@ pub(crate) struct Common {
     #[arg(long, env = "TLPRIVATE_SDK_IMAGE")]
     pub(crate) sdk_image: String,
 
+    /// Pull the SDK through this registry mirror, given as a host with an optional port and path.
+    /// The mirror takes the place of the registry named in the image, or of Docker Hub if it names
+    /// none. Labels and cache keys still use the original image name.
+    #[arg(long, env = "BUILDSYS_REGISTRY_MIRROR")]
+    pub(crate) registry_mirror: Option<String>,
+
     #[arg(long, env = "TWOLITER_TOOLS_DIR")]
     pub(crate) tools_dir: PathBuf,
 
@@ -92,6 +290,201 @@ pub(crate) struct Common {
     /// build failures that are difficult to troubleshoot.
     #[arg(long, env = "BUILDSYS_CICD_HACK")]
     pub(crate) cicd_hack: bool,
+
+    /// The maximum number of artifacts that a build may produce. If the build creates more than
+    /// this, it fails before any artifacts are moved into the output directory.
+    #[arg(long, env = "BUILDSYS_MAX_ARTIFACTS", default_value_t = DEFAULT_MAX_ARTIFACTS)]
+    pub(crate) max_artifacts: usize,
+
+    /// Glob patterns for files that builds may leave in the output directory, but which are not
+    /// artifacts, such as `*.rpmdb`. Patterns match paths relative to the output directory, and
+    /// matching files are neither copied nor tracked.
+    #[arg(
+        long = "artifact-ignore",
+        env = "BUILDSYS_ARTIFACT_IGNORE",
+        value_delimiter = ','
+    )]
+    pub(crate) artifact_ignore: Vec<String>,
+
+    /// Only print the output from docker commands if they fail. Successful commands are reported
+    /// with a short progress line instead.
+    #[arg(long, env = "BUILDSYS_QUIET")]
+    pub(crate) quiet: bool,
+
+    /// When a build fails because `createrepo_c` could not read an RPM header, sync the RPMs in
+    /// the project's build directory to disk before the first retry.
+    #[arg(long, env = "BUILDSYS_SYNC_RPMS_ON_RETRY")]
+    pub(crate) sync_rpms_on_retry: bool,
+
+    /// Run the docker build only once, without retrying known transient failures, so that the
+    /// first error is reported with its full output. Useful when debugging a failing build.
+    #[arg(long, env = "BUILDSYS_FAIL_FAST")]
+    pub(crate) fail_fast: bool,
+
+    /// Write checksums for the artifacts produced by the build to this file.
+    #[arg(long, env = "BUILDSYS_REPRO_MANIFEST")]
+    pub(crate) repro_manifest: Option<PathBuf>,
+
+    /// Compare the artifacts produced by the build to the checksums in this file, which was
+    /// written by an earlier build, and warn about any that differ.
+    #[arg(long, env = "BUILDSYS_REPRO_CHECK")]
+    pub(crate) repro_check: Option<PathBuf>,
+
+    /// The most artifacts to compute checksums for at once, when checksums are needed. Defaults to
+    /// the number of threads the host can run in parallel.
+    #[arg(long, env = "BUILDSYS_CHECKSUM_JOBS", value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) checksum_jobs: Option<u16>,
+
+    /// How much to randomly vary the delay before retrying a failed docker build, as a fraction
+    /// of the delay. This spreads out the retries of concurrent builds that failed together.
+    #[arg(long, env = "BUILDSYS_RETRY_JITTER", default_value_t = DEFAULT_RETRY_JITTER, value_parser = parse_fraction)]
+    pub(crate) retry_jitter: f64,
+
+    /// Also retry a failed docker build if its output matches this regular expression, along with
+    /// the known transient failures. May be repeated.
+    #[arg(long = "retry-pattern", env = "BUILDSYS_RETRY_PATTERN", value_parser = Regex::new)]
+    pub(crate) retry_patterns: Vec<Regex>,
+
+    /// Serve the output directory on a second socket as well, which builds try if the first
+    /// server is unavailable.
+    #[arg(long, env = "BUILDSYS_BACKUP_OUTPUT_SOCKET")]
+    pub(crate) backup_output_socket: bool,
+
+    /// Skip the bypass container, and give builds the project root as a named build context
+    /// instead. This is faster for local iteration, but the whole root is sent to docker and
+    /// mounted into the build directly, rather than shared through a read-only file descriptor,
+    /// so it should only be used for trusted builds.
+    #[arg(long, env = "BUILDSYS_NO_BYPASS")]
+    pub(crate) no_bypass: bool,
+
+    /// Copy the project root into a snapshot before the build, and give the build the snapshot
+    /// instead of the live root, so that files edited while it runs don't change what it sees.
+    /// The snapshot takes as much space as the root, apart from the state directory and what the
+    /// `.dockerignore` for the Dockerfile excludes. It is removed after the build.
+    #[arg(long, env = "BUILDSYS_SNAPSHOT_ROOT")]
+    pub(crate) snapshot_root: bool,
+
+    /// Where to put the snapshot of the project root, instead of the state directory. A tmpfs such
+    /// as `/dev/shm` is faster to copy to, but holds the whole snapshot in memory until the build
+    /// is done.
+    #[arg(long, env = "BUILDSYS_SNAPSHOT_DIR", requires = "snapshot_root")]
+    pub(crate) snapshot_dir: Option<PathBuf>,
+
+    /// The most bytes of files to copy into the snapshot of the project root. The build fails if
+    /// the root is larger.
+    #[arg(long, env = "BUILDSYS_SNAPSHOT_MAX_BYTES", default_value_t = DEFAULT_SNAPSHOT_MAX_BYTES)]
+    pub(crate) snapshot_max_bytes: u64,
+
+    /// Mount the project root into the bypass container read-write, and have pipesys serve it as
+    /// writable, so that builds can stage files into the root through the descriptor. The root is
+    /// read-only to builds by default. This can't be combined with a snapshot of the root, since
+    /// anything written there is thrown away with it.
+    #[arg(
+        long,
+        env = "BUILDSYS_WRITABLE_BYPASS",
+        conflicts_with_all = ["no_bypass", "snapshot_root"]
+    )]
+    pub(crate) writable_bypass: bool,
+
+    /// Extra flags for the `docker run` command that starts the bypass container, which serves the
+    /// project root to builds. Flags that would change the required network, PID namespace, user,
+    /// or volume settings are rejected. Other flags are passed through unchecked, so they can
+    /// weaken the isolation of the container or stop it from serving the project root at all.
+    #[arg(
+        long = "bypass-run-flag",
+        env = "BUILDSYS_BYPASS_RUN_FLAGS",
+        value_delimiter = ' ',
+        allow_hyphen_values = true
+    )]
+    pub(crate) bypass_run_flags: Vec<String>,
+
+    /// The pipesys binary to mount into the bypass container. By default, this is the one in
+    /// `build/tools` under the project root.
+    #[arg(
+        long,
+        env = "BUILDSYS_PIPESYS_BIN",
+        conflicts_with = "pipesys_from_image"
+    )]
+    pub(crate) pipesys_bin: Option<PathBuf>,
+
+    /// Run the pipesys that the SDK image already provides in the bypass container, instead of
+    /// mounting one from the host.
+    #[arg(long, env = "BUILDSYS_PIPESYS_FROM_IMAGE")]
+    pub(crate) pipesys_from_image: bool,
+
+    /// Environment variables to forward into builds as secrets, in addition to the AWS
+    /// credentials that variant builds always receive. `MY_TOKEN` is available to the Dockerfile
+    /// as the secret `my-token.env`. Variables that are not set are skipped. Only the names are
+    /// passed to docker, so the values never appear in the build command or the logs.
+    #[arg(
+        long = "forward-env",
+        env = "BUILDSYS_FORWARD_ENV",
+        value_delimiter = ','
+    )]
+    pub(crate) forward_env: Vec<String>,
+
+    /// The most output to keep and log from each build attempt, in bytes. Past this, output is
+    /// left out apart from the last few lines, and a marker notes how much was omitted. The
+    /// build itself is not affected.
+    #[arg(long, env = "BUILDSYS_MAX_OUTPUT_BYTES")]
+    pub(crate) max_output_bytes: Option<u64>,
+
+    /// Stop a build attempt if it produces no output for this many seconds, since it has likely
+    /// hung. The attempt is retried like other transient failures.
+    #[arg(long, env = "BUILDSYS_OUTPUT_STALL_TIMEOUT_SECS")]
+    pub(crate) output_stall_timeout_secs: Option<u64>,
+
+    /// The user namespace mapping for build containers, as `<INSIDE>:<OUTSIDE>:<COUNT>`. Set this
+    /// when running under rootless docker or podman, where root in the build is not root on the
+    /// host, so that buildsys can recognize the build when it connects to fetch the output
+    /// directory. This is usually `0:<UID>:1`, where `<UID>` is the user running the daemon.
+    #[arg(long, env = "BUILDSYS_UID_MAP")]
+    pub(crate) uid_map: Option<UidMap>,
+
+    /// Resource limits for the build and the bypass container, as `<NAME>=<SOFT>[:<HARD>]`, such
+    /// as `nofile=65536:65536`. Names are those docker accepts for `--ulimit`, and `-1` means no
+    /// limit. May be repeated.
+    #[arg(
+        long = "build-ulimit",
+        env = "BUILDSYS_BUILD_ULIMITS",
+        value_delimiter = ','
+    )]
+    pub(crate) build_ulimits: Vec<Ulimit>,
+
+    /// Copy artifacts to the output directory, instead of moving them, so that the build
+    /// directory under the state directory keeps a full set for debugging. The copies left
+    /// behind are removed with the markers before the next build.
+    #[arg(long, env = "BUILDSYS_COPY_NOT_MOVE")]
+    pub(crate) copy_not_move: bool,
+
+    /// Copy artifacts to this directory instead of the usual one for the build, such as
+    /// `build/rpms/<package>`. Markers that track the artifacts are still kept under the state
+    /// directory.
+    #[arg(long, env = "BUILDSYS_OUTPUT_DIR")]
+    pub(crate) output_dir: Option<PathBuf>,
+
+    /// Also write the output of each docker build to a file in this directory, named after the
+    /// build, such as `package-x86_64-glibc.log`. The file gets the same output that is printed,
+    /// so with `--quiet` it only holds the full output of a build that fails.
+    #[arg(long, env = "BUILDSYS_BUILD_LOG_DIR")]
+    pub(crate) build_log_dir: Option<PathBuf>,
+
+    /// Compress the files in the build log directory with gzip, as `.log.gz`. The output that is
+    /// printed is not affected.
+    #[arg(long, env = "BUILDSYS_COMPRESS_LOGS", requires = "build_log_dir")]
+    pub(crate) compress_logs: bool,
+
+    /// Write how long each phase of a successful build took to this file, as JSON: cleanup,
+    /// starting the bypass container, the docker build and each of its attempts, moving the
+    /// artifacts, and computing checksums.
+    #[arg(long, env = "BUILDSYS_BUILD_TIMINGS")]
+    pub(crate) build_timings: Option<PathBuf>,
+
+    /// Write the names generated for the build to this file, as JSON, before any container is
+    /// started: the image tag, the build token, the bypass container and socket, and the output
+    /// sockets. Other processes can use them to coordinate with the build.
+    #[arg(long, env = "BUILDSYS_EMIT_NAMES")]
+    pub(crate) emit_names: Option<PathBuf>,
 }
 
 /// Build RPMs from a spec file and sources.
@@ -121,10 +514,37 @@ pub(crate) struct BuildPackageArgs {
     #[arg(long, env = "BUILDSYS_UPSTREAM_SOURCE_FALLBACK")]
     pub(crate) upstream_source_fallback: String,
 
+    /// Skip the build if nothing in the package directory, the directories of the packages it
+    /// depends on, or their source groups changed since this git ref, counting untracked files.
+    /// The package is still built if the artifacts of its previous build are missing.
+    #[arg(long, env = "BUILDSYS_CHANGED_SINCE")]
+    pub(crate) changed_since: Option<String>,
+
+    /// Cache the packages and kits that the package depends on under the state directory, and
+    /// reuse them until the package manifest or the cargo metadata changes.
+    #[arg(long, env = "BUILDSYS_RESOLVED_PACKAGE_CACHE")]
+    pub(crate) resolved_package_cache: bool,
+
     #[command(flatten)]
     pub(crate) common: Common,
 }
 
+/// Build a set of packages in dependency order, building independent packages in parallel. Each
+/// package is built by running `build-package` in its directory, with the same environment.
+#[derive(Debug, Parser)]
+pub(crate) struct BuildPackagesArgs {
+    /// The package directories to build.
+    #[arg(required = true)]
+    pub(crate) packages: Vec<PathBuf>,
+
+    /// The most packages to build at once.
+    #[arg(long, env = "BUILDSYS_JOBS", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) jobs: u16,
+
+    #[arg(long, env = "BUILDSYS_CARGO_METADATA_PATH")]
+    pub(crate) cargo_metadata_path: PathBuf,
+}
+
 /// Place the required RPMs into a kit (directory) and make a yum repo.
 #[derive(Debug, Parser)]
 pub(crate) struct BuildKitArgs {
@@ -170,6 +590,31 @@ pub(crate) struct BuildVariantArgs {
     #[arg(long, env = "BUILDSYS_IMAGES_DIR")]
     pub(crate) image_dir: PathBuf,
 
+    /// Also tag the image as `{variant}-{arch}:{version_image}-{version_build}` so that it can
+    /// be found after the build.
+    #[arg(long, env = "BUILDSYS_VERSION_TAG")]
+    pub(crate) version_tag: bool,
+
+    /// After a successful build, write a JSON document to this file that describes the builder,
+    /// the inputs and build arguments, and checksums of the images.
+    #[arg(long, env = "BUILDSYS_PROVENANCE")]
+    pub(crate) provenance: Option<PathBuf>,
+
+    /// After a variant with the vmdk image format is built, package its disks into an OVA with a
+    /// generated OVF descriptor, unless the image build already produced one.
+    #[arg(long, env = "BUILDSYS_PACKAGE_OVA")]
+    pub(crate) package_ova: bool,
+
+    /// Build the variant even if nothing it is made from has changed since the last successful
+    /// build, and its images are still in place. Without this, such a build is skipped.
+    #[arg(long, env = "BUILDSYS_FORCE")]
+    pub(crate) force: bool,
+
+    /// After the build, check that every package the variant installs was built for this
+    /// architecture or provided by a kit, and fail if any are missing.
+    #[arg(long, env = "BUILDSYS_VERIFY_PACKAGES")]
+    pub(crate) verify_packages: bool,
+
     #[command(flatten)]
     pub(crate) common: Common,
 }
@@ -193,6 +638,226 @@ pub(crate) struct RepackVariantArgs {
     pub(crate) common: Common,
 }
 
+/// Show the differences in build settings between two variant manifests.
+#[derive(Debug, Parser)]
+pub(crate) struct DiffArgs {
+    /// The manifest to compare against.
+    pub(crate) old_manifest: PathBuf,
+
+    /// The manifest with the changes.
+    pub(crate) new_manifest: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub(crate) format: OutputFormat,
+}
+
+/// Print the build arguments and secrets that a build would pass to docker, without running it.
+#[derive(Debug, Parser)]
+pub(crate) struct ShowArgsArgs {
+    #[command(subcommand)]
+    pub(crate) command: BuildCommand,
+}
+
+/// Print the base and publish image sizes for a variant manifest, without running a build.
+#[derive(Debug, Parser)]
+pub(crate) struct ImageSizesArgs {
+    /// The variant manifest to read the image layout from.
+    pub(crate) manifest: PathBuf,
+
+    #[arg(long)]
+    pub(crate) arch: SupportedArch,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub(crate) format: OutputFormat,
+}
+
+/// Check the partition table of a built raw "os" image against the layout in a variant manifest.
+#[derive(Debug, Parser)]
+pub(crate) struct VerifyImageArgs {
+    /// The raw image to check.
+    pub(crate) image: PathBuf,
+
+    /// The variant manifest to read the image layout from.
+    #[arg(long)]
+    pub(crate) manifest: PathBuf,
+
+    #[arg(long)]
+    pub(crate) arch: SupportedArch,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub(crate) format: OutputFormat,
+}
+
+/// Remove images and stopped containers left behind by interrupted builds.
+#[derive(Debug, Parser)]
+pub(crate) struct PruneArgs {
+    /// List what would be removed without removing anything.
+    #[arg(long)]
+    pub(crate) dry_run: bool,
+}
+
+/// Remove the artifacts that earlier builds of a package, kit, or variant left in the output
+/// directory, along with the markers that track them, without running a build.
+#[derive(Debug, Parser)]
+pub(crate) struct CleanArgs {
+    #[arg(long, value_enum)]
+    pub(crate) kind: CleanKind,
+
+    /// The name that the build tracks its artifacts under, such as the package name.
+    #[arg(long)]
+    pub(crate) name: String,
+
+    #[arg(long, env = "BUILDSYS_ARCH")]
+    pub(crate) arch: SupportedArch,
+
+    #[arg(long, env = "BUILDSYS_STATE_DIR")]
+    pub(crate) state_dir: PathBuf,
+
+    #[arg(long, env = "BUILDSYS_MARKER_LAYOUT", value_enum, default_value_t)]
+    pub(crate) marker_layout: MarkerLayout,
+
+    #[arg(long, env = "BUILDSYS_PACKAGES_DIR")]
+    pub(crate) packages_dir: Option<PathBuf>,
+
+    #[arg(long, env = "BUILDSYS_KITS_DIR")]
+    pub(crate) kits_dir: Option<PathBuf>,
+
+    #[arg(long, env = "BUILDSYS_IMAGES_DIR")]
+    pub(crate) image_dir: Option<PathBuf>,
+
+    /// The directory that artifacts were copied to instead of the usual one for the build.
+    #[arg(long, env = "BUILDSYS_OUTPUT_DIR")]
+    pub(crate) output_dir: Option<PathBuf>,
+
+    /// List what would be removed without removing anything.
+    #[arg(long)]
+    pub(crate) dry_run: bool,
+}
+
+/// The kinds of build that `clean` can remove the artifacts of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CleanKind {
+    Package,
+    Kit,
+    Variant,
+}
+
+impl From<CleanKind> for BuildType {
+    fn from(kind: CleanKind) -> Self {
+        match kind {
+            CleanKind::Package => BuildType::Package,
+            CleanKind::Kit => BuildType::Kit,
+            CleanKind::Variant => BuildType::Variant,
+        }
+    }
+}
+
+/// Print the architectures that buildsys can build for, with the Go architecture for each.
+#[derive(Debug, Parser)]
+pub(crate) struct ArchesArgs {
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub(crate) format: OutputFormat,
+}
+
+/// Print a shell completion script for buildsys.
+#[derive(Debug, Parser)]
+pub(crate) struct CompletionsArgs {
+    /// The shell to generate completions for.
+    #[arg(value_enum)]
+    pub(crate) shell: Shell,
+}
+
+/// Writes the completion script for `shell` covering every buildsys subcommand.
+pub(crate) fn write_completions(shell: Shell, out: &mut dyn Write) {
+    let mut command = Buildsys::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+/// The resource limits that docker can set for a container.
+const ULIMIT_NAMES: [&str; 15] = [
+    "core",
+    "cpu",
+    "data",
+    "fsize",
+    "locks",
+    "memlock",
+    "msgqueue",
+    "nice",
+    "nofile",
+    "nproc",
+    "rss",
+    "rtprio",
+    "rttime",
+    "sigpending",
+    "stack",
+];
+
+/// A resource limit for a container, passed to docker as `--ulimit <NAME>=<SOFT>:<HARD>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Ulimit {
+    name: String,
+    soft: i64,
+    hard: i64,
+}
+
+impl FromStr for Ulimit {
+    type Err = String;
+
+    /// Parse a limit as `<NAME>=<SOFT>[:<HARD>]`. The hard limit defaults to the soft one, and
+    /// `-1` means no limit.
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (name, limits) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("ulimit '{arg}' is not of the form <NAME>=<SOFT>[:<HARD>]"))?;
+        if !ULIMIT_NAMES.contains(&name) {
+            return Err(format!(
+                "unknown ulimit '{name}', expected one of: {}",
+                ULIMIT_NAMES.join(", ")
+            ));
+        }
+        let parse = |value: &str| {
+            value
+                .parse::<i64>()
+                .ok()
+                .filter(|value| *value >= -1)
+                .ok_or_else(|| format!("invalid limit '{value}' in ulimit '{arg}'"))
+        };
+        let (soft, hard) = match limits.split_once(':') {
+            Some((soft, hard)) => (parse(soft)?, parse(hard)?),
+            None => (parse(limits)?, parse(limits)?),
+        };
+        // Unlike any number, no limit is above every other limit.
+        if hard != -1 && (soft == -1 || soft > hard) {
+            return Err(format!(
+                "soft limit is above the hard limit in ulimit '{arg}'"
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            soft,
+            hard,
+        })
+    }
+}
+
+impl fmt::Display for Ulimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}:{}", self.name, self.soft, self.hard)
+    }
+}
+
+/// Parse a fraction between 0 and 1 from the command line.
+fn parse_fraction(arg: &str) -> Result<f64, String> {
+    let value: f64 = arg
+        .parse()
+        .map_err(|e| format!("invalid fraction '{arg}': {e}"))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("fraction '{arg}' is not between 0 and 1"));
+    }
+    Ok(value)
+}
+
 /// Returns the environment variables that need to be watched for a given `[BuildType]`.
 fn sensitive_env_vars(build_type: BuildFlags) -> impl Iterator<Item = &'static str> {
     REBUILD_VARS
@@ -201,12 +866,19 @@ fn sensitive_env_vars(build_type: BuildFlags) -> impl Iterator<Item = &'static s
         .map(|(var, _)| var)
 }
 
+/// Returns the cargo directives for the list of sensitive environment variables for a given
+/// `[BuildType]`.
+fn rerun_directives(build_type: BuildType) -> Vec<String> {
+    sensitive_env_vars(build_type.into())
+        .map(|var| format!("cargo:rerun-if-env-changed={}", var))
+        .collect()
+}
+
 /// Emits the cargo directives for the list of sensitive environment variables for a given
 /// `[BuildType]`.
 pub(crate) fn rerun_for_envs(build_type: BuildType) {
-    let build_flags: BuildFlags = build_type.into();
-    for var in sensitive_env_vars(build_flags) {
-        println!("cargo:rerun-if-env-changed={}", var)
+    for directive in rerun_directives(build_type) {
+        println!("{directive}");
     }
 }
 
@@ -241,7 +913,6 @@ impl BuildFlags {
     }
 }
 
-#[allow(dead_code)]
 const REPACK: u8 = BuildFlags::Repack as u8;
 const PACKAGE: u8 = BuildFlags::Package as u8;
 const KIT: u8 = BuildFlags::Kit as u8;
@@ -286,3 +957,110 @@ fn test_sensitive_env_vars_kit() {
     assert!(list.contains(&"BUILDSYS_KITS_DIR"));
     assert!(!list.contains(&"BUILDSYS_IMAGES_DIR"));
 }
+
+#[test]
+fn test_rerun_directives_repack() {
+    assert_eq!(
+        rerun_directives(BuildType::Repack),
+        [
+            "BUILDSYS_ARCH",
+            "BUILDSYS_ARTIFACT_IGNORE",
+            "BUILDSYS_BUILD_ARG_FILE",
+            "BUILDSYS_CACERTS_BUNDLE_OVERRIDE",
+            "BUILDSYS_CARGO_METADATA_PATH",
+            "BUILDSYS_CONTEXT",
+            "BUILDSYS_CONTEXT_TAR",
+            "BUILDSYS_EXTERNAL_KITS_DIR",
+            "BUILDSYS_IMAGES_DIR",
+            "BUILDSYS_MARKER_LAYOUT",
+            "BUILDSYS_NAME",
+            "BUILDSYS_OUTPUT_DIR",
+            "BUILDSYS_OUTPUT_GENERATION_ID",
+            "BUILDSYS_ROOT_DIR",
+            "BUILDSYS_SBKEYS_PROFILE_DIR",
+            "BUILDSYS_STATE_DIR",
+            "BUILDSYS_VERSION_BUILD",
+            "BUILDSYS_VERSION_FULL",
+            "BUILDSYS_VERSION_IMAGE",
+            "PUBLISH_REPO_ROOT_JSON",
+            "TLPRIVATE_SDK_IMAGE",
+            "TWOLITER_TOOLS_DIR",
+        ]
+        .map(|var| format!("cargo:rerun-if-env-changed={var}"))
+    );
+}
+
+#[test]
+fn test_env_vars_classified() {
+    use clap::CommandFactory;
+
+    fn collect_env_vars(command: &clap::Command, vars: &mut Vec<String>) {
+        vars.extend(
+            command
+                .get_arguments()
+                .filter_map(|arg| arg.get_env())
+                .map(|env| env.to_string_lossy().to_string()),
+        );
+        for subcommand in command.get_subcommands() {
+            collect_env_vars(subcommand, vars);
+        }
+    }
+
+    let mut vars = Vec::new();
+    collect_env_vars(&Buildsys::command(), &mut vars);
+    assert!(!vars.is_empty());
+    for var in vars {
+        assert!(
+            REBUILD_VARS.iter().any(|(name, _)| *name == var)
+                || NON_REBUILD_VARS.contains(&var.as_str()),
+            "{var} is not listed in REBUILD_VARS or NON_REBUILD_VARS"
+        );
+    }
+
+    // Each variable is listed once, in one place.
+    let mut listed = REBUILD_VARS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(NON_REBUILD_VARS)
+        .collect::<Vec<_>>();
+    let count = listed.len();
+    listed.sort();
+    listed.dedup();
+    assert_eq!(listed.len(), count);
+}
+
+#[test]
+fn test_parse_fraction() {
+    assert_eq!(parse_fraction("0.25"), Ok(0.25));
+    assert_eq!(parse_fraction("1"), Ok(1.0));
+    assert!(parse_fraction("1.5").is_err());
+    assert!(parse_fraction("-0.1").is_err());
+    assert!(parse_fraction("half").is_err());
+}
+
+#[test]
+fn test_parse_ulimit() {
+    let ulimit = |arg: &str| arg.parse::<Ulimit>().map(|ulimit| ulimit.to_string());
+    assert_eq!(ulimit("nofile=1024:65536").unwrap(), "nofile=1024:65536");
+    assert_eq!(ulimit("stack=8388608").unwrap(), "stack=8388608:8388608");
+    assert_eq!(ulimit("memlock=-1:-1").unwrap(), "memlock=-1:-1");
+    assert_eq!(ulimit("core=0:-1").unwrap(), "core=0:-1");
+    assert!(ulimit("files=1024").is_err());
+    assert!(ulimit("nofile").is_err());
+    assert!(ulimit("nofile=").is_err());
+    assert!(ulimit("nofile=many").is_err());
+    assert!(ulimit("nofile=-2").is_err());
+    assert!(ulimit("nofile=4096:1024").is_err());
+    assert!(ulimit("nofile=-1:1024").is_err());
+}
+
+#[test]
+fn test_write_completions() {
+    for shell in Shell::value_variants() {
+        let mut out = Vec::new();
+        write_completions(*shell, &mut out);
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("buildsys"), "{shell}");
+        assert!(script.contains("build-package"), "{shell}");
+    }
+}
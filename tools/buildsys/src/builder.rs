@@ -6,30 +6,51 @@ the repository's top-level Dockerfile.
 */
 pub(crate) mod error;
 
-use crate::args::{BuildKitArgs, BuildPackageArgs, BuildVariantArgs, RepackVariantArgs};
+use crate::args::{
+    BuildKitArgs, BuildPackageArgs, BuildVariantArgs, CleanArgs, Common, DuplicateBuildArgs,
+    MarkerLayout, RepackVariantArgs, Ulimit,
+};
+use crate::command::{run_command, OutputLimits, Retry, SyncRetry};
+use crate::ova::{self, OvaRequest};
+use crate::project::ProjectInfo;
+use crate::provenance::{Inputs, Provenance, ProvenanceRequest, SdkImage};
+use crate::repro::ArtifactHashes;
+use crate::resolved::{ResolvedPackage, ResolvedPackageCache};
+use crate::snapshot::RootSnapshot;
+use crate::timings::{BuildPhase, PhaseTimings};
+use crate::uptodate::UpToDateRecord;
 use bottlerocket_variant::Variant;
 use buildsys::manifest::{
-    ExternalKitMetadataView, ImageFeature, ImageFormat, ImageLayout, Manifest, PartitionPlan,
-    SupportedArch,
+    ExternalKitMetadataView, ImageFeature, ImageFormat, ImageLayout, IncludedPackage, Manifest,
+    ManifestInfo, PartitionPlan, SupportedArch,
 };
 use buildsys::BuildType;
 use buildsys_config::EXTERNAL_KIT_METADATA;
 use duct::cmd;
 use error::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use lazy_static::lazy_static;
 use nonzero_ext::nonzero;
-use pipesys::server::Server as PipesysServer;
+use pipesys::server::{Listener, Server as PipesysServer, UidMap};
 use rand::Rng;
 use regex::Regex;
+use serde::Serialize;
 use sha2::{Digest, Sha512};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::fmt;
 use std::fs::{self, read_dir, File};
+use std::io::{self, Write};
 use std::num::NonZeroU16;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Output;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 use walkdir::{DirEntry, WalkDir};
 
 /*
@@ -73,7 +94,7 @@ We use (?m) for multi-line mode so we can match the message on a line of its own
 the output ourselves; we match the regexes against the whole of stdout.
 */
 lazy_static! {
-    static ref UNEXPECTED_EOF_ERROR: Regex = Regex::new("(?m)unexpected EOF$").unwrap();
+    pub(crate) static ref UNEXPECTED_EOF_ERROR: Regex = Regex::new("(?m)unexpected EOF$").unwrap();
 }
 
 /*
@@ -81,16 +102,83 @@ Sometimes new RPMs are not fully written to the host directory before another bu
 exposes `createrepo_c` to partially-written RPMs that cannot be added to the repo metadata. Retry
 these errors by restarting the build since the alternatives are to ignore the `createrepo_c` exit
 code (masking other problems) or aggressively `sync()` the host directory (hurting performance).
+
+As a middle ground, builds can opt in to syncing the host directory once, only after this error is
+seen, before the first retry. If that retry also fails, we fall back to the usual retries.
 */
 lazy_static! {
-    static ref CREATEREPO_C_READ_HEADER_ERROR: Regex = Regex::new(&regex::escape(
+    pub(crate) static ref CREATEREPO_C_READ_HEADER_ERROR: Regex = Regex::new(&regex::escape(
         r#"C_CREATEREPOLIB: Warning: read_header: rpmReadPackageFile() error"#
     ))
     .unwrap();
 }
 
+/// The transient failures that a docker build is retried for, unless the caller adds more.
+pub(crate) fn default_retry_patterns() -> [&'static Regex; 4] {
+    [
+        &DOCKER_BUILD_FRONTEND_ERROR,
+        &DOCKER_BUILD_DEAD_RECORD_ERROR,
+        &UNEXPECTED_EOF_ERROR,
+        &CREATEREPO_C_READ_HEADER_ERROR,
+    ]
+}
+
 static DOCKER_BUILD_MAX_ATTEMPTS: NonZeroU16 = nonzero!(10u16);
 
+/// How long to wait for the bypass container to start serving the project root.
+const BYPASS_START_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often to check whether the bypass container has started.
+const BYPASS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many names to try for the output socket, if another server already holds one.
+const OUTPUT_SOCKET_BIND_ATTEMPTS: usize = 3;
+
+/// How long to wait before retrying a failed build, before jitter is applied.
+const DOCKER_BUILD_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Where a pipesys binary from the host is mounted in the bypass container, which is on its `PATH`.
+const PIPESYS_IMAGE_PATH: &str = "/usr/local/bin/pipesys";
+
+/// The context argument for docker, and the path for a context archive, that mean stdin.
+const STDIN_CONTEXT: &str = "-";
+
+/// The registry hosts that Docker Hub images may be referenced by.
+const DOCKER_HUB_HOSTS: [&str; 3] = ["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+/// The image label that records which SDK image the build used.
+const SDK_LABEL: &str = "org.bottlerocket.buildsys.sdk";
+
+/// Build arguments that are always set by buildsys, either directly in the build command or for
+/// every type of build, and which must not be overridden by a manifest.
+const RESERVED_BUILD_ARGS: [&str; 9] = [
+    "ARCH",
+    "BUILDER_UID",
+    "BYPASS_SOCKET",
+    "GOARCH",
+    "NOCACHE",
+    "OUTPUT_SOCKET",
+    "OUTPUT_SOCKET_TOKEN",
+    "SDK",
+    "TOKEN",
+];
+
+/// Flags for the bypass container that buildsys sets itself, and which must not be overridden.
+const RESERVED_BYPASS_RUN_FLAGS: [&str; 12] = [
+    "--name",
+    "--rm",
+    "--net",
+    "--network",
+    "--pid",
+    "-u",
+    "--user",
+    "-v",
+    "--volume",
+    "--mount",
+    "--volumes-from",
+    "--read-only",
+];
+
 // Expected UID for privileged and unprivileged processes inside the build container.
 const ROOT_UID: u32 = 0;
 lazy_static! {
@@ -104,13 +192,34 @@ enum OutputCleanup {
     None,
 }
 
+/// How artifacts get from the build directory to the output directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArtifactTransfer {
+    /// Rename each artifact into place.
+    Move,
+    /// Copy each artifact, and leave the original in the build directory for debugging.
+    Copy,
+}
+
+impl ArtifactTransfer {
+    fn new(copy_not_move: bool) -> Self {
+        if copy_not_move {
+            ArtifactTransfer::Copy
+        } else {
+            ArtifactTransfer::Move
+        }
+    }
+}
+
 struct CommonBuildArgs {
     arch: SupportedArch,
     sdk: String,
+    registry_mirror: Option<String>,
     nocache: String,
     token: String,
     cleanup: OutputCleanup,
     output_socket: String,
+    output_token: String,
 }
 
 impl CommonBuildArgs {
@@ -119,25 +228,56 @@ impl CommonBuildArgs {
         sdk: String,
         arch: SupportedArch,
         cleanup: OutputCleanup,
+    ) -> Self {
+        Self::with_rng(root, sdk, arch, cleanup, &mut rand::thread_rng())
+    }
+
+    /// Create the arguments, drawing the random values from `rng`. Tests use a seeded generator
+    /// so that the values are predictable.
+    fn with_rng(
+        root: impl AsRef<Path>,
+        sdk: String,
+        arch: SupportedArch,
+        cleanup: OutputCleanup,
+        rng: &mut impl Rng,
     ) -> Self {
         let token = token(&root);
 
-        // Avoid using a cached layer from a previous build.
-        let nocache = rand::thread_rng().gen::<u128>().to_string();
+        // Avoid using a cached layer from a previous build. Unless a random value is requested,
+        // this is replaced with a hash of the build inputs once they are known.
+        let nocache = random_nocache(rng);
 
         // Generate a unique address for the socket that sends the output directory file
-        // descriptor.
-        let output_socket = format!("buildsys-output-{token}-{nocache}");
+        // descriptor. This must differ even for builds with the same inputs.
+        let output_socket = output_socket_name(&token, rng);
+
+        // The output servers only serve a client that presents this, so that another build
+        // running as the same user cannot fetch this build's output directory.
+        let output_token = format!("{:032x}", rng.gen::<u128>());
 
         Self {
             arch,
             sdk,
+            registry_mirror: None,
             nocache,
             token,
             cleanup,
             output_socket,
+            output_token,
         }
     }
+
+    /// Pull the SDK through `mirror`, if one is given.
+    fn with_registry_mirror(mut self, mirror: Option<&str>) -> Self {
+        self.registry_mirror = mirror.map(str::to_string);
+        self
+    }
+
+    /// The reference used to pull and run the SDK, which goes through the registry mirror if
+    /// there is one.
+    fn sdk_pull(&self) -> String {
+        pull_image(&self.sdk, self.registry_mirror.as_deref())
+    }
 }
 
 struct PackageBuildArgs {
@@ -195,6 +335,7 @@ struct VariantBuildArgs {
     external_kit_dependencies: Vec<String>,
     data_image_publish_size_gib: i32,
     data_image_size_gib: String,
+    data_volumes: String,
     image_features: HashSet<ImageFeature>,
     image_format: String,
     kernel_parameters: String,
@@ -202,6 +343,8 @@ struct VariantBuildArgs {
     os_image_publish_size_gib: String,
     os_image_size_gib: String,
     packages: String,
+    /// Whether to check that every package in `packages` was built, once the build is done.
+    verify_packages: bool,
     partition_plan: String,
     pretty_name: String,
     variant: String,
@@ -222,6 +365,7 @@ impl VariantBuildArgs {
         );
         args.build_arg("BUILD_ID", &self.version_build);
         args.build_arg("DATA_IMAGE_SIZE_GIB", &self.data_image_size_gib);
+        args.build_arg("DATA_VOLUMES", &self.data_volumes);
         args.build_arg("IMAGE_FORMAT", &self.image_format);
         args.build_arg("IMAGE_NAME", &self.name);
         args.build_arg("KERNEL_PARAMETERS", &self.kernel_parameters);
@@ -254,6 +398,7 @@ impl VariantBuildArgs {
 struct RepackVariantBuildArgs {
     data_image_publish_size_gib: i32,
     data_image_size_gib: String,
+    data_volumes: String,
     image_features: HashSet<ImageFeature>,
     image_format: String,
     name: String,
@@ -275,6 +420,7 @@ impl RepackVariantBuildArgs {
             self.data_image_publish_size_gib.to_string(),
         );
         args.build_arg("DATA_IMAGE_SIZE_GIB", &self.data_image_size_gib);
+        args.build_arg("DATA_VOLUMES", &self.data_volumes);
         args.build_arg("IMAGE_FORMAT", &self.image_format);
         args.build_arg("IMAGE_NAME", &self.name);
         args.build_arg("OS_IMAGE_PUBLISH_SIZE_GIB", &self.os_image_publish_size_gib);
@@ -301,6 +447,15 @@ enum TargetBuildArgs {
 }
 
 impl TargetBuildArgs {
+    fn build_args(&self) -> Vec<String> {
+        match self {
+            TargetBuildArgs::Package(p) => p.build_args(),
+            TargetBuildArgs::Kit(k) => k.build_args(),
+            TargetBuildArgs::Variant(v) => v.build_args(),
+            TargetBuildArgs::Repack(r) => r.build_args(),
+        }
+    }
+
     pub(crate) fn build_type(&self) -> BuildType {
         match self {
             TargetBuildArgs::Package(_) => BuildType::Package,
@@ -314,14 +469,66 @@ impl TargetBuildArgs {
 pub(crate) struct DockerBuild {
     dockerfile: PathBuf,
     context: PathBuf,
+    context_tar: Option<PathBuf>,
     target: String,
     tag: String,
+    extra_tags: Vec<String>,
     root_dir: PathBuf,
     artifacts_dirs: Vec<PathBuf>,
     state_dir: PathBuf,
+    marker_layout: MarkerLayout,
+    duplicate_build_args: DuplicateBuildArgs,
     artifact_name: String,
+    max_artifacts: usize,
+    artifact_ignore: GlobSet,
+    artifact_transfer: ArtifactTransfer,
+    quiet: bool,
+    sync_rpms_on_retry: bool,
+    fail_fast: bool,
+    retry_jitter: f64,
+    retry_patterns: Vec<Regex>,
+    backup_output_socket: bool,
+    no_bypass: bool,
+    bypass_run_flags: Vec<String>,
+    writable_bypass: bool,
+    ulimits: Vec<Ulimit>,
+    pipesys: PipesysBin,
+    snapshot: Option<RootSnapshot>,
+    uid_map: Option<UidMap>,
+    output_limits: OutputLimits,
+    build_log_dir: Option<PathBuf>,
+    build_timings: Option<PathBuf>,
+    emit_names: Option<PathBuf>,
+    /// A digest of everything a variant is built from, if the build may be skipped when the last
+    /// successful one had the same digest.
+    input_digest: Option<String>,
+    compress_logs: bool,
+    repro_manifest: Option<PathBuf>,
+    repro_check: Option<PathBuf>,
+    checksum_jobs: usize,
+    provenance: Option<ProvenanceRequest>,
+    ova: Option<OvaRequest>,
     common_build_args: CommonBuildArgs,
     target_build_args: TargetBuildArgs,
+    manifest_build_args: BTreeMap<String, String>,
+    file_build_args: BTreeMap<String, String>,
+    secrets_args: Vec<String>,
+}
+
+/// The parts of a `DockerBuild` that depend on what is being built. Everything else is taken from
+/// the common arguments.
+struct BuildTarget {
+    /// The Dockerfile stage to build.
+    target: &'static str,
+    /// The image tag, before the root directory token is appended.
+    tag: String,
+    artifact_name: String,
+    artifacts_dirs: Vec<PathBuf>,
+    sdk: String,
+    cleanup: OutputCleanup,
+    no_bypass: bool,
+    target_build_args: TargetBuildArgs,
+    manifest_build_args: BTreeMap<String, String>,
     secrets_args: Vec<String>,
 }
 
@@ -329,71 +536,73 @@ impl DockerBuild {
     /// Create a new `DockerBuild` that can build a package.
     pub(crate) fn new_package(args: BuildPackageArgs, manifest: &Manifest) -> Result<Self> {
         let package = manifest.info().package_name();
-        let per_package_dir = format!("{}/{}", args.packages_dir.display(), package).into();
-        let old_package_dir = format!("{}", args.packages_dir.display()).into();
 
-        Ok(Self {
-            dockerfile: args.common.tools_dir.join("build.Dockerfile"),
-            context: args.common.root_dir.clone(),
-            target: "package".to_string(),
-            tag: append_token(
-                format!(
-                    "buildsys-pkg-{package}-{arch}",
-                    package = package,
-                    arch = args.common.arch,
-                ),
-                &args.common.root_dir,
+        let resolved = if args.resolved_package_cache {
+            ResolvedPackageCache::new(&args.common.state_dir).get_or_resolve(
+                manifest.info().manifest_name(),
+                &args.common.cargo_manifest_dir.join("Cargo.toml"),
+                &args.common.cargo_metadata_path,
+                || ResolvedPackage::resolve(manifest),
+            )
+        } else {
+            ResolvedPackage::resolve(manifest)
+        }
+        .context(error::ResolvedPackageSnafu)?;
+
+        let target = BuildTarget {
+            target: "package",
+            tag: format!(
+                "buildsys-pkg-{package}-{arch}",
+                package = package,
+                arch = args.common.arch,
             ),
-            root_dir: args.common.root_dir.clone(),
-            artifacts_dirs: vec![per_package_dir, old_package_dir],
-            state_dir: args.common.state_dir,
             artifact_name: package.to_string(),
-            common_build_args: CommonBuildArgs::new(
-                &args.common.root_dir,
-                args.common.sdk_image,
-                args.common.arch,
-                OutputCleanup::BeforeBuild,
+            artifacts_dirs: artifacts_dirs(
+                &BuildType::Package,
+                package,
+                &args.common.arch.to_string(),
+                &args.packages_dir,
             ),
+            sdk: sdk_image(&args.common, manifest.info()),
+            cleanup: OutputCleanup::BeforeBuild,
+            no_bypass: no_bypass(&args.common, manifest.info()),
             target_build_args: TargetBuildArgs::Package(PackageBuildArgs {
                 package: package.to_string(),
-                package_dependencies: manifest.package_dependencies().context(error::GraphSnafu)?,
-                kit_dependencies: manifest.kit_dependencies().context(error::GraphSnafu)?,
-                external_kit_dependencies: ExternalKitMetadataView::load(args.common.root_dir)
+                package_dependencies: resolved.package_dependencies,
+                kit_dependencies: resolved.kit_dependencies,
+                external_kit_dependencies: ExternalKitMetadataView::load(&args.common.root_dir)
                     .context(error::GraphSnafu)?
                     .list(),
                 version_build: args.version_build,
                 version_build_timestamp: args.version_build_timestamp,
             }),
-            secrets_args: Vec::new(),
-        })
+            manifest_build_args: manifest.info().build_args().cloned().unwrap_or_default(),
+            secrets_args: forward_env_secrets(&args.common.forward_env, env_is_set),
+        };
+
+        Self::common(args.common, target)?.validated()
     }
 
     pub(crate) fn new_kit(args: BuildKitArgs, manifest: &Manifest) -> Result<Self> {
         let kit = manifest.info().kit_name();
-        let per_kit_dir = args.kits_dir.join(kit);
 
-        Ok(Self {
-            dockerfile: args.common.tools_dir.join("build.Dockerfile"),
-            context: args.common.root_dir.clone(),
-            target: "kit".to_string(),
-            tag: append_token(
-                format!(
-                    "buildsys-kit-{kit}-{arch}",
-                    kit = kit,
-                    arch = args.common.arch,
-                ),
-                &args.common.root_dir,
+        let target = BuildTarget {
+            target: "kit",
+            tag: format!(
+                "buildsys-kit-{kit}-{arch}",
+                kit = kit,
+                arch = args.common.arch,
             ),
-            root_dir: args.common.root_dir.clone(),
-            artifacts_dirs: vec![per_kit_dir],
-            state_dir: args.common.state_dir,
             artifact_name: kit.to_string(),
-            common_build_args: CommonBuildArgs::new(
-                &args.common.root_dir,
-                args.common.sdk_image,
-                args.common.arch,
-                OutputCleanup::BeforeBuild,
+            artifacts_dirs: artifacts_dirs(
+                &BuildType::Kit,
+                kit,
+                &args.common.arch.to_string(),
+                &args.kits_dir,
             ),
+            sdk: args.common.sdk_image.clone(),
+            cleanup: OutputCleanup::BeforeBuild,
+            no_bypass: args.common.no_bypass,
             target_build_args: TargetBuildArgs::Kit(KitBuildArgs {
                 kit: kit.to_string(),
                 vendor: manifest.info().kit_vendor().context(error::GraphSnafu)?,
@@ -403,8 +612,11 @@ impl DockerBuild {
                 version_build: args.version_build,
                 version_id: args.version_image,
             }),
-            secrets_args: Vec::new(),
-        })
+            manifest_build_args: BTreeMap::new(),
+            secrets_args: forward_env_secrets(&args.common.forward_env, env_is_set),
+        };
+
+        Self::common(args.common, target)?.validated()
     }
 
     /// Create a new `DockerBuild` that can build a variant image.
@@ -420,7 +632,30 @@ impl DockerBuild {
         let (os_image_publish_size_gib, data_image_publish_size_gib) =
             image_layout.publish_image_sizes_gib();
 
-        let variant = filename(args.common.cargo_manifest_dir);
+        // A reproducibility check needs a fresh build to compare.
+        let force = args.force || args.common.repro_check.is_some();
+        let sources = vec![
+            dockerfile(&args.common),
+            args.common.cargo_manifest_dir.clone(),
+        ];
+        let provenance = args.provenance.clone().map(|path| ProvenanceRequest {
+            path,
+            manifest: args.common.cargo_manifest_dir.join("Cargo.toml"),
+            sources: sources.clone(),
+        });
+        let ova = if args.package_ova {
+            ensure!(
+                matches!(manifest.info().image_format(), Some(ImageFormat::Vmdk)),
+                error::OvaFormatSnafu
+            );
+            Some(OvaRequest::new(
+                os_image_publish_size_gib,
+                data_image_publish_size_gib,
+            ))
+        } else {
+            None
+        };
+        let variant = filename(&args.common.cargo_manifest_dir);
 
         let v = Variant::new(&variant).context(error::VariantParseSnafu)?;
         let variant_platform = v.platform().into();
@@ -428,34 +663,38 @@ impl DockerBuild {
         let variant_family = v.family().into();
         let variant_flavor = v.variant_flavor().unwrap_or("").into();
 
-        Ok(Self {
-            dockerfile: args.common.tools_dir.join("build.Dockerfile"),
-            context: args.common.root_dir.clone(),
-            target: "variant".to_string(),
-            tag: append_token(
-                format!("buildsys-var-{variant}-{arch}", arch = args.common.arch),
-                &args.common.root_dir,
-            ),
-            root_dir: args.common.root_dir.clone(),
-            artifacts_dirs: vec![args
-                .image_dir
-                .join(format!("{}-{}", args.common.arch, variant))],
-            state_dir: args.common.state_dir,
-            artifact_name: variant.clone(),
-            common_build_args: CommonBuildArgs::new(
-                &args.common.root_dir,
-                args.common.sdk_image,
+        let mut extra_tags = Vec::new();
+        if args.version_tag {
+            extra_tags.push(version_tag(
+                &variant,
                 args.common.arch,
-                OutputCleanup::BeforeBuild,
+                &args.version_image,
+                &args.version_build,
+            ));
+        }
+
+        let target = BuildTarget {
+            target: "variant",
+            tag: format!("buildsys-var-{variant}-{arch}", arch = args.common.arch),
+            artifact_name: variant.clone(),
+            artifacts_dirs: artifacts_dirs(
+                &BuildType::Variant,
+                &variant,
+                &args.common.arch.to_string(),
+                &args.image_dir,
             ),
+            sdk: sdk_image(&args.common, manifest.info()),
+            cleanup: OutputCleanup::BeforeBuild,
+            no_bypass: args.common.no_bypass,
             target_build_args: TargetBuildArgs::Variant(VariantBuildArgs {
                 package_dependencies: manifest.package_dependencies().context(error::GraphSnafu)?,
                 kit_dependencies: manifest.kit_dependencies().context(error::GraphSnafu)?,
-                external_kit_dependencies: ExternalKitMetadataView::load(args.common.root_dir)
+                external_kit_dependencies: ExternalKitMetadataView::load(&args.common.root_dir)
                     .context(error::GraphSnafu)?
                     .list(),
                 data_image_publish_size_gib,
                 data_image_size_gib: data_image_size_gib.to_string(),
+                data_volumes: data_volumes(&image_layout),
                 image_features: manifest.info().image_features().unwrap_or_default(),
                 image_format: match manifest.info().image_format() {
                     Some(ImageFormat::Raw) | None => "raw",
@@ -472,12 +711,8 @@ impl DockerBuild {
                 name: args.name,
                 os_image_publish_size_gib: os_image_publish_size_gib.to_string(),
                 os_image_size_gib: os_image_size_gib.to_string(),
-                packages: manifest
-                    .info()
-                    .included_packages()
-                    .cloned()
-                    .unwrap_or_default()
-                    .join(" "),
+                packages: effective_packages(manifest.info(), args.common.arch)?.join(" "),
+                verify_packages: args.verify_packages,
                 partition_plan: match partition_plan {
                     PartitionPlan::Split => "split",
                     PartitionPlan::Unified => "unified",
@@ -492,8 +727,15 @@ impl DockerBuild {
                 version_build: args.version_build,
                 version_image: args.version_image,
             }),
-            secrets_args: secrets_args()?,
-        })
+            manifest_build_args: manifest.info().build_args().cloned().unwrap_or_default(),
+            secrets_args: secrets_args(&args.common.forward_env)?,
+        };
+
+        let mut build = Self::common(args.common, target)?;
+        build.extra_tags = extra_tags;
+        build.provenance = provenance;
+        build.ova = ova;
+        build.with_up_to_date_check(force, &sources)?.validated()
     }
 
     /// Create a new `DockerBuild` that can repackage a variant image.
@@ -509,31 +751,25 @@ impl DockerBuild {
         let (os_image_publish_size_gib, data_image_publish_size_gib) =
             image_layout.publish_image_sizes_gib();
 
-        let variant = filename(args.common.cargo_manifest_dir);
+        let variant = filename(&args.common.cargo_manifest_dir);
 
-        Ok(Self {
-            dockerfile: args.common.tools_dir.join("build.Dockerfile"),
-            context: args.common.root_dir.clone(),
-            target: "repack".to_string(),
-            tag: append_token(
-                format!("buildsys-repack-{variant}-{arch}", arch = args.common.arch),
-                &args.common.root_dir,
-            ),
-            root_dir: args.common.root_dir.clone(),
-            artifacts_dirs: vec![args
-                .image_dir
-                .join(format!("{}-{}", args.common.arch, variant))],
-            state_dir: args.common.state_dir,
+        let target = BuildTarget {
+            target: "repack",
+            tag: format!("buildsys-repack-{variant}-{arch}", arch = args.common.arch),
             artifact_name: variant.clone(),
-            common_build_args: CommonBuildArgs::new(
-                &args.common.root_dir,
-                args.common.sdk_image,
-                args.common.arch,
-                OutputCleanup::None,
+            artifacts_dirs: artifacts_dirs(
+                &BuildType::Variant,
+                &variant,
+                &args.common.arch.to_string(),
+                &args.image_dir,
             ),
+            sdk: sdk_image(&args.common, manifest.info()),
+            cleanup: OutputCleanup::None,
+            no_bypass: args.common.no_bypass,
             target_build_args: TargetBuildArgs::Repack(RepackVariantBuildArgs {
                 data_image_publish_size_gib,
                 data_image_size_gib: data_image_size_gib.to_string(),
+                data_volumes: data_volumes(&image_layout),
                 image_features: manifest.info().image_features().unwrap_or_default(),
                 image_format: match manifest.info().image_format() {
                     Some(ImageFormat::Raw) | None => "raw",
@@ -553,14 +789,110 @@ impl DockerBuild {
                 version_build: args.version_build,
                 version_image: args.version_image,
             }),
-            secrets_args: secrets_args()?,
-        })
+            manifest_build_args: manifest.info().build_args().cloned().unwrap_or_default(),
+            secrets_args: secrets_args(&args.common.forward_env)?,
+        };
+
+        Self::common(args.common, target)?.validated()
+    }
+
+    /// Set up the parts of a build that every target shares, from the common arguments.
+    fn common(common: Common, target: BuildTarget) -> Result<Self> {
+        let dockerfile = dockerfile(&common);
+        let snapshot = root_snapshot(&common, &dockerfile)?;
+        let context = build_context(&common)?;
+        let context_tar = context_tar(&common)?;
+        let pipesys = PipesysBin::new(&common)?;
+        let file_build_args = file_build_args(&common)?;
+
+        Ok(Self {
+            dockerfile,
+            context,
+            context_tar,
+            target: target.target.to_string(),
+            tag: append_token(target.tag, &common.root_dir),
+            extra_tags: Vec::new(),
+            root_dir: common.root_dir.clone(),
+            artifacts_dirs: target.artifacts_dirs,
+            state_dir: common.state_dir,
+            marker_layout: common.marker_layout,
+            duplicate_build_args: common.duplicate_build_args,
+            artifact_name: target.artifact_name,
+            max_artifacts: common.max_artifacts,
+            artifact_ignore: artifact_ignore(&common.artifact_ignore)?,
+            artifact_transfer: ArtifactTransfer::new(common.copy_not_move),
+            quiet: common.quiet,
+            sync_rpms_on_retry: common.sync_rpms_on_retry,
+            fail_fast: common.fail_fast,
+            retry_jitter: common.retry_jitter,
+            retry_patterns: Vec::new(),
+            backup_output_socket: common.backup_output_socket,
+            no_bypass: target.no_bypass,
+            bypass_run_flags: common.bypass_run_flags.clone(),
+            writable_bypass: common.writable_bypass,
+            ulimits: common.build_ulimits.clone(),
+            pipesys,
+            snapshot,
+            uid_map: common.uid_map,
+            output_limits: OutputLimits::new(
+                common.max_output_bytes,
+                common.output_stall_timeout_secs,
+            ),
+            build_log_dir: common.build_log_dir.clone(),
+            build_timings: common.build_timings.clone(),
+            emit_names: common.emit_names.clone(),
+            input_digest: None,
+            compress_logs: common.compress_logs,
+            repro_manifest: common.repro_manifest.clone(),
+            repro_check: common.repro_check.clone(),
+            checksum_jobs: checksum_jobs(common.checksum_jobs),
+            provenance: None,
+            ova: None,
+            common_build_args: CommonBuildArgs::new(
+                &common.root_dir,
+                target.sdk,
+                common.arch,
+                target.cleanup,
+            )
+            .with_registry_mirror(common.registry_mirror.as_deref()),
+            target_build_args: target.target_build_args,
+            manifest_build_args: target.manifest_build_args,
+            file_build_args,
+            secrets_args: target.secrets_args,
+        }
+        .with_output_dir(common.output_dir)?
+        .with_retry_patterns(common.retry_patterns))
+    }
+
+    pub(crate) fn build(&mut self) -> Result<()> {
+        self.build_with_progress(None)
+    }
+
+    /// Returns true if a previous build left artifacts behind, and every artifact it tracked is
+    /// still in the output directory.
+    pub(crate) fn has_artifacts(&self) -> bool {
+        let marker_dir = marker_dir(
+            &self.target_build_args.build_type(),
+            &self.artifact_name,
+            &self.common_build_args.arch.to_string(),
+            &self.state_dir,
+            self.marker_layout,
+        );
+        let mut markers = find_files(&marker_dir, has_markers).peekable();
+        markers.peek().is_some()
+            && markers.all(|marker_file| {
+                marker_file
+                    .strip_prefix(&marker_dir)
+                    .map(|path| self.artifacts_dirs[0].join(path).with_extension(""))
+                    .is_ok_and(|artifact| artifact.exists())
+            })
     }
 
-    pub(crate) fn build(&self) -> Result<()> {
-        env::set_current_dir(&self.root_dir).context(error::DirectoryChangeSnafu {
-            path: &self.root_dir,
-        })?;
+    /// Run the build, reporting each step to the progress callback, if one is provided.
+    pub(crate) fn build_with_progress(&mut self, progress: Option<ProgressCallback>) -> Result<()> {
+        let started = Instant::now();
+        let mut progress = progress.unwrap_or_else(|| Box::new(|_| {}));
+        let mut timings = PhaseTimings::default();
 
         // Create a directory for tracking outputs before we move them into position.
         let marker_dir = create_marker_dir(
@@ -568,8 +900,23 @@ impl DockerBuild {
             &self.artifact_name,
             &self.common_build_args.arch.to_string(),
             &self.state_dir,
+            self.marker_layout,
         )?;
 
+        // Skip the build if nothing it is made from has changed, and its artifacts are intact.
+        let record = UpToDateRecord::new(&marker_dir);
+        if let Some(digest) = &self.input_digest {
+            if record.matches(digest, &self.artifacts_dirs[0], self.checksum_jobs) {
+                println!(
+                    "cargo:warning={} is up to date, skipping the build (use --force to rebuild)",
+                    self.artifact_name
+                );
+                progress(BuildEvent::UpToDate);
+                return Ok(());
+            }
+        }
+        record.remove().context(error::UpToDateSnafu)?;
+
         // Clean up any previous outputs we have tracked.
         match self.common_build_args.cleanup {
             OutputCleanup::BeforeBuild => {
@@ -577,196 +924,925 @@ impl DockerBuild {
             }
             OutputCleanup::None => (),
         }
+        finish_phase(&mut timings, BuildPhase::Cleanup, started, &mut *progress);
 
-        let mut build = format!(
-            "build {context} \
-            --target {target} \
-            --tag {tag} \
-            --network host \
-            --file {dockerfile} \
-            --no-cache-filter rpmbuild,kitbuild,repobuild,imgbuild,migrationbuild,kmodkitbuild,imgrepack \
-            --build-arg BYPASS_SOCKET={tag}-bypass \
-            --build-arg BUILDER_UID={uid}",
-            context = self.context.display(),
-            dockerfile = self.dockerfile.display(),
-            target = self.target,
-            tag = self.tag,
-            uid = *BUILDER_UID,
-        )
-        .split_string();
+        let bypass = self.bypass_commands();
 
-        build.extend(self.build_args());
-        build.extend(self.secrets_args.clone());
+        // Copy the build output to a log file, if requested.
+        let mut build_log = self.build_log()?;
 
-        // Run a container with the project's root as a read-only volume mount, so that pipesys can
-        // serve a read-only file descriptor that's safe to pass into builds.
-        let run_bypass = format!(
-            "run \
-            --name {tag}-bypass \
-            --rm \
-            --init \
-            --net host \
-            --pid host \
-            -u {uid} \
-            -v {root}:/bypass:ro \
-            -v {root}/build/tools/pipesys:/usr/local/bin/pipesys:ro \
-            {sdk} \
-            pipesys serve --socket {tag}-bypass --client-uid {uid} --path /bypass",
-            tag = self.tag,
-            root = self.root_dir.display(),
-            sdk = self.common_build_args.sdk,
-            uid = ROOT_UID,
-        )
-        .split_string();
+        // Save a context archive from stdin before starting anything that would need cleaning up.
+        // Everything from here on is undone by the cleanup guard, however the build ends.
+        let context_tar = self.saved_context_tar()?;
+        let mut cleanup = BuildCleanup {
+            saved_context_tar: context_tar
+                .clone()
+                .filter(|_| self.context_tar.as_deref() == Some(Path::new(STDIN_CONTEXT))),
+            snapshot: None,
+            runtime: None,
+        };
 
         let rm_image = format!("rmi --force {}", self.tag).split_string();
-        let rm_bypass = format!("rm --force {}-bypass", self.tag).split_string();
 
         // Clean up the previous image if it exists.
-        let _ = docker(&rm_image, Retry::No);
+        let cleanup_started = Instant::now();
+        let _ = docker(&rm_image, &self.root_dir, Retry::No, self.quiet);
 
         // Clean up the stopped bypass container if it exists.
-        let _ = docker(&rm_bypass, Retry::No);
+        if let Some(BypassCommands { rm, .. }) = &bypass {
+            let _ = docker(rm, &self.root_dir, Retry::No, self.quiet);
+        }
+        finish_phase(
+            &mut timings,
+            BuildPhase::Cleanup,
+            cleanup_started,
+            &mut *progress,
+        );
 
-        let runtime = tokio::runtime::Runtime::new().context(error::AsyncRuntimeSnafu)?;
+        // Take the snapshot of the project root before the build or the bypass container can read
+        // it.
+        if let Some(snapshot) = &self.snapshot {
+            let snapshot_started = Instant::now();
+            cleanup.snapshot = Some((snapshot.clone(), self.tag.clone()));
+            snapshot.take(&self.tag).context(error::SnapshotSnafu)?;
+            finish_phase(
+                &mut timings,
+                BuildPhase::Snapshot,
+                snapshot_started,
+                &mut *progress,
+            );
+        }
 
-        // Spawn a background task to share the file descriptors for the output directory.
-        let output_socket = self.common_build_args.output_socket.clone();
-        let output_dir = marker_dir.clone();
-        runtime.spawn(async move {
-            PipesysServer::for_path(output_socket, ROOT_UID, &output_dir)
-                .serve()
-                .await
-        });
+        let runtime = &*cleanup
+            .runtime
+            .insert(tokio::runtime::Runtime::new().context(error::AsyncRuntimeSnafu)?);
+
+        // Bind the sockets for the output directory before the build command refers to them, in
+        // case the name has to change. Then spawn background tasks to share the file descriptors.
+        let output_servers = {
+            let _runtime = runtime.enter();
+            let token = &self.common_build_args.token;
+            let (output_socket, output_servers) = bind_with_retry(
+                self.common_build_args.output_socket.clone(),
+                OUTPUT_SOCKET_BIND_ATTEMPTS,
+                || output_socket_name(token, &mut rand::thread_rng()),
+                |output_socket| self.bind_output_servers(output_socket, &marker_dir),
+            )?;
+            self.common_build_args.output_socket = output_socket;
+            output_servers
+        };
+        for (output_server, listener) in output_servers {
+            runtime.spawn(async move { output_server.serve_listener(listener).await });
+        }
+
+        // The output socket names are settled now, and the bypass container has not started.
+        self.emit_names()?;
+
+        let build = self.build_command();
 
         // Spawn a background task for the bypass container that will serve the project root file
-        // descriptor.
-        runtime.spawn(async move {
-            let _ = docker(&run_bypass, Retry::No);
+        // descriptor, and wait for it to start before building.
+        if let Some(BypassCommands { run, rm }) = &bypass {
+            let bypass_started = Instant::now();
+            let run = run.clone();
+            let root_dir = self.root_dir.clone();
+            let quiet = self.quiet;
+            let (tx, rx) = mpsc::channel();
+            runtime.spawn(async move {
+                let _ = tx.send(docker(&run, &root_dir, Retry::No, quiet));
+            });
+
+            let socket = format!("{}-bypass", self.tag);
+            let started = wait_for_bypass(
+                &rx,
+                || pipesys::client::is_listening(&socket),
+                BYPASS_START_TIMEOUT,
+            );
+            if started.is_err() {
+                let _ = docker(rm, &self.root_dir, Retry::No, self.quiet);
+                return started;
+            }
+            finish_phase(
+                &mut timings,
+                BuildPhase::BypassStart,
+                bypass_started,
+                &mut *progress,
+            );
+        }
+
+        // The Dockerfile reads RPMs from this directory through the bypass mount.
+        let rpms_dir = self.root_dir.join("build").join("rpms");
+        let sync = self.sync_rpms_on_retry.then(|| SyncRetry {
+            message: &CREATEREPO_C_READ_HEADER_ERROR,
+            dir: &rpms_dir,
         });
 
         // Build the image, which builds the artifacts we want.
         // Work around transient, known failure cases with Docker.
-        let build_result = docker(
+        let retry_patterns = self.retry_patterns();
+        let build_started = Instant::now();
+        let mut attempt_timer = AttemptTimer::default();
+        let build_result = run_command(
+            "docker",
             &build,
-            Retry::Yes {
-                attempts: DOCKER_BUILD_MAX_ATTEMPTS,
-                messages: &[
-                    &*DOCKER_BUILD_FRONTEND_ERROR,
-                    &*DOCKER_BUILD_DEAD_RECORD_ERROR,
-                    &*UNEXPECTED_EOF_ERROR,
-                    &*CREATEREPO_C_READ_HEADER_ERROR,
-                ],
+            &self.root_dir,
+            context_tar.as_deref(),
+            self.build_retry(&retry_patterns, sync),
+            self.quiet,
+            self.output_limits,
+            &mut TeeLog {
+                log: build_log.as_mut(),
+            },
+            &mut |event| {
+                attempt_timer.observe(&event);
+                progress(event);
             },
+        )
+        .context(error::CommandSnafu);
+        timings.set_attempts(attempt_timer.finish());
+        finish_phase(
+            &mut timings,
+            BuildPhase::DockerBuild,
+            build_started,
+            &mut *progress,
         );
 
+        // Finish the log whether or not the build succeeded, so that a compressed log is complete.
+        let log_result = build_log.map(BuildLog::finish).transpose();
+
         // Clean up our bypass container.
-        let _ = docker(&rm_bypass, Retry::No);
+        let cleanup_started = Instant::now();
+        if let Some(BypassCommands { rm, .. }) = &bypass {
+            let _ = docker(rm, &self.root_dir, Retry::No, self.quiet);
+        }
 
-        // Stop the runtime and the background threads.
-        runtime.shutdown_background();
+        // Stop the runtime and the background threads, and remove the snapshot.
+        let snapshot_result = cleanup.finish();
 
         // Check whether the build succeeded before continuing.
         build_result?;
+        log_result?;
+        snapshot_result?;
 
         // Clean up our image now that we're done.
-        docker(&rm_image, Retry::No)?;
+        docker(&rm_image, &self.root_dir, Retry::No, self.quiet)?;
+        finish_phase(
+            &mut timings,
+            BuildPhase::Cleanup,
+            cleanup_started,
+            &mut *progress,
+        );
+
+        // Package the disks before they are copied, so that the OVA is tracked like the images.
+        let copy_started = Instant::now();
+        if let Some(request) = &self.ova {
+            ova::package(&marker_dir, request).context(error::OvaSnafu)?;
+        }
 
         // Copy artifacts to the expected directory and write markers to track them.
-        copy_build_files(&marker_dir, &self.artifacts_dirs[0])?;
+        let artifacts = copy_build_files(
+            &marker_dir,
+            &self.artifacts_dirs[0],
+            self.max_artifacts,
+            &self.artifact_ignore,
+            self.artifact_transfer,
+        )?;
+        for path in &artifacts {
+            progress(BuildEvent::ArtifactCopied { path: path.clone() });
+        }
+        finish_phase(
+            &mut timings,
+            BuildPhase::ArtifactCopy,
+            copy_started,
+            &mut *progress,
+        );
+        self.verify_packages()?;
+
+        // Checksums are only needed to compare builds, to describe their outputs, or to tell
+        // whether a later build can be skipped.
+        if self.repro_manifest.is_some()
+            || self.repro_check.is_some()
+            || self.provenance.is_some()
+            || self.input_digest.is_some()
+        {
+            let checksums_started = Instant::now();
+            let hashes =
+                ArtifactHashes::new(&self.artifacts_dirs[0], &artifacts, self.checksum_jobs)
+                    .context(error::ReproSnafu)?;
+            finish_phase(
+                &mut timings,
+                BuildPhase::Checksums,
+                checksums_started,
+                &mut *progress,
+            );
+            self.check_reproducibility(&hashes)?;
+            self.write_provenance(hashes.clone())?;
+            // Record the inputs last, so that a build that failed in any step is not skipped.
+            if let Some(digest) = &self.input_digest {
+                record
+                    .write(digest, &hashes)
+                    .context(error::UpToDateSnafu)?;
+            }
+        }
 
+        let duration = started.elapsed();
+        if let Some(path) = &self.build_timings {
+            fs::write(path, timings.to_json(duration))
+                .context(error::BuildTimingsWriteSnafu { path })?;
+        }
+        progress(BuildEvent::Finished { duration, timings });
         Ok(())
     }
 
-    fn build_args(&self) -> Vec<String> {
-        let mut args = match &self.target_build_args {
-            TargetBuildArgs::Package(p) => p.build_args(),
-            TargetBuildArgs::Kit(k) => k.build_args(),
-            TargetBuildArgs::Variant(v) => v.build_args(),
-            TargetBuildArgs::Repack(r) => r.build_args(),
+    /// Check that every package the variant installs was built or provided by a kit, if requested.
+    /// The image build skips packages it cannot find, so a missing package would otherwise go
+    /// unnoticed.
+    fn verify_packages(&self) -> Result<()> {
+        let TargetBuildArgs::Variant(args) = &self.target_build_args else {
+            return Ok(());
         };
-        args.build_arg("ARCH", self.common_build_args.arch.to_string());
-        args.build_arg("GOARCH", self.common_build_args.arch.goarch());
-        args.build_arg("SDK", &self.common_build_args.sdk);
-        args.build_arg("NOCACHE", &self.common_build_args.nocache);
-        args.build_arg("TOKEN", &self.common_build_args.token);
-        args.build_arg("OUTPUT_SOCKET", &self.common_build_args.output_socket);
-        args
+        if !args.verify_packages {
+            return Ok(());
+        }
+        let packages = args
+            .packages
+            .split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let missing = missing_packages(
+            &self.root_dir.join("build"),
+            &packages,
+            self.common_build_args.arch,
+        );
+        ensure!(missing.is_empty(), error::MissingPackagesSnafu { missing });
+        Ok(())
     }
-}
 
-// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+    /// Record checksums for the artifacts, and compare them to a previous build, if requested.
+    fn check_reproducibility(&self, hashes: &ArtifactHashes) -> Result<()> {
+        if let Some(path) = &self.repro_check {
+            let baseline = ArtifactHashes::read(path).context(error::ReproSnafu)?;
+            for difference in hashes.differences(&baseline) {
+                println!(
+                    "cargo:warning=Artifact {difference} since the build recorded in {}",
+                    path.display()
+                );
+            }
+        }
+
+        if let Some(path) = &self.repro_manifest {
+            hashes.write(path).context(error::ReproSnafu)?;
+        }
 
-/// Run `docker` with the specified arguments.
-fn docker(args: &[String], retry: Retry) -> Result<Output> {
-    let mut max_attempts: u16 = 1;
-    let mut retry_messages: &[&Regex] = &[];
-    if let Retry::Yes { attempts, messages } = retry {
-        max_attempts = attempts.into();
-        retry_messages = messages;
+        Ok(())
     }
 
-    let mut attempt = 1;
-    loop {
-        let output = cmd("docker", args)
-            .stderr_to_stdout()
-            .stdout_capture()
-            .unchecked()
-            .run()
-            .context(error::CommandStartSnafu)?;
+    /// Describe the inputs and outputs of the build, if requested.
+    fn write_provenance(&self, outputs: ArtifactHashes) -> Result<()> {
+        let Some(request) = &self.provenance else {
+            return Ok(());
+        };
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        println!("{}", &stdout);
-        if output.status.success() {
-            return Ok(output);
-        }
+        let sdk = SdkImage {
+            image: self.common_build_args.sdk.clone(),
+            digest: image_id(&self.common_build_args.sdk_pull())?,
+        };
+        let inputs = Inputs::new(&self.root_dir, request, sdk).context(error::ProvenanceSnafu)?;
+        let EffectiveArgs {
+            build_args,
+            secrets,
+        } = self.effective_args();
+        Provenance::new(
+            &self.artifact_name,
+            &self.common_build_args.arch.to_string(),
+            inputs,
+            build_args,
+            &secrets,
+            outputs,
+        )
+        .write(&request.path)
+        .context(error::ProvenanceSnafu)
+    }
 
-        ensure!(
-            retry_messages.iter().any(|m| m.is_match(&stdout)) && attempt < max_attempts,
-            error::DockerExecutionSnafu {
-                args: &args.join(" ")
-            }
-        );
+    /// Returns the arguments for the `docker build` command.
+    fn build_command(&self) -> Vec<String> {
+        // A context archive is read from stdin, and the Dockerfile from the same path inside it.
+        let (context, dockerfile) = match &self.context_tar {
+            Some(_) => (
+                STDIN_CONTEXT.to_string(),
+                self.dockerfile
+                    .strip_prefix(&self.root_dir)
+                    .unwrap_or(&self.dockerfile),
+            ),
+            None => (
+                self.context.display().to_string(),
+                self.dockerfile.as_path(),
+            ),
+        };
+        let mut build = format!(
+            "build {context} \
+            --target {target} \
+            --tag {tag} \
+            --network host \
+            --file {dockerfile} \
+            --no-cache-filter rpmbuild,kitbuild,repobuild,imgbuild,migrationbuild,kmodkitbuild,imgrepack \
+            --build-arg BUILDER_UID={uid}",
+            dockerfile = dockerfile.display(),
+            target = self.target,
+            tag = self.tag,
+            uid = *BUILDER_UID,
+        )
+        .split_string();
+        build.extend(self.ulimit_flags());
+
+        // Without the bypass container, the Dockerfile falls back to the named build context when
+        // the socket is empty.
+        if self.no_bypass {
+            build.extend([
+                "--build-arg".to_string(),
+                "BYPASS_SOCKET=".to_string(),
+                "--build-context".to_string(),
+                format!("bypass={}", self.bypass_root().display()),
+            ]);
+        } else {
+            build.extend([
+                "--build-arg".to_string(),
+                format!("BYPASS_SOCKET={}-bypass", self.tag),
+            ]);
+        }
 
-        attempt += 1;
+        for tag in &self.extra_tags {
+            build.extend(["--tag".to_string(), tag.clone()]);
+        }
+        // Record the SDK on the image, since the manifest may have chosen a different one than the
+        // rest of the build.
+        build.extend([
+            "--label".to_string(),
+            format!("{SDK_LABEL}={}", self.common_build_args.sdk),
+        ]);
+        build.extend(self.build_args());
+        build.extend(self.secrets_args.clone());
+        build
     }
-}
 
-/// Allow the caller to configure retry behavior, since the command may fail
-/// for spurious reasons that should not be treated as an error.
-enum Retry<'a> {
-    No,
-    Yes {
-        attempts: NonZeroU16,
-        messages: &'a [&'static Regex],
-    },
-}
+    /// Returns the build arguments and secrets that are passed to `docker build`.
+    pub(crate) fn effective_args(&self) -> EffectiveArgs {
+        let build = self.build_command();
+        let mut build_args = flag_values(&build, "--build-arg")
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        build_args.sort();
+        let secrets = flag_values(&build, "--secret")
+            .map(str::to_string)
+            .collect();
+        EffectiveArgs {
+            build_args,
+            secrets,
+        }
+    }
 
-// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+    /// Let the build be skipped when nothing it is made from has changed since the last successful
+    /// build: the given sources, the build arguments, and the packages and kits that a variant
+    /// installs.
+    fn with_up_to_date_check(mut self, force: bool, sources: &[PathBuf]) -> Result<Self> {
+        if force {
+            return Ok(self);
+        }
 
-/// Add secrets that might be needed for builds. Since most builds won't use
-/// them, they are not automatically tracked for changes. If necessary, builds
-/// can emit the relevant cargo directives for tracking in their build script.
-fn secrets_args() -> Result<Vec<String>> {
-    let mut args = Vec::new();
-    let sbkeys_var = "BUILDSYS_SBKEYS_PROFILE_DIR";
-    let sbkeys_dir = env::var(sbkeys_var).context(error::EnvironmentSnafu { var: sbkeys_var })?;
+        let mut args = self.target_build_args.build_args();
+        args.build_arg("ARCH", self.common_build_args.arch.to_string());
+        args.build_arg("SDK", &self.common_build_args.sdk);
+        for (key, value) in self.extra_build_args() {
+            args.build_arg(key, value);
+        }
 
-    let sbkeys = read_dir(&sbkeys_dir).context(error::DirectoryReadSnafu { path: &sbkeys_dir })?;
-    for s in sbkeys {
-        let s = s.context(error::DirectoryReadSnafu { path: &sbkeys_dir })?;
-        args.build_secret(
-            "file",
-            &s.file_name().to_string_lossy(),
-            &s.path().to_string_lossy(),
+        let build_dir = self.root_dir.join("build");
+        let mut inputs = sources.to_vec();
+        inputs.extend(
+            PACKAGE_DIRS
+                .map(|dir| build_dir.join(dir))
+                .into_iter()
+                .filter(|dir| dir.exists()),
         );
+        self.input_digest = Some(input_hash(&self.root_dir, &inputs, &args)?);
+        Ok(self)
     }
 
-    let ca_bundle_var = "BUILDSYS_CACERTS_BUNDLE_OVERRIDE";
-    let ca_bundle_value =
-        env::var(ca_bundle_var).context(error::EnvironmentSnafu { var: ca_bundle_var })?;
+    /// Copy artifacts to the overridden output directory, if there is one, instead of the usual
+    /// directory for the build. The directory must be writable.
+    fn with_output_dir(mut self, output_dir: Option<PathBuf>) -> Result<Self> {
+        if let Some(output_dir) = output_dir {
+            check_writable(&output_dir)?;
+            self.artifacts_dirs = vec![output_dir];
+        }
+        Ok(self)
+    }
 
-    if !ca_bundle_value.is_empty() {
+    /// Also retry the build if it fails with output that matches one of these patterns, in
+    /// addition to the default transient failures.
+    pub(crate) fn with_retry_patterns(mut self, patterns: impl IntoIterator<Item = Regex>) -> Self {
+        self.retry_patterns.extend(patterns);
+        self
+    }
+
+    /// How to retry the build command if it fails. Nothing is retried with `--fail-fast`.
+    fn build_retry<'a>(&self, messages: &'a [&'a Regex], sync: Option<SyncRetry<'a>>) -> Retry<'a> {
+        if self.fail_fast {
+            return Retry::No;
+        }
+        Retry::Yes {
+            attempts: DOCKER_BUILD_MAX_ATTEMPTS,
+            messages,
+            sync,
+            delay: DOCKER_BUILD_RETRY_DELAY,
+            jitter: self.retry_jitter,
+        }
+    }
+
+    /// The patterns for failures that are worth retrying, starting with the defaults.
+    fn retry_patterns(&self) -> Vec<&Regex> {
+        default_retry_patterns()
+            .into_iter()
+            .chain(&self.retry_patterns)
+            .collect()
+    }
+
+    /// Create the log file for this build, if there is a build log directory.
+    fn build_log(&self) -> Result<Option<BuildLog>> {
+        let Some(dir) = &self.build_log_dir else {
+            return Ok(None);
+        };
+        fs::create_dir_all(dir).context(error::DirectoryCreateSnafu { path: dir })?;
+        let path = build_log_path(
+            dir,
+            &self.target_build_args.build_type(),
+            &self.artifact_name,
+            &self.common_build_args.arch.to_string(),
+            self.compress_logs,
+        );
+        BuildLog::create(path, self.compress_logs).map(Some)
+    }
+
+    /// Find the context archive for docker to read. An archive on our own stdin can only be read
+    /// once, so it is saved to the state directory first, where each attempt can read it again.
+    fn saved_context_tar(&self) -> Result<Option<PathBuf>> {
+        let Some(context_tar) = &self.context_tar else {
+            return Ok(None);
+        };
+        if context_tar != Path::new(STDIN_CONTEXT) {
+            return Ok(Some(context_tar.clone()));
+        }
+
+        let mut path = marker_dir(
+            &self.target_build_args.build_type(),
+            &self.artifact_name,
+            &self.common_build_args.arch.to_string(),
+            &self.state_dir,
+            MarkerLayout::Flat,
+        )
+        .into_os_string();
+        path.push(".context.tar");
+        let path = PathBuf::from(path);
+        let mut file = File::create(&path).context(error::FileCreateSnafu { path: &path })?;
+        io::copy(&mut io::stdin().lock(), &mut file)
+            .context(error::ContextTarSaveSnafu { path: &path })?;
+        Ok(Some(path))
+    }
+
+    /// The sockets that serve the output directory, starting with the primary one.
+    fn output_sockets(&self) -> Vec<String> {
+        self.output_sockets_for(&self.common_build_args.output_socket)
+    }
+
+    fn output_sockets_for(&self, output_socket: &str) -> Vec<String> {
+        let mut sockets = vec![output_socket.to_string()];
+        if self.backup_output_socket {
+            sockets.push(format!("{output_socket}-backup"));
+        }
+        sockets
+    }
+
+    /// Bind a server for the output directory on each of the output sockets.
+    fn bind_output_servers(
+        &self,
+        output_socket: &str,
+        marker_dir: &Path,
+    ) -> pipesys::Result<Vec<(PipesysServer, Listener)>> {
+        self.output_sockets_for(output_socket)
+            .into_iter()
+            .map(|socket| {
+                let mut server = PipesysServer::for_path(socket, ROOT_UID, marker_dir)
+                    .with_token(&self.common_build_args.output_token)?;
+                if let Some(uid_map) = self.uid_map {
+                    server = server.with_uid_map(uid_map);
+                }
+                let listener = server.bind()?;
+                Ok((server, listener))
+            })
+            .collect()
+    }
+
+    /// The names generated for this build.
+    fn names(&self) -> BuildNames {
+        let bypass = (!self.no_bypass).then(|| format!("{}-bypass", self.tag));
+        BuildNames {
+            image: self.tag.clone(),
+            token: self.common_build_args.token.clone(),
+            bypass_container: bypass.clone(),
+            bypass_socket: bypass,
+            output_sockets: self.output_sockets(),
+        }
+    }
+
+    /// Write the names generated for this build to a file, if requested.
+    fn emit_names(&self) -> Result<()> {
+        if let Some(path) = &self.emit_names {
+            let names =
+                serde_json::to_string_pretty(&self.names()).expect("names serialize to JSON");
+            fs::write(path, names).context(error::EmitNamesWriteSnafu { path })?;
+        }
+        Ok(())
+    }
+
+    /// The `--ulimit` flags for the resource limits requested for the build.
+    fn ulimit_flags(&self) -> Vec<String> {
+        self.ulimits
+            .iter()
+            .flat_map(|ulimit| ["--ulimit".to_string(), ulimit.to_string()])
+            .collect()
+    }
+
+    /// The directory that the build reads the project root from, which is the snapshot of the
+    /// root if one is taken.
+    fn bypass_root(&self) -> PathBuf {
+        match &self.snapshot {
+            Some(snapshot) => snapshot.path(&self.tag),
+            None => self.root_dir.clone(),
+        }
+    }
+
+    /// The commands to start and remove the bypass container, unless it is disabled.
+    fn bypass_commands(&self) -> Option<BypassCommands> {
+        if self.no_bypass {
+            return None;
+        }
+        Some(BypassCommands {
+            run: self.bypass_run_command(),
+            rm: format!("rm --force {}-bypass", self.tag).split_string(),
+        })
+    }
+
+    /// Run a container with the project's root as a read-only volume mount, so that pipesys can
+    /// serve a read-only file descriptor that's safe to pass into builds. If the build was asked
+    /// for a writable bypass, the root is mounted and served read-write instead.
+    fn bypass_run_command(&self) -> Vec<String> {
+        let mount = if self.writable_bypass { "rw" } else { "ro" };
+        let mut args = format!(
+            "run \
+            --name {tag}-bypass \
+            --rm \
+            --init \
+            --net host \
+            --pid host \
+            -u {uid} \
+            -v {root}:/bypass:{mount}",
+            tag = self.tag,
+            root = self.bypass_root().display(),
+            uid = ROOT_UID,
+        )
+        .split_string();
+        if let PipesysBin::Host(path) = &self.pipesys {
+            args.extend([
+                "-v".to_string(),
+                format!("{}:{PIPESYS_IMAGE_PATH}:ro", path.display()),
+            ]);
+        }
+        args.extend(self.ulimit_flags());
+        args.extend(self.bypass_run_flags.iter().cloned());
+        args.extend(
+            format!(
+                "{sdk} pipesys serve --socket {tag}-bypass --client-uid {uid} --path /bypass",
+                tag = self.tag,
+                sdk = self.common_build_args.sdk_pull(),
+                uid = ROOT_UID,
+            )
+            .split_string(),
+        );
+        if self.writable_bypass {
+            args.push("--writable".to_string());
+        }
+        args
+    }
+
+    /// Check that the build arguments from the manifest and the build argument file do not collide
+    /// with the ones that buildsys sets itself.
+    fn validated(self) -> Result<Self> {
+        for flag in &self.bypass_run_flags {
+            ensure!(
+                !is_reserved_bypass_run_flag(flag),
+                error::ReservedBypassRunFlagSnafu { flag }
+            );
+        }
+
+        let builtin_args = self.builtin_build_args();
+        let builtin_keys = build_arg_keys(&builtin_args);
+        for key in self.extra_build_args().into_keys() {
+            ensure!(
+                !RESERVED_BUILD_ARGS.contains(&key) && !builtin_keys.contains(key),
+                error::ReservedBuildArgSnafu { key }
+            );
+        }
+
+        check_duplicate_build_args(&self.assembled_build_args(), self.duplicate_build_args)?;
+        Ok(self)
+    }
+
+    /// The build arguments to pass to docker, with only the last value for any key that is set
+    /// more than once.
+    fn build_args(&self) -> Vec<String> {
+        dedup_build_args(self.assembled_build_args())
+    }
+
+    fn assembled_build_args(&self) -> Vec<String> {
+        let mut args = self.builtin_build_args();
+        for (key, value) in self.extra_build_args() {
+            args.build_arg(key, value);
+        }
+        args
+    }
+
+    /// The build arguments from the manifest, with those from the build argument file taking
+    /// their place where both set the same key.
+    fn extra_build_args(&self) -> BTreeMap<&str, &str> {
+        self.manifest_build_args
+            .iter()
+            .chain(&self.file_build_args)
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    }
+
+    fn builtin_build_args(&self) -> Vec<String> {
+        let mut args = self.target_build_args.build_args();
+        args.build_arg("ARCH", self.common_build_args.arch.to_string());
+        args.build_arg("GOARCH", self.common_build_args.arch.goarch());
+        args.build_arg("SDK", self.common_build_args.sdk_pull());
+        args.build_arg("NOCACHE", &self.common_build_args.nocache);
+        args.build_arg("TOKEN", &self.common_build_args.token);
+        // pipesys tries each of the comma-separated sockets in turn.
+        args.build_arg("OUTPUT_SOCKET", self.output_sockets().join(","));
+        args.build_arg("OUTPUT_SOCKET_TOKEN", &self.common_build_args.output_token);
+        args
+    }
+}
+
+/// The resolved build arguments and secrets for a build, kept apart so that it is clear which
+/// values are visible to the Dockerfile as build arguments.
+pub(crate) struct EffectiveArgs {
+    build_args: Vec<String>,
+    secrets: Vec<String>,
+}
+
+impl fmt::Display for EffectiveArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Build arguments:")?;
+        for arg in &self.build_args {
+            writeln!(f, "  {arg}")?;
+        }
+        writeln!(f, "Secrets:")?;
+        for secret in &self.secrets {
+            writeln!(f, "  {secret}")?;
+        }
+        Ok(())
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Writes output to stdout, and copies it to the build log if there is one.
+struct TeeLog<'a> {
+    log: Option<&'a mut BuildLog>,
+}
+
+impl Write for TeeLog<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write_all(buf)?;
+        if let Some(log) = &mut self.log {
+            log.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()?;
+        if let Some(log) = &mut self.log {
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A file that holds a copy of the output of a build, which may be compressed.
+struct BuildLog {
+    path: PathBuf,
+    writer: BuildLogWriter,
+}
+
+enum BuildLogWriter {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl BuildLog {
+    fn create(path: PathBuf, compress: bool) -> Result<Self> {
+        let file = File::create(&path).context(error::FileCreateSnafu { path: &path })?;
+        let writer = if compress {
+            BuildLogWriter::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            BuildLogWriter::Plain(file)
+        };
+        Ok(Self { path, writer })
+    }
+
+    /// Flush the log, and write the gzip trailer if it is compressed. A compressed log that is
+    /// not finished can't be read to the end.
+    fn finish(self) -> Result<()> {
+        let path = self.path;
+        match self.writer {
+            BuildLogWriter::Plain(mut file) => file.flush(),
+            BuildLogWriter::Gzip(encoder) => encoder.finish().map(|_| ()),
+        }
+        .context(error::BuildLogWriteSnafu { path })
+    }
+}
+
+impl Write for BuildLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.writer {
+            BuildLogWriter::Plain(file) => file.write(buf),
+            BuildLogWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            BuildLogWriter::Plain(file) => file.flush(),
+            BuildLogWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Create a directory if needed, and check that files can be created in it.
+fn check_writable(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).context(error::DirectoryCreateSnafu { path: dir })?;
+    let probe = dir.join(format!(".buildsys-write-check-{}", std::process::id()));
+    File::create(&probe).context(error::OutputDirNotWritableSnafu { path: dir })?;
+    fs::remove_file(&probe).context(error::FileRemoveSnafu { path: &probe })
+}
+
+/// Find the path for the log of a build, which is named like the flat marker directory.
+fn build_log_path(dir: &Path, kind: &BuildType, name: &str, arch: &str, compress: bool) -> PathBuf {
+    let mut path = marker_dir(kind, name, arch, dir, MarkerLayout::Flat).into_os_string();
+    path.push(if compress { ".log.gz" } else { ".log" });
+    path.into()
+}
+
+/// Run `docker` with the specified arguments, from the directory `dir`.
+fn docker(args: &[String], dir: &Path, retry: Retry, quiet: bool) -> Result<Output> {
+    run_command(
+        "docker",
+        args,
+        dir,
+        None,
+        retry,
+        quiet,
+        OutputLimits::default(),
+        &mut io::stdout(),
+        &mut |_| {},
+    )
+    .context(error::CommandSnafu)
+}
+
+/// Find the ID of a local image, which is the digest of its configuration.
+fn image_id(image: &str) -> Result<String> {
+    let args = ["image", "inspect", "--format", "{{.Id}}", image];
+    let output = cmd("docker", args)
+        .stderr_capture()
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+    ensure!(
+        output.status.success(),
+        error::DockerExecutionSnafu {
+            args: args.join(" ")
+        }
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Progress reported while a build runs, for callers that want more than the raw output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BuildEvent {
+    /// An attempt at the command started. Attempts are numbered from 1.
+    AttemptStarted { n: u16 },
+    /// A line of output from the current attempt.
+    OutputLine(String),
+    /// An attempt failed. If the failure matched a known transient error, it will be retried.
+    AttemptFailed { n: u16, matched_retry: bool },
+    /// An artifact was moved into the output directory, at this relative path.
+    ArtifactCopied { path: PathBuf },
+    /// A phase of the build finished, after this long. Cleanup runs before and after the build,
+    /// so it is reported twice.
+    PhaseFinished {
+        phase: BuildPhase,
+        duration: Duration,
+    },
+    /// The build was skipped, because nothing it is made from has changed since the last
+    /// successful build.
+    UpToDate,
+    /// The build and all of its follow-up steps succeeded.
+    Finished {
+        duration: Duration,
+        timings: PhaseTimings,
+    },
+}
+
+/// Receives each `BuildEvent` as the build runs.
+pub(crate) type ProgressCallback = Box<dyn FnMut(BuildEvent)>;
+
+/// Record the time since `started` for a phase of the build, and report it.
+fn finish_phase(
+    timings: &mut PhaseTimings,
+    phase: BuildPhase,
+    started: Instant,
+    progress: &mut dyn FnMut(BuildEvent),
+) {
+    let duration = timings.record(phase, started);
+    progress(BuildEvent::PhaseFinished { phase, duration });
+}
+
+/// Times each attempt at a command from the events that `run_command` reports. An attempt ends
+/// when it fails, or when the command finishes.
+#[derive(Debug, Default)]
+struct AttemptTimer {
+    started: Option<Instant>,
+    attempts: Vec<Duration>,
+}
+
+impl AttemptTimer {
+    fn observe(&mut self, event: &BuildEvent) {
+        match event {
+            BuildEvent::AttemptStarted { .. } => self.started = Some(Instant::now()),
+            BuildEvent::AttemptFailed { .. } => self.end_attempt(),
+            _ => (),
+        }
+    }
+
+    fn end_attempt(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.attempts.push(started.elapsed());
+        }
+    }
+
+    /// Returns how long each attempt took, in order.
+    fn finish(mut self) -> Vec<Duration> {
+        self.end_attempt();
+        self.attempts
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// AWS credentials are forwarded to every variant build, for signing with KMS keys.
+const AWS_SECRET_VARS: [&str; 3] = [
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "AWS_SESSION_TOKEN",
+];
+
+/// Add secrets that might be needed for builds. Since most builds won't use
+/// them, they are not automatically tracked for changes. If necessary, builds
+/// can emit the relevant cargo directives for tracking in their build script.
+fn secrets_args(forward_env: &[String]) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let sbkeys_var = "BUILDSYS_SBKEYS_PROFILE_DIR";
+    let sbkeys_dir = env::var(sbkeys_var).context(error::EnvironmentSnafu { var: sbkeys_var })?;
+
+    let sbkeys = read_dir(&sbkeys_dir).context(error::DirectoryReadSnafu { path: &sbkeys_dir })?;
+    for s in sbkeys {
+        let s = s.context(error::DirectoryReadSnafu { path: &sbkeys_dir })?;
+        args.build_secret(
+            "file",
+            &s.file_name().to_string_lossy(),
+            &s.path().to_string_lossy(),
+        );
+    }
+
+    let ca_bundle_var = "BUILDSYS_CACERTS_BUNDLE_OVERRIDE";
+    let ca_bundle_value =
+        env::var(ca_bundle_var).context(error::EnvironmentSnafu { var: ca_bundle_var })?;
+
+    if !ca_bundle_value.is_empty() {
         let ca_bundle_path = PathBuf::from(&ca_bundle_value);
         if !ca_bundle_path.exists() {
             return error::BadCaBundleSnafu { ca_bundle_path }.fail();
@@ -786,18 +1862,42 @@ fn secrets_args() -> Result<Vec<String>> {
         args.build_secret("file", "root.json", &root_json_path.to_string_lossy());
     }
 
-    for var in [
-        "AWS_ACCESS_KEY_ID",
-        "AWS_SECRET_ACCESS_KEY",
-        "AWS_SESSION_TOKEN",
-    ] {
-        let id = format!("{}.env", var.to_lowercase().replace('_', "-"));
-        args.build_secret("env", &id, var);
+    for var in AWS_SECRET_VARS {
+        args.build_secret("env", &env_secret_id(var), var);
     }
 
+    // The AWS credentials are always forwarded, so skip them here to avoid duplicate secrets.
+    let forward_env = forward_env
+        .iter()
+        .filter(|var| !AWS_SECRET_VARS.contains(&var.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    args.extend(forward_env_secrets(&forward_env, env_is_set));
+
     Ok(args)
 }
 
+/// Forward the environment variables that are set, according to `is_set`, as secrets. Only the
+/// names are passed to docker, which reads the values itself, so they never appear in the build
+/// command or the logs.
+fn forward_env_secrets(vars: &[String], is_set: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut args = Vec::new();
+    for var in vars.iter().filter(|var| is_set(var)) {
+        args.build_secret("env", env_secret_id(var).as_str(), var);
+    }
+    args
+}
+
+fn env_is_set(var: &str) -> bool {
+    env::var_os(var).is_some()
+}
+
+/// The ID for a secret forwarded from an environment variable, like `aws-session-token.env` for
+/// `AWS_SESSION_TOKEN`.
+fn env_secret_id(var: &str) -> String {
+    format!("{}.env", var.to_lowercase().replace('_', "-"))
+}
+
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 /// Create a directory for build artifacts.
@@ -806,28 +1906,87 @@ fn create_marker_dir(
     name: &str,
     arch: &str,
     state_dir: &Path,
+    layout: MarkerLayout,
 ) -> Result<PathBuf> {
-    let prefix = match kind {
-        BuildType::Package => "packages",
-        BuildType::Kit => "kits",
-        BuildType::Variant => "variants",
-        BuildType::Repack => "variants",
-    };
-
-    let path = [&state_dir.display().to_string(), arch, prefix, name]
-        .iter()
-        .collect();
+    let path = marker_dir(kind, name, arch, state_dir, layout);
 
     fs::create_dir_all(&path).context(error::DirectoryCreateSnafu { path: &path })?;
 
     Ok(path)
 }
 
+/// Find the directory for build artifacts under the state directory, given the layout in use.
+fn marker_dir(
+    kind: &BuildType,
+    name: &str,
+    arch: &str,
+    state_dir: &Path,
+    layout: MarkerLayout,
+) -> PathBuf {
+    match layout {
+        MarkerLayout::Nested => {
+            let prefix = match kind {
+                BuildType::Package => "packages",
+                BuildType::Kit => "kits",
+                BuildType::Variant => "variants",
+                BuildType::Repack => "variants",
+            };
+            state_dir.join(arch).join(prefix).join(name)
+        }
+        MarkerLayout::Flat => {
+            let prefix = match kind {
+                BuildType::Package => "package",
+                BuildType::Kit => "kit",
+                BuildType::Variant => "variant",
+                BuildType::Repack => "variant",
+            };
+            state_dir.join(format!("{prefix}-{arch}-{name}"))
+        }
+    }
+}
+
+/// Find the directories that a build copies its artifacts to, under the usual directory for its
+/// kind of build. Packages also clean up artifacts left directly in the packages directory by
+/// older builds.
+fn artifacts_dirs(kind: &BuildType, name: &str, arch: &str, dir: &Path) -> Vec<PathBuf> {
+    match kind {
+        BuildType::Package => vec![dir.join(name), dir.to_path_buf()],
+        BuildType::Kit => vec![dir.join(name)],
+        BuildType::Variant | BuildType::Repack => vec![dir.join(format!("{arch}-{name}"))],
+    }
+}
+
 const MARKER_EXTENSION: &str = ".buildsys_marker";
 
+/// Compile the patterns for files that builds may leave in the output directory, but which
+/// should not be treated as artifacts.
+fn artifact_ignore(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).context(error::ArtifactIgnoreSnafu { pattern })?);
+    }
+    builder.build().context(error::ArtifactIgnoreSnafu {
+        pattern: patterns.join(","),
+    })
+}
+
 /// Copy build artifacts to the output directory.
 /// Before we copy each file, we create a corresponding marker file to record its existence.
-fn copy_build_files<P>(build_dir: P, output_dir: P) -> Result<()>
+/// Files whose paths relative to the build directory match `ignore` are left where they are,
+/// without markers, so they are neither published nor cleaned up later.
+/// Artifacts are moved unless `transfer` asks for them to be copied, which leaves the originals
+/// in the build directory.
+/// Symlinks that loop, or that lead into the output directory or to a directory holding the link,
+/// are skipped with a warning, since following them could loop or overwrite published artifacts.
+/// If the build produced more than `max_artifacts` files, nothing is copied.
+/// Returns the paths of the artifacts, relative to the output directory.
+fn copy_build_files<P>(
+    build_dir: P,
+    output_dir: P,
+    max_artifacts: usize,
+    ignore: &GlobSet,
+    transfer: ArtifactTransfer,
+) -> Result<Vec<PathBuf>>
 where
     P: AsRef<Path>,
 {
@@ -844,18 +2003,49 @@ where
         is_dir || is_not_marker || is_symlink
     }
 
-    for artifact_file in find_files(&build_dir, has_artifacts) {
+    let is_ignored = |path: &Path| {
+        path.strip_prefix(&build_dir)
+            .is_ok_and(|relative| ignore.is_match(relative))
+    };
+    let is_unsafe_symlink = |path: &Path| match unsafe_symlink(path, output_dir.as_ref()) {
+        Some(reason) => {
+            println!(
+                "cargo:warning=Skipping symlink '{}' in the build output: {reason}",
+                path.display()
+            );
+            true
+        }
+        None => false,
+    };
+    // Stop scanning as soon as we know the limit was exceeded, in case the build produced an
+    // enormous number of files.
+    let artifact_files = find_files(&build_dir, has_artifacts)
+        .filter(|path| !is_ignored(path) && !is_unsafe_symlink(path))
+        .take(max_artifacts.saturating_add(1))
+        .collect::<Vec<_>>();
+
+    ensure!(
+        artifact_files.len() <= max_artifacts,
+        error::TooManyArtifactsSnafu {
+            path: build_dir.as_ref(),
+            max_artifacts,
+        }
+    );
+
+    let mut artifacts = Vec::new();
+    for artifact_file in artifact_files {
         let mut marker_file = artifact_file.clone().into_os_string();
         marker_file.push(MARKER_EXTENSION);
         File::create(&marker_file).context(error::FileCreateSnafu { path: &marker_file })?;
 
-        let mut output_file: PathBuf = output_dir.as_ref().into();
-        output_file.push(artifact_file.strip_prefix(&build_dir).context(
-            error::StripPathPrefixSnafu {
+        let artifact = artifact_file
+            .strip_prefix(&build_dir)
+            .context(error::StripPathPrefixSnafu {
                 path: &marker_file,
                 prefix: build_dir.as_ref(),
-            },
-        )?);
+            })?
+            .to_path_buf();
+        let output_file = output_dir.as_ref().join(&artifact);
 
         let parent_dir = output_file
             .parent()
@@ -863,17 +2053,147 @@ where
         fs::create_dir_all(parent_dir)
             .context(error::DirectoryCreateSnafu { path: &parent_dir })?;
 
-        fs::rename(&artifact_file, &output_file).context(error::FileRenameSnafu {
-            old_path: &artifact_file,
-            new_path: &output_file,
-        })?;
+        match transfer {
+            ArtifactTransfer::Move => {
+                fs::rename(&artifact_file, &output_file).context(error::FileRenameSnafu {
+                    old_path: &artifact_file,
+                    new_path: &output_file,
+                })?
+            }
+            ArtifactTransfer::Copy => copy_artifact(&artifact_file, &output_file)?,
+        }
+        artifacts.push(artifact);
+    }
+
+    Ok(artifacts)
+}
+
+/// Check whether `path` is a symlink that is not safe to publish, and if so, return why. Symlinks
+/// that do not point anywhere yet are fine, since they are copied as links and never followed.
+// Resolving the symlinks is the point here, so that loops and links into the output directory
+// are caught wherever they lead.
+#[allow(clippy::disallowed_methods)]
+fn unsafe_symlink(path: &Path, output_dir: &Path) -> Option<String> {
+    if !path.is_symlink() {
+        return None;
+    }
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => return Some(format!("cannot be resolved: {e}")),
+    };
+    if fs::canonicalize(output_dir).is_ok_and(|output_dir| target.starts_with(output_dir)) {
+        return Some(format!(
+            "it leads into the output directory at '{}'",
+            target.display()
+        ));
+    }
+    let parent = path
+        .parent()
+        .and_then(|parent| fs::canonicalize(parent).ok());
+    if target.is_dir() && parent.is_some_and(|parent| parent.starts_with(&target)) {
+        return Some(format!(
+            "it leads to '{}', which holds the link",
+            target.display()
+        ));
+    }
+    None
+}
+
+/// Copy an artifact, replacing any file already at the destination. Symlinks are copied as links,
+/// the same as if they were moved.
+fn copy_artifact(from: &Path, to: &Path) -> Result<()> {
+    let copy_error = || error::FileCopySnafu {
+        old_path: from,
+        new_path: to,
+    };
+    if to.exists() || to.is_symlink() {
+        fs::remove_file(to).context(error::FileRemoveSnafu { path: to })?;
+    }
+    if from.is_symlink() {
+        let target = fs::read_link(from).with_context(|_| copy_error())?;
+        std::os::unix::fs::symlink(target, to).with_context(|_| copy_error())
+    } else {
+        fs::copy(from, to)
+            .map(|_| ())
+            .with_context(|_| copy_error())
     }
+}
+
+/// Remove the artifacts and markers that earlier builds tracked for a package, kit, or variant,
+/// the same way that a new build does before it starts. With `dry_run`, only print what would be
+/// removed.
+pub(crate) fn clean(args: &CleanArgs) -> Result<()> {
+    let kind = BuildType::from(args.kind);
+    let arch = args.arch.to_string();
+    let output_dirs = match &args.output_dir {
+        Some(output_dir) => vec![output_dir.clone()],
+        None => {
+            let (var, dir) = match kind {
+                BuildType::Package => ("BUILDSYS_PACKAGES_DIR", &args.packages_dir),
+                BuildType::Kit => ("BUILDSYS_KITS_DIR", &args.kits_dir),
+                BuildType::Variant | BuildType::Repack => ("BUILDSYS_IMAGES_DIR", &args.image_dir),
+            };
+            let dir = dir.as_ref().context(error::CleanOutputDirSnafu { var })?;
+            artifacts_dirs(&kind, &args.name, &arch, dir)
+        }
+    };
 
+    let marker_dir = marker_dir(
+        &kind,
+        &args.name,
+        &arch,
+        &args.state_dir,
+        args.marker_layout,
+    );
+    for (path, _) in tracked_build_files(&marker_dir, &output_dirs)? {
+        if args.dry_run {
+            println!("Would remove {}", path.display());
+        } else {
+            println!("Removing {}", path.display());
+        }
+    }
+    if !args.dry_run {
+        UpToDateRecord::new(&marker_dir)
+            .remove()
+            .context(error::UpToDateSnafu)?;
+        clean_build_files(&marker_dir, &output_dirs)?;
+    }
     Ok(())
 }
 
+/// Find the files that cleaning up after a build would remove, each with the directory it was
+/// found under. For every marker file, that is the artifact in each of the output directories, the
+/// original artifact in the build directory if it was copied rather than moved, and the marker
+/// file itself.
+fn tracked_build_files(
+    build_dir: &Path,
+    output_dirs: &[PathBuf],
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut files = Vec::new();
+    for marker_file in find_files(build_dir, has_markers) {
+        for output_dir in output_dirs {
+            let mut output_file: PathBuf = output_dir.into();
+            output_file.push(marker_file.strip_prefix(build_dir).context(
+                error::StripPathPrefixSnafu {
+                    path: &marker_file,
+                    prefix: build_dir,
+                },
+            )?);
+            output_file.set_extension("");
+            files.push((output_file, output_dir.clone()));
+        }
+        files.push((marker_file.with_extension(""), build_dir.to_path_buf()));
+        files.push((marker_file, build_dir.to_path_buf()));
+    }
+    files.retain(|(path, _)| path.exists() || path.is_symlink());
+    Ok(files)
+}
+
 /// Remove build artifacts from any of the known output directories.
-/// Any marker file we find could have a corresponding file that should be cleaned up.
+/// Any marker file we find could have a corresponding file that should be cleaned up, in the
+/// output directories and also in the build directory, if the artifact was copied rather than
+/// moved.
 /// We also clean up the marker files so they do not accumulate across builds.
 /// For the same reason, if a directory is empty after build artifacts, marker files, and other
 /// empty directories have been removed, then that directory will also be removed.
@@ -883,18 +2203,6 @@ where
 {
     let build_dir = build_dir.as_ref();
 
-    fn has_markers(entry: &DirEntry) -> bool {
-        let is_dir = entry.path().is_dir();
-        let is_file = entry.file_type().is_file();
-        let is_marker = is_file
-            && entry
-                .file_name()
-                .to_str()
-                .map(|s| s.ends_with(MARKER_EXTENSION))
-                .unwrap_or(false);
-        is_dir || is_marker
-    }
-
     fn cleanup(path: &Path, top: &Path, dirs: &mut HashSet<PathBuf>) -> Result<()> {
         if !path.exists() && !path.is_symlink() {
             return Ok(());
@@ -922,19 +2230,8 @@ where
 
     let mut clean_dirs: HashSet<PathBuf> = HashSet::new();
 
-    for marker_file in find_files(&build_dir, has_markers) {
-        for output_dir in output_dirs {
-            let mut output_file: PathBuf = output_dir.into();
-            output_file.push(marker_file.strip_prefix(build_dir).context(
-                error::StripPathPrefixSnafu {
-                    path: &marker_file,
-                    prefix: build_dir,
-                },
-            )?);
-            output_file.set_extension("");
-            cleanup(&output_file, output_dir, &mut clean_dirs)?;
-        }
-        cleanup(&marker_file, build_dir, &mut clean_dirs)?;
+    for (path, top) in tracked_build_files(build_dir, output_dirs)? {
+        cleanup(&path, &top, &mut clean_dirs)?;
     }
 
     // Clean up directories in reverse order, so that empty child directories don't stop an
@@ -952,6 +2249,20 @@ where
     Ok(())
 }
 
+/// Filter for walking a marker directory, which keeps the marker files and the directories that
+/// may hold them.
+fn has_markers(entry: &DirEntry) -> bool {
+    let is_dir = entry.path().is_dir();
+    let is_file = entry.file_type().is_file();
+    let is_marker = is_file
+        && entry
+            .file_name()
+            .to_str()
+            .map(|s| s.ends_with(MARKER_EXTENSION))
+            .unwrap_or(false);
+    is_dir || is_marker
+}
+
 /// Create an iterator over files matching the supplied filter.
 fn find_files<P>(
     dir: P,
@@ -973,6 +2284,317 @@ where
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
+/// Construct a human-readable Docker tag for a variant image from its version information.
+fn version_tag(
+    variant: &str,
+    arch: SupportedArch,
+    version_image: &str,
+    version_build: &str,
+) -> String {
+    format!("{variant}-{arch}:{version_image}-{version_build}")
+}
+
+/// Format the additional data volumes in an image layout as a space-separated list of
+/// `name:role:size` entries, which is empty for the single data image form.
+fn data_volumes(image_layout: &ImageLayout) -> String {
+    image_layout
+        .data_volumes
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Wait until the bypass container is ready to serve the project root, which `ready` checks. If the
+/// `docker run` command for it exits first, its result arrives on `exited`, and the build should
+/// stop. The command has already written the container's output to the log.
+fn wait_for_bypass(
+    exited: &mpsc::Receiver<Result<Output>>,
+    ready: impl Fn() -> bool,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match exited.try_recv() {
+            Ok(Err(e)) => {
+                return Err(e).context(error::BypassStartFailedSnafu);
+            }
+            Ok(Ok(_)) | Err(mpsc::TryRecvError::Disconnected) => {
+                return error::BypassExitedSnafu.fail();
+            }
+            Err(mpsc::TryRecvError::Empty) => {}
+        }
+
+        if ready() {
+            return Ok(());
+        }
+
+        ensure!(
+            Instant::now() < deadline,
+            error::BypassStartTimeoutSnafu { timeout }
+        );
+        thread::sleep(BYPASS_POLL_INTERVAL);
+    }
+}
+
+/// The names generated for a build, which other processes need in order to coordinate with it.
+/// The bypass container and its socket share a name, and neither exists without the bypass.
+#[derive(Debug, Serialize)]
+struct BuildNames {
+    image: String,
+    token: String,
+    bypass_container: Option<String>,
+    bypass_socket: Option<String>,
+    output_sockets: Vec<String>,
+}
+
+/// The docker commands for the bypass container, which serves the project root to builds.
+struct BypassCommands {
+    run: Vec<String>,
+    rm: Vec<String>,
+}
+
+/// The parts of a build that must not outlive it: the context archive saved from stdin, the
+/// snapshot of the project root with its name, and the runtime for the background tasks. They are
+/// cleaned up when the guard is dropped, so that a build that returns early cleans up too.
+struct BuildCleanup {
+    saved_context_tar: Option<PathBuf>,
+    snapshot: Option<(RootSnapshot, String)>,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl BuildCleanup {
+    /// Clean up now, and report whether the snapshot could be removed.
+    fn finish(mut self) -> Result<()> {
+        self.cleanup()
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        if let Some(path) = self.saved_context_tar.take() {
+            let _ = fs::remove_file(path);
+        }
+        // The background tasks may be blocked, so don't wait for them.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+        match self.snapshot.take() {
+            Some((snapshot, name)) => snapshot.remove(&name).context(error::SnapshotSnafu),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BuildCleanup {
+    fn drop(&mut self) {
+        let _ = self.cleanup();
+    }
+}
+
+/// Check whether a flag for the bypass container would override one that buildsys sets itself.
+/// Flags may be given as `--flag=value`, and short flags may have their value attached.
+fn is_reserved_bypass_run_flag(flag: &str) -> bool {
+    let name = flag.split_once('=').map_or(flag, |(name, _)| name);
+    RESERVED_BYPASS_RUN_FLAGS.iter().any(|reserved| {
+        name == *reserved || (!reserved.starts_with("--") && flag.starts_with(reserved))
+    })
+}
+
+/// Prepare to snapshot the project root for the build, if requested. The snapshot leaves out the
+/// state directory, and what the Dockerfile's `.dockerignore` excludes.
+fn root_snapshot(common: &Common, dockerfile: &Path) -> Result<Option<RootSnapshot>> {
+    if !common.snapshot_root {
+        return Ok(None);
+    }
+    let dir = common.snapshot_dir.as_ref().unwrap_or(&common.state_dir);
+    let mut dockerignore = dockerfile.as_os_str().to_owned();
+    dockerignore.push(".dockerignore");
+    RootSnapshot::new(&common.root_dir, dir, common.snapshot_max_bytes)
+        .skipping(&common.state_dir)
+        .with_dockerignore(PathBuf::from(dockerignore))
+        .map(Some)
+        .context(error::SnapshotSnafu)
+}
+
+/// Find the Dockerfile used for every type of build.
+pub(crate) fn dockerfile(common: &Common) -> PathBuf {
+    common.tools_dir.join("build.Dockerfile")
+}
+
+/// Find the packages that a variant installs when built for `arch`, in the order the manifest
+/// lists them. Packages limited to another architecture are left out, and a package listed more
+/// than once is only included once. A package cannot be listed both for every architecture and
+/// for one in particular, since it is not clear which was meant.
+pub(crate) fn effective_packages(
+    manifest: &ManifestInfo,
+    arch: SupportedArch,
+) -> Result<Vec<String>> {
+    let included = manifest.included_packages().map_or(&[][..], Vec::as_slice);
+    for package in included {
+        if let IncludedPackage::ForArch { name, arch } = package {
+            ensure!(
+                !included.contains(&IncludedPackage::Name(name.clone())),
+                error::ConflictingIncludedPackageSnafu {
+                    name,
+                    arch: arch.to_string(),
+                }
+            );
+        }
+    }
+
+    let mut seen = HashSet::new();
+    Ok(included
+        .iter()
+        .filter(|package| package.includes_arch(arch))
+        .map(IncludedPackage::name)
+        .filter(|name| seen.insert(*name))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The directories under the project's build directory that hold the RPMs a variant can install:
+/// the packages built by the project, and those provided by its kits.
+const PACKAGE_DIRS: [&str; 3] = ["rpms", "kits", "external-kits"];
+
+/// Find the packages in `packages` that have no RPM for `arch` under `build_dir`. Each package is
+/// installed as `bottlerocket-<name>`, either built for one architecture or for any.
+fn missing_packages(build_dir: &Path, packages: &[String], arch: SupportedArch) -> Vec<String> {
+    let arch = arch.to_string();
+    let available = PACKAGE_DIRS
+        .iter()
+        .flat_map(|dir| WalkDir::new(build_dir.join(dir)).into_iter().flatten())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| Some(rpm_name(entry.file_name().to_str()?, &arch)?.to_string()))
+        .collect::<HashSet<_>>();
+    packages
+        .iter()
+        .filter(|package| !available.contains(&format!("bottlerocket-{package}")))
+        .cloned()
+        .collect()
+}
+
+/// Find the package name in an RPM file name of the form `<name>-<version>-<release>.<arch>.rpm`,
+/// if the RPM is for `arch` or for any architecture.
+fn rpm_name<'a>(file_name: &'a str, arch: &str) -> Option<&'a str> {
+    let (rest, rpm_arch) = file_name.strip_suffix(".rpm")?.rsplit_once('.')?;
+    if rpm_arch != arch && rpm_arch != "noarch" {
+        return None;
+    }
+    let mut parts = rest.rsplitn(3, '-');
+    let (_release, _version) = (parts.next()?, parts.next()?);
+    parts.next()
+}
+
+/// Find the SDK image for a package or variant build. The manifest can pin its own SDK, which
+/// takes the place of the one given for the whole build.
+pub(crate) fn sdk_image(common: &Common, manifest: &ManifestInfo) -> String {
+    manifest
+        .sdk_image()
+        .map_or_else(|| common.sdk_image.clone(), str::to_string)
+}
+
+/// The reference to pull `image` with, going through `mirror` if one is given.
+pub(crate) fn pull_image(image: &str, mirror: Option<&str>) -> String {
+    mirror.map_or_else(|| image.to_string(), |mirror| mirror_image(image, mirror))
+}
+
+/// Rewrite an image reference so that it is pulled through a registry mirror. The mirror replaces
+/// the registry host if the reference names one. Otherwise the image comes from Docker Hub, where
+/// images without a namespace live under `library/`.
+fn mirror_image(image: &str, mirror: &str) -> String {
+    let mirror = mirror.trim_end_matches('/');
+    if image
+        .strip_prefix(mirror)
+        .is_some_and(|rest| rest.starts_with('/'))
+    {
+        return image.to_string();
+    }
+
+    // Docker treats the first component as a registry host only if it could not be a namespace.
+    let (registry, path) = match image.split_once('/') {
+        Some((first, rest)) if first.contains(['.', ':']) || first == "localhost" => {
+            (Some(first), rest)
+        }
+        _ => (None, image),
+    };
+    let docker_hub = registry.map_or(true, |r| DOCKER_HUB_HOSTS.contains(&r));
+    if docker_hub && !path.contains('/') {
+        format!("{mirror}/library/{path}")
+    } else {
+        format!("{mirror}/{path}")
+    }
+}
+
+/// Decide whether to skip the bypass container for a package build. A package that does not need it
+/// reads the project root through the named build context, the same as every build does with
+/// `--no-bypass`.
+fn no_bypass(common: &Common, manifest: &ManifestInfo) -> bool {
+    common.no_bypass || !manifest.needs_bypass()
+}
+
+/// Generate a name for the socket that serves the output directory.
+fn output_socket_name(token: &str, rng: &mut impl Rng) -> String {
+    format!("buildsys-output-{token}-{}", rng.gen::<u128>())
+}
+
+/// Bind sockets under `name`, choosing a new name from `rename` if another server already holds
+/// it, up to `attempts` times in all. Returns the name that was bound.
+fn bind_with_retry<T>(
+    mut name: String,
+    attempts: usize,
+    mut rename: impl FnMut() -> String,
+    mut bind: impl FnMut(&str) -> pipesys::Result<T>,
+) -> Result<(String, T)> {
+    for _ in 1..attempts {
+        match bind(&name) {
+            Err(pipesys::Error::SocketInUse { socket }) => {
+                println!("cargo:warning=Socket {socket} is already in use, choosing a new name");
+                name = rename();
+            }
+            result => return Ok((name, result.context(error::OutputServerSnafu)?)),
+        }
+    }
+    let bound = bind(&name).context(error::OutputServerSnafu)?;
+    Ok((name, bound))
+}
+
+/// Generate a random value for NOCACHE. The stages that read it are also excluded from the layer
+/// cache with `--no-cache-filter`, and it stays random rather than following the build inputs:
+/// those stages send their artifacts out through the output socket while they run, and read
+/// packages and kits through the bypass, so reusing a cached layer would leave no artifacts behind.
+fn random_nocache(rng: &mut impl Rng) -> String {
+    rng.gen::<u128>().to_string()
+}
+
+/// Hash the build arguments and the contents of the input files and directories to get a value
+/// that only changes when the inputs do. Paths are hashed relative to the project root so that the
+/// value does not depend on where the project is checked out.
+fn input_hash(root: &Path, inputs: &[PathBuf], build_args: &[String]) -> Result<String> {
+    let mut d = Sha512::new();
+
+    // The order of some build arguments, like image features, is not stable.
+    let mut build_args = build_args.to_vec();
+    build_args.sort();
+    for arg in build_args {
+        d.update(arg);
+        d.update([0]);
+    }
+
+    let mut files = ProjectInfo::crawl(inputs)
+        .context(error::ProjectCrawlSnafu)?
+        .files;
+    files.sort();
+    for file in files {
+        let name = file.strip_prefix(root).unwrap_or(&file);
+        d.update(name.as_os_str().as_encoded_bytes());
+        d.update([0]);
+        let mut f = File::open(&file).context(error::FileReadSnafu { path: &file })?;
+        io::copy(&mut f, &mut d).context(error::FileReadSnafu { path: &file })?;
+    }
+
+    let digest = hex::encode(d.finalize());
+    Ok(digest[..32].to_string())
+}
+
 /// Compute a per-checkout suffix for the tag to avoid collisions.
 fn token(p: impl AsRef<Path>) -> String {
     let mut d = Sha512::new();
@@ -986,6 +2608,30 @@ fn append_token(tag: impl AsRef<str>, p: impl AsRef<Path>) -> String {
     format!("{}-{}", tag.as_ref(), token(p))
 }
 
+/// Where the bypass container gets pipesys from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PipesysBin {
+    /// Mount this binary from the host.
+    Host(PathBuf),
+    /// Use the binary already in the SDK image.
+    Image,
+}
+
+impl PipesysBin {
+    fn new(common: &Common) -> Result<Self> {
+        if common.pipesys_from_image {
+            return Ok(Self::Image);
+        }
+        match &common.pipesys_bin {
+            Some(path) => {
+                ensure!(path.is_file(), error::PipesysBinMissingSnafu { path });
+                Ok(Self::Host(path.clone()))
+            }
+            None => Ok(Self::Host(common.root_dir.join("build/tools/pipesys"))),
+        }
+    }
+}
+
 /// Helper trait for constructing buildkit --build-arg arguments.
 trait BuildArg {
     fn build_arg<S1, S2>(&mut self, key: S1, value: S2)
@@ -1005,6 +2651,166 @@ impl BuildArg for Vec<String> {
     }
 }
 
+/// Determine the build context, which defaults to the project's root directory. The Dockerfile is
+/// not part of the context, so we only need to make sure that both of them exist.
+fn build_context(common: &Common) -> Result<PathBuf> {
+    let context = match &common.context {
+        Some(context) => common.root_dir.join(context),
+        None => common.root_dir.clone(),
+    };
+    ensure!(
+        context.is_dir(),
+        error::BuildContextSnafu { path: &context }
+    );
+
+    let dockerfile = common.tools_dir.join("build.Dockerfile");
+    ensure!(
+        dockerfile.is_file(),
+        error::DockerfileMissingSnafu { path: &dockerfile }
+    );
+
+    Ok(context)
+}
+
+/// Find the archive to send as the build context instead of a directory, if there is one. The
+/// Dockerfile must be under the root directory, since docker reads it from inside the archive.
+fn context_tar(common: &Common) -> Result<Option<PathBuf>> {
+    let Some(context_tar) = &common.context_tar else {
+        return Ok(None);
+    };
+    let dockerfile = dockerfile(common);
+    ensure!(
+        dockerfile.starts_with(&common.root_dir),
+        error::ContextTarDockerfileSnafu { path: &dockerfile }
+    );
+    if context_tar == Path::new(STDIN_CONTEXT) {
+        return Ok(Some(context_tar.clone()));
+    }
+
+    let context_tar = common.root_dir.join(context_tar);
+    ensure!(
+        context_tar.is_file(),
+        error::ContextTarSnafu { path: &context_tar }
+    );
+    Ok(Some(context_tar))
+}
+
+/// The number of artifacts to compute checksums for at once, which defaults to the number of
+/// threads the host can run in parallel.
+fn checksum_jobs(jobs: Option<u16>) -> usize {
+    jobs.map(usize::from).unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map(usize::from)
+            .unwrap_or(1)
+    })
+}
+
+/// Find the build argument file, if one was given. Relative paths are resolved against the root
+/// directory.
+pub(crate) fn build_arg_file(common: &Common) -> Option<PathBuf> {
+    common
+        .build_arg_file
+        .as_ref()
+        .map(|path| common.root_dir.join(path))
+}
+
+/// Read the build arguments from the build argument file, if one was given.
+fn file_build_args(common: &Common) -> Result<BTreeMap<String, String>> {
+    let Some(path) = build_arg_file(common) else {
+        return Ok(BTreeMap::new());
+    };
+    let contents = fs::read_to_string(&path).context(error::BuildArgFileSnafu { path: &path })?;
+    parse_build_arg_file(&path, &contents)
+}
+
+/// Parse `KEY=VALUE` lines into build arguments. Blank lines and lines that start with `#` are
+/// ignored, and a later line for the same key replaces an earlier one.
+fn parse_build_arg_file(path: &Path, contents: &str) -> Result<BTreeMap<String, String>> {
+    let mut args = BTreeMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .filter(|(key, _)| !key.trim().is_empty())
+            .context(error::BuildArgFileLineSnafu {
+                path,
+                line: index + 1,
+            })?;
+        args.insert(key.trim().to_string(), value.to_string());
+    }
+    Ok(args)
+}
+
+/// Iterate over the values that follow each occurrence of a flag in a list of arguments.
+fn flag_values<'a>(args: &'a [String], flag: &'a str) -> impl Iterator<Item = &'a str> {
+    args.iter()
+        .zip(args.iter().skip(1))
+        .filter(move |(f, _)| *f == flag)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Collect the keys from a list of buildkit --build-arg arguments.
+fn build_arg_keys(args: &[String]) -> HashSet<&str> {
+    flag_values(args, "--build-arg")
+        .filter_map(|arg| arg.split_once('=').map(|(key, _)| key))
+        .collect()
+}
+
+/// Find the keys that are set more than once in a list of buildkit --build-arg arguments, in the
+/// order they first appear.
+fn duplicate_build_arg_keys(args: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    for key in
+        flag_values(args, "--build-arg").filter_map(|arg| arg.split_once('=').map(|(key, _)| key))
+    {
+        if !seen.insert(key) && !duplicates.iter().any(|d| d == key) {
+            duplicates.push(key.to_string());
+        }
+    }
+    duplicates
+}
+
+/// Fail if a key is set more than once in a list of buildkit --build-arg arguments, or only warn
+/// about it if `policy` allows that.
+fn check_duplicate_build_args(args: &[String], policy: DuplicateBuildArgs) -> Result<()> {
+    for key in duplicate_build_arg_keys(args) {
+        match policy {
+            DuplicateBuildArgs::Error => return error::DuplicateBuildArgSnafu { key }.fail(),
+            DuplicateBuildArgs::Warn => println!(
+                "cargo:warning=Build argument '{key}' is set more than once, using the last value"
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Drop every --build-arg argument whose key is set again later in the list, so that each key
+/// appears once with the value docker would have used.
+fn dedup_build_args(args: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut kept = Vec::new();
+    let mut args = args.into_iter().rev().peekable();
+    while let Some(arg) = args.next() {
+        let key = arg.split_once('=').map(|(key, _)| key.to_string());
+        if args.peek().map(String::as_str) == Some("--build-arg") {
+            let flag = args.next().expect("peeked");
+            if key.is_some_and(|key| !seen.insert(key)) {
+                continue;
+            }
+            kept.push(arg);
+            kept.push(flag);
+        } else {
+            kept.push(arg);
+        }
+    }
+    kept.reverse();
+    kept
+}
+
 /// Helper trait for constructing buildkit --secret arguments.
 trait BuildSecret {
     fn build_secret<S>(&mut self, typ: S, id: S, src: S)
@@ -1057,3 +2863,1767 @@ fn filename(p: impl AsRef<Path>) -> String {
         .to_string_lossy()
         .to_string()
 }
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::RetryAction;
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    fn write_files(dir: &Path, names: &[&str]) {
+        for name in names {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, name).unwrap();
+        }
+    }
+
+    fn dir_entries(dir: &Path) -> Vec<PathBuf> {
+        let mut entries = WalkDir::new(dir)
+            .min_depth(1)
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    fn test_package_build() -> DockerBuild {
+        let root_dir = PathBuf::from("/home/user/project");
+        DockerBuild {
+            dockerfile: root_dir.join("build/tools/build.Dockerfile"),
+            context: root_dir.clone(),
+            context_tar: None,
+            target: "package".to_string(),
+            tag: append_token("buildsys-pkg-pkg-a-x86_64", &root_dir),
+            extra_tags: Vec::new(),
+            root_dir: root_dir.clone(),
+            artifacts_dirs: vec![root_dir.join("build/rpms/pkg-a")],
+            state_dir: root_dir.join("build/state"),
+            marker_layout: MarkerLayout::Nested,
+            duplicate_build_args: DuplicateBuildArgs::Error,
+            artifact_name: "pkg-a".to_string(),
+            max_artifacts: 10,
+            artifact_ignore: GlobSet::empty(),
+            artifact_transfer: ArtifactTransfer::Move,
+            quiet: false,
+            sync_rpms_on_retry: false,
+            fail_fast: false,
+            retry_jitter: 0.5,
+            retry_patterns: Vec::new(),
+            backup_output_socket: false,
+            no_bypass: false,
+            bypass_run_flags: Vec::new(),
+            writable_bypass: false,
+            ulimits: Vec::new(),
+            pipesys: PipesysBin::Host(root_dir.join("build/tools/pipesys")),
+            snapshot: None,
+            uid_map: None,
+            output_limits: OutputLimits::default(),
+            build_log_dir: None,
+            build_timings: None,
+            emit_names: None,
+            input_digest: None,
+            compress_logs: false,
+            repro_manifest: None,
+            repro_check: None,
+            checksum_jobs: 1,
+            provenance: None,
+            ova: None,
+            common_build_args: CommonBuildArgs::new(
+                &root_dir,
+                "sdk:latest".to_string(),
+                SupportedArch::X86_64,
+                OutputCleanup::BeforeBuild,
+            ),
+            target_build_args: TargetBuildArgs::Package(PackageBuildArgs {
+                package: "pkg-a".to_string(),
+                package_dependencies: vec!["pkg-b".to_string()],
+                kit_dependencies: Vec::new(),
+                external_kit_dependencies: Vec::new(),
+                version_build: "abcdef".to_string(),
+                version_build_timestamp: "1700000000".to_string(),
+            }),
+            manifest_build_args: BTreeMap::new(),
+            file_build_args: BTreeMap::new(),
+            secrets_args: Vec::new(),
+        }
+    }
+
+    /// Return the value for a build argument, if it is present.
+    fn build_arg_value<'a>(args: &'a [String], key: &str) -> Option<&'a str> {
+        flag_values(args, "--build-arg").find_map(|arg| arg.strip_prefix(&format!("{key}=")))
+    }
+
+    fn test_variant_build() -> DockerBuild {
+        let root_dir = PathBuf::from("/home/user/project");
+        let mut build = test_package_build();
+        build.target = "variant".to_string();
+        build.tag = append_token("buildsys-var-aws-dev-x86_64", &root_dir);
+        build.target_build_args = TargetBuildArgs::Variant(VariantBuildArgs {
+            package_dependencies: vec!["release".to_string()],
+            kit_dependencies: vec!["core-kit".to_string()],
+            external_kit_dependencies: Vec::new(),
+            data_image_publish_size_gib: 20,
+            data_image_size_gib: "1".to_string(),
+            data_volumes: String::new(),
+            image_features: HashSet::from([ImageFeature::Fips]),
+            image_format: "raw".to_string(),
+            kernel_parameters: "console=tty0".to_string(),
+            name: "bottlerocket".to_string(),
+            os_image_publish_size_gib: "2".to_string(),
+            os_image_size_gib: "2".to_string(),
+            packages: "release".to_string(),
+            verify_packages: false,
+            partition_plan: "split".to_string(),
+            pretty_name: "Bottlerocket OS".to_string(),
+            variant: "aws-dev".to_string(),
+            variant_family: "aws".to_string(),
+            variant_flavor: String::new(),
+            variant_platform: "aws".to_string(),
+            variant_runtime: "dev".to_string(),
+            version_build: "abcdef".to_string(),
+            version_image: "1.0.0".to_string(),
+        });
+        build
+            .secrets_args
+            .build_secret("file", "root.json", "/tmp/root.json");
+        build
+    }
+
+    #[test]
+    fn test_manifest_build_args() {
+        let mut build = test_package_build();
+        build.manifest_build_args =
+            BTreeMap::from([("GO_BUILD_TAGS".to_string(), "netgo osusergo".to_string())]);
+        let build = build.validated().unwrap();
+
+        let args = build.build_args();
+        assert_eq!(
+            build_arg_value(&args, "GO_BUILD_TAGS"),
+            Some("netgo osusergo")
+        );
+        assert_eq!(build_arg_value(&args, "PACKAGE"), Some("pkg-a"));
+        assert_eq!(build_arg_value(&args, "ARCH"), Some("x86_64"));
+    }
+
+    #[test]
+    fn test_manifest_build_args_reserved() {
+        for key in ["ARCH", "NOCACHE", "TOKEN", "BYPASS_SOCKET", "PACKAGE"] {
+            let mut build = test_package_build();
+            build.manifest_build_args = BTreeMap::from([(key.to_string(), "x".to_string())]);
+            let err = build.validated().err().unwrap();
+            assert!(
+                matches!(err, error::Error::ReservedBuildArg { key: ref k } if k == key),
+                "expected {key} to be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_duplicate_build_args() {
+        let mut args = test_package_build().build_args();
+        args.build_arg("PACKAGE", "pkg-b");
+        args.build_arg("EXTRA", "1");
+        args.build_arg("PACKAGE", "pkg-c");
+        assert_eq!(duplicate_build_arg_keys(&args), ["PACKAGE"]);
+
+        // The last value is kept, in the place where it was set.
+        let deduped = dedup_build_args(args);
+        assert!(duplicate_build_arg_keys(&deduped).is_empty());
+        assert_eq!(build_arg_value(&deduped, "PACKAGE"), Some("pkg-c"));
+        assert_eq!(
+            &deduped[deduped.len() - 4..],
+            ["--build-arg", "EXTRA=1", "--build-arg", "PACKAGE=pkg-c"]
+        );
+
+        let deduped_again = dedup_build_args(deduped.clone());
+        assert_eq!(deduped_again, deduped);
+    }
+
+    #[test]
+    fn test_check_duplicate_build_args() {
+        let mut args = Vec::new();
+        args.build_arg("GO_BUILD_TAGS", "netgo");
+        args.build_arg("GO_BUILD_TAGS", "osusergo");
+        let err = check_duplicate_build_args(&args, DuplicateBuildArgs::Error).unwrap_err();
+        assert!(
+            matches!(err, error::Error::DuplicateBuildArg { ref key } if key == "GO_BUILD_TAGS")
+        );
+        assert!(check_duplicate_build_args(&args, DuplicateBuildArgs::Warn).is_ok());
+
+        // The arguments that buildsys sets itself never collide.
+        for build in [test_package_build(), test_variant_build()] {
+            assert!(duplicate_build_arg_keys(&build.assembled_build_args()).is_empty());
+            assert!(build.validated().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_build_arg_file() {
+        let contents = "\
+# Build tags for the go packages.
+GO_BUILD_TAGS=netgo
+
+  # An indented comment.
+EXTRA_FLAGS=-O2 -g
+EMPTY=
+GO_BUILD_TAGS=netgo osusergo
+";
+        let args = parse_build_arg_file(Path::new("build-args.env"), contents).unwrap();
+        assert_eq!(
+            args,
+            BTreeMap::from([
+                ("EMPTY".to_string(), String::new()),
+                ("EXTRA_FLAGS".to_string(), "-O2 -g".to_string()),
+                ("GO_BUILD_TAGS".to_string(), "netgo osusergo".to_string()),
+            ])
+        );
+
+        let err = parse_build_arg_file(Path::new("build-args.env"), "A=1\nB\n").unwrap_err();
+        assert!(matches!(
+            err,
+            error::Error::BuildArgFileLine { line: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn test_file_build_args_override_manifest() {
+        let mut build = test_package_build();
+        build.manifest_build_args = BTreeMap::from([
+            ("GO_BUILD_TAGS".to_string(), "netgo".to_string()),
+            ("EXTRA_FLAGS".to_string(), "-g".to_string()),
+        ]);
+        build.file_build_args =
+            BTreeMap::from([("GO_BUILD_TAGS".to_string(), "osusergo".to_string())]);
+        let args = build.validated().unwrap().build_args();
+        assert_eq!(build_arg_value(&args, "GO_BUILD_TAGS"), Some("osusergo"));
+        assert_eq!(build_arg_value(&args, "EXTRA_FLAGS"), Some("-g"));
+
+        let mut build = test_package_build();
+        build.file_build_args = BTreeMap::from([("TOKEN".to_string(), "x".to_string())]);
+        let err = build.validated().err().unwrap();
+        assert!(matches!(err, error::Error::ReservedBuildArg { ref key } if key == "TOKEN"));
+    }
+
+    fn test_common(root_dir: &Path, extra_args: &[&str]) -> Common {
+        let root = root_dir.display().to_string();
+        let tools = root_dir.join("build/tools").display().to_string();
+        let mut args = vec![
+            "buildsys",
+            "--arch=x86_64",
+            "--cargo-metadata-path=/dev/null",
+            "--root-dir",
+            &root,
+            "--state-dir=/tmp/state",
+            "--version-full=1.0.0",
+            "--cargo-manifest-dir=/tmp/pkg",
+            "--sdk-image=sdk:latest",
+            "--tools-dir",
+            &tools,
+        ];
+        args.extend(extra_args);
+        Common::parse_from(args)
+    }
+
+    #[test]
+    fn test_build_context() {
+        let root_dir = TempDir::new().unwrap();
+        write_files(
+            root_dir.path(),
+            &["build/tools/build.Dockerfile", "sources/Cargo.toml"],
+        );
+
+        let common = test_common(root_dir.path(), &[]);
+        assert_eq!(build_context(&common).unwrap(), root_dir.path());
+
+        let common = test_common(root_dir.path(), &["--context", "sources"]);
+        let context = build_context(&common).unwrap();
+        assert_eq!(context, root_dir.path().join("sources"));
+
+        let mut build = test_package_build();
+        build.context = context.clone();
+        let command = build.build_command();
+        assert_eq!(command[0], "build");
+        assert_eq!(command[1], context.display().to_string());
+    }
+
+    #[test]
+    fn test_context_tar() {
+        let root_dir = TempDir::new().unwrap();
+        write_files(
+            root_dir.path(),
+            &["build/tools/build.Dockerfile", "context.tar"],
+        );
+
+        let common = test_common(root_dir.path(), &[]);
+        assert_eq!(context_tar(&common).unwrap(), None);
+
+        let common = test_common(root_dir.path(), &["--context-tar", "context.tar"]);
+        assert_eq!(
+            context_tar(&common).unwrap(),
+            Some(root_dir.path().join("context.tar"))
+        );
+
+        let common = test_common(root_dir.path(), &["--context-tar", "missing.tar"]);
+        assert!(matches!(
+            context_tar(&common),
+            Err(error::Error::ContextTar { .. })
+        ));
+
+        // The context is read from stdin, and the Dockerfile from the same path inside it.
+        let mut build = test_package_build();
+        build.context_tar = Some(PathBuf::from(STDIN_CONTEXT));
+        let command = build.build_command();
+        assert_eq!(command[0], "build");
+        assert_eq!(command[1], "-");
+        assert_eq!(
+            flag_values(&command, "--file").collect::<Vec<_>>(),
+            ["build/tools/build.Dockerfile"]
+        );
+    }
+
+    #[test]
+    fn test_mirror_image() {
+        let mirror = "mirror.example.com:5000";
+        for (image, expected) in [
+            ("sdk", "mirror.example.com:5000/library/sdk"),
+            ("sdk:latest", "mirror.example.com:5000/library/sdk:latest"),
+            (
+                "bottlerocket/sdk:v1",
+                "mirror.example.com:5000/bottlerocket/sdk:v1",
+            ),
+            (
+                "docker.io/alpine:3",
+                "mirror.example.com:5000/library/alpine:3",
+            ),
+            (
+                "index.docker.io/library/alpine",
+                "mirror.example.com:5000/library/alpine",
+            ),
+            (
+                "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0",
+                "mirror.example.com:5000/bottlerocket/bottlerocket-sdk:v0.50.0",
+            ),
+            (
+                "localhost:5000/sdk@sha256:abcd",
+                "mirror.example.com:5000/sdk@sha256:abcd",
+            ),
+            (
+                "localhost/team/sdk:v1",
+                "mirror.example.com:5000/team/sdk:v1",
+            ),
+            // References that already go through the mirror are left alone.
+            (
+                "mirror.example.com:5000/bottlerocket/sdk:v1",
+                "mirror.example.com:5000/bottlerocket/sdk:v1",
+            ),
+        ] {
+            assert_eq!(mirror_image(image, mirror), expected, "{image}");
+        }
+
+        // A mirror may serve its images under a path, and a trailing slash is ignored.
+        assert_eq!(
+            mirror_image("public.ecr.aws/bottlerocket/sdk:v1", "mirror.local/ecr/"),
+            "mirror.local/ecr/bottlerocket/sdk:v1"
+        );
+        assert_eq!(
+            mirror_image("mirror.local/ecr/bottlerocket/sdk:v1", "mirror.local/ecr"),
+            "mirror.local/ecr/bottlerocket/sdk:v1"
+        );
+        assert_eq!(pull_image("sdk:latest", None), "sdk:latest");
+    }
+
+    #[test]
+    fn test_registry_mirror_keeps_sdk_label() {
+        let mut build = test_variant_build();
+        build.common_build_args = build
+            .common_build_args
+            .with_registry_mirror(Some("mirror.local"));
+        let command = build.build_command();
+        assert_eq!(
+            build_arg_value(&command, "SDK"),
+            Some("mirror.local/library/sdk:latest")
+        );
+        assert_eq!(
+            flag_values(&command, "--label").collect::<Vec<_>>(),
+            [format!("{SDK_LABEL}=sdk:latest")]
+        );
+        assert!(build
+            .bypass_run_command()
+            .contains(&"mirror.local/library/sdk:latest".to_string()));
+    }
+
+    fn variant_manifest(root: &Path, included_packages: &str) -> ManifestInfo {
+        let path = root.join("Cargo.toml");
+        fs::write(
+            &path,
+            format!(
+                r#"
+                [package]
+                name = "aws-dev"
+
+                [package.metadata.build-variant]
+                included-packages = {included_packages}
+                "#
+            ),
+        )
+        .unwrap();
+        ManifestInfo::new(&path).unwrap()
+    }
+
+    #[test]
+    fn test_effective_packages() {
+        let root_dir = TempDir::new().unwrap();
+        let manifest = variant_manifest(
+            root_dir.path(),
+            r#"[
+                "release",
+                { name = "grub", arch = "x86_64" },
+                { name = "shim", arch = "aarch64" },
+                "kernel",
+                "release",
+                { name = "grub", arch = "x86_64" },
+                { name = "efi", arch = "x86_64" },
+                { name = "efi", arch = "aarch64" },
+            ]"#,
+        );
+        assert_eq!(
+            effective_packages(&manifest, SupportedArch::X86_64).unwrap(),
+            ["release", "grub", "kernel", "efi"]
+        );
+        assert_eq!(
+            effective_packages(&manifest, SupportedArch::Aarch64).unwrap(),
+            ["release", "shim", "kernel", "efi"]
+        );
+
+        // A variant without included packages installs none.
+        fs::write(
+            root_dir.path().join("Cargo.toml"),
+            "[package]\nname = \"glibc\"\n",
+        )
+        .unwrap();
+        let manifest = ManifestInfo::new(root_dir.path().join("Cargo.toml")).unwrap();
+        assert!(effective_packages(&manifest, SupportedArch::X86_64)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_effective_packages_conflict() {
+        let root_dir = TempDir::new().unwrap();
+        let manifest = variant_manifest(
+            root_dir.path(),
+            r#"["release", "grub", { name = "grub", arch = "aarch64" }]"#,
+        );
+        // The conflict is an error even for the architecture the narrower entry does not name.
+        for arch in [SupportedArch::X86_64, SupportedArch::Aarch64] {
+            let err = effective_packages(&manifest, arch).unwrap_err();
+            assert!(
+                matches!(err, error::Error::ConflictingIncludedPackage { ref name, ref arch } if name == "grub" && arch == "aarch64"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rpm_name() {
+        let name = |file| rpm_name(file, "x86_64");
+        assert_eq!(
+            name("bottlerocket-kernel-6.1-6.1.102-1.1722000000.br1.x86_64.rpm"),
+            Some("bottlerocket-kernel-6.1")
+        );
+        assert_eq!(
+            name("bottlerocket-os-1.0.0-1.noarch.rpm"),
+            Some("bottlerocket-os")
+        );
+        assert_eq!(name("bottlerocket-grub-2.06-1.aarch64.rpm"), None);
+        assert_eq!(name("bottlerocket-release.x86_64.rpm"), None);
+        assert_eq!(name("repomd.xml"), None);
+    }
+
+    #[test]
+    fn test_missing_packages() {
+        let build_dir = TempDir::new().unwrap();
+        for rpm in [
+            "rpms/release/bottlerocket-release-1.0-1.x86_64.rpm",
+            "rpms/grub/bottlerocket-grub-2.06-1.aarch64.rpm",
+            "kits/core-kit/x86_64/Packages/bottlerocket-kernel-6.1-6.1.102-1.x86_64.rpm",
+            "external-kits/vendor/kit/bottlerocket-os-1.0.0-1.noarch.rpm",
+        ] {
+            let path = build_dir.path().join(rpm);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let expected =
+            ["release", "kernel-6.1", "os", "grub", "acpid", "kernel"].map(str::to_string);
+        // The grub RPM is for another architecture, and the kernel RPM belongs to "kernel-6.1".
+        assert_eq!(
+            missing_packages(build_dir.path(), &expected, SupportedArch::X86_64),
+            ["grub", "acpid", "kernel"]
+        );
+        assert_eq!(
+            missing_packages(build_dir.path(), &expected[..3], SupportedArch::X86_64),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_verify_packages() {
+        let root_dir = TempDir::new().unwrap();
+        let rpm = root_dir
+            .path()
+            .join("build/rpms/release/bottlerocket-release-1.0-1.x86_64.rpm");
+        fs::create_dir_all(rpm.parent().unwrap()).unwrap();
+        fs::write(rpm, "").unwrap();
+
+        let mut build = test_variant_build();
+        build.root_dir = root_dir.path().to_path_buf();
+        let TargetBuildArgs::Variant(ref mut variant_args) = build.target_build_args else {
+            unreachable!()
+        };
+        variant_args.packages = "release os".to_string();
+        // Nothing is checked unless it was asked for.
+        build.verify_packages().unwrap();
+
+        let TargetBuildArgs::Variant(ref mut variant_args) = build.target_build_args else {
+            unreachable!()
+        };
+        variant_args.verify_packages = true;
+        let err = build.verify_packages().unwrap_err();
+        assert!(
+            matches!(err, error::Error::MissingPackages { ref missing } if missing == &["os"]),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_manifest_sdk_image() {
+        let root_dir = TempDir::new().unwrap();
+        let common = test_common(root_dir.path(), &[]);
+        let path = root_dir.path().join("Cargo.toml");
+        fs::write(
+            &path,
+            r#"
+            [package]
+            name = "aws-dev"
+
+            [package.metadata.build-variant]
+            sdk-image = "sdk:pinned"
+            "#,
+        )
+        .unwrap();
+        let manifest = ManifestInfo::new(&path).unwrap();
+
+        let mut build = test_variant_build();
+        build.common_build_args.sdk = sdk_image(&common, &manifest);
+        let build_args = build.effective_args().build_args;
+        assert!(build_args.contains(&"SDK=sdk:pinned".to_string()));
+        assert!(!build_args.contains(&"SDK=sdk:latest".to_string()));
+        let command = build.build_command();
+        let label = format!("{SDK_LABEL}=sdk:pinned");
+        assert_eq!(
+            flag_values(&command, "--label").collect::<Vec<_>>(),
+            [label]
+        );
+
+        fs::write(&path, "[package]\nname = \"glibc\"\n").unwrap();
+        let manifest = ManifestInfo::new(&path).unwrap();
+        assert_eq!(sdk_image(&common, &manifest), "sdk:latest");
+    }
+
+    #[test]
+    fn test_build_context_missing() {
+        let root_dir = TempDir::new().unwrap();
+        write_files(root_dir.path(), &["sources/Cargo.toml"]);
+
+        let common = test_common(root_dir.path(), &["--context", "nonexistent"]);
+        assert!(matches!(
+            build_context(&common),
+            Err(error::Error::BuildContext { .. })
+        ));
+
+        let common = test_common(root_dir.path(), &["--context", "sources"]);
+        assert!(matches!(
+            build_context(&common),
+            Err(error::Error::DockerfileMissing { .. })
+        ));
+    }
+
+    #[test]
+    fn test_effective_args() {
+        let build = test_variant_build();
+        let effective_args = build.effective_args();
+
+        let keys = effective_args
+            .build_args
+            .iter()
+            .filter_map(|arg| arg.split_once('=').map(|(key, _)| key))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "ARCH",
+                "BUILDER_UID",
+                "BUILD_ID",
+                "BYPASS_SOCKET",
+                "DATA_IMAGE_PUBLISH_SIZE_GIB",
+                "DATA_IMAGE_SIZE_GIB",
+                "DATA_VOLUMES",
+                "EXTERNAL_KIT_DEPENDENCIES",
+                "FIPS",
+                "GOARCH",
+                "IMAGE_FORMAT",
+                "IMAGE_NAME",
+                "KERNEL_PARAMETERS",
+                "KIT_DEPENDENCIES",
+                "NOCACHE",
+                "OS_IMAGE_PUBLISH_SIZE_GIB",
+                "OS_IMAGE_SIZE_GIB",
+                "OUTPUT_SOCKET",
+                "OUTPUT_SOCKET_TOKEN",
+                "PACKAGES",
+                "PACKAGE_DEPENDENCIES",
+                "PARTITION_PLAN",
+                "PRETTY_NAME",
+                "SDK",
+                "TOKEN",
+                "VARIANT",
+                "VARIANT_FAMILY",
+                "VARIANT_FLAVOR",
+                "VARIANT_PLATFORM",
+                "VARIANT_RUNTIME",
+                "VERSION_ID",
+            ]
+        );
+        assert!(effective_args
+            .build_args
+            .contains(&"PRETTY_NAME=Bottlerocket OS".to_string()));
+        assert_eq!(
+            effective_args.secrets,
+            ["type=file,id=root.json,src=/tmp/root.json"]
+        );
+
+        let printed = effective_args.to_string();
+        let (build_args, secrets) = printed.split_once("Secrets:\n").unwrap();
+        assert!(build_args.starts_with("Build arguments:\n  ARCH=x86_64\n"));
+        assert!(!build_args.contains("root.json"));
+        assert_eq!(secrets, "  type=file,id=root.json,src=/tmp/root.json\n");
+    }
+
+    #[test]
+    fn test_retry_patterns() {
+        let build = test_package_build()
+            .with_retry_patterns([Regex::new("(?m)^registry unavailable$").unwrap()]);
+        let messages = build.retry_patterns();
+        assert_eq!(messages.len(), default_retry_patterns().len() + 1);
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &messages,
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+
+        // The caller's pattern is retried along with the defaults.
+        assert_eq!(
+            retry.action("registry unavailable\n", 1, false),
+            RetryAction::Retry
+        );
+        assert_eq!(
+            retry.action("ERROR: unexpected EOF\n", 1, false),
+            RetryAction::Retry
+        );
+        assert_eq!(retry.action("oops\n", 1, false), RetryAction::Fail);
+
+        // Without it, the same failure is not retried.
+        let build = test_package_build();
+        let messages = build.retry_patterns();
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &messages,
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+        assert_eq!(
+            retry.action("registry unavailable\n", 1, false),
+            RetryAction::Fail
+        );
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let messages = default_retry_patterns();
+        let build = test_package_build();
+        assert!(matches!(
+            build.build_retry(&messages, None),
+            Retry::Yes { .. }
+        ));
+
+        // The first failure is final, even if it would otherwise be retried.
+        let mut build = test_package_build();
+        build.fail_fast = true;
+        let retry = build.build_retry(&messages, None);
+        let mut events = Vec::new();
+        let err = run_command(
+            "sh",
+            &sh("echo 'ERROR: unexpected EOF'; exit 1"),
+            Path::new("."),
+            None,
+            retry,
+            false,
+            OutputLimits::default(),
+            &mut io::sink(),
+            &mut |e| events.push(e),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            crate::command::error::Error::DockerExecution { .. }
+        ));
+        assert_eq!(
+            events,
+            [
+                BuildEvent::AttemptStarted { n: 1 },
+                BuildEvent::OutputLine("ERROR: unexpected EOF".to_string()),
+                BuildEvent::AttemptFailed {
+                    n: 1,
+                    matched_retry: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bypass_run_flags() {
+        let mut build = test_package_build();
+        build.bypass_run_flags = vec![
+            "--security-opt=no-new-privileges".to_string(),
+            "--cap-drop".to_string(),
+            "ALL".to_string(),
+        ];
+
+        let command = build.bypass_run_command();
+        let sdk = command.iter().position(|a| a == "sdk:latest").unwrap();
+        assert_eq!(
+            command[sdk - 3..sdk],
+            ["--security-opt=no-new-privileges", "--cap-drop", "ALL"]
+        );
+        assert_eq!(command[sdk + 1..sdk + 3], ["pipesys", "serve"]);
+        assert!(build.validated().is_ok());
+    }
+
+    #[test]
+    fn test_writable_bypass() {
+        let mut build = test_package_build();
+        let command = build.bypass_run_command();
+        assert!(flag_values(&command, "-v").any(|v| v.ends_with(":/bypass:ro")));
+        assert!(!command.contains(&"--writable".to_string()));
+
+        build.writable_bypass = true;
+        let command = build.bypass_run_command();
+        assert!(flag_values(&command, "-v").any(|v| v.ends_with(":/bypass:rw")));
+        assert_eq!(command.last().unwrap(), "--writable");
+    }
+
+    #[test]
+    fn test_build_ulimits() {
+        let mut build = test_package_build();
+        assert_eq!(flag_values(&build.build_command(), "--ulimit").count(), 0);
+
+        build.ulimits = ["nofile=1024:65536", "stack=-1"]
+            .map(|ulimit| ulimit.parse().unwrap())
+            .to_vec();
+        let expected = ["nofile=1024:65536", "stack=-1:-1"];
+        assert_eq!(
+            flag_values(&build.build_command(), "--ulimit").collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            flag_values(&build.bypass_run_command(), "--ulimit").collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_pipesys_bin() {
+        let root_dir = TempDir::new().unwrap();
+        write_files(root_dir.path(), &["bin/pipesys"]);
+        let pipesys = root_dir.path().join("bin/pipesys");
+        let mount = |build: &DockerBuild| {
+            let command = build.bypass_run_command();
+            flag_values(&command, "-v")
+                .filter(|v| v.ends_with(":/usr/local/bin/pipesys:ro"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let mut build = test_package_build();
+        let common = test_common(root_dir.path(), &[]);
+        build.pipesys = PipesysBin::new(&common).unwrap();
+        let default = root_dir.path().join("build/tools/pipesys");
+        assert_eq!(
+            mount(&build),
+            [format!("{}:/usr/local/bin/pipesys:ro", default.display())]
+        );
+
+        let pipesys_arg = format!("--pipesys-bin={}", pipesys.display());
+        let common = test_common(root_dir.path(), &[&pipesys_arg]);
+        build.pipesys = PipesysBin::new(&common).unwrap();
+        assert_eq!(
+            mount(&build),
+            [format!("{}:/usr/local/bin/pipesys:ro", pipesys.display())]
+        );
+
+        let common = test_common(root_dir.path(), &["--pipesys-from-image"]);
+        build.pipesys = PipesysBin::new(&common).unwrap();
+        assert!(mount(&build).is_empty());
+        let command = build.bypass_run_command();
+        let sdk = command.iter().position(|a| a == "sdk:latest").unwrap();
+        assert_eq!(command[sdk + 1..sdk + 3], ["pipesys", "serve"]);
+
+        let common = test_common(root_dir.path(), &["--pipesys-bin=/nonexistent/pipesys"]);
+        assert!(matches!(
+            PipesysBin::new(&common),
+            Err(error::Error::PipesysBinMissing { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bind_with_retry() {
+        let mut names = vec!["second".to_string(), "third".to_string()].into_iter();
+        let mut attempts = Vec::new();
+        let (name, bound) = bind_with_retry(
+            "first".to_string(),
+            3,
+            || names.next().unwrap(),
+            |name| {
+                attempts.push(name.to_string());
+                if name == "first" {
+                    return Err(pipesys::Error::SocketInUse {
+                        socket: name.to_string(),
+                    });
+                }
+                Ok(name.len())
+            },
+        )
+        .unwrap();
+        assert_eq!(attempts, ["first", "second"]);
+        assert_eq!((name.as_str(), bound), ("second", 6));
+
+        // The attempts are bounded.
+        let mut attempts = 0;
+        let result = bind_with_retry(
+            "taken".to_string(),
+            3,
+            || "taken".to_string(),
+            |name| {
+                attempts += 1;
+                Err::<(), _>(pipesys::Error::SocketInUse {
+                    socket: name.to_string(),
+                })
+            },
+        );
+        assert_eq!(attempts, 3);
+        assert!(matches!(
+            result,
+            Err(error::Error::OutputServer {
+                source: pipesys::Error::SocketInUse { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_backup_output_socket() {
+        let mut build = test_package_build();
+        let primary = build.common_build_args.output_socket.clone();
+        assert_eq!(
+            build_arg_value(&build.build_command(), "OUTPUT_SOCKET"),
+            Some(primary.as_str())
+        );
+
+        build.backup_output_socket = true;
+        assert_eq!(
+            build_arg_value(&build.build_command(), "OUTPUT_SOCKET"),
+            Some(format!("{primary},{primary}-backup").as_str())
+        );
+    }
+
+    #[test]
+    fn test_emit_names() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("names.json");
+        let mut build = test_variant_build();
+        build.emit_names = Some(path.clone());
+        build.backup_output_socket = true;
+        build.emit_names().unwrap();
+
+        let token = token("/home/user/project");
+        let tag = format!("buildsys-var-aws-dev-x86_64-{token}");
+        let output_socket = build.common_build_args.output_socket.clone();
+        assert!(output_socket.starts_with(&format!("buildsys-output-{token}-")));
+        let names: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            names,
+            serde_json::json!({
+                "image": tag,
+                "token": token,
+                "bypass_container": format!("{tag}-bypass"),
+                "bypass_socket": format!("{tag}-bypass"),
+                "output_sockets": [output_socket, format!("{output_socket}-backup")],
+            })
+        );
+
+        // Without the bypass container, there is nothing to attach to.
+        build.no_bypass = true;
+        build.emit_names().unwrap();
+        let names: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert!(names["bypass_container"].is_null());
+        assert!(names["bypass_socket"].is_null());
+    }
+
+    #[test]
+    fn test_wait_for_bypass() {
+        let timeout = Duration::from_secs(5);
+
+        // The container starts serving.
+        let (_tx, rx) = mpsc::channel::<Result<Output>>();
+        assert!(wait_for_bypass(&rx, || true, timeout).is_ok());
+
+        // The `docker run` command fails before the container is ready.
+        let (tx, rx) = mpsc::channel();
+        tx.send(error::DockerExecutionSnafu { args: "run" }.fail())
+            .unwrap();
+        assert!(matches!(
+            wait_for_bypass(&rx, || false, timeout),
+            Err(error::Error::BypassStartFailed { .. })
+        ));
+
+        // The container exits without ever serving.
+        let (tx, rx) = mpsc::channel();
+        drop(tx);
+        assert!(matches!(
+            wait_for_bypass(&rx, || false, timeout),
+            Err(error::Error::BypassExited)
+        ));
+
+        // The container never becomes ready.
+        let (_tx, rx) = mpsc::channel::<Result<Output>>();
+        assert!(matches!(
+            wait_for_bypass(&rx, || false, Duration::ZERO),
+            Err(error::Error::BypassStartTimeout { .. })
+        ));
+    }
+
+    #[test]
+    fn test_no_bypass() {
+        let mut build = test_package_build();
+        let bypass = build.bypass_commands().unwrap();
+        assert_eq!(bypass.run[0], "run");
+        assert_eq!(bypass.rm[..2], ["rm", "--force"]);
+        assert!(build_arg_value(&build.build_command(), "BYPASS_SOCKET")
+            .unwrap()
+            .ends_with("-bypass"));
+
+        build.no_bypass = true;
+        assert!(build.bypass_commands().is_none());
+
+        let command = build.build_command();
+        assert_eq!(build_arg_value(&command, "BYPASS_SOCKET"), Some(""));
+        assert_eq!(
+            flag_values(&command, "--build-context").collect::<Vec<_>>(),
+            ["bypass=/home/user/project"]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_root() {
+        let root_dir = TempDir::new().unwrap();
+        let common = test_common(
+            root_dir.path(),
+            &["--snapshot-root", "--snapshot-dir=/dev/shm"],
+        );
+        let snapshot = root_snapshot(&common, &dockerfile(&common)).unwrap();
+        let mut build = test_package_build();
+        build.snapshot = snapshot;
+        let path = format!("/dev/shm/{}-snapshot", build.tag);
+
+        // The bypass container serves the snapshot instead of the live root.
+        assert!(flag_values(&build.bypass_run_command(), "-v")
+            .any(|v| v == format!("{path}:/bypass:ro")));
+
+        // So does the named build context that takes its place.
+        build.no_bypass = true;
+        assert_eq!(
+            flag_values(&build.build_command(), "--build-context").collect::<Vec<_>>(),
+            [format!("bypass={path}")]
+        );
+
+        let common = test_common(root_dir.path(), &[]);
+        assert!(root_snapshot(&common, &dockerfile(&common))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_build_cleanup() {
+        let root_dir = TempDir::new().unwrap();
+        let snapshot_dir = TempDir::new().unwrap();
+        write_files(root_dir.path(), &["Cargo.toml", "context.tar"]);
+        let snapshot = RootSnapshot::new(root_dir.path(), snapshot_dir.path(), u64::MAX);
+        snapshot.take("pkg-a").unwrap();
+        let context_tar = root_dir.path().join("context.tar");
+
+        // Dropping the guard cleans up, as it does when a build returns early.
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (tx, rx) = mpsc::channel::<()>();
+        runtime.spawn_blocking(move || rx.recv());
+        let cleanup = BuildCleanup {
+            saved_context_tar: Some(context_tar.clone()),
+            snapshot: Some((snapshot.clone(), "pkg-a".to_string())),
+            runtime: Some(runtime),
+        };
+        // The runtime is shut down without waiting for the blocked task.
+        drop(cleanup);
+        drop(tx);
+        assert!(!context_tar.exists());
+        assert!(!snapshot.path("pkg-a").exists());
+
+        // Finishing cleans up once, without leaving anything for the drop to do.
+        snapshot.take("pkg-a").unwrap();
+        let cleanup = BuildCleanup {
+            saved_context_tar: None,
+            snapshot: Some((snapshot.clone(), "pkg-a".to_string())),
+            runtime: None,
+        };
+        cleanup.finish().unwrap();
+        assert!(!snapshot.path("pkg-a").exists());
+        assert!(root_dir.path().join("Cargo.toml").exists());
+    }
+
+    #[test]
+    fn test_manifest_needs_bypass() {
+        let root_dir = TempDir::new().unwrap();
+        let common = test_common(root_dir.path(), &[]);
+        let path = root_dir.path().join("Cargo.toml");
+        fs::write(
+            &path,
+            r#"
+            [package]
+            name = "pkg-a"
+
+            [package.metadata.build-package]
+            needs-bypass = false
+            "#,
+        )
+        .unwrap();
+        let manifest = ManifestInfo::new(&path).unwrap();
+
+        let mut build = test_package_build();
+        build.no_bypass = no_bypass(&common, &manifest);
+        assert!(build.bypass_commands().is_none());
+
+        // The package stage still reads the project root, so the build must get it through the
+        // named build context instead of the socket.
+        let command = build.build_command();
+        assert_eq!(build_arg_value(&command, "BYPASS_SOCKET"), Some(""));
+        assert_eq!(
+            flag_values(&command, "--build-context").collect::<Vec<_>>(),
+            ["bypass=/home/user/project"]
+        );
+
+        fs::write(
+            &path,
+            "[package]\nname = \"pkg-a\"\n[package.metadata.build-package]\n",
+        )
+        .unwrap();
+        let manifest = ManifestInfo::new(&path).unwrap();
+        assert!(!no_bypass(&common, &manifest));
+        let common = test_common(root_dir.path(), &["--no-bypass"]);
+        assert!(no_bypass(&common, &manifest));
+    }
+
+    #[test]
+    fn test_reserved_bypass_run_flags() {
+        for flag in [
+            "--net=none",
+            "--pid",
+            "-v/tmp:/bypass",
+            "--volume",
+            "--user=1000",
+        ] {
+            let mut build = test_package_build();
+            build.bypass_run_flags = vec![flag.to_string()];
+            assert!(
+                matches!(
+                    build.validated(),
+                    Err(error::Error::ReservedBypassRunFlag { .. })
+                ),
+                "{flag}"
+            );
+        }
+
+        assert!(!is_reserved_bypass_run_flag("--tmpfs=/tmp"));
+        assert!(!is_reserved_bypass_run_flag("--volume-driver=local"));
+    }
+
+    #[test]
+    fn test_unchanged_variant_skipped() {
+        let root_dir = TempDir::new().unwrap();
+        let rpms_dir = root_dir.path().join("build").join("rpms");
+        fs::create_dir_all(&rpms_dir).unwrap();
+        fs::write(rpms_dir.join("release.rpm"), "release-1").unwrap();
+        write_files(root_dir.path(), &["variants/aws-dev/Cargo.toml"]);
+        let sources = [root_dir.path().join("variants/aws-dev")];
+        let output_dir = TempDir::new().unwrap();
+        fs::write(output_dir.path().join("os.img"), "image").unwrap();
+
+        let variant = || {
+            let mut build = test_variant_build();
+            build.root_dir = root_dir.path().to_path_buf();
+            build.artifacts_dirs = vec![output_dir.path().to_path_buf()];
+            build
+        };
+        let digest = |build: DockerBuild| {
+            build
+                .with_up_to_date_check(false, &sources)
+                .unwrap()
+                .input_digest
+                .unwrap()
+        };
+
+        // Record a successful build, as the end of a real one would.
+        let record = UpToDateRecord::new(&root_dir.path().join("state"));
+        let hashes = ArtifactHashes::new(output_dir.path(), &["os.img"], 1).unwrap();
+        record.write(&digest(variant()), &hashes).unwrap();
+
+        // The same inputs match the record, so the build is skipped, even though every build draws
+        // a new NOCACHE.
+        let mut rerun = variant();
+        rerun.common_build_args.nocache = "other".to_string();
+        assert!(record.matches(&digest(rerun), output_dir.path(), 1));
+
+        // A changed package, or a changed source, means the variant is built again.
+        fs::write(rpms_dir.join("release.rpm"), "release-2").unwrap();
+        assert!(!record.matches(&digest(variant()), output_dir.path(), 1));
+        fs::write(rpms_dir.join("release.rpm"), "release-1").unwrap();
+        fs::write(
+            root_dir.path().join("variants/aws-dev/Cargo.toml"),
+            "changed",
+        )
+        .unwrap();
+        assert!(!record.matches(&digest(variant()), output_dir.path(), 1));
+
+        // Forcing the build leaves nothing to compare against.
+        let forced = variant().with_up_to_date_check(true, &sources).unwrap();
+        assert!(forced.input_digest.is_none());
+    }
+
+    #[test]
+    fn test_common_build_args_seeded() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let root_dir = TempDir::new().unwrap();
+        let args = |seed| {
+            CommonBuildArgs::with_rng(
+                root_dir.path(),
+                "sdk:latest".to_string(),
+                SupportedArch::X86_64,
+                OutputCleanup::BeforeBuild,
+                &mut StdRng::seed_from_u64(seed),
+            )
+        };
+
+        let (a, b, c) = (args(1), args(1), args(2));
+        assert_eq!(a.nocache, b.nocache);
+        assert_eq!(a.output_socket, b.output_socket);
+        assert!(a
+            .output_socket
+            .starts_with(&format!("buildsys-output-{}-", a.token)));
+        assert_ne!(a.nocache, c.nocache);
+        assert_ne!(a.output_socket, c.output_socket);
+        // The socket name does not reuse the value drawn for NOCACHE.
+        assert!(!a.output_socket.ends_with(&a.nocache));
+        assert_eq!(a.output_token, b.output_token);
+        assert_ne!(a.output_token, c.output_token);
+        assert_eq!(a.output_token.len(), 32);
+        assert!(!a.output_socket.contains(&a.output_token));
+    }
+
+    #[test]
+    fn test_input_hash() {
+        let root_dir = TempDir::new().unwrap();
+        let root = root_dir.path();
+        write_files(
+            root,
+            &["build/tools/build.Dockerfile", "packages/pkg-a/pkg-a.spec"],
+        );
+        let inputs = [
+            root.join("build/tools/build.Dockerfile"),
+            root.join("packages/pkg-a"),
+        ];
+        let build_args = vec!["--build-arg".to_string(), "PACKAGE=pkg-a".to_string()];
+
+        // The same inputs always produce the same value.
+        let nocache = input_hash(root, &inputs, &build_args).unwrap();
+        assert_eq!(nocache, input_hash(root, &inputs, &build_args).unwrap());
+
+        // Changing the contents of a file changes the value.
+        fs::write(root.join("packages/pkg-a/pkg-a.spec"), "Version: 2").unwrap();
+        let changed = input_hash(root, &inputs, &build_args).unwrap();
+        assert_ne!(nocache, changed);
+
+        // So does adding a file, or changing a build argument.
+        write_files(root, &["packages/pkg-a/0001-fix.patch"]);
+        let added = input_hash(root, &inputs, &build_args).unwrap();
+        assert_ne!(changed, added);
+
+        let build_args = vec!["--build-arg".to_string(), "PACKAGE=pkg-b".to_string()];
+        assert_ne!(added, input_hash(root, &inputs, &build_args).unwrap());
+    }
+
+    #[test]
+    fn test_input_hash_dockerfile() {
+        let root_dir = TempDir::new().unwrap();
+        let root = root_dir.path();
+        let common = test_common(root, &[]);
+        let dockerfile = dockerfile(&common);
+        write_files(root, &["build/tools/build.Dockerfile"]);
+        let inputs = [dockerfile.clone()];
+
+        let before = input_hash(root, &inputs, &[]).unwrap();
+        fs::write(&dockerfile, "FROM scratch AS other\n").unwrap();
+        let after = input_hash(root, &inputs, &[]).unwrap();
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn test_version_tag() {
+        let tag = version_tag("aws-k8s-1.29", SupportedArch::Aarch64, "1.19.2", "a1b2c3d4");
+        assert_eq!(tag, "aws-k8s-1.29-aarch64:1.19.2-a1b2c3d4");
+
+        let mut build = test_package_build();
+        build.extra_tags = vec![tag.clone()];
+        let command = build.build_command();
+        let tags = command
+            .iter()
+            .zip(command.iter().skip(1))
+            .filter(|(flag, _)| *flag == "--tag")
+            .map(|(_, tag)| tag.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tags, [build.tag.as_str(), tag.as_str()]);
+    }
+
+    #[test]
+    fn test_data_volumes_build_args() {
+        let image_layout: ImageLayout = toml::from_str(
+            r#"
+            os-image-size-gib = 2
+            data-image-size-gib = 1
+            publish-image-size-hint-gib = 30
+            data-volumes = [
+                { name = "cache", size-gib = 4, role = "scratch" },
+                { name = "logs", size-gib = 2, role = "persistent" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let (os_image_publish_size_gib, data_image_publish_size_gib) =
+            image_layout.publish_image_sizes_gib();
+
+        let mut build = test_variant_build();
+        let TargetBuildArgs::Variant(ref mut variant_args) = build.target_build_args else {
+            panic!("expected variant build args");
+        };
+        variant_args.data_volumes = data_volumes(&image_layout);
+        variant_args.data_image_publish_size_gib = data_image_publish_size_gib;
+        variant_args.os_image_publish_size_gib = os_image_publish_size_gib.to_string();
+
+        let args = build.build_args();
+        assert_eq!(
+            build_arg_value(&args, "DATA_VOLUMES"),
+            Some("cache:scratch:4 logs:persistent:2")
+        );
+        assert_eq!(
+            build_arg_value(&args, "OS_IMAGE_PUBLISH_SIZE_GIB"),
+            Some("2")
+        );
+        assert_eq!(
+            build_arg_value(&args, "DATA_IMAGE_PUBLISH_SIZE_GIB"),
+            Some("28")
+        );
+
+        // The single data image form passes an empty list.
+        assert_eq!(data_volumes(&ImageLayout::default()), "");
+        let args = test_variant_build().build_args();
+        assert_eq!(build_arg_value(&args, "DATA_VOLUMES"), Some(""));
+    }
+
+    #[test]
+    fn test_forward_env_secrets() {
+        let vars = ["MY_TOKEN".to_string(), "UNSET_VAR".to_string()];
+        let args = forward_env_secrets(&vars, |var| var == "MY_TOKEN");
+        assert_eq!(args, ["--secret", "type=env,id=my-token.env,src=MY_TOKEN"]);
+
+        assert!(forward_env_secrets(&vars, |_| false).is_empty());
+    }
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn test_attempt_timer() {
+        let dir = TempDir::new().unwrap();
+        let marker = dir.path().join("failed-once");
+        // Fail with a known transient error the first time, then succeed.
+        let script = sh(&format!(
+            "if [ -e {m} ]; then sleep 0.1; echo built; else touch {m}; echo 'ERROR: unexpected EOF'; exit 1; fi",
+            m = marker.display()
+        ));
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &[&*UNEXPECTED_EOF_ERROR],
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+
+        let mut timings = PhaseTimings::default();
+        let mut events = Vec::new();
+        let mut attempt_timer = AttemptTimer::default();
+        let build_started = Instant::now();
+        run_command(
+            "sh",
+            &script,
+            Path::new("."),
+            None,
+            retry,
+            true,
+            OutputLimits::default(),
+            &mut Vec::new(),
+            &mut |event| {
+                attempt_timer.observe(&event);
+                events.push(event);
+            },
+        )
+        .unwrap();
+        timings.set_attempts(attempt_timer.finish());
+        finish_phase(
+            &mut timings,
+            BuildPhase::DockerBuild,
+            build_started,
+            &mut |event| events.push(event),
+        );
+
+        // Both attempts are timed, including the one that succeeded without a closing event.
+        assert_eq!(timings.attempts().len(), 2);
+        assert!(timings.attempts()[1] >= Duration::from_millis(100));
+        let docker_build = timings.get(BuildPhase::DockerBuild).unwrap();
+        assert!(docker_build >= timings.attempts().iter().sum());
+        assert_eq!(
+            events.last(),
+            Some(&BuildEvent::PhaseFinished {
+                phase: BuildPhase::DockerBuild,
+                duration: docker_build
+            })
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&timings.to_json(build_started.elapsed())).unwrap();
+        assert!(json["phases"]["docker-build"].as_f64().unwrap() > 0.0);
+        assert_eq!(json["attempts"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_copy_build_files() {
+        let build_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        write_files(build_dir.path(), &["a.rpm", "sub/b.rpm"]);
+
+        let mut artifacts = copy_build_files(
+            build_dir.path(),
+            output_dir.path(),
+            2,
+            &GlobSet::empty(),
+            ArtifactTransfer::Move,
+        )
+        .unwrap();
+        artifacts.sort();
+        assert_eq!(
+            artifacts,
+            [PathBuf::from("a.rpm"), PathBuf::from("sub/b.rpm")]
+        );
+
+        assert!(output_dir.path().join("a.rpm").is_file());
+        assert!(output_dir.path().join("sub/b.rpm").is_file());
+        assert!(build_dir.path().join("a.rpm.buildsys_marker").is_file());
+        assert!(build_dir.path().join("sub/b.rpm.buildsys_marker").is_file());
+    }
+
+    #[test]
+    fn test_copy_build_files_symlink_loops() {
+        let build_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        write_files(build_dir.path(), &["a.rpm", "sub/b.rpm"]);
+        let link = |target: &Path, name: &str| {
+            std::os::unix::fs::symlink(target, build_dir.path().join(name)).unwrap()
+        };
+        link(Path::new("self.rpm"), "self.rpm");
+        link(Path::new(".."), "sub/parent");
+        link(output_dir.path(), "published");
+        link(Path::new("a.rpm"), "latest.rpm");
+        link(Path::new("missing.rpm"), "dangling.rpm");
+
+        let mut artifacts = copy_build_files(
+            build_dir.path(),
+            output_dir.path(),
+            10,
+            &GlobSet::empty(),
+            ArtifactTransfer::Move,
+        )
+        .unwrap();
+        artifacts.sort();
+        assert_eq!(
+            artifacts,
+            [
+                PathBuf::from("a.rpm"),
+                PathBuf::from("dangling.rpm"),
+                PathBuf::from("latest.rpm"),
+                PathBuf::from("sub/b.rpm")
+            ]
+        );
+
+        // The unsafe links stay where they are, without markers.
+        for name in ["self.rpm", "sub/parent", "published"] {
+            assert!(build_dir.path().join(name).is_symlink(), "{name}");
+            assert!(!output_dir.path().join(name).is_symlink(), "{name}");
+            let mut marker = build_dir.path().join(name).into_os_string();
+            marker.push(MARKER_EXTENSION);
+            assert!(!Path::new(&marker).exists(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_copy_build_files_keeps_originals() {
+        let build_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        write_files(build_dir.path(), &["a.rpm", "sub/b.rpm"]);
+        std::os::unix::fs::symlink("a.rpm", build_dir.path().join("latest.rpm")).unwrap();
+
+        let mut artifacts = copy_build_files(
+            build_dir.path(),
+            output_dir.path(),
+            10,
+            &GlobSet::empty(),
+            ArtifactTransfer::Copy,
+        )
+        .unwrap();
+        artifacts.sort();
+        assert_eq!(
+            artifacts,
+            [
+                PathBuf::from("a.rpm"),
+                PathBuf::from("latest.rpm"),
+                PathBuf::from("sub/b.rpm")
+            ]
+        );
+        for artifact in &artifacts {
+            assert!(build_dir.path().join(artifact).exists());
+            assert!(output_dir.path().join(artifact).exists());
+        }
+        assert_eq!(
+            fs::read_link(output_dir.path().join("latest.rpm")).unwrap(),
+            PathBuf::from("a.rpm")
+        );
+
+        // Cleaning up removes the published files, the originals, and the markers.
+        clean_build_files(build_dir.path(), &[output_dir.path().to_path_buf()]).unwrap();
+        assert_eq!(fs::read_dir(build_dir.path()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_output_dir_override() {
+        let build_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap().path().join("store/pkg-a");
+        write_files(build_dir.path(), &["a.rpm"]);
+
+        let build = test_package_build()
+            .with_output_dir(Some(output_dir.clone()))
+            .unwrap();
+        assert_eq!(build.artifacts_dirs, std::slice::from_ref(&output_dir));
+
+        copy_build_files(
+            build_dir.path(),
+            &build.artifacts_dirs[0],
+            build.max_artifacts,
+            &build.artifact_ignore,
+            build.artifact_transfer,
+        )
+        .unwrap();
+        assert!(output_dir.join("a.rpm").is_file());
+        assert!(build_dir.path().join("a.rpm.buildsys_marker").is_file());
+
+        // Without an override, the usual directory is kept.
+        let build = test_package_build().with_output_dir(None).unwrap();
+        assert_eq!(
+            build.artifacts_dirs,
+            [PathBuf::from("/home/user/project/build/rpms/pkg-a")]
+        );
+    }
+
+    #[test]
+    fn test_output_dir_not_writable() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(test_package_build()
+            .with_output_dir(Some(file.join("sub")))
+            .is_err());
+    }
+
+    #[test]
+    fn test_build_log_compressed() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = build_log_path(dir.path(), &BuildType::Package, "pkg-a", "x86_64", true);
+        assert_eq!(path, dir.path().join("package-x86_64-pkg-a.log.gz"));
+
+        let mut log = BuildLog::create(path.clone(), true).unwrap();
+        let output = "#1 [internal] load build definition\n".repeat(1000);
+        log.write_all(output.as_bytes()).unwrap();
+        log.finish().unwrap();
+
+        let compressed = fs::read(&path).unwrap();
+        assert!(compressed.len() < output.len());
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, output);
+
+        let path = build_log_path(dir.path(), &BuildType::Kit, "kit-a", "aarch64", false);
+        let mut log = BuildLog::create(path.clone(), false).unwrap();
+        log.write_all(b"plain\n").unwrap();
+        log.finish().unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "plain\n");
+    }
+
+    #[test]
+    fn test_copy_build_files_ignored() {
+        let build_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        write_files(build_dir.path(), &["a.rpm", "sub/state.rpmdb", "b.rpmdb"]);
+        let ignore = artifact_ignore(&["*.rpmdb".to_string()]).unwrap();
+
+        // Ignored files don't count towards the limit.
+        let artifacts = copy_build_files(
+            build_dir.path(),
+            output_dir.path(),
+            1,
+            &ignore,
+            ArtifactTransfer::Move,
+        )
+        .unwrap();
+        assert_eq!(artifacts, [PathBuf::from("a.rpm")]);
+
+        // Ignored files are left in the build directory, without markers.
+        assert_eq!(
+            dir_entries(output_dir.path()),
+            [output_dir.path().join("a.rpm")]
+        );
+        assert!(build_dir.path().join("b.rpmdb").is_file());
+        assert!(build_dir.path().join("sub/state.rpmdb").is_file());
+        assert!(!build_dir.path().join("b.rpmdb.buildsys_marker").exists());
+        assert!(!build_dir
+            .path()
+            .join("sub/state.rpmdb.buildsys_marker")
+            .exists());
+
+        // Cleanup only touches the files that were marked.
+        clean_build_files(build_dir.path(), &[output_dir.path().to_path_buf()]).unwrap();
+        assert!(dir_entries(output_dir.path()).is_empty());
+        assert!(build_dir.path().join("b.rpmdb").is_file());
+        assert!(build_dir.path().join("sub/state.rpmdb").is_file());
+    }
+
+    #[test]
+    fn test_artifact_ignore_invalid() {
+        assert!(matches!(
+            artifact_ignore(&["a[".to_string()]),
+            Err(error::Error::ArtifactIgnore { .. })
+        ));
+    }
+
+    #[test]
+    fn test_copy_build_files_too_many_artifacts() {
+        let build_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        write_files(build_dir.path(), &["a.rpm", "b.rpm", "sub/c.rpm"]);
+        let before = dir_entries(build_dir.path());
+
+        let err = copy_build_files(
+            build_dir.path(),
+            output_dir.path(),
+            2,
+            &GlobSet::empty(),
+            ArtifactTransfer::Move,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            error::Error::TooManyArtifacts {
+                max_artifacts: 2,
+                ..
+            }
+        ));
+
+        // Nothing was moved or marked.
+        assert!(dir_entries(output_dir.path()).is_empty());
+        assert_eq!(dir_entries(build_dir.path()), before);
+    }
+
+    #[test]
+    fn test_marker_dir_layouts() {
+        let state_dir = Path::new("/state");
+        let nested = marker_dir(
+            &BuildType::Package,
+            "pkg-a",
+            "x86_64",
+            state_dir,
+            MarkerLayout::Nested,
+        );
+        assert_eq!(nested, Path::new("/state/x86_64/packages/pkg-a"));
+
+        let flat = marker_dir(
+            &BuildType::Repack,
+            "aws-dev",
+            "aarch64",
+            state_dir,
+            MarkerLayout::Flat,
+        );
+        assert_eq!(flat, Path::new("/state/variant-aarch64-aws-dev"));
+    }
+
+    #[test]
+    fn test_markers_and_cleanup_agree() {
+        for layout in [MarkerLayout::Nested, MarkerLayout::Flat] {
+            let state_dir = TempDir::new().unwrap();
+            let output_dir = TempDir::new().unwrap();
+
+            let build_dir = create_marker_dir(
+                &BuildType::Package,
+                "pkg-a",
+                "x86_64",
+                state_dir.path(),
+                layout,
+            )
+            .unwrap();
+            write_files(&build_dir, &["a.rpm", "sub/b.rpm"]);
+            copy_build_files(
+                build_dir.as_path(),
+                output_dir.path(),
+                10,
+                &GlobSet::empty(),
+                ArtifactTransfer::Move,
+            )
+            .unwrap();
+            assert!(output_dir.path().join("sub/b.rpm").is_file());
+
+            // A later build finds the same directory, and cleans up everything the first one
+            // left behind.
+            let build_dir = create_marker_dir(
+                &BuildType::Package,
+                "pkg-a",
+                "x86_64",
+                state_dir.path(),
+                layout,
+            )
+            .unwrap();
+            clean_build_files(&build_dir, &[output_dir.path().to_path_buf()]).unwrap();
+            assert!(dir_entries(output_dir.path()).is_empty(), "{layout:?}");
+            assert!(dir_entries(&build_dir).is_empty(), "{layout:?}");
+        }
+    }
+
+    #[test]
+    fn test_clean() {
+        let state_dir = TempDir::new().unwrap();
+        let packages_dir = TempDir::new().unwrap();
+        for package in ["pkg-a", "pkg-b"] {
+            let marker_dir = create_marker_dir(
+                &BuildType::Package,
+                package,
+                "x86_64",
+                state_dir.path(),
+                MarkerLayout::Nested,
+            )
+            .unwrap();
+            write_files(&marker_dir, &["a.rpm", "sub/b.rpm"]);
+            copy_build_files(
+                marker_dir.as_path(),
+                &packages_dir.path().join(package),
+                10,
+                &GlobSet::empty(),
+                ArtifactTransfer::Move,
+            )
+            .unwrap();
+        }
+        write_files(packages_dir.path(), &["pkg-a/untracked.rpm"]);
+        let before = dir_entries(packages_dir.path());
+
+        let state_dir_arg = format!("--state-dir={}", state_dir.path().display());
+        let packages_dir_arg = format!("--packages-dir={}", packages_dir.path().display());
+        let clean_args = |dry_run: bool| {
+            let mut args = vec![
+                "clean",
+                "--kind=package",
+                "--name=pkg-a",
+                "--arch=x86_64",
+                &state_dir_arg,
+                &packages_dir_arg,
+            ];
+            if dry_run {
+                args.push("--dry-run");
+            }
+            CleanArgs::parse_from(args)
+        };
+
+        // A dry run removes nothing.
+        clean(&clean_args(true)).unwrap();
+        assert_eq!(dir_entries(packages_dir.path()), before);
+
+        // Only the tracked artifacts and markers for pkg-a are removed.
+        clean(&clean_args(false)).unwrap();
+        assert_eq!(
+            dir_entries(packages_dir.path()),
+            [
+                packages_dir.path().join("pkg-a"),
+                packages_dir.path().join("pkg-a/untracked.rpm"),
+                packages_dir.path().join("pkg-b"),
+                packages_dir.path().join("pkg-b/a.rpm"),
+                packages_dir.path().join("pkg-b/sub"),
+                packages_dir.path().join("pkg-b/sub/b.rpm"),
+            ]
+        );
+        let markers = |package| {
+            dir_entries(&marker_dir(
+                &BuildType::Package,
+                package,
+                "x86_64",
+                state_dir.path(),
+                MarkerLayout::Nested,
+            ))
+        };
+        assert!(markers("pkg-a").is_empty());
+        assert_eq!(markers("pkg-b").len(), 3);
+    }
+}
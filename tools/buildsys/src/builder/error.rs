@@ -4,6 +4,26 @@ use std::path::PathBuf;
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(super)))]
 pub(crate) enum Error {
+    #[snafu(display("Invalid artifact ignore pattern '{pattern}': {source}"))]
+    ArtifactIgnore {
+        pattern: String,
+        source: globset::Error,
+    },
+
+    #[snafu(display("Build argument '{key}' is set more than once"))]
+    DuplicateBuildArg { key: String },
+
+    #[snafu(display(
+        "Package '{name}' is included for every architecture, and also for {arch} alone"
+    ))]
+    ConflictingIncludedPackage { name: String, arch: String },
+
+    #[snafu(display(
+        "Packages included in the variant were not built or provided by a kit: {}",
+        missing.join(", ")
+    ))]
+    MissingPackages { missing: Vec<String> },
+
     #[snafu(display("Failed to create async runtime: {}", source))]
     AsyncRuntime { source: std::io::Error },
 
@@ -16,14 +36,80 @@ pub(crate) enum Error {
     #[snafu(display("Failed to read repo root '{}'", root_json_path.display()))]
     BadRootJson { root_json_path: PathBuf },
 
+    #[snafu(display("Failed to write build log '{}': {}", path.display(), source))]
+    BuildLogWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to read build argument file '{}': {}", path.display(), source))]
+    BuildArgFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "Invalid line {line} in build argument file '{}', expected KEY=VALUE",
+        path.display()
+    ))]
+    BuildArgFileLine { path: PathBuf, line: usize },
+
+    #[snafu(display("Failed to write build names '{}': {}", path.display(), source))]
+    EmitNamesWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write build timings '{}': {}", path.display(), source))]
+    BuildTimingsWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Build context '{}' is not a directory", path.display()))]
+    BuildContext { path: PathBuf },
+
+    #[snafu(display("Bypass container exited before serving the project root"))]
+    BypassExited,
+
+    #[snafu(display("Bypass container failed to start, see its output above: {source}"))]
+    BypassStartFailed {
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+    },
+
+    #[snafu(display("Bypass container did not start serving within {timeout:?}"))]
+    BypassStartTimeout { timeout: std::time::Duration },
+
+    #[snafu(display("No directory to clean, set {var} or BUILDSYS_OUTPUT_DIR"))]
+    CleanOutputDir { var: String },
+
+    #[snafu(display("Context tar '{}' is not a file", path.display()))]
+    ContextTar { path: PathBuf },
+
+    #[snafu(display(
+        "Dockerfile '{}' must be under the root directory to send a context tar",
+        path.display()
+    ))]
+    ContextTarDockerfile { path: PathBuf },
+
+    #[snafu(display("Failed to save the context tar from stdin to '{}': {}", path.display(), source))]
+    ContextTarSave {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Failed to start command: {}", source))]
     CommandStart { source: std::io::Error },
 
+    #[snafu(display("Dockerfile '{}' does not exist", path.display()))]
+    DockerfileMissing { path: PathBuf },
+
     #[snafu(display("Failed to execute command: 'docker {}'", args))]
     DockerExecution { args: String },
 
-    #[snafu(display("Failed to change directory to '{}': {}", path.display(), source))]
-    DirectoryChange {
+    #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+    FileRead {
         path: PathBuf,
         source: std::io::Error,
     },
@@ -52,6 +138,13 @@ pub(crate) enum Error {
     #[snafu(display("Failed to walk directory to find marker files: {}", source))]
     DirectoryWalk { source: walkdir::Error },
 
+    #[snafu(display("Failed to copy file '{}' to '{}': {}", old_path.display(), new_path.display(), source))]
+    FileCopy {
+        old_path: PathBuf,
+        new_path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Failed to create file '{}': {}", path.display(), source))]
     FileCreate {
         path: PathBuf,
@@ -80,6 +173,67 @@ pub(crate) enum Error {
         source: std::env::VarError,
     },
 
+    #[snafu(display("{source}"))]
+    ProjectCrawl {
+        source: crate::project::error::Error,
+    },
+
+    #[snafu(display("pipesys binary '{}' does not exist", path.display()))]
+    PipesysBinMissing { path: PathBuf },
+
+    #[snafu(display("Failed to snapshot the project root: {source}"))]
+    Snapshot {
+        source: crate::snapshot::error::Error,
+    },
+
+    #[snafu(display("Failed to package OVA: {source}"))]
+    Ova { source: crate::ova::error::Error },
+
+    #[snafu(display("Packaging an OVA requires the vmdk image format"))]
+    OvaFormat,
+
+    #[snafu(display("{source}"))]
+    Provenance {
+        source: crate::provenance::error::Error,
+    },
+
+    #[snafu(display("{source}"))]
+    Command {
+        source: crate::command::error::Error,
+    },
+
+    #[snafu(display("{source}"))]
+    Repro { source: crate::repro::error::Error },
+
+    #[snafu(display("{source}"))]
+    UpToDate {
+        source: crate::uptodate::error::Error,
+    },
+
+    #[snafu(display("{source}"))]
+    ResolvedPackage {
+        source: crate::resolved::error::Error,
+    },
+
+    #[snafu(display(
+        "Build argument '{key}' from the manifest or build argument file conflicts with a build argument set by buildsys"
+    ))]
+    ReservedBuildArg { key: String },
+
+    #[snafu(display(
+        "Flag '{flag}' for the bypass container conflicts with a flag set by buildsys"
+    ))]
+    ReservedBypassRunFlag { flag: String },
+
+    #[snafu(display("Output directory '{}' is not writable: {}", path.display(), source))]
+    OutputDirNotWritable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to start server for the output directory: {source}"))]
+    OutputServer { source: pipesys::Error },
+
     #[snafu(display("Failed to strip prefix '{}' from path '{}': {}", prefix.display(), path.display(), source))]
     StripPathPrefix {
         path: PathBuf,
@@ -87,6 +241,12 @@ pub(crate) enum Error {
         source: std::path::StripPrefixError,
     },
 
+    #[snafu(display(
+        "Build produced more than {max_artifacts} artifacts in '{}'",
+        path.display()
+    ))]
+    TooManyArtifacts { path: PathBuf, max_artifacts: usize },
+
     #[snafu(display("Failed to parse variant: {source}"))]
     VariantParse {
         source: bottlerocket_variant::error::Error,
@@ -0,0 +1,202 @@
+/*!
+This module finds the files in a project that changed since a git ref, so that CI for a change can
+skip packages that the change does not touch.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::manifest::ManifestInfo;
+use duct::cmd;
+use snafu::{ensure, ResultExt};
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+pub(crate) struct ChangedPaths {
+    root_dir: PathBuf,
+    paths: Vec<PathBuf>,
+}
+
+impl ChangedPaths {
+    /// Ask git for the files under the project root that differ from `git_ref`, including any
+    /// uncommitted changes, and any new files that git does not ignore.
+    pub(crate) fn since(root_dir: &Path, git_ref: &str) -> Result<Self> {
+        let changed = git(
+            root_dir,
+            git_ref,
+            &["diff", "--name-only", "--relative", git_ref],
+        )?;
+        let untracked = git(
+            root_dir,
+            git_ref,
+            &["ls-files", "--others", "--exclude-standard"],
+        )?;
+        Ok(Self::new(
+            root_dir,
+            changed.lines().chain(untracked.lines()),
+        ))
+    }
+
+    fn new<I, P>(root_dir: &Path, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self {
+            root_dir: root_dir.into(),
+            paths: paths.into_iter().map(|p| p.as_ref().into()).collect(),
+        }
+    }
+
+    /// Map the changed files to the names of the package directories that own them.
+    pub(crate) fn packages(&self, packages_dir: &Path) -> BTreeSet<String> {
+        let packages_dir = packages_dir
+            .strip_prefix(&self.root_dir)
+            .unwrap_or(packages_dir);
+
+        self.paths
+            .iter()
+            .filter_map(|path| path.strip_prefix(packages_dir).ok())
+            .filter_map(|path| match path.components().next() {
+                Some(Component::Normal(name)) if path.components().count() > 1 => {
+                    name.to_str().map(str::to_string)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns true if any of the changed files are in the directory.
+    pub(crate) fn touches(&self, dir: &Path) -> bool {
+        let dir = dir.strip_prefix(&self.root_dir).unwrap_or(dir);
+        self.paths.iter().any(|path| path.starts_with(dir))
+    }
+
+    /// Returns true if any of the package directories, or the source groups that their manifests
+    /// use, have changes. A package has to be checked along with every package it depends on,
+    /// since a change to one of those means the package is built from different inputs.
+    pub(crate) fn packages_changed(
+        &self,
+        package_dirs: &[PathBuf],
+        sources_dir: &Path,
+    ) -> Result<bool> {
+        for package_dir in package_dirs {
+            let dir_changed = match (package_dir.parent(), package_dir.file_name()) {
+                (Some(packages_dir), Some(dir_name)) => self
+                    .packages(packages_dir)
+                    .contains(dir_name.to_string_lossy().as_ref()),
+                _ => true,
+            };
+            if dir_changed {
+                return Ok(true);
+            }
+
+            let path = package_dir.join("Cargo.toml");
+            let manifest = ManifestInfo::new(&path).context(error::ManifestSnafu)?;
+            if manifest
+                .source_groups()
+                .into_iter()
+                .flatten()
+                .any(|group| self.touches(&sources_dir.join(group)))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Run git in the project root, and return what it printed.
+fn git(root_dir: &Path, git_ref: &str, args: &[&str]) -> Result<String> {
+    let output = cmd("git", args)
+        .dir(root_dir)
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    ensure!(
+        output.status.success(),
+        error::GitDiffSnafu {
+            git_ref,
+            output: stdout.trim(),
+        }
+    );
+    Ok(stdout.into_owned())
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changed_packages() {
+        let root_dir = Path::new("/home/user/project");
+        let changes = ChangedPaths::new(
+            root_dir,
+            [
+                "packages/kernel-6.1/config-bottlerocket",
+                "packages/release/release.spec",
+                "packages/Cargo.toml",
+                "sources/api/apiserver/src/main.rs",
+            ],
+        );
+
+        let packages = changes.packages(&root_dir.join("packages"));
+        assert_eq!(
+            packages,
+            BTreeSet::from(["kernel-6.1".to_string(), "release".to_string()])
+        );
+
+        assert!(changes.touches(&root_dir.join("sources/api")));
+        assert!(!changes.touches(&root_dir.join("sources/updater")));
+    }
+
+    #[test]
+    fn test_changed_dependency() {
+        let root_dir = tempfile::TempDir::new().unwrap();
+        let root = root_dir.path();
+        let packages_dir = root.join("packages");
+        let sources_dir = root.join("sources");
+        for (package, source_groups) in [("app", "[]"), ("libfoo", r#"["foo"]"#)] {
+            let dir = packages_dir.join(package);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(
+                dir.join("Cargo.toml"),
+                format!(
+                    r#"
+                    [package]
+                    name = "{package}"
+
+                    [package.metadata.build-package]
+                    source-groups = {source_groups}
+                    "#
+                ),
+            )
+            .unwrap();
+        }
+        let app = [packages_dir.join("app")];
+        let app_and_libfoo = [packages_dir.join("app"), packages_dir.join("libfoo")];
+
+        // A change to a dependency's directory counts as a change for the package.
+        let changes = ChangedPaths::new(root, ["packages/libfoo/libfoo.spec"]);
+        assert!(!changes.packages_changed(&app, &sources_dir).unwrap());
+        assert!(changes
+            .packages_changed(&app_and_libfoo, &sources_dir)
+            .unwrap());
+
+        // So does a change to one of the dependency's source groups.
+        let changes = ChangedPaths::new(root, ["sources/foo/src/lib.rs"]);
+        assert!(changes
+            .packages_changed(&app_and_libfoo, &sources_dir)
+            .unwrap());
+        let changes = ChangedPaths::new(root, ["sources/bar/src/lib.rs"]);
+        assert!(!changes
+            .packages_changed(&app_and_libfoo, &sources_dir)
+            .unwrap());
+    }
+}
@@ -0,0 +1,16 @@
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: std::io::Error },
+
+    #[snafu(display("Failed to find changes since '{}': {}", git_ref, output))]
+    GitDiff { git_ref: String, output: String },
+
+    #[snafu(display("Failed to read dependency manifest: {}", source))]
+    Manifest { source: buildsys::manifest::Error },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,890 @@
+/*!
+This module runs the commands for a build, such as `docker build`, and retries them when they fail
+for reasons that are likely to be transient.
+
+Each attempt's output is read line by line as it arrives, so that it can be logged, reported as
+progress, and matched against the known transient failures. The output kept for an attempt can be
+capped in size, and an attempt that stops producing output for too long is stopped and counted as
+failed, so that a runaway or hung build can't exhaust memory or run forever.
+*/
+pub(crate) mod error;
+use error::Result;
+
+use crate::builder::BuildEvent;
+use duct::cmd;
+use lazy_static::lazy_static;
+use rand::Rng;
+use regex::Regex;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::num::NonZeroU16;
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+/*
+Other failures look transient, such as network timeouts and server errors from a registry, but are
+not retried since they can also be lasting problems. When a build fails with one of them, the error
+suggests retrying it with a pattern of its own.
+*/
+lazy_static! {
+    static ref LIKELY_TRANSIENT_ERROR: Regex = Regex::new(concat!(
+        r"(?i)i/o timeout|TLS handshake timeout|context deadline exceeded|",
+        r"connection reset by peer|connection refused|temporary failure in name resolution|",
+        r"\b(429 Too Many Requests|toomanyrequests|500 Internal Server Error|502 Bad Gateway|",
+        r"503 Service Unavailable|504 Gateway Timeout)\b",
+    ))
+    .unwrap();
+}
+
+/// Run a command from the directory `dir`, rather than changing the working directory of the
+/// process, so that builds in separate threads don't interfere. Each attempt reads its standard
+/// input from `stdin`, if given.
+///
+/// The command is retried if it fails with one of the expected messages. The output from each
+/// attempt is written to `log` as it arrives, unless `quiet` is set, in which case the output is
+/// held back and only written if the command ultimately fails. Each attempt and its output are
+/// also reported to `progress`. The output kept for each attempt, and the time it may go without
+/// producing any, are bounded by `limits`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_command(
+    program: &str,
+    args: &[String],
+    dir: &Path,
+    stdin: Option<&Path>,
+    retry: Retry,
+    quiet: bool,
+    limits: OutputLimits,
+    log: &mut impl Write,
+    progress: &mut dyn FnMut(BuildEvent),
+) -> Result<Output> {
+    let mut captured = String::new();
+    let mut synced = false;
+    let mut attempt = 1;
+    loop {
+        progress(BuildEvent::AttemptStarted { n: attempt });
+        let live_log: Option<&mut dyn Write> = if quiet { None } else { Some(&mut *log) };
+        let output = run_attempt(program, args, dir, stdin, limits, live_log, progress)?;
+        if quiet {
+            captured.push_str(&output.text);
+        }
+
+        if output.status.success() {
+            if quiet {
+                let subcommand = args.first().map(String::as_str).unwrap_or_default();
+                writeln!(log, "{program} {subcommand} succeeded")
+                    .context(error::OutputWriteSnafu)?;
+            }
+            return Ok(Output {
+                status: output.status,
+                stdout: output.text.into_bytes(),
+                stderr: Vec::new(),
+            });
+        }
+
+        // A hang has no output to match against, but is as likely to be transient as the known
+        // errors, so it is retried the same way.
+        let action = if output.stalled {
+            retry.stall_action(attempt)
+        } else {
+            retry.action(&output.text, attempt, synced)
+        };
+        progress(BuildEvent::AttemptFailed {
+            n: attempt,
+            matched_retry: action != RetryAction::Fail,
+        });
+        if action == RetryAction::Fail && quiet {
+            writeln!(log, "{}", &captured).context(error::OutputWriteSnafu)?;
+        }
+
+        if let (RetryAction::Fail, Some(timeout)) = (&action, limits.stall_timeout) {
+            ensure!(
+                !output.stalled,
+                error::OutputStalledSnafu {
+                    args: &args.join(" "),
+                    timeout,
+                }
+            );
+        }
+        if action == RetryAction::Fail {
+            if let Some(line) = retry.likely_transient(&output.text) {
+                return error::LikelyTransientSnafu {
+                    args: args.join(" "),
+                    line,
+                }
+                .fail();
+            }
+            return error::DockerExecutionSnafu {
+                args: args.join(" "),
+            }
+            .fail();
+        }
+
+        if let (RetryAction::SyncAndRetry, Retry::Yes { sync: Some(s), .. }) = (&action, &retry) {
+            sync_files(s.dir)?;
+            synced = true;
+        }
+
+        if let Retry::Yes { delay, jitter, .. } = &retry {
+            thread::sleep(jittered_delay(*delay, *jitter, &mut rand::thread_rng()));
+        }
+
+        attempt += 1;
+    }
+}
+
+/// The number of lines kept from the end of an attempt's output once it passes the size limit,
+/// since that is where errors are usually reported.
+const OUTPUT_TAIL_LINES: usize = 100;
+
+/// Bounds on the output from each attempt at a command, so that a runaway or hung build can't
+/// exhaust memory, fill the log, or run forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OutputLimits {
+    /// Stop keeping and logging output after this many bytes, apart from the last few lines.
+    max_bytes: Option<u64>,
+    /// Stop an attempt if it goes this long without producing any output.
+    stall_timeout: Option<Duration>,
+}
+
+impl OutputLimits {
+    pub(crate) fn new(max_bytes: Option<u64>, stall_timeout_secs: Option<u64>) -> Self {
+        Self {
+            max_bytes,
+            stall_timeout: stall_timeout_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+/// The result of one attempt at a command.
+struct AttemptOutput {
+    status: ExitStatus,
+    /// The output that was kept, with a marker where any was left out.
+    text: String,
+    /// Whether the attempt was stopped for not producing output.
+    stalled: bool,
+}
+
+/// Run a command once, reading its output line by line as it arrives. Lines are reported to
+/// `progress`, and written to `log` if one is given, until the output passes the size limit. The
+/// last few lines and a marker are written when the attempt ends.
+fn run_attempt(
+    program: &str,
+    args: &[String],
+    dir: &Path,
+    stdin: Option<&Path>,
+    limits: OutputLimits,
+    mut log: Option<&mut dyn Write>,
+    progress: &mut dyn FnMut(BuildEvent),
+) -> Result<AttemptOutput> {
+    let mut command = cmd(program, args).dir(dir);
+    if let Some(stdin) = stdin {
+        command = command.stdin_path(stdin);
+    }
+    let reader = Arc::new(
+        command
+            .stderr_to_stdout()
+            .unchecked()
+            .reader()
+            .context(error::CommandStartSnafu)?,
+    );
+
+    // Read on a separate thread, so that a command which stops writing can be noticed and killed.
+    // The thread is not joined, since a process the command started may hold the pipe open after
+    // the command itself is killed.
+    let (tx, rx) = mpsc::channel();
+    let lines = Arc::clone(&reader);
+    thread::spawn(move || {
+        for line in BufReader::new(&*lines).split(b'\n') {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut buffer = OutputBuffer::new(limits.max_bytes);
+    let mut stalled = false;
+    loop {
+        let line = match limits.stall_timeout {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(line) => line,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    stalled = true;
+                    break;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(line) => line,
+                Err(mpsc::RecvError) => break,
+            },
+        };
+        let line = String::from_utf8_lossy(&line).into_owned();
+        if buffer.push(&line) {
+            if let Some(log) = log.as_mut() {
+                writeln!(log, "{line}").context(error::OutputWriteSnafu)?;
+            }
+        }
+        progress(BuildEvent::OutputLine(line));
+    }
+
+    if stalled {
+        let timeout = limits.stall_timeout.unwrap_or_default();
+        buffer.mark(&format!(
+            "[buildsys: no output for {timeout:?}, stopping the attempt]"
+        ));
+    }
+
+    // The command has usually exited by the time its output ends. If it hasn't, because it
+    // stalled or closed its output early, stop it so the attempt can be finished.
+    let status = match reader.try_wait().context(error::CommandWaitSnafu)? {
+        Some(output) => output.status,
+        None => {
+            reader.kill().context(error::CommandWaitSnafu)?;
+            reader
+                .try_wait()
+                .context(error::CommandWaitSnafu)?
+                .map(|output| output.status)
+                .context(error::CommandStillRunningSnafu)?
+        }
+    };
+
+    let end = buffer.end();
+    if let Some(log) = log.as_mut() {
+        if !end.is_empty() {
+            write!(log, "{end}").context(error::OutputWriteSnafu)?;
+        }
+    }
+
+    Ok(AttemptOutput {
+        status,
+        text: buffer.into_string(),
+        stalled,
+    })
+}
+
+/// The output kept from one attempt, up to an optional size limit. Past the limit, only the last
+/// few lines are kept.
+#[derive(Debug, Default)]
+struct OutputBuffer {
+    max_bytes: Option<u64>,
+    head: String,
+    tail: VecDeque<String>,
+    truncated: bool,
+    omitted_lines: usize,
+    markers: Vec<String>,
+}
+
+impl OutputBuffer {
+    fn new(max_bytes: Option<u64>) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// Add a line of output, and return whether it was kept in the head of the output, rather
+    /// than the tail.
+    fn push(&mut self, line: &str) -> bool {
+        let len = line.len() as u64 + 1;
+        let fits = self
+            .max_bytes
+            .map_or(true, |max| self.head.len() as u64 + len <= max);
+        if !self.truncated && fits {
+            self.head.push_str(line);
+            self.head.push('\n');
+            return true;
+        }
+
+        self.truncated = true;
+        self.tail.push_back(line.to_string());
+        if self.tail.len() > OUTPUT_TAIL_LINES {
+            self.tail.pop_front();
+            self.omitted_lines += 1;
+        }
+        false
+    }
+
+    /// Add a note from buildsys about the output, after everything else.
+    fn mark(&mut self, marker: &str) {
+        self.markers.push(marker.to_string());
+    }
+
+    /// The part of the output that comes after the head: a marker if the output was truncated,
+    /// followed by the tail and any other markers.
+    fn end(&self) -> String {
+        let mut end = String::new();
+        if self.truncated {
+            end.push_str(&format!(
+                "[buildsys: output exceeded {} bytes, {} lines omitted]\n",
+                self.max_bytes.unwrap_or_default(),
+                self.omitted_lines
+            ));
+        }
+        for line in self.tail.iter().chain(&self.markers) {
+            end.push_str(line);
+            end.push('\n');
+        }
+        end
+    }
+
+    fn into_string(self) -> String {
+        let end = self.end();
+        self.head + &end
+    }
+}
+
+/// Allow the caller to configure retry behavior, since the command may fail
+/// for spurious reasons that should not be treated as an error.
+pub(crate) enum Retry<'a> {
+    No,
+    Yes {
+        attempts: NonZeroU16,
+        messages: &'a [&'a Regex],
+        sync: Option<SyncRetry<'a>>,
+        delay: Duration,
+        jitter: f64,
+    },
+}
+
+/// A failure that is worth syncing a directory to disk for, before retrying once.
+pub(crate) struct SyncRetry<'a> {
+    pub(crate) message: &'static Regex,
+    pub(crate) dir: &'a Path,
+}
+
+/// What to do after a failed attempt.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RetryAction {
+    Fail,
+    Retry,
+    SyncAndRetry,
+}
+
+impl Retry<'_> {
+    /// Decide how to handle an attempt that was stopped for not producing output.
+    fn stall_action(&self, attempt: u16) -> RetryAction {
+        match self {
+            Retry::Yes { attempts, .. } if attempt < u16::from(*attempts) => RetryAction::Retry,
+            _ => RetryAction::Fail,
+        }
+    }
+
+    /// Decide how to handle a failed attempt, based on its output.
+    pub(crate) fn action(&self, output: &str, attempt: u16, synced: bool) -> RetryAction {
+        let Retry::Yes {
+            attempts,
+            messages,
+            sync,
+            ..
+        } = self
+        else {
+            return RetryAction::Fail;
+        };
+
+        if attempt >= u16::from(*attempts) {
+            return RetryAction::Fail;
+        }
+
+        if let Some(sync) = sync {
+            if !synced && sync.message.is_match(output) {
+                return RetryAction::SyncAndRetry;
+            }
+        }
+
+        if messages.iter().any(|m| m.is_match(output)) {
+            RetryAction::Retry
+        } else {
+            RetryAction::Fail
+        }
+    }
+
+    /// Find a line in the output of a failed attempt that looks like a transient failure, if the
+    /// command could have been retried but none of the patterns matched.
+    fn likely_transient<'a>(&self, output: &'a str) -> Option<&'a str> {
+        let Retry::Yes { messages, .. } = self else {
+            return None;
+        };
+        if messages.iter().any(|m| m.is_match(output)) {
+            return None;
+        }
+        output
+            .lines()
+            .find(|line| LIKELY_TRANSIENT_ERROR.is_match(line))
+            .map(str::trim)
+    }
+}
+
+/// Vary the delay by up to `jitter` times its length in either direction, so that builds which
+/// failed at the same time do not all retry at the same time.
+fn jittered_delay(delay: Duration, jitter: f64, rng: &mut impl Rng) -> Duration {
+    if jitter <= 0.0 {
+        return delay;
+    }
+    delay.mul_f64(1.0 + rng.gen_range(-jitter..=jitter))
+}
+
+/// Flush every file under a directory to disk.
+fn sync_files(dir: &Path) -> Result<()> {
+    for entry in WalkDir::new(dir).follow_links(false) {
+        let entry = entry.context(error::DirectoryWalkSnafu)?;
+        if entry.file_type().is_file() {
+            let path = entry.path();
+            File::open(path)
+                .and_then(|f| f.sync_all())
+                .context(error::FileSyncSnafu { path })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::{
+        default_retry_patterns, CREATEREPO_C_READ_HEADER_ERROR, UNEXPECTED_EOF_ERROR,
+    };
+    use nonzero_ext::nonzero;
+    use std::collections::HashSet;
+    use std::env;
+    use std::io;
+    use std::path::PathBuf;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn test_retry_action() {
+        let rpms_dir = PathBuf::from("/tmp/rpms");
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &[&*UNEXPECTED_EOF_ERROR, &*CREATEREPO_C_READ_HEADER_ERROR],
+            sync: Some(SyncRetry {
+                message: &CREATEREPO_C_READ_HEADER_ERROR,
+                dir: &rpms_dir,
+            }),
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+        let createrepo_error =
+            "C_CREATEREPOLIB: Warning: read_header: rpmReadPackageFile() error\n";
+        let eof_error = "ERROR: unexpected EOF\n";
+
+        // The createrepo_c error syncs once, then falls back to a plain retry.
+        assert_eq!(
+            retry.action(createrepo_error, 1, false),
+            RetryAction::SyncAndRetry
+        );
+        assert_eq!(retry.action(createrepo_error, 2, true), RetryAction::Retry);
+
+        // Other known errors are retried without syncing.
+        assert_eq!(retry.action(eof_error, 1, false), RetryAction::Retry);
+
+        // Unknown errors and exhausted attempts fail.
+        assert_eq!(retry.action("oops\n", 1, false), RetryAction::Fail);
+        assert_eq!(retry.action(createrepo_error, 3, false), RetryAction::Fail);
+        assert_eq!(Retry::No.action(eof_error, 1, false), RetryAction::Fail);
+
+        // Without the option, the createrepo_c error is retried as before.
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &[&*CREATEREPO_C_READ_HEADER_ERROR],
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+        assert_eq!(retry.action(createrepo_error, 1, false), RetryAction::Retry);
+    }
+
+    #[test]
+    fn test_likely_transient() {
+        let messages = default_retry_patterns();
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &messages,
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+        let timeout = "ERROR: failed to do request: Head \"https://registry.example/v2/sdk\": \
+            net/http: TLS handshake timeout";
+        let output = format!("#5 [internal] load metadata\n{timeout}\n");
+        assert_eq!(retry.likely_transient(&output), Some(timeout));
+        assert_eq!(
+            retry.likely_transient("received unexpected HTTP status: 503 Service Unavailable\n"),
+            Some("received unexpected HTTP status: 503 Service Unavailable")
+        );
+        assert_eq!(retry.likely_transient("error: oops\n"), None);
+        assert_eq!(retry.likely_transient("exit status 5030\n"), None);
+
+        // Output that a pattern already matched was retried, and commands that are never retried
+        // can't be helped by another pattern.
+        let matched = format!("{timeout}\nERROR: unexpected EOF\n");
+        assert_eq!(retry.likely_transient(&matched), None);
+        assert_eq!(Retry::No.likely_transient(&output), None);
+
+        // The hint is attached to the error, without retrying the failure.
+        let mut events = Vec::new();
+        let err = run_command(
+            "sh",
+            &sh("echo 'dial tcp 10.0.0.1:443: i/o timeout'; exit 1"),
+            Path::new("."),
+            None,
+            retry,
+            false,
+            OutputLimits::default(),
+            &mut io::sink(),
+            &mut |e| events.push(e),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, error::Error::LikelyTransient { ref line, .. } if line == "dial tcp 10.0.0.1:443: i/o timeout"),
+            "{err}"
+        );
+        assert!(err.to_string().contains("--retry-pattern"));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, BuildEvent::AttemptStarted { .. }))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_jittered_delay() {
+        let delay = Duration::from_secs(2);
+        let mut rng = rand::thread_rng();
+        let samples = (0..1000)
+            .map(|_| jittered_delay(delay, 0.25, &mut rng))
+            .collect::<HashSet<_>>();
+
+        assert!(samples
+            .iter()
+            .all(|d| *d >= Duration::from_millis(1500) && *d <= Duration::from_millis(2500)));
+        // The delays are actually spread out, rather than all the same.
+        assert!(samples.len() > 1);
+
+        assert_eq!(jittered_delay(delay, 0.0, &mut rng), delay);
+    }
+
+    #[test]
+    fn test_run_command_dir() {
+        let cwd = env::current_dir().unwrap();
+        let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+
+        // Commands for separate builds run at the same time, each in its own directory.
+        let outputs = thread::scope(|scope| {
+            let handles = dirs
+                .iter()
+                .map(|dir| {
+                    scope.spawn(|| {
+                        run_command(
+                            "sh",
+                            &sh("sleep 0.1; pwd"),
+                            dir.path(),
+                            None,
+                            Retry::No,
+                            false,
+                            OutputLimits::default(),
+                            &mut io::sink(),
+                            &mut |_| {},
+                        )
+                        .unwrap()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        for (dir, output) in dirs.iter().zip(outputs) {
+            let pwd = PathBuf::from(String::from_utf8(output.stdout).unwrap().trim());
+            assert_eq!(pwd, dir.path());
+        }
+        assert_eq!(env::current_dir().unwrap(), cwd);
+    }
+
+    #[test]
+    fn test_run_command_stdin() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("context.tar");
+        let mut archive = tar::Builder::new(File::create(&path).unwrap());
+        let data = b"FROM scratch\n";
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, "build/tools/build.Dockerfile", &data[..])
+            .unwrap();
+        archive.finish().unwrap();
+
+        let output = run_command(
+            "sh",
+            &sh("tar -tf -"),
+            dir.path(),
+            Some(&path),
+            Retry::No,
+            false,
+            OutputLimits::default(),
+            &mut io::sink(),
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            "build/tools/build.Dockerfile"
+        );
+    }
+
+    #[test]
+    fn test_run_command_quiet_success() {
+        let mut log = Vec::new();
+        run_command(
+            "sh",
+            &sh("echo verbose output"),
+            Path::new("."),
+            None,
+            Retry::No,
+            true,
+            OutputLimits::default(),
+            &mut log,
+            &mut |_| {},
+        )
+        .unwrap();
+        let log = String::from_utf8(log).unwrap();
+        assert!(!log.contains("verbose output"));
+        assert_eq!(log, "sh -c succeeded\n");
+    }
+
+    #[test]
+    fn test_run_command_quiet_failure() {
+        let mut log = Vec::new();
+        let script = sh("echo verbose output; exit 1");
+        assert!(run_command(
+            "sh",
+            &script,
+            Path::new("."),
+            None,
+            Retry::No,
+            true,
+            OutputLimits::default(),
+            &mut log,
+            &mut |_| {}
+        )
+        .is_err());
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.contains("verbose output"));
+        assert!(!log.contains("succeeded"));
+    }
+
+    #[test]
+    fn test_run_command_verbose() {
+        let mut log = Vec::new();
+        run_command(
+            "sh",
+            &sh("echo verbose output"),
+            Path::new("."),
+            None,
+            Retry::No,
+            false,
+            OutputLimits::default(),
+            &mut log,
+            &mut |_| {},
+        )
+        .unwrap();
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.contains("verbose output"));
+    }
+
+    #[test]
+    fn test_run_command_progress() {
+        let dir = TempDir::new().unwrap();
+        let marker = dir.path().join("failed-once");
+        // Fail with a known transient error the first time, then succeed.
+        let script = sh(&format!(
+            "if [ -e {m} ]; then echo built; else touch {m}; echo 'ERROR: unexpected EOF'; exit 1; fi",
+            m = marker.display()
+        ));
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &[&*UNEXPECTED_EOF_ERROR],
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+
+        let mut events = Vec::new();
+        let mut log = Vec::new();
+        run_command(
+            "sh",
+            &script,
+            Path::new("."),
+            None,
+            retry,
+            true,
+            OutputLimits::default(),
+            &mut log,
+            &mut |e| events.push(e),
+        )
+        .unwrap();
+
+        assert_eq!(
+            events,
+            [
+                BuildEvent::AttemptStarted { n: 1 },
+                BuildEvent::OutputLine("ERROR: unexpected EOF".to_string()),
+                BuildEvent::AttemptFailed {
+                    n: 1,
+                    matched_retry: true
+                },
+                BuildEvent::AttemptStarted { n: 2 },
+                BuildEvent::OutputLine("built".to_string()),
+            ]
+        );
+
+        // An unknown error is reported as not retried.
+        let mut events = Vec::new();
+        let script = sh("echo oops; exit 1");
+        let retry = Retry::Yes {
+            attempts: nonzero!(3u16),
+            messages: &[&*UNEXPECTED_EOF_ERROR],
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+        assert!(run_command(
+            "sh",
+            &script,
+            Path::new("."),
+            None,
+            retry,
+            true,
+            OutputLimits::default(),
+            &mut log,
+            &mut |e| events.push(e)
+        )
+        .is_err());
+        assert_eq!(
+            events.last(),
+            Some(&BuildEvent::AttemptFailed {
+                n: 1,
+                matched_retry: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_run_command_truncated_output() {
+        let mut log = Vec::new();
+        let limits = OutputLimits {
+            max_bytes: Some(20),
+            stall_timeout: None,
+        };
+        let output = run_command(
+            "sh",
+            &sh("for i in $(seq 1 200); do echo line-$i; done"),
+            Path::new("."),
+            None,
+            Retry::No,
+            false,
+            limits,
+            &mut log,
+            &mut |_| {},
+        )
+        .unwrap();
+
+        // The first lines that fit and the last lines are kept, with a marker between them.
+        let expected_marker = format!(
+            "line-2\n[buildsys: output exceeded 20 bytes, {} lines omitted]\nline-{}\n",
+            200 - 2 - OUTPUT_TAIL_LINES,
+            200 - OUTPUT_TAIL_LINES + 1
+        );
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.starts_with("line-1\nline-2\n"));
+        assert!(stdout.contains(&expected_marker));
+        assert!(stdout.ends_with("line-200\n"));
+        assert!(!stdout.contains("line-3\n"));
+        assert_eq!(String::from_utf8(log).unwrap(), stdout);
+    }
+
+    #[test]
+    fn test_run_command_output_stall() {
+        let limits = OutputLimits {
+            max_bytes: None,
+            stall_timeout: Some(Duration::from_millis(200)),
+        };
+        let retry = Retry::Yes {
+            attempts: nonzero!(2u16),
+            messages: &[],
+            sync: None,
+            delay: Duration::ZERO,
+            jitter: 0.0,
+        };
+
+        let mut events = Vec::new();
+        let mut log = Vec::new();
+        let started = Instant::now();
+        let err = run_command(
+            "sh",
+            &sh("echo starting; exec sleep 10"),
+            Path::new("."),
+            None,
+            retry,
+            true,
+            limits,
+            &mut log,
+            &mut |e| events.push(e),
+        )
+        .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(err, error::Error::OutputStalled { .. }));
+
+        // Stalls are retried while attempts remain.
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, BuildEvent::AttemptFailed { .. }))
+                .collect::<Vec<_>>(),
+            [
+                &BuildEvent::AttemptFailed {
+                    n: 1,
+                    matched_retry: true
+                },
+                &BuildEvent::AttemptFailed {
+                    n: 2,
+                    matched_retry: false
+                },
+            ]
+        );
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.contains("starting\n[buildsys: no output for 200ms, stopping the attempt]"));
+
+        // Output that keeps arriving does not count as a stall.
+        let script = sh("for i in 1 2 3; do echo $i; sleep 0.1; done");
+        let mut log = Vec::new();
+        run_command(
+            "sh",
+            &script,
+            Path::new("."),
+            None,
+            Retry::No,
+            true,
+            limits,
+            &mut log,
+            &mut |_| {},
+        )
+        .unwrap();
+    }
+}
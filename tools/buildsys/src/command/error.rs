@@ -0,0 +1,48 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: std::io::Error },
+
+    #[snafu(display("Command was stopped but has not exited"))]
+    CommandStillRunning,
+
+    #[snafu(display("Failed to wait for command: {}", source))]
+    CommandWait { source: std::io::Error },
+
+    #[snafu(display("Failed to walk directory to sync it to disk: {}", source))]
+    DirectoryWalk { source: walkdir::Error },
+
+    #[snafu(display("Failed to execute command: 'docker {}'", args))]
+    DockerExecution { args: String },
+
+    #[snafu(display("Failed to sync '{}' to disk: {}", path.display(), source))]
+    FileSync {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "Failed to execute command: 'docker {}'. The output looks like a transient failure: \
+        '{}'. If it is, consider retrying it with --retry-pattern",
+        args,
+        line
+    ))]
+    LikelyTransient { args: String, line: String },
+
+    #[snafu(display(
+        "Command 'docker {args}' produced no output for {timeout:?} and was stopped"
+    ))]
+    OutputStalled {
+        args: String,
+        timeout: std::time::Duration,
+    },
+
+    #[snafu(display("Failed to write command output: {}", source))]
+    OutputWrite { source: std::io::Error },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,251 @@
+/*!
+This module compares the build settings in two variant manifests, so that reviewers can see which
+changes affect the resulting image.
+
+*/
+use buildsys::manifest::ManifestInfo;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+
+/// The differences between two manifests.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ManifestDiff {
+    included_packages: SetDiff,
+    image_features: SetDiff,
+    kernel_parameters: SetDiff,
+    image_layout: BTreeMap<String, ValueChange>,
+}
+
+/// The items added to and removed from a collection.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct SetDiff {
+    added: BTreeSet<String>,
+    removed: BTreeSet<String>,
+}
+
+/// The old and new values for a setting.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ValueChange {
+    old: String,
+    new: String,
+}
+
+impl ManifestDiff {
+    pub(crate) fn new(old: &ManifestInfo, new: &ManifestInfo) -> Self {
+        Self {
+            included_packages: SetDiff::new(
+                old.included_packages().into_iter().flatten(),
+                new.included_packages().into_iter().flatten(),
+            ),
+            image_features: SetDiff::new(
+                old.enabled_image_features().into_iter().flatten(),
+                new.enabled_image_features().into_iter().flatten(),
+            ),
+            kernel_parameters: SetDiff::new(
+                old.kernel_parameters().into_iter().flatten(),
+                new.kernel_parameters().into_iter().flatten(),
+            ),
+            image_layout: image_layout_diff(old, new),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl SetDiff {
+    fn new<T: ToString>(
+        old: impl IntoIterator<Item = T>,
+        new: impl IntoIterator<Item = T>,
+    ) -> Self {
+        let old = old
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<BTreeSet<_>>();
+        let new = new
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<BTreeSet<_>>();
+        Self {
+            added: new.difference(&old).cloned().collect(),
+            removed: old.difference(&new).cloned().collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Compare the image layouts field by field, using the names from the manifest.
+fn image_layout_diff(old: &ManifestInfo, new: &ManifestInfo) -> BTreeMap<String, ValueChange> {
+    let layout_fields = |info: &ManifestInfo| match serde_json::to_value(
+        info.image_layout().cloned().unwrap_or_default(),
+    ) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        _ => serde_json::Map::new(),
+    };
+
+    let old = layout_fields(old);
+    let new = layout_fields(new);
+    old.iter()
+        .filter_map(|(field, old_value)| {
+            let new_value = new.get(field)?;
+            (old_value != new_value).then(|| {
+                (
+                    field.clone(),
+                    ValueChange {
+                        old: value_string(old_value),
+                        new: value_string(new_value),
+                    },
+                )
+            })
+        })
+        .collect()
+}
+
+/// Format a JSON value without the quotes around strings.
+fn value_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+impl Display for ManifestDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes");
+        }
+
+        for (name, set) in [
+            ("included-packages", &self.included_packages),
+            ("image-features", &self.image_features),
+            ("kernel-parameters", &self.kernel_parameters),
+        ] {
+            if set.is_empty() {
+                continue;
+            }
+            writeln!(f, "{name}:")?;
+            for item in &set.added {
+                writeln!(f, "  + {item}")?;
+            }
+            for item in &set.removed {
+                writeln!(f, "  - {item}")?;
+            }
+        }
+
+        if !self.image_layout.is_empty() {
+            writeln!(f, "image-layout:")?;
+            for (field, change) in &self.image_layout {
+                writeln!(f, "  {field}: {} -> {}", change.old, change.new)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OLD_MANIFEST: &str = r#"
+        [package]
+        name = "aws-dev"
+
+        [package.metadata.build-variant]
+        included-packages = ["release", "kernel-6.1", "docker-engine"]
+        kernel-parameters = ["console=tty0"]
+
+        [package.metadata.build-variant.image-features]
+        fips = false
+    "#;
+
+    const NEW_MANIFEST: &str = r#"
+        [package]
+        name = "aws-dev"
+
+        [package.metadata.build-variant]
+        included-packages = ["release", "kernel-6.1", "containerd"]
+        kernel-parameters = ["console=tty0"]
+
+        [package.metadata.build-variant.image-features]
+        fips = true
+        host-containers = false
+
+        [package.metadata.build-variant.image-layout]
+        os-image-size-gib = 4
+        partition-plan = "unified"
+    "#;
+
+    fn manifest_info(manifest: &str) -> ManifestInfo {
+        toml::from_str(manifest).unwrap()
+    }
+
+    #[test]
+    fn test_manifest_diff() {
+        let old = manifest_info(OLD_MANIFEST);
+        let new = manifest_info(NEW_MANIFEST);
+        let diff = ManifestDiff::new(&old, &new);
+
+        assert_eq!(
+            diff.included_packages.added,
+            BTreeSet::from(["containerd".to_string()])
+        );
+        assert_eq!(
+            diff.included_packages.removed,
+            BTreeSet::from(["docker-engine".to_string()])
+        );
+        assert_eq!(
+            diff.image_features.added,
+            BTreeSet::from(["FIPS".to_string()])
+        );
+        assert_eq!(
+            diff.image_features.removed,
+            BTreeSet::from(["HOST_CONTAINERS".to_string()])
+        );
+        assert!(diff.kernel_parameters.is_empty());
+        assert_eq!(
+            diff.image_layout.keys().collect::<Vec<_>>(),
+            ["os-image-size-gib", "partition-plan"]
+        );
+        assert_eq!(diff.image_layout["partition-plan"].old, "split");
+        assert_eq!(diff.image_layout["partition-plan"].new, "unified");
+    }
+
+    #[test]
+    fn test_manifest_diff_text() {
+        let old = manifest_info(OLD_MANIFEST);
+        let new = manifest_info(NEW_MANIFEST);
+        let diff = ManifestDiff::new(&old, &new).to_string();
+        assert_eq!(
+            diff,
+            "included-packages:\n  + containerd\n  - docker-engine\n\
+            image-features:\n  + FIPS\n  - HOST_CONTAINERS\n\
+            image-layout:\n  os-image-size-gib: 2 -> 4\n  partition-plan: split -> unified\n"
+        );
+    }
+
+    #[test]
+    fn test_manifest_diff_json() {
+        let old = manifest_info(OLD_MANIFEST);
+        let new = manifest_info(NEW_MANIFEST);
+        let diff = serde_json::to_value(ManifestDiff::new(&old, &new)).unwrap();
+        assert_eq!(diff["included-packages"]["added"][0], "containerd");
+        assert_eq!(diff["image-layout"]["os-image-size-gib"]["new"], "4");
+    }
+
+    #[test]
+    fn test_manifest_diff_unchanged() {
+        let old = manifest_info(OLD_MANIFEST);
+        let diff = ManifestDiff::new(&old, &old);
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "No changes\n");
+    }
+}
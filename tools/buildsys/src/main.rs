@@ -11,25 +11,47 @@ The implementation is closely tied to the top-level Dockerfile.
 mod args;
 mod builder;
 mod cache;
+mod changes;
+mod command;
+mod diff;
 mod gomod;
+mod ova;
 mod project;
+mod provenance;
+mod prune;
+mod repro;
+mod resolved;
+mod schedule;
+mod sizes;
+mod snapshot;
 mod spec;
+mod timings;
+mod uptodate;
+mod verify;
 
 use crate::args::{
-    BuildKitArgs, BuildPackageArgs, BuildVariantArgs, Buildsys, Command, RepackVariantArgs,
+    ArchesArgs, BuildCommand, BuildKitArgs, BuildPackageArgs, BuildPackagesArgs, BuildVariantArgs,
+    Buildsys, CleanArgs, Command, CompletionsArgs, DiffArgs, ImageSizesArgs, OutputFormat,
+    RepackVariantArgs, VerifyImageArgs,
 };
 use crate::builder::DockerBuild;
+use crate::diff::ManifestDiff;
 use buildsys::manifest::{BundleModule, Manifest, ManifestInfo, SupportedArch};
 use buildsys_config::EXTERNAL_KIT_METADATA;
 use cache::LookasideCache;
+use changes::ChangedPaths;
 use clap::Parser;
 use filetime::FileTime;
 use gomod::GoMod;
 use project::ProjectInfo;
+use schedule::BuildPlan;
+use serde::Serialize;
+use sizes::ImageSizes;
 use snafu::{ensure, ResultExt};
 use spec::SpecInfo;
 use std::path::{Path, PathBuf};
 use std::process;
+use verify::ImageVerification;
 
 mod error {
     use snafu::Snafu;
@@ -41,6 +63,44 @@ mod error {
         #[snafu(display("{source}"))]
         ManifestParse { source: buildsys::manifest::Error },
 
+        #[snafu(display("Failed to serialize manifest diff: {source}"))]
+        DiffSerialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to serialize architectures: {source}"))]
+        ArchesSerialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to serialize image sizes: {source}"))]
+        ImageSizesSerialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to serialize image verification: {source}"))]
+        ImageVerifySerialize { source: serde_json::Error },
+
+        #[snafu(display("{source}"))]
+        ImageVerify { source: super::verify::error::Error },
+
+        #[snafu(display(
+            "Image '{}' does not match the layout in the manifest, found {count} mismatches",
+            image.display()
+        ))]
+        ImageMismatch { image: PathBuf, count: usize },
+
+        #[snafu(display("{source}"))]
+        BuildSchedule {
+            source: super::schedule::error::Error,
+        },
+
+        #[snafu(display("Failed to find the buildsys executable: {source}"))]
+        CurrentExe { source: std::io::Error },
+
+        #[snafu(display("{count} of the packages were not built"))]
+        PackagesNotBuilt { count: usize },
+
+        #[snafu(display("Variant '{name}' does not support {arch}"))]
+        UnsupportedArch { name: String, arch: String },
+
+        #[snafu(display("{source}"))]
+        Prune { source: super::prune::error::Error },
+
         #[snafu(display("{source}"))]
         SpecParse { source: super::spec::error::Error },
 
@@ -53,6 +113,11 @@ mod error {
             source: std::io::Error,
         },
 
+        #[snafu(display("{source}"))]
+        ChangeDetection {
+            source: super::changes::error::Error,
+        },
+
         #[snafu(display("{source}"))]
         GoMod { source: super::gomod::error::Error },
 
@@ -66,6 +131,11 @@ mod error {
             source: super::builder::error::Error,
         },
 
+        #[snafu(display("Failed to clean build artifacts: {source}"))]
+        Clean {
+            source: super::builder::error::Error,
+        },
+
         #[snafu(display("Unable to instantiate the builder: {source}"))]
         BuilderInstantiation {
             source: crate::builder::error::Error,
@@ -107,12 +177,31 @@ fn main() {
 }
 
 fn run(args: Buildsys) -> Result<()> {
-    args::rerun_for_envs(args.command.build_type());
+    if let Some(build_type) = args.command.build_type() {
+        args::rerun_for_envs(build_type);
+    }
+    if let Command::Build(build) = &args.command {
+        // Changes to the Dockerfile affect every build.
+        let dockerfile = builder::dockerfile(build.common());
+        println!("cargo:rerun-if-changed={}", dockerfile.display());
+        if let Some(build_arg_file) = builder::build_arg_file(build.common()) {
+            println!("cargo:rerun-if-changed={}", build_arg_file.display());
+        }
+    }
     match args.command {
-        Command::BuildPackage(args) => build_package(*args),
-        Command::BuildKit(args) => build_kit(*args),
-        Command::BuildVariant(args) => build_variant(*args),
-        Command::RepackVariant(args) => repack_variant(*args),
+        Command::Build(BuildCommand::BuildPackage(args)) => build_package(*args),
+        Command::Build(BuildCommand::BuildKit(args)) => build_kit(*args),
+        Command::Build(BuildCommand::BuildVariant(args)) => build_variant(*args),
+        Command::Build(BuildCommand::RepackVariant(args)) => repack_variant(*args),
+        Command::Diff(args) => diff(*args),
+        Command::ShowArgs(args) => show_args(args.command),
+        Command::Prune(args) => prune::prune(args.dry_run).context(error::PruneSnafu),
+        Command::ImageSizes(args) => image_sizes(args),
+        Command::VerifyImage(args) => verify_image(args),
+        Command::BuildPackages(args) => build_packages(args),
+        Command::Completions(args) => completions(args),
+        Command::Clean(args) => clean(args),
+        Command::Arches(args) => arches(args),
     }
 }
 
@@ -161,7 +250,10 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
                         &args.common.root_dir,
                         &args.common.cargo_manifest_dir,
                         f,
-                        &args.common.sdk_image,
+                        &builder::pull_image(
+                            &builder::sdk_image(&args.common, manifest.info()),
+                            args.common.registry_mirror.as_deref(),
+                        ),
                         mtime,
                     )
                     .context(error::GoModSnafu)?,
@@ -201,10 +293,78 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
         return Ok(());
     }
 
-    DockerBuild::new_package(args, &manifest)
-        .context(error::BuilderInstantiationSnafu)?
-        .build()
-        .context(error::BuildAttemptSnafu)
+    let unchanged_since = match &args.changed_since {
+        Some(git_ref) if !package_changed(git_ref, &args, &manifest)? => Some(git_ref.clone()),
+        _ => None,
+    };
+
+    let mut build =
+        DockerBuild::new_package(args, &manifest).context(error::BuilderInstantiationSnafu)?;
+    if let Some(git_ref) = unchanged_since {
+        if build.has_artifacts() {
+            println!("{package} is up to date with {git_ref}, skipping build");
+            return Ok(());
+        }
+        println!("{package} is up to date with {git_ref}, but its artifacts are missing");
+    }
+    build.build().context(error::BuildAttemptSnafu)
+}
+
+fn build_packages(args: BuildPackagesArgs) -> Result<()> {
+    let packages = schedule::package_dependencies(&args.packages, &args.cargo_metadata_path)
+        .context(error::BuildScheduleSnafu)?;
+    let plan = BuildPlan::new(
+        packages
+            .iter()
+            .map(|(package, (_, deps))| (package.clone(), deps.clone()))
+            .collect(),
+    )
+    .context(error::BuildScheduleSnafu)?;
+    println!(
+        "Building {} packages in {} batches",
+        packages.len(),
+        plan.batches().len()
+    );
+    print!("{plan}");
+
+    let buildsys = std::env::current_exe().context(error::CurrentExeSnafu)?;
+    let report = plan.run(usize::from(args.jobs), |package| {
+        let dir = &packages[package].0;
+        let status = duct::cmd!(&buildsys, "build-package")
+            .dir(dir)
+            .env("CARGO_MANIFEST_DIR", dir)
+            .unchecked()
+            .run();
+        match status {
+            Ok(output) if output.status.success() => true,
+            Ok(_) => false,
+            Err(e) => {
+                eprintln!("Failed to start build for {package}: {e}");
+                false
+            }
+        }
+    });
+    print!("{report}");
+
+    let count = report.unbuilt();
+    ensure!(count == 0, error::PackagesNotBuiltSnafu { count });
+    Ok(())
+}
+
+/// Check whether the package directory, the directories of the packages it depends on, or any of
+/// their source groups have changes since the git ref.
+fn package_changed(git_ref: &str, args: &BuildPackageArgs, manifest: &Manifest) -> Result<bool> {
+    let changes =
+        ChangedPaths::since(&args.common.root_dir, git_ref).context(error::ChangeDetectionSnafu)?;
+    let mut package_dirs = vec![args.common.cargo_manifest_dir.clone()];
+    package_dirs.extend(
+        manifest
+            .package_dependency_dirs()
+            .context(error::ManifestParseSnafu)?,
+    );
+    changes
+        .packages_changed(&package_dirs, &args.sources_dir)
+        .context(error::ChangeDetectionSnafu)
 }
 
 fn build_kit(args: BuildKitArgs) -> Result<()> {
@@ -278,6 +438,140 @@ fn repack_variant(args: RepackVariantArgs) -> Result<()> {
         .context(error::BuildAttemptSnafu)
 }
 
+fn show_args(command: BuildCommand) -> Result<()> {
+    let common = command.common();
+    let manifest = Manifest::new(
+        common.cargo_manifest_dir.join("Cargo.toml"),
+        &common.cargo_metadata_path,
+    )
+    .context(error::ManifestParseSnafu)?;
+
+    let build = match command {
+        BuildCommand::BuildPackage(args) => DockerBuild::new_package(*args, &manifest),
+        BuildCommand::BuildKit(args) => DockerBuild::new_kit(*args, &manifest),
+        BuildCommand::BuildVariant(args) => DockerBuild::new_variant(*args, &manifest),
+        BuildCommand::RepackVariant(args) => DockerBuild::repack_variant(*args, &manifest),
+    }
+    .context(error::BuilderInstantiationSnafu)?;
+
+    print!("{}", build.effective_args());
+    Ok(())
+}
+
+fn clean(args: CleanArgs) -> Result<()> {
+    builder::clean(&args).context(error::CleanSnafu)
+}
+
+/// An architecture as printed by the `arches` subcommand.
+#[derive(Debug, Serialize)]
+struct Arch {
+    arch: SupportedArch,
+    goarch: &'static str,
+}
+
+fn arches(args: ArchesArgs) -> Result<()> {
+    let arches = SupportedArch::all()
+        .iter()
+        .map(|&arch| Arch {
+            arch,
+            goarch: arch.goarch(),
+        })
+        .collect::<Vec<_>>();
+
+    match args.format {
+        OutputFormat::Text => {
+            for arch in &arches {
+                println!("{}\t{}", arch.arch, arch.goarch);
+            }
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&arches).context(error::ArchesSerializeSnafu)?
+        ),
+    }
+
+    Ok(())
+}
+
+fn completions(args: CompletionsArgs) -> Result<()> {
+    args::write_completions(args.shell, &mut std::io::stdout());
+    Ok(())
+}
+
+fn diff(args: DiffArgs) -> Result<()> {
+    let old = ManifestInfo::new(&args.old_manifest).context(error::ManifestParseSnafu)?;
+    let new = ManifestInfo::new(&args.new_manifest).context(error::ManifestParseSnafu)?;
+    let diff = ManifestDiff::new(&old, &new);
+
+    match args.format {
+        OutputFormat::Text => print!("{diff}"),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&diff).context(error::DiffSerializeSnafu)?
+        ),
+    }
+
+    Ok(())
+}
+
+fn image_sizes(args: ImageSizesArgs) -> Result<()> {
+    let manifest = ManifestInfo::new(&args.manifest).context(error::ManifestParseSnafu)?;
+    ensure!(
+        manifest
+            .supported_arches()
+            .map_or(true, |arches| arches.contains(&args.arch)),
+        error::UnsupportedArchSnafu {
+            name: manifest.manifest_name(),
+            arch: args.arch.to_string(),
+        }
+    );
+    let sizes = ImageSizes::new(&manifest, args.arch);
+
+    match args.format {
+        OutputFormat::Text => print!("{sizes}"),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&sizes).context(error::ImageSizesSerializeSnafu)?
+        ),
+    }
+
+    Ok(())
+}
+
+fn verify_image(args: VerifyImageArgs) -> Result<()> {
+    let manifest = ManifestInfo::new(&args.manifest).context(error::ManifestParseSnafu)?;
+    ensure!(
+        manifest
+            .supported_arches()
+            .map_or(true, |arches| arches.contains(&args.arch)),
+        error::UnsupportedArchSnafu {
+            name: manifest.manifest_name(),
+            arch: args.arch.to_string(),
+        }
+    );
+    let verification =
+        ImageVerification::new(&args.image, &manifest).context(error::ImageVerifySnafu)?;
+
+    match args.format {
+        OutputFormat::Text => print!("{verification}"),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&verification)
+                .context(error::ImageVerifySerializeSnafu)?
+        ),
+    }
+
+    let count = verification.mismatches().len();
+    ensure!(
+        count == 0,
+        error::ImageMismatchSnafu {
+            image: args.image,
+            count
+        }
+    );
+    Ok(())
+}
+
 /// Ensure that the current arch is supported by the current variant
 fn check_arch_support(manifest: &ManifestInfo, arch: SupportedArch) {
     if let Some(supported_arches) = manifest.supported_arches() {
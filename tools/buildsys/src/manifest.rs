@@ -87,6 +87,32 @@ to indicate a good URL for checking whether the software has had a new release.
 releases-url = "https://www.example.com/releases"
 ```
 
+`build-args` is a map of additional build arguments to pass to the Dockerfile,
+for cases where a build needs a custom value that buildsys does not provide.
+The keys may not collide with any build argument that buildsys sets itself,
+such as `ARCH`, `SDK`, `NOCACHE`, or `TOKEN`.
+```ignore
+[package.metadata.build-package.build-args]
+GO_BUILD_TAGS = "netgo"
+```
+
+`sdk-image` is the SDK image to build with, in place of the one given to buildsys
+for the whole build. It must be a valid image reference, with an optional tag or
+digest.
+```ignore
+[package.metadata.build-package]
+sdk-image = "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0"
+```
+
+`needs-bypass` can be set to `false` for a package whose build is faster without
+the bypass container, such as one with few dependencies. buildsys then skips the
+container for this package only, and the build reads the project root through a
+named build context instead, as it does for every build with `--no-bypass`.
+```ignore
+[package.metadata.build-package]
+needs-bypass = false
+```
+
 ## Metadata for kits
 
 When building a kit, it is necessary to include a `package.metadata.build-kit` key even though there
@@ -114,6 +140,16 @@ some-package = { path = "../../packages/some-package" }
 included-packages = ["release"]
 ```
 
+A package that only applies to one architecture can be listed with that architecture.
+Packages listed by name alone are included for every architecture.
+```ignore
+[package.metadata.build-variant]
+included-packages = [
+    "release",
+    { name = "ecr-credential-provider", arch = "x86_64" },
+]
+```
+
 `image-format` is the desired format for the built images.
 This can be `raw` (the default), `vmdk`, or `qcow2`.
 ```ignore
@@ -153,6 +189,36 @@ publish-image-size-hint-gib = 22
 partition-plan = "split"
 ```
 
+`data-volumes` is an optional list of additional data volumes, built and
+published alongside the "os" and "data" images. Each volume has a `name`, a
+`size-gib`, and a `role` that tells the image how to mount it. Volumes are
+published at their built size, so when any are listed, the publish size hint
+must be large enough to hold every image.
+```ignore
+[package.metadata.build-variant.image-layout]
+os-image-size-gib = 2
+data-image-size-gib = 1
+publish-image-size-hint-gib = 30
+data-volumes = [
+    { name = "cache", size-gib = 4, role = "scratch" },
+    { name = "logs", size-gib = 2, role = "persistent" },
+]
+```
+
+`build-args` is a map of additional build arguments to pass to the Dockerfile.
+It follows the same rules as the `build-args` map for packages.
+```ignore
+[package.metadata.build-variant.build-args]
+EXTRA_IMAGE_LABEL = "appliance"
+```
+
+`sdk-image` is the SDK image to build the variant with. It follows the same
+rules as the `sdk-image` key for packages.
+```ignore
+[package.metadata.build-variant]
+sdk-image = "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0"
+```
+
 `supported-arches` is the list of architectures the variant is able to run on.
 The values can be `x86_64` and `aarch64`.
 If not specified, the variant can run on any of those architectures.
@@ -247,10 +313,13 @@ use crate::BuildType;
 use buildsys_config::EXTERNAL_KIT_METADATA;
 use guppy::graph::{DependencyDirection, PackageGraph, PackageLink, PackageMetadata};
 use guppy::{CargoMetadata, PackageId};
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::cell::OnceCell;
 use std::cmp::max;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Display};
 use std::fs;
@@ -263,18 +332,32 @@ type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub struct Manifest {
-    graph: PackageGraph,
+    cargo_metadata: PathBuf,
+    graph: OnceCell<PackageGraph>,
     manifest_info: ManifestInfo,
 }
 
 impl Manifest {
-    /// Extract the settings we understand from `Cargo.toml` and construct a dependency graph.
+    /// Extract the settings we understand from `Cargo.toml`. The dependency graph is constructed
+    /// from the cargo metadata the first time it is needed, since it is expensive for large
+    /// projects.
     pub fn new(manifest: impl AsRef<Path>, cargo_metadata: impl AsRef<Path>) -> Result<Self> {
         let manifest_info = ManifestInfo::new(manifest)?;
-        let cargo_metadata = cargo_metadata.as_ref();
+        Ok(Self {
+            cargo_metadata: cargo_metadata.as_ref().to_path_buf(),
+            graph: OnceCell::new(),
+            manifest_info,
+        })
+    }
+
+    fn graph(&self) -> Result<&PackageGraph> {
+        if let Some(graph) = self.graph.get() {
+            return Ok(graph);
+        }
+        let cargo_metadata = &self.cargo_metadata;
         let cargo_metadata_json_contents =
             fs::read_to_string(cargo_metadata).context(error::CargoMetadataReadSnafu {
-                path: &cargo_metadata,
+                path: cargo_metadata,
             })?;
         let graph = CargoMetadata::parse_json(cargo_metadata_json_contents)
             .context(error::CargoMetadataParseSnafu {
@@ -284,10 +367,7 @@ impl Manifest {
             .context(error::GraphBuildSnafu {
                 path: cargo_metadata,
             })?;
-        Ok(Self {
-            manifest_info,
-            graph,
-        })
+        Ok(self.graph.get_or_init(|| graph))
     }
 
     /// List all packages that are package dependencies. That is, follow all dependencies in the cargo
@@ -295,38 +375,59 @@ impl Manifest {
     /// gives a list of all the packages that are required when we are build a package, or all of the
     /// packages that should be included when building a kit.
     pub fn package_dependencies(&self) -> Result<Vec<String>> {
+        let name = self.info().manifest_name();
+        let mut packages: Vec<String> = self
+            .package_dependency_metadata()?
+            .iter()
+            .filter_map(|pkg_metadata| filter_map_to_name(name, pkg_metadata))
+            .collect();
+
+        // Sort so that this function has consistent, dependable output regardless of graph internals.
+        packages.sort();
+        Ok(packages)
+    }
+
+    /// List the directories of the same packages as `package_dependencies`, so that their files
+    /// can be checked for changes.
+    pub fn package_dependency_dirs(&self) -> Result<Vec<PathBuf>> {
+        let name = self.info().manifest_name();
+        let mut dirs: Vec<PathBuf> = self
+            .package_dependency_metadata()?
+            .iter()
+            .filter(|pkg_metadata| pkg_metadata.name() != name)
+            .filter_map(|pkg_metadata| pkg_metadata.manifest_path().parent())
+            .map(|dir| dir.as_std_path().to_path_buf())
+            .collect();
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    fn package_dependency_metadata(&self) -> Result<Vec<PackageMetadata<'_>>> {
         let name = self.info().manifest_name();
         let manifest_type = self.info().build_type()?;
-        let id = find_id(name, &self.graph, manifest_type)
+        let graph = self.graph()?;
+        let id = find_id(name, graph, manifest_type)
             .context(error::RootDependencyMissingSnafu { name })?;
         let ids = [&id];
-        let query = self
-            .graph
+        let query = graph
             .query_forward(ids.into_iter())
             .context(error::CargoPackageQuerySnafuSnafu { id })?;
         let package_set = query.resolve_with_fn(|_, link| {
             let to = link.to();
             is_valid_dep(name, &link) && is_manifest_type(&to, BuildType::Package)
         });
-        let mut packages: Vec<String> = package_set
-            .packages(DependencyDirection::Forward)
-            .filter_map(|pkg_metadata| filter_map_to_name(name, &pkg_metadata))
-            .collect();
-
-        // Sort so that this function has consistent, dependable output regardless of graph internals.
-        packages.sort();
-        Ok(packages)
+        Ok(package_set.packages(DependencyDirection::Forward).collect())
     }
 
     /// List all kits needed for the build.
     pub fn kit_dependencies(&self) -> Result<Vec<String>> {
         let name = self.info().manifest_name();
         let manifest_type = self.info().build_type()?;
-        let id = find_id(name, &self.graph, manifest_type)
+        let graph = self.graph()?;
+        let id = find_id(name, graph, manifest_type)
             .context(error::RootDependencyMissingSnafu { name })?;
         let ids = [&id];
-        let query = self
-            .graph
+        let query = graph
             .query_forward(ids.into_iter())
             .context(error::CargoPackageQuerySnafuSnafu { id })?;
         let package_set = query.resolve();
@@ -398,6 +499,15 @@ impl ManifestInfo {
             fs::read_to_string(path).context(error::ManifestFileReadSnafu { path })?;
         let manifest_info: ManifestInfo =
             toml::from_str(&manifest_data).context(error::ManifestFileLoadSnafu { path })?;
+        if let Some(image_layout) = manifest_info.image_layout() {
+            image_layout.validate()?;
+        }
+        if let Some(image) = manifest_info.sdk_image() {
+            ensure!(
+                IMAGE_REFERENCE.is_match(image),
+                error::SdkImageSnafu { path, image }
+            );
+        }
         Ok(manifest_info)
     }
 
@@ -457,11 +567,44 @@ impl ManifestInfo {
     }
 
     /// Convenience method to return the list of included packages.
-    pub fn included_packages(&self) -> Option<&Vec<String>> {
+    pub fn included_packages(&self) -> Option<&Vec<IncludedPackage>> {
         self.build_variant()
             .and_then(|b| b.included_packages.as_ref())
     }
 
+    /// Convenience method to return the names of the included packages for an architecture.
+    pub fn included_packages_for_arch(&self, arch: SupportedArch) -> Option<Vec<String>> {
+        self.included_packages().map(|packages| {
+            packages
+                .iter()
+                .filter(|p| p.includes_arch(arch))
+                .map(|p| p.name().to_string())
+                .collect()
+        })
+    }
+
+    /// Convenience method to return the additional build arguments for a package or variant.
+    pub fn build_args(&self) -> Option<&BTreeMap<String, String>> {
+        self.build_package()
+            .and_then(|b| b.build_args.as_ref())
+            .or_else(|| self.build_variant().and_then(|b| b.build_args.as_ref()))
+    }
+
+    /// Convenience method to return the SDK image override for a package or variant, if any.
+    pub fn sdk_image(&self) -> Option<&str> {
+        self.build_package()
+            .and_then(|b| b.sdk_image.as_deref())
+            .or_else(|| self.build_variant().and_then(|b| b.sdk_image.as_deref()))
+    }
+
+    /// Convenience method to return whether a package build needs the bypass container. This is
+    /// true unless the package opts out.
+    pub fn needs_bypass(&self) -> bool {
+        self.build_package()
+            .and_then(|b| b.needs_bypass)
+            .unwrap_or(true)
+    }
+
     /// Convenience method to return the image format override, if any.
     pub fn image_format(&self) -> Option<&ImageFormat> {
         self.build_variant().and_then(|b| b.image_format.as_ref())
@@ -486,6 +629,18 @@ impl ManifestInfo {
 
     /// Convenience method to return the enabled image features for this variant.
     pub fn image_features(&self) -> Option<HashSet<ImageFeature>> {
+        let features = self.enabled_image_features()?;
+        for experiment in EXPERIMENTAL_IMAGE_FEATURES {
+            if features.contains(experiment) {
+                println!("cargo:warning=Image feature {experiment} is experimental; use at your own risk!");
+            }
+        }
+        Some(features)
+    }
+
+    /// Returns the enabled image features for this variant, without warning about experimental
+    /// features.
+    pub fn enabled_image_features(&self) -> Option<HashSet<ImageFeature>> {
         let variant = self.build_variant()?;
         let mut features =
             HashSet::from([ImageFeature::InPlaceUpdates, ImageFeature::HostContainers]);
@@ -498,11 +653,6 @@ impl ManifestInfo {
                 }
             }
         }
-        for experiment in EXPERIMENTAL_IMAGE_FEATURES {
-            if features.contains(experiment) {
-                println!("cargo:warning=Image feature {experiment} is experimental; use at your own risk!");
-            }
-        }
         Some(features)
     }
 
@@ -547,6 +697,18 @@ impl ManifestInfo {
     }
 }
 
+lazy_static! {
+    /// An image reference: an optional registry host, a lowercase repository path, and an optional
+    /// tag and digest.
+    static ref IMAGE_REFERENCE: Regex = Regex::new(concat!(
+        r"^(?:[a-zA-Z0-9.-]+(?::[0-9]+)?/)?",
+        r"[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*(?:/[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*)*",
+        r"(?::\w[\w.-]{0,127})?",
+        r"(?:@[A-Za-z][A-Za-z0-9]*(?:[-_+.][A-Za-z][A-Za-z0-9]*)*:[0-9a-fA-F]{32,})?$",
+    ))
+    .unwrap();
+}
+
 /// For the "top-level manifest", i.e. the thing that `buildsys` is building, only
 /// `build-dependencies` are valid. This is because we would need all artifacts before the top-level
 /// manifest's `build.rs` runs. Once we go deeper in the graph, then both `build-dependencies` and
@@ -632,6 +794,9 @@ pub struct BuildPackage {
     pub source_groups: Option<Vec<PathBuf>>,
     pub variant_sensitive: Option<VariantSensitivity>,
     pub package_features: Option<Vec<ImageFeature>>,
+    pub build_args: Option<BTreeMap<String, String>>,
+    pub sdk_image: Option<String>,
+    pub needs_bypass: Option<bool>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -662,13 +827,49 @@ pub enum SensitivityType {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct BuildVariant {
-    pub included_packages: Option<Vec<String>>,
+    pub included_packages: Option<Vec<IncludedPackage>>,
     pub image_format: Option<ImageFormat>,
     #[serde(default)]
     pub image_layout: ImageLayout,
     pub supported_arches: Option<HashSet<SupportedArch>>,
     pub kernel_parameters: Option<Vec<String>>,
     pub image_features: Option<HashMap<ImageFeature, bool>>,
+    pub build_args: Option<BTreeMap<String, String>>,
+    pub sdk_image: Option<String>,
+}
+
+/// A package to include in a variant, which may be limited to one architecture.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum IncludedPackage {
+    Name(String),
+    ForArch { name: String, arch: SupportedArch },
+}
+
+impl IncludedPackage {
+    pub fn name(&self) -> &str {
+        match self {
+            IncludedPackage::Name(name) => name,
+            IncludedPackage::ForArch { name, .. } => name,
+        }
+    }
+
+    /// Returns true if the package should be included when building for `arch`.
+    pub fn includes_arch(&self, arch: SupportedArch) -> bool {
+        match self {
+            IncludedPackage::Name(_) => true,
+            IncludedPackage::ForArch { arch: only, .. } => *only == arch,
+        }
+    }
+}
+
+impl Display for IncludedPackage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncludedPackage::Name(name) => write!(f, "{name}"),
+            IncludedPackage::ForArch { name, arch } => write!(f, "{name} ({arch})"),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -679,17 +880,24 @@ pub enum ImageFormat {
     Vmdk,
 }
 
-#[derive(Deserialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 /// Constrain specified image sizes to a plausible range, from 0 - 65535 GiB.
 pub struct ImageSize(u16);
 
+impl ImageSize {
+    /// Returns the size in GiB.
+    pub fn gib(self) -> u16 {
+        self.0
+    }
+}
+
 impl Display for ImageSize {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
-#[derive(Deserialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct ImageLayout {
     #[serde(default = "ImageLayout::default_os_image_size_gib")]
@@ -700,6 +908,35 @@ pub struct ImageLayout {
     publish_image_size_hint_gib: ImageSize,
     #[serde(default = "ImageLayout::default_partition_plan")]
     pub partition_plan: PartitionPlan,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub data_volumes: Vec<DataVolume>,
+}
+
+/// An additional data volume, built and published alongside the "os" and "data" images.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DataVolume {
+    pub name: String,
+    pub size_gib: ImageSize,
+    pub role: String,
+}
+
+impl DataVolume {
+    /// Names and roles are passed to the image build as a delimited list, so they are limited to
+    /// characters that can't be confused with the delimiters.
+    fn valid_field(field: &str) -> bool {
+        !field.is_empty()
+            && field
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    }
+}
+
+/// Volumes are formatted as `name:role:size`, the form the image build expects.
+impl Display for DataVolume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.role, self.size_gib)
+    }
 }
 
 /// These are the historical defaults for all variants, before we added support
@@ -726,13 +963,72 @@ impl ImageLayout {
         DEFAULT_PARTITION_PLAN
     }
 
+    /// The combined size of the additional data volumes.
+    fn data_volumes_size_gib(&self) -> u32 {
+        self.data_volumes
+            .iter()
+            .map(|v| u32::from(v.size_gib.0))
+            .sum()
+    }
+
+    /// The combined size of every image and volume in the layout.
+    fn total_image_size_gib(&self) -> u32 {
+        u32::from(self.os_image_size_gib.0)
+            + u32::from(self.data_image_size_gib.0)
+            + self.data_volumes_size_gib()
+    }
+
+    /// Check that the additional data volumes are well-formed, and that the combined sizes fit
+    /// within the image size limits and the publish size hint.
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for volume in &self.data_volumes {
+            ensure!(
+                DataVolume::valid_field(&volume.name),
+                error::DataVolumeNameSnafu { name: &volume.name }
+            );
+            ensure!(
+                DataVolume::valid_field(&volume.role),
+                error::DataVolumeRoleSnafu {
+                    name: &volume.name,
+                    role: &volume.role
+                }
+            );
+            ensure!(
+                volume.size_gib.0 > 0,
+                error::DataVolumeSizeSnafu { name: &volume.name }
+            );
+            ensure!(
+                names.insert(volume.name.as_str()),
+                error::DataVolumeDuplicateSnafu { name: &volume.name }
+            );
+        }
+
+        let total = self.total_image_size_gib();
+        ensure!(
+            total <= u16::MAX.into(),
+            error::ImageSizeTotalSnafu { total }
+        );
+
+        // The hint is silently raised to fit the "os" and "data" images, for compatibility with
+        // existing layouts. Volumes are new, so a hint too small to hold them is an error instead.
+        let hint = self.publish_image_size_hint_gib.0;
+        ensure!(
+            self.data_volumes.is_empty() || u32::from(hint) >= total,
+            error::PublishSizeHintSnafu { hint, total }
+        );
+
+        Ok(())
+    }
+
     // At publish time we will need specific sizes for the OS image and the (optional) data image.
     // The sizes returned by this function depend on the image layout, and whether the publish
-    // image hint is larger than the required minimum size.
+    // image hint is larger than the required minimum size. Additional data volumes are passed to
+    // the image build, but it does not create them yet, so no space is set aside for them here.
     pub fn publish_image_sizes_gib(&self) -> (i32, i32) {
-        let os_image_base_size_gib = self.os_image_size_gib.0;
-        let data_image_base_size_gib = self.data_image_size_gib.0;
-        let publish_image_size_hint_gib = self.publish_image_size_hint_gib.0;
+        let os_image_base_size_gib = i32::from(self.os_image_size_gib.0);
+        let data_image_base_size_gib = i32::from(self.data_image_size_gib.0);
+        let publish_image_size_hint_gib = i32::from(self.publish_image_size_hint_gib.0);
 
         let min_publish_image_size_gib = os_image_base_size_gib + data_image_base_size_gib;
         let publish_image_size_gib = max(publish_image_size_hint_gib, min_publish_image_size_gib);
@@ -741,12 +1037,9 @@ impl ImageLayout {
             PartitionPlan::Split => {
                 let os_image_publish_size_gib = os_image_base_size_gib;
                 let data_image_publish_size_gib = publish_image_size_gib - os_image_base_size_gib;
-                (
-                    os_image_publish_size_gib.into(),
-                    data_image_publish_size_gib.into(),
-                )
+                (os_image_publish_size_gib, data_image_publish_size_gib)
             }
-            PartitionPlan::Unified => (publish_image_size_gib.into(), -1),
+            PartitionPlan::Unified => (publish_image_size_gib, -1),
         }
     }
 }
@@ -758,11 +1051,12 @@ impl Default for ImageLayout {
             data_image_size_gib: Self::default_data_image_size_gib(),
             publish_image_size_hint_gib: Self::default_publish_image_size_hint_gib(),
             partition_plan: Self::default_partition_plan(),
+            data_volumes: Vec::new(),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum PartitionPlan {
     Split,
@@ -779,8 +1073,13 @@ pub enum SupportedArch {
 serde_plain::derive_fromstr_from_deserialize!(SupportedArch);
 serde_plain::derive_display_from_serialize!(SupportedArch);
 
-/// Map a Linux architecture into the corresponding Docker architecture.
 impl SupportedArch {
+    /// Every supported architecture, so that callers can list them without naming each one.
+    pub fn all() -> &'static [SupportedArch] {
+        &[SupportedArch::X86_64, SupportedArch::Aarch64]
+    }
+
+    /// Map a Linux architecture into the corresponding Docker architecture.
     pub fn goarch(&self) -> &'static str {
         match self {
             SupportedArch::X86_64 => "amd64",
@@ -864,6 +1163,22 @@ mod test {
     use std::path::PathBuf;
     use tempfile::TempDir;
 
+    #[test]
+    fn test_supported_arch_all() {
+        // Adding a variant fails to compile here until it is handled, and then fails the test
+        // until `all` includes it.
+        for arch in [SupportedArch::X86_64, SupportedArch::Aarch64] {
+            match arch {
+                SupportedArch::X86_64 | SupportedArch::Aarch64 => {}
+            }
+            assert!(SupportedArch::all().contains(&arch), "{arch}");
+        }
+        for arch in SupportedArch::all() {
+            assert!(!arch.goarch().is_empty(), "{arch}");
+            assert_eq!(arch.to_string().parse::<SupportedArch>().unwrap(), *arch);
+        }
+    }
+
     fn test_projects_dir() -> PathBuf {
         let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         p.pop();
@@ -907,6 +1222,270 @@ mod test {
         output_path
     }
 
+    fn write_manifest(temp_dir: &TempDir, contents: &str) -> PathBuf {
+        let path = temp_dir.path().join("Cargo.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_build_args_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write_manifest(
+            &temp_dir,
+            r#"
+            [package]
+            name = "pkg-x"
+
+            [package.metadata.build-package.build-args]
+            GO_BUILD_TAGS = "netgo"
+            EXTRA = "a b c"
+            "#,
+        );
+        let manifest_info = ManifestInfo::new(path).unwrap();
+        let build_args = manifest_info.build_args().unwrap();
+        let expected = BTreeMap::from([
+            ("EXTRA".to_string(), "a b c".to_string()),
+            ("GO_BUILD_TAGS".to_string(), "netgo".to_string()),
+        ]);
+        assert_eq!(build_args, &expected);
+    }
+
+    #[test]
+    fn test_build_args_variant() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write_manifest(
+            &temp_dir,
+            r#"
+            [package]
+            name = "aws-dev"
+
+            [package.metadata.build-variant]
+            included-packages = ["release"]
+
+            [package.metadata.build-variant.build-args]
+            EXTRA_IMAGE_LABEL = "appliance"
+            "#,
+        );
+        let manifest_info = ManifestInfo::new(path).unwrap();
+        let build_args = manifest_info.build_args().unwrap();
+        assert_eq!(build_args.get("EXTRA_IMAGE_LABEL").unwrap(), "appliance");
+    }
+
+    #[test]
+    fn test_sdk_image() {
+        let temp_dir = TempDir::new().unwrap();
+        for image in [
+            "sdk",
+            "sdk:v1.0",
+            "localhost:5000/bottlerocket/sdk-x86_64:v0.50.0",
+            "public.ecr.aws/bottlerocket/sdk@sha256:0123456789abcdef0123456789abcdef",
+        ] {
+            let path = write_manifest(
+                &temp_dir,
+                &format!(
+                    r#"
+                    [package]
+                    name = "aws-dev"
+
+                    [package.metadata.build-variant]
+                    sdk-image = "{image}"
+                    "#
+                ),
+            );
+            let manifest_info = ManifestInfo::new(path).unwrap();
+            assert_eq!(manifest_info.sdk_image(), Some(image));
+        }
+
+        for image in ["", "SDK:latest", "sdk:", "sdk latest", "sdk@sha256:xyz"] {
+            let path = write_manifest(
+                &temp_dir,
+                &format!(
+                    r#"
+                    [package]
+                    name = "glibc"
+
+                    [package.metadata.build-package]
+                    sdk-image = "{image}"
+                    "#
+                ),
+            );
+            let err = ManifestInfo::new(path).unwrap_err();
+            assert!(matches!(err.0, error::Error::SdkImage { .. }), "{image}");
+        }
+    }
+
+    fn included_packages_manifest() -> (TempDir, PathBuf) {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write_manifest(
+            &temp_dir,
+            r#"
+            [package]
+            name = "aws-dev"
+
+            [package.metadata.build-variant]
+            included-packages = [
+                "release",
+                { name = "x86-only", arch = "x86_64" },
+                { name = "arm-only", arch = "aarch64" },
+            ]
+            "#,
+        );
+        (temp_dir, path)
+    }
+
+    #[test]
+    fn test_included_packages_x86_64() {
+        let (_temp_dir, path) = included_packages_manifest();
+        let manifest_info = ManifestInfo::new(path).unwrap();
+        let packages = manifest_info
+            .included_packages_for_arch(SupportedArch::X86_64)
+            .unwrap();
+        assert_eq!(packages, ["release", "x86-only"]);
+    }
+
+    #[test]
+    fn test_included_packages_aarch64() {
+        let (_temp_dir, path) = included_packages_manifest();
+        let manifest_info = ManifestInfo::new(path).unwrap();
+        let packages = manifest_info
+            .included_packages_for_arch(SupportedArch::Aarch64)
+            .unwrap();
+        assert_eq!(packages, ["release", "arm-only"]);
+    }
+
+    fn image_layout_manifest(temp_dir: &TempDir, image_layout: &str) -> PathBuf {
+        write_manifest(
+            temp_dir,
+            &format!(
+                r#"
+                [package]
+                name = "aws-dev"
+
+                [package.metadata.build-variant.image-layout]
+                {image_layout}
+                "#
+            ),
+        )
+    }
+
+    #[test]
+    fn test_image_layout_single_data_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = image_layout_manifest(
+            &temp_dir,
+            r#"
+            os-image-size-gib = 4
+            data-image-size-gib = 2
+            publish-image-size-hint-gib = 5
+            "#,
+        );
+        let manifest_info = ManifestInfo::new(path).unwrap();
+        let image_layout = manifest_info.image_layout().unwrap();
+        assert!(image_layout.data_volumes.is_empty());
+        // The hint is raised to fit the images, as before.
+        assert_eq!(image_layout.publish_image_sizes_gib(), (4, 2));
+    }
+
+    #[test]
+    fn test_image_layout_data_volumes() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = image_layout_manifest(
+            &temp_dir,
+            r#"
+            os-image-size-gib = 2
+            data-image-size-gib = 1
+            publish-image-size-hint-gib = 30
+            data-volumes = [
+                { name = "cache", size-gib = 4, role = "scratch" },
+                { name = "logs", size-gib = 2, role = "persistent" },
+            ]
+            "#,
+        );
+        let manifest_info = ManifestInfo::new(path).unwrap();
+        let image_layout = manifest_info.image_layout().unwrap();
+        assert_eq!(
+            image_layout.data_volumes,
+            [
+                DataVolume {
+                    name: "cache".to_string(),
+                    size_gib: ImageSize(4),
+                    role: "scratch".to_string(),
+                },
+                DataVolume {
+                    name: "logs".to_string(),
+                    size_gib: ImageSize(2),
+                    role: "persistent".to_string(),
+                },
+            ]
+        );
+        // The volumes are not built yet, so the publish sizes are the same as without them.
+        assert_eq!(image_layout.publish_image_sizes_gib(), (2, 28));
+
+        let unified = ImageLayout {
+            partition_plan: PartitionPlan::Unified,
+            ..image_layout.clone()
+        };
+        assert_eq!(unified.publish_image_sizes_gib(), (30, -1));
+    }
+
+    #[test]
+    fn test_image_layout_data_volumes_invalid() {
+        let cases = [
+            (
+                r#"data-volumes = [{ name = "a:b", size-gib = 1, role = "scratch" }]"#,
+                "name",
+            ),
+            (
+                r#"data-volumes = [{ name = "cache", size-gib = 1, role = "" }]"#,
+                "role",
+            ),
+            (
+                r#"data-volumes = [{ name = "cache", size-gib = 0, role = "scratch" }]"#,
+                "at least 1 GiB",
+            ),
+            (
+                r#"data-volumes = [
+                    { name = "cache", size-gib = 1, role = "scratch" },
+                    { name = "cache", size-gib = 1, role = "scratch" },
+                ]"#,
+                "more than once",
+            ),
+            (
+                r#"publish-image-size-hint-gib = 5
+                data-volumes = [{ name = "cache", size-gib = 4, role = "scratch" }]"#,
+                "smaller than the combined image size",
+            ),
+            (
+                r#"publish-image-size-hint-gib = 65535
+                data-volumes = [{ name = "cache", size-gib = 65535, role = "scratch" }]"#,
+                "exceeds the limit",
+            ),
+        ];
+        for (image_layout, expected) in cases {
+            let temp_dir = TempDir::new().unwrap();
+            let path = image_layout_manifest(&temp_dir, image_layout);
+            let err = ManifestInfo::new(path).unwrap_err().to_string();
+            assert!(err.contains(expected), "expected '{expected}' in '{err}'");
+        }
+    }
+
+    #[test]
+    fn test_included_packages_bad_arch() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = write_manifest(
+            &temp_dir,
+            r#"
+            [package]
+            name = "aws-dev"
+
+            [package.metadata.build-variant]
+            included-packages = [{ name = "release", arch = "riscv64" }]
+            "#,
+        );
+        assert!(ManifestInfo::new(path).is_err());
+    }
+
     #[test]
     fn test_package_list_pkg_g() {
         let manifest_path = cargo_manifest("pkg-g");
@@ -943,6 +1522,11 @@ mod test {
             "pkg-g".to_string(),
         ];
         assert_eq!(package_list, expected);
+
+        let package_dirs = manifest.package_dependency_dirs().unwrap();
+        let packages_dir = test_projects_dir().join("local-kit").join("packages");
+        let expected = ["pkg-e", "pkg-f", "pkg-g"].map(|name| packages_dir.join(name));
+        assert_eq!(package_dirs, expected);
     }
 
     #[test]
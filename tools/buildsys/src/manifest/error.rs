@@ -30,6 +30,33 @@ pub(super) enum Error {
         source: toml::de::Error,
     },
 
+    #[snafu(display("Invalid SDK image reference '{image}' in '{}'", path.display()))]
+    SdkImage { path: PathBuf, image: String },
+
+    #[snafu(display("Data volume '{name}' is listed more than once"))]
+    DataVolumeDuplicate { name: String },
+
+    #[snafu(display(
+        "Invalid data volume name '{name}', expected lowercase letters, digits, and dashes"
+    ))]
+    DataVolumeName { name: String },
+
+    #[snafu(display(
+        "Invalid role '{role}' for data volume '{name}', expected lowercase letters, digits, and dashes"
+    ))]
+    DataVolumeRole { name: String, role: String },
+
+    #[snafu(display("Data volume '{name}' must have a size of at least 1 GiB"))]
+    DataVolumeSize { name: String },
+
+    #[snafu(display("Combined image size of {total} GiB exceeds the limit of 65535 GiB"))]
+    ImageSizeTotal { total: u32 },
+
+    #[snafu(display(
+        "Publish image size hint of {hint} GiB is smaller than the combined image size of {total} GiB"
+    ))]
+    PublishSizeHint { hint: u16, total: u32 },
+
     #[snafu(display("Failed to read external kit metadata file '{}': {}", path.display(), source))]
     ExternalKitMetadataFileRead { path: PathBuf, source: io::Error },
 
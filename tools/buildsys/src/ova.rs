@@ -0,0 +1,436 @@
+/*!
+This module packages the VMDK disks from a variant build into an OVA, for VMware. An OVA is a tar
+archive of an OVF descriptor, a manifest of checksums, and the disks that the descriptor refers to,
+in that order.
+
+The descriptor gives the virtual machine a modest hardware profile, which can be changed when the
+OVA is imported. The disks are sized to match the published image sizes.
+
+Image builds that already produce an OVA are left alone.
+*/
+pub(crate) mod error;
+use error::Result;
+
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ResultExt};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The number of virtual CPUs in the hardware profile.
+const VCPUS: u32 = 2;
+
+/// The memory in the hardware profile, in MiB.
+const MEMORY_MIB: u32 = 4096;
+
+/// The oldest virtual hardware version that supports the devices in the hardware profile.
+const HARDWARE_VERSION: &str = "vmx-15";
+
+/// The sizes of the disks to describe in the OVF descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OvaRequest {
+    os_image_size_gib: u32,
+    data_image_size_gib: Option<u32>,
+}
+
+impl OvaRequest {
+    /// Describe disks of the published image sizes, where a data image size that is not positive
+    /// means that there is no separate data disk.
+    pub(crate) fn new(os_image_publish_size_gib: i32, data_image_publish_size_gib: i32) -> Self {
+        Self {
+            os_image_size_gib: u32::try_from(os_image_publish_size_gib).unwrap_or_default(),
+            data_image_size_gib: u32::try_from(data_image_publish_size_gib)
+                .ok()
+                .filter(|size| *size > 0),
+        }
+    }
+}
+
+/// A disk to include in the OVA.
+#[derive(Debug)]
+struct Disk {
+    path: PathBuf,
+    name: String,
+    capacity_gib: u32,
+}
+
+/// Package the VMDK disks in `dir` into an OVA next to them, named after the OS disk. Returns the
+/// path to the OVA, or `None` if there are no disks or the directory already has an OVA.
+pub(crate) fn package(dir: &Path, request: &OvaRequest) -> Result<Option<PathBuf>> {
+    let mut vmdks = Vec::new();
+    for entry in fs::read_dir(dir).context(error::ReadDirSnafu { path: dir })? {
+        let path = entry.context(error::ReadDirSnafu { path: dir })?.path();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ova") => return Ok(None),
+            Some("vmdk") if path.is_file() => vmdks.push(path),
+            _ => {}
+        }
+    }
+    if vmdks.is_empty() {
+        return Ok(None);
+    }
+    vmdks.sort();
+
+    let disks = disks(dir, vmdks, request)?;
+    let stem = disks[0].name.trim_end_matches(".vmdk");
+    let ovf_name = format!("{stem}.ovf");
+    let ovf = descriptor(stem, &disks)?;
+
+    let mut manifest = format!(
+        "SHA256({ovf_name})= {}\n",
+        hex::encode(Sha256::digest(ovf.as_bytes()))
+    );
+    for disk in &disks {
+        let mut file = File::open(&disk.path).context(error::ReadSnafu { path: &disk.path })?;
+        let digest = sha256(&mut file).context(error::ReadSnafu { path: &disk.path })?;
+        writeln!(manifest, "SHA256({})= {digest}", disk.name).expect("write to string");
+    }
+
+    let path = dir.join(format!("{stem}.ova"));
+    write_archive(
+        &path,
+        &ovf_name,
+        &ovf,
+        &format!("{stem}.mf"),
+        &manifest,
+        &disks,
+    )?;
+    Ok(Some(path))
+}
+
+/// Order the disks with the OS disk first, and pair each with its size. The data disk, if any, has
+/// the same name as the OS disk with a `-data` suffix.
+fn disks(dir: &Path, vmdks: Vec<PathBuf>, request: &OvaRequest) -> Result<Vec<Disk>> {
+    let (data, os): (Vec<_>, Vec<_>) = vmdks
+        .into_iter()
+        .partition(|path| path.to_string_lossy().ends_with("-data.vmdk"));
+    let [os] = &os[..] else {
+        return error::OsDiskSnafu {
+            dir,
+            count: os.len(),
+        }
+        .fail();
+    };
+
+    let mut disks = vec![disk(os, request.os_image_size_gib)];
+    match (&data[..], request.data_image_size_gib) {
+        ([], _) => {}
+        ([data], Some(size)) => disks.push(disk(data, size)),
+        ([data, ..], _) => return error::UnexpectedDiskSnafu { path: data }.fail(),
+    }
+    Ok(disks)
+}
+
+fn disk(path: &Path, capacity_gib: u32) -> Disk {
+    Disk {
+        path: path.to_path_buf(),
+        name: path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        capacity_gib,
+    }
+}
+
+/// Generate the OVF descriptor for a virtual machine with these disks attached, in order, to a
+/// paravirtual SCSI controller.
+fn descriptor(name: &str, disks: &[Disk]) -> Result<String> {
+    let name = escape(name);
+    let mut references = String::new();
+    let mut disk_section = String::new();
+    let mut disk_items = String::new();
+    for (n, disk) in disks.iter().enumerate() {
+        let size = fs::metadata(&disk.path)
+            .context(error::ReadSnafu { path: &disk.path })?
+            .len();
+        let href = escape(&disk.name);
+        let capacity = disk.capacity_gib;
+        let instance = n + 4;
+        references.push_str(&format!(
+            r#"    <File ovf:href="{href}" ovf:id="file{n}" ovf:size="{size}"/>
+"#
+        ));
+        disk_section.push_str(&format!(
+            r#"    <Disk ovf:capacity="{capacity}" ovf:capacityAllocationUnits="byte * 2^30" ovf:diskId="vmdisk{n}" ovf:fileRef="file{n}" ovf:format="http://www.vmware.com/interfaces/specifications/vmdk.html#streamOptimized"/>
+"#
+        ));
+        disk_items.push_str(&format!(
+            r#"      <Item>
+        <rasd:AddressOnParent>{n}</rasd:AddressOnParent>
+        <rasd:ElementName>Hard disk {disk}</rasd:ElementName>
+        <rasd:HostResource>ovf:/disk/vmdisk{n}</rasd:HostResource>
+        <rasd:InstanceID>{instance}</rasd:InstanceID>
+        <rasd:Parent>3</rasd:Parent>
+        <rasd:ResourceType>17</rasd:ResourceType>
+      </Item>
+"#,
+            disk = n + 1,
+        ));
+    }
+    let nic_instance = disks.len() + 4;
+
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Envelope xmlns="http://schemas.dmtf.org/ovf/envelope/1" xmlns:ovf="http://schemas.dmtf.org/ovf/envelope/1" xmlns:rasd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_ResourceAllocationSettingData" xmlns:vssd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_VirtualSystemSettingData">
+  <References>
+{references}  </References>
+  <DiskSection>
+    <Info>Virtual disks</Info>
+{disk_section}  </DiskSection>
+  <NetworkSection>
+    <Info>Logical networks</Info>
+    <Network ovf:name="VM Network">
+      <Description>The VM Network network</Description>
+    </Network>
+  </NetworkSection>
+  <VirtualSystem ovf:id="{name}">
+    <Info>A virtual machine</Info>
+    <Name>{name}</Name>
+    <OperatingSystemSection ovf:id="101">
+      <Info>The operating system installed</Info>
+    </OperatingSystemSection>
+    <VirtualHardwareSection>
+      <Info>Virtual hardware requirements</Info>
+      <System>
+        <vssd:ElementName>Virtual Hardware Family</vssd:ElementName>
+        <vssd:InstanceID>0</vssd:InstanceID>
+        <vssd:VirtualSystemIdentifier>{name}</vssd:VirtualSystemIdentifier>
+        <vssd:VirtualSystemType>{HARDWARE_VERSION}</vssd:VirtualSystemType>
+      </System>
+      <Item>
+        <rasd:AllocationUnits>hertz * 10^6</rasd:AllocationUnits>
+        <rasd:ElementName>{VCPUS} virtual CPU(s)</rasd:ElementName>
+        <rasd:InstanceID>1</rasd:InstanceID>
+        <rasd:ResourceType>3</rasd:ResourceType>
+        <rasd:VirtualQuantity>{VCPUS}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:AllocationUnits>byte * 2^20</rasd:AllocationUnits>
+        <rasd:ElementName>{MEMORY_MIB}MB of memory</rasd:ElementName>
+        <rasd:InstanceID>2</rasd:InstanceID>
+        <rasd:ResourceType>4</rasd:ResourceType>
+        <rasd:VirtualQuantity>{MEMORY_MIB}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:Address>0</rasd:Address>
+        <rasd:ElementName>SCSI controller 0</rasd:ElementName>
+        <rasd:InstanceID>3</rasd:InstanceID>
+        <rasd:ResourceSubType>VirtualSCSI</rasd:ResourceSubType>
+        <rasd:ResourceType>6</rasd:ResourceType>
+      </Item>
+{disk_items}      <Item>
+        <rasd:AutomaticAllocation>true</rasd:AutomaticAllocation>
+        <rasd:Connection>VM Network</rasd:Connection>
+        <rasd:ElementName>Network adapter 1</rasd:ElementName>
+        <rasd:InstanceID>{nic_instance}</rasd:InstanceID>
+        <rasd:ResourceSubType>VmxNet3</rasd:ResourceSubType>
+        <rasd:ResourceType>10</rasd:ResourceType>
+      </Item>
+    </VirtualHardwareSection>
+  </VirtualSystem>
+</Envelope>
+"#
+    ))
+}
+
+/// Escape the characters that are special in XML attributes and text.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn sha256(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Write the archive, with fixed ownership and timestamps so that it only depends on its contents.
+/// It's written to a temporary name and renamed into place, so that a partial OVA is never left
+/// behind to be mistaken for one from the image build.
+fn write_archive(
+    path: &Path,
+    ovf_name: &str,
+    ovf: &str,
+    manifest_name: &str,
+    manifest: &str,
+    disks: &[Disk],
+) -> Result<()> {
+    let partial = path.with_extension("ova.partial");
+    let write_error = error::WriteSnafu { path: &partial };
+    let file = File::create(&partial).context(write_error)?;
+    let mut archive = tar::Builder::new(BufWriter::new(file));
+
+    archive
+        .append_data(&mut header(ovf.len() as u64), ovf_name, ovf.as_bytes())
+        .context(write_error)?;
+    archive
+        .append_data(
+            &mut header(manifest.len() as u64),
+            manifest_name,
+            manifest.as_bytes(),
+        )
+        .context(write_error)?;
+    for disk in disks {
+        let file = File::open(&disk.path).context(error::ReadSnafu { path: &disk.path })?;
+        let size = file
+            .metadata()
+            .context(error::ReadSnafu { path: &disk.path })?
+            .len();
+        archive
+            .append_data(&mut header(size), &disk.name, file)
+            .context(write_error)?;
+    }
+
+    let mut writer = archive.into_inner().context(write_error)?;
+    writer.flush().context(write_error)?;
+    writer
+        .into_inner()
+        .ok()
+        .context(error::FlushSnafu { path: &partial })?
+        .sync_all()
+        .context(write_error)?;
+    fs::rename(&partial, path).context(error::WriteSnafu { path })
+}
+
+fn header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_ustar();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_entry_type(tar::EntryType::Regular);
+    header
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entries(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut archive = tar::Archive::new(File::open(path).unwrap());
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                (name, contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_package() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("image-data.vmdk"), b"data disk").unwrap();
+        fs::write(dir.path().join("image.vmdk"), b"os disk").unwrap();
+        fs::write(dir.path().join("image.img.lz4"), b"other").unwrap();
+
+        let path = package(dir.path(), &OvaRequest::new(2, 20))
+            .unwrap()
+            .unwrap();
+        assert_eq!(path, dir.path().join("image.ova"));
+
+        let entries = entries(&path);
+        let names = entries.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["image.ovf", "image.mf", "image.vmdk", "image-data.vmdk"]
+        );
+        assert_eq!(entries[2].1, b"os disk");
+        assert_eq!(entries[3].1, b"data disk");
+
+        let ovf = String::from_utf8(entries[0].1.clone()).unwrap();
+        assert!(ovf.contains(r#"<File ovf:href="image.vmdk" ovf:id="file0" ovf:size="7"/>"#));
+        assert!(ovf.contains(
+            r#"ovf:capacity="2" ovf:capacityAllocationUnits="byte * 2^30" ovf:diskId="vmdisk0""#
+        ));
+        assert!(ovf.contains(
+            r#"ovf:capacity="20" ovf:capacityAllocationUnits="byte * 2^30" ovf:diskId="vmdisk1""#
+        ));
+        assert!(ovf.contains("<rasd:VirtualQuantity>2</rasd:VirtualQuantity>"));
+
+        let manifest = String::from_utf8(entries[1].1.clone()).unwrap();
+        assert_eq!(
+            manifest.lines().collect::<Vec<_>>(),
+            [
+                format!(
+                    "SHA256(image.ovf)= {}",
+                    hex::encode(Sha256::digest(&entries[0].1))
+                ),
+                format!(
+                    "SHA256(image.vmdk)= {}",
+                    hex::encode(Sha256::digest(b"os disk"))
+                ),
+                format!(
+                    "SHA256(image-data.vmdk)= {}",
+                    hex::encode(Sha256::digest(b"data disk"))
+                ),
+            ]
+        );
+        assert!(!dir.path().join("image.ova.partial").exists());
+    }
+
+    #[test]
+    fn test_package_unified() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("image.vmdk"), b"os disk").unwrap();
+        let path = package(dir.path(), &OvaRequest::new(8, -1))
+            .unwrap()
+            .unwrap();
+        let names = entries(&path)
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["image.ovf", "image.mf", "image.vmdk"]);
+
+        // A data disk is unexpected without a size for it.
+        fs::remove_file(&path).unwrap();
+        fs::write(dir.path().join("image-data.vmdk"), b"data disk").unwrap();
+        assert!(matches!(
+            package(dir.path(), &OvaRequest::new(8, -1)),
+            Err(error::Error::UnexpectedDisk { .. })
+        ));
+    }
+
+    #[test]
+    fn test_package_skipped() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(package(dir.path(), &OvaRequest::new(2, 20)).unwrap(), None);
+
+        // An OVA from the image build is kept as it is.
+        fs::write(dir.path().join("image.vmdk"), b"os disk").unwrap();
+        fs::write(dir.path().join("image.ova"), b"from the build").unwrap();
+        assert_eq!(package(dir.path(), &OvaRequest::new(2, 20)).unwrap(), None);
+        assert_eq!(
+            fs::read(dir.path().join("image.ova")).unwrap(),
+            b"from the build"
+        );
+    }
+
+    #[test]
+    fn test_package_os_disk_count() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.vmdk"), b"os disk").unwrap();
+        fs::write(dir.path().join("b.vmdk"), b"os disk").unwrap();
+        assert!(matches!(
+            package(dir.path(), &OvaRequest::new(2, 20)),
+            Err(error::Error::OsDisk { count: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(r#"a<b>&"c'"#), "a&lt;b&gt;&amp;&quot;c&apos;");
+    }
+}
@@ -0,0 +1,35 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to flush OVA '{}'", path.display()))]
+    Flush { path: PathBuf },
+
+    #[snafu(display("Expected one OS disk for the OVA in '{}', found {count}", dir.display()))]
+    OsDisk { dir: PathBuf, count: usize },
+
+    #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to list disks in '{}': {}", path.display(), source))]
+    ReadDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unexpected disk '{}' for the OVA", path.display()))]
+    UnexpectedDisk { path: PathBuf },
+
+    #[snafu(display("Failed to write OVA '{}': {}", path.display(), source))]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
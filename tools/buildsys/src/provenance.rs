@@ -0,0 +1,219 @@
+/*!
+This module describes how a variant was built, for supply chain attestation. The provenance
+document names the builder, checksums the inputs and outputs of the build, and lists the build
+arguments that were passed to docker.
+
+Secrets are identified only by their IDs. Their values and sources are never recorded.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use crate::project::ProjectInfo;
+use crate::repro::{self, ArtifactHashes};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The version of the provenance document. It changes whenever a field is changed or removed, so
+/// that consumers can tell which fields to expect.
+pub(crate) const PROVENANCE_VERSION: u32 = 1;
+
+/// The name that identifies buildsys as the builder.
+const BUILDER_ID: &str = "buildsys";
+
+/// What was built, what it was built from, and how.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Provenance {
+    version: u32,
+    builder: Builder,
+    name: String,
+    arch: String,
+    inputs: Inputs,
+    build_args: Vec<String>,
+    secrets: Vec<String>,
+    outputs: ArtifactHashes,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Builder {
+    id: String,
+    version: String,
+}
+
+/// The checksums for everything the build read, keyed by path relative to the project root.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Inputs {
+    manifest: FileChecksum,
+    sources: BTreeMap<PathBuf, String>,
+    sdk: SdkImage,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct FileChecksum {
+    path: PathBuf,
+    sha512: String,
+}
+
+/// The SDK image the build ran in, by reference and by the ID of the image that it resolved to.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SdkImage {
+    pub(crate) image: String,
+    pub(crate) digest: String,
+}
+
+/// Where to write the provenance for a build, and which of its inputs to describe.
+#[derive(Debug, Clone)]
+pub(crate) struct ProvenanceRequest {
+    pub(crate) path: PathBuf,
+    pub(crate) manifest: PathBuf,
+    pub(crate) sources: Vec<PathBuf>,
+}
+
+impl Inputs {
+    /// Compute checksums for the manifest and for every file in the requested sources.
+    pub(crate) fn new(root: &Path, request: &ProvenanceRequest, sdk: SdkImage) -> Result<Self> {
+        let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_path_buf();
+
+        let manifest = FileChecksum {
+            path: relative(&request.manifest),
+            sha512: repro::checksum(&request.manifest).context(error::ChecksumSnafu)?,
+        };
+
+        let mut sources = BTreeMap::new();
+        let files = ProjectInfo::crawl(&request.sources)
+            .context(error::InputCrawlSnafu)?
+            .files;
+        for file in files {
+            let checksum = repro::checksum(&file).context(error::ChecksumSnafu)?;
+            sources.insert(relative(&file), checksum);
+        }
+
+        Ok(Self {
+            manifest,
+            sources,
+            sdk,
+        })
+    }
+}
+
+impl Provenance {
+    /// Describe a finished build. `secrets` are the `--secret` values passed to docker, and only
+    /// the ID of each is kept.
+    pub(crate) fn new(
+        name: &str,
+        arch: &str,
+        inputs: Inputs,
+        build_args: Vec<String>,
+        secrets: &[String],
+        outputs: ArtifactHashes,
+    ) -> Self {
+        let mut secrets = secrets
+            .iter()
+            .filter_map(|secret| secret_id(secret))
+            .collect::<Vec<_>>();
+        secrets.sort();
+        Self {
+            version: PROVENANCE_VERSION,
+            builder: Builder {
+                id: BUILDER_ID.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            name: name.to_string(),
+            arch: arch.to_string(),
+            inputs,
+            build_args,
+            secrets,
+            outputs,
+        }
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).context(error::SerializeSnafu)?;
+        fs::write(path, json + "\n").context(error::WriteSnafu { path })
+    }
+}
+
+/// Find the ID in a `--secret` value like `type=file,id=ca-bundle.crt,src=/path`.
+fn secret_id(secret: &str) -> Option<String> {
+    secret
+        .split(',')
+        .find_map(|field| field.strip_prefix("id="))
+        .map(str::to_string)
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_provenance() {
+        let root = TempDir::new().unwrap();
+        let variant_dir = root.path().join("variants/aws-dev");
+        fs::create_dir_all(&variant_dir).unwrap();
+        fs::write(variant_dir.join("Cargo.toml"), "[package]\n").unwrap();
+        let output_dir = root.path().join("build/images");
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(output_dir.join("os.img"), "image").unwrap();
+
+        let request = ProvenanceRequest {
+            path: root.path().join("provenance.json"),
+            manifest: variant_dir.join("Cargo.toml"),
+            sources: vec![variant_dir.clone()],
+        };
+        let sdk = SdkImage {
+            image: "sdk:latest".to_string(),
+            digest: "sha256:0123456789abcdef".to_string(),
+        };
+        let inputs = Inputs::new(root.path(), &request, sdk).unwrap();
+        let outputs = ArtifactHashes::new(&output_dir, &["os.img"], 1).unwrap();
+        let secrets = [
+            "type=env,id=aws-access-key-id.env,src=AWS_ACCESS_KEY_ID".to_string(),
+            "type=file,id=ca-bundle.crt,src=/secret/ca-bundle.crt".to_string(),
+        ];
+        let provenance = Provenance::new(
+            "aws-dev",
+            "x86_64",
+            inputs,
+            vec!["SDK=sdk:latest".to_string()],
+            &secrets,
+            outputs,
+        );
+        provenance.write(&request.path).unwrap();
+
+        let json = fs::read_to_string(&request.path).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(doc["version"], PROVENANCE_VERSION);
+        assert_eq!(doc["builder"]["id"], "buildsys");
+        assert_eq!(doc["inputs"]["sdk"]["digest"], "sha256:0123456789abcdef");
+        assert_eq!(
+            doc["inputs"]["manifest"]["path"],
+            "variants/aws-dev/Cargo.toml"
+        );
+        assert_eq!(
+            doc["inputs"]["sources"]["variants/aws-dev/Cargo.toml"],
+            doc["inputs"]["manifest"]["sha512"]
+        );
+        assert_eq!(
+            doc["outputs"]["os.img"],
+            repro::checksum(&output_dir.join("os.img")).unwrap()
+        );
+        assert_eq!(
+            doc["secrets"],
+            serde_json::json!(["aws-access-key-id.env", "ca-bundle.crt"])
+        );
+        // Only the IDs of secrets are recorded, never where they came from.
+        assert!(!json.contains("AWS_ACCESS_KEY_ID"));
+        assert!(!json.contains("/secret/"));
+    }
+}
@@ -0,0 +1,25 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to compute checksum for provenance: {}", source))]
+    Checksum { source: crate::repro::error::Error },
+
+    #[snafu(display("Failed to find build inputs for provenance: {}", source))]
+    InputCrawl {
+        source: crate::project::error::Error,
+    },
+
+    #[snafu(display("Failed to serialize provenance: {}", source))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to write provenance to '{}': {}", path.display(), source))]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,184 @@
+/*!
+This module cleans up the Docker images and containers that buildsys leaves behind when a build is
+interrupted before it can remove them.
+
+Only resources that follow the naming scheme used by `DockerBuild` are touched: images are named
+`buildsys-{type}-{name}-{arch}-{token}`, and the bypass container for a build is named after its
+image with a `-bypass` suffix.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use duct::cmd;
+use snafu::{ensure, ResultExt};
+
+/// The prefixes for each type of image that buildsys builds.
+const IMAGE_PREFIXES: [&str; 4] = [
+    "buildsys-pkg-",
+    "buildsys-kit-",
+    "buildsys-var-",
+    "buildsys-repack-",
+];
+
+/// The length of the per-checkout token at the end of each image name.
+const TOKEN_LEN: usize = 12;
+
+const BYPASS_SUFFIX: &str = "-bypass";
+
+/// Remove stopped buildsys containers and leftover buildsys images. With `dry_run`, only print
+/// what would be removed.
+pub(crate) fn prune(dry_run: bool) -> Result<()> {
+    let containers = docker_lines(&[
+        "container",
+        "ls",
+        "--all",
+        "--filter",
+        "status=created",
+        "--filter",
+        "status=exited",
+        "--format",
+        "{{.Names}}",
+    ])?;
+    let containers = buildsys_containers(&containers);
+
+    let images = docker_lines(&["image", "ls", "--format", "{{.Repository}}"])?;
+    let images = buildsys_images(&images);
+
+    // Remove the containers first, since they may hold references to the images.
+    for (kind, names, rm) in [("container", containers, "rm"), ("image", images, "rmi")] {
+        if names.is_empty() {
+            continue;
+        }
+        for name in &names {
+            if dry_run {
+                println!("Would remove {kind} {name}");
+            } else {
+                println!("Removing {kind} {name}");
+            }
+        }
+        if !dry_run {
+            let mut args = vec![rm];
+            args.extend(names.iter().map(String::as_str));
+            docker_lines(&args)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if the name matches one of the images that buildsys creates.
+fn is_buildsys_image(name: &str) -> bool {
+    let Some(rest) = IMAGE_PREFIXES
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+    else {
+        return false;
+    };
+
+    match rest.rsplit_once('-') {
+        Some((target, token)) => {
+            !target.is_empty()
+                && token.len() == TOKEN_LEN
+                && token.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+/// Returns true if the name matches the bypass container for a buildsys image.
+fn is_buildsys_container(name: &str) -> bool {
+    name.strip_suffix(BYPASS_SUFFIX)
+        .map(is_buildsys_image)
+        .unwrap_or(false)
+}
+
+fn buildsys_images(names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|n| is_buildsys_image(n))
+        .cloned()
+        .collect()
+}
+
+fn buildsys_containers(names: &[String]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|n| is_buildsys_container(n))
+        .cloned()
+        .collect()
+}
+
+/// Run `docker` with the specified arguments and return the lines of output.
+fn docker_lines(args: &[&str]) -> Result<Vec<String>> {
+    let output = cmd("docker", args)
+        .stderr_capture()
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+
+    ensure!(
+        output.status.success(),
+        error::DockerExecutionSnafu {
+            args: args.join(" "),
+            stderr: String::from_utf8_lossy(&output.stderr).trim(),
+        }
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_buildsys_images() {
+        let images = names(&[
+            "buildsys-pkg-kernel-6.1-x86_64-0123456789ab",
+            "buildsys-kit-core-kit-aarch64-abcdef012345",
+            "buildsys-var-aws-dev-x86_64-0123456789ab",
+            "buildsys-repack-aws-dev-x86_64-0123456789ab",
+            "buildsys-pkg-kernel-6.1-x86_64",
+            "buildsys-pkg-kernel-6.1-x86_64-notahexvalue",
+            "buildsys-sdk-x86_64-0123456789ab",
+            "aws-dev-x86_64",
+            "public.ecr.aws/bottlerocket/bottlerocket-sdk",
+            "<none>",
+        ]);
+        assert_eq!(
+            buildsys_images(&images),
+            names(&[
+                "buildsys-pkg-kernel-6.1-x86_64-0123456789ab",
+                "buildsys-kit-core-kit-aarch64-abcdef012345",
+                "buildsys-var-aws-dev-x86_64-0123456789ab",
+                "buildsys-repack-aws-dev-x86_64-0123456789ab",
+            ])
+        );
+    }
+
+    #[test]
+    fn test_buildsys_containers() {
+        let containers = names(&[
+            "buildsys-pkg-release-x86_64-0123456789ab-bypass",
+            "buildsys-pkg-release-x86_64-0123456789ab",
+            "buildsys-pkg-release-x86_64-bypass",
+            "my-bypass",
+            "eager_turing",
+        ]);
+        assert_eq!(
+            buildsys_containers(&containers),
+            names(&["buildsys-pkg-release-x86_64-0123456789ab-bypass"])
+        );
+    }
+}
@@ -0,0 +1,13 @@
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to start command: {}", source))]
+    CommandStart { source: std::io::Error },
+
+    #[snafu(display("Failed to execute command 'docker {}': {}", args, stderr))]
+    DockerExecution { args: String, stderr: String },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,234 @@
+/*!
+This module records checksums for the artifacts that a build produced, so that two builds of the
+same inputs can be compared to find sources of nondeterminism.
+
+The manifest uses the same layout as the output of `sha512sum`: one line per artifact, with the
+checksum followed by two spaces and the path relative to the output directory.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// How much of a file to read at a time while computing its checksum, so that memory use stays the
+/// same for large images.
+const CHECKSUM_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Checksums for a set of artifacts, keyed by their path relative to the output directory.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub(crate) struct ArtifactHashes(BTreeMap<PathBuf, String>);
+
+/// An artifact that is different from the baseline.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ArtifactDifference {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Changed(PathBuf),
+}
+
+impl ArtifactHashes {
+    /// Compute checksums for artifacts in `dir`, with up to `jobs` artifacts at once. Symlinks are
+    /// not followed; the checksum covers the link target instead. If more than one artifact can't
+    /// be read, the error is for the first of them in `artifacts`, whichever order they finish in.
+    pub(crate) fn new<P>(dir: &Path, artifacts: &[P], jobs: usize) -> Result<Self>
+    where
+        P: AsRef<Path> + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let workers = jobs.clamp(1, artifacts.len().max(1));
+        let mut results = thread::scope(|s| {
+            let handles = (0..workers)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut results = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::SeqCst);
+                            let Some(artifact) = artifacts.get(index) else {
+                                break;
+                            };
+                            results.push((index, checksum(&dir.join(artifact))));
+                        }
+                        results
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap_or_default())
+                .collect::<Vec<_>>()
+        });
+        results.sort_by_key(|(index, _)| *index);
+
+        let mut hashes = BTreeMap::new();
+        for (index, result) in results {
+            hashes.insert(artifacts[index].as_ref().to_path_buf(), result?);
+        }
+        Ok(Self(hashes))
+    }
+
+    /// Load checksums from a manifest written by a previous build.
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).context(error::ManifestReadSnafu { path })?;
+        let mut hashes = BTreeMap::new();
+        for (index, line) in contents.lines().enumerate() {
+            let (hash, artifact) = line.split_once("  ").context(error::ManifestParseSnafu {
+                path,
+                line: index + 1,
+            })?;
+            hashes.insert(PathBuf::from(artifact), hash.to_string());
+        }
+        Ok(Self(hashes))
+    }
+
+    /// Save the checksums so that a later build can be compared against them.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string()).context(error::ManifestWriteSnafu { path })
+    }
+
+    /// The paths of the artifacts, relative to the output directory.
+    pub(crate) fn artifacts(&self) -> Vec<&Path> {
+        self.0.keys().map(PathBuf::as_path).collect()
+    }
+
+    /// List the artifacts that were added, removed, or changed relative to the baseline.
+    pub(crate) fn differences(&self, baseline: &Self) -> Vec<ArtifactDifference> {
+        let mut differences = Vec::new();
+        for (artifact, hash) in &self.0 {
+            match baseline.0.get(artifact) {
+                None => differences.push(ArtifactDifference::Added(artifact.clone())),
+                Some(baseline_hash) if baseline_hash != hash => {
+                    differences.push(ArtifactDifference::Changed(artifact.clone()))
+                }
+                Some(_) => (),
+            }
+        }
+        for artifact in baseline.0.keys() {
+            if !self.0.contains_key(artifact) {
+                differences.push(ArtifactDifference::Removed(artifact.clone()));
+            }
+        }
+        differences
+    }
+}
+
+impl Display for ArtifactHashes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (artifact, hash) in &self.0 {
+            writeln!(f, "{hash}  {}", artifact.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for ArtifactDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArtifactDifference::Added(path) => write!(f, "{} was added", path.display()),
+            ArtifactDifference::Removed(path) => write!(f, "{} was removed", path.display()),
+            ArtifactDifference::Changed(path) => write!(f, "{} changed", path.display()),
+        }
+    }
+}
+
+/// Compute the checksum for a file, or for the target of a symlink.
+pub(crate) fn checksum(path: &Path) -> Result<String> {
+    let metadata = fs::symlink_metadata(path).context(error::FileReadSnafu { path })?;
+    let mut d = Sha512::new();
+    if metadata.is_symlink() {
+        let target = fs::read_link(path).context(error::FileReadSnafu { path })?;
+        d.update(target.as_os_str().as_bytes());
+    } else {
+        let f = File::open(path).context(error::FileReadSnafu { path })?;
+        let mut reader = BufReader::with_capacity(CHECKSUM_BUFFER_SIZE, f);
+        io::copy(&mut reader, &mut d).context(error::FileReadSnafu { path })?;
+    }
+    Ok(hex::encode(d.finalize()))
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_artifact_differences() {
+        let output_dir = TempDir::new().unwrap();
+        let dir = output_dir.path();
+        fs::write(dir.join("a.rpm"), "a").unwrap();
+        fs::write(dir.join("b.rpm"), "b").unwrap();
+        fs::write(dir.join("c.rpm"), "c").unwrap();
+
+        let manifest = dir.join("repro-manifest");
+        ArtifactHashes::new(dir, &["a.rpm", "b.rpm"], 1)
+            .unwrap()
+            .write(&manifest)
+            .unwrap();
+        let baseline = ArtifactHashes::read(&manifest).unwrap();
+
+        // The same artifacts produce the same checksums.
+        let unchanged = ArtifactHashes::new(dir, &["b.rpm", "a.rpm"], 2).unwrap();
+        assert_eq!(unchanged, baseline);
+        assert!(unchanged.differences(&baseline).is_empty());
+
+        fs::write(dir.join("b.rpm"), "b2").unwrap();
+        let rebuilt = ArtifactHashes::new(dir, &["b.rpm", "c.rpm"], 1).unwrap();
+        assert_eq!(
+            rebuilt.differences(&baseline),
+            [
+                ArtifactDifference::Changed("b.rpm".into()),
+                ArtifactDifference::Added("c.rpm".into()),
+                ArtifactDifference::Removed("a.rpm".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parallel_checksums() {
+        let output_dir = TempDir::new().unwrap();
+        let dir = output_dir.path();
+        let artifacts = (0..20)
+            .map(|i| PathBuf::from(format!("{i}.rpm")))
+            .collect::<Vec<_>>();
+        for (i, artifact) in artifacts.iter().enumerate() {
+            // Vary the sizes so that the workers finish out of order.
+            fs::write(dir.join(artifact), vec![i as u8; (20 - i) * 10_000]).unwrap();
+        }
+        std::os::unix::fs::symlink("0.rpm", dir.join("latest.rpm")).unwrap();
+        let mut artifacts = artifacts;
+        artifacts.push(PathBuf::from("latest.rpm"));
+
+        let serial = ArtifactHashes::new(dir, &artifacts, 1).unwrap();
+        for artifact in &artifacts {
+            assert_eq!(serial.0[artifact], checksum(&dir.join(artifact)).unwrap());
+        }
+        for jobs in [2, 4, 64] {
+            let parallel = ArtifactHashes::new(dir, &artifacts, jobs).unwrap();
+            assert_eq!(parallel, serial);
+            assert_eq!(parallel.to_string(), serial.to_string());
+        }
+
+        // With several missing artifacts, the error is always for the first one.
+        let missing = ["a.rpm", "0.rpm", "b.rpm", "c.rpm"];
+        for jobs in [1, 4] {
+            let err = ArtifactHashes::new(dir, &missing, jobs).unwrap_err();
+            assert!(
+                matches!(err, error::Error::FileRead { ref path, .. } if path.ends_with("a.rpm")),
+                "{err}"
+            );
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to read artifact '{}': {}", path.display(), source))]
+    FileRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to parse line {} of reproducibility manifest '{}'", line, path.display()))]
+    ManifestParse { path: PathBuf, line: usize },
+
+    #[snafu(display("Failed to read reproducibility manifest '{}': {}", path.display(), source))]
+    ManifestRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write reproducibility manifest '{}': {}", path.display(), source))]
+    ManifestWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
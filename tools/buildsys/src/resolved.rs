@@ -0,0 +1,208 @@
+/*!
+Package builds need the packages and kits that a package depends on, which are resolved from the
+cargo dependency graph. Building that graph from the cargo metadata is the slowest part of setting
+up a package build in a large project, and the result rarely changes between invocations.
+
+This module caches the resolved dependencies under the state directory, keyed by the path and
+modification time of both the package manifest and the cargo metadata, so that a change to either
+one makes the cached entry stale. Entries are written to a temporary file and renamed into place,
+so that concurrent builds only ever read complete entries.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::manifest::Manifest;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The directory under the state directory that holds the cached entries.
+const CACHE_DIR: &str = "resolved-packages";
+
+/// The dependencies of a package, as resolved from the cargo dependency graph.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ResolvedPackage {
+    pub(crate) package_dependencies: Vec<String>,
+    pub(crate) kit_dependencies: Vec<String>,
+}
+
+impl ResolvedPackage {
+    pub(crate) fn resolve(manifest: &Manifest) -> Result<Self> {
+        Ok(Self {
+            package_dependencies: manifest.package_dependencies().context(error::GraphSnafu)?,
+            kit_dependencies: manifest.kit_dependencies().context(error::GraphSnafu)?,
+        })
+    }
+}
+
+/// The inputs that a cached entry was resolved from.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheKey {
+    manifest: PathBuf,
+    manifest_mtime: (i64, u32),
+    cargo_metadata: PathBuf,
+    cargo_metadata_mtime: (i64, u32),
+}
+
+impl CacheKey {
+    fn new(manifest: &Path, cargo_metadata: &Path) -> Result<Self> {
+        Ok(Self {
+            manifest: manifest.to_path_buf(),
+            manifest_mtime: mtime(manifest)?,
+            cargo_metadata: cargo_metadata.to_path_buf(),
+            cargo_metadata_mtime: mtime(cargo_metadata)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    resolved: ResolvedPackage,
+}
+
+pub(crate) struct ResolvedPackageCache {
+    dir: PathBuf,
+}
+
+impl ResolvedPackageCache {
+    pub(crate) fn new(state_dir: &Path) -> Self {
+        Self {
+            dir: state_dir.join(CACHE_DIR),
+        }
+    }
+
+    /// Returns the cached dependencies of the package named `name` if they were resolved from the
+    /// same manifest and cargo metadata, or else calls `resolve` and caches the result.
+    pub(crate) fn get_or_resolve<F>(
+        &self,
+        name: &str,
+        manifest: &Path,
+        cargo_metadata: &Path,
+        resolve: F,
+    ) -> Result<ResolvedPackage>
+    where
+        F: FnOnce() -> Result<ResolvedPackage>,
+    {
+        let key = CacheKey::new(manifest, cargo_metadata)?;
+        let path = self.dir.join(format!("{name}.json"));
+        if let Some(entry) = read_entry(&path).filter(|entry| entry.key == key) {
+            return Ok(entry.resolved);
+        }
+
+        let resolved = resolve()?;
+        self.write_entry(
+            &path,
+            &CacheEntry {
+                key,
+                resolved: resolved.clone(),
+            },
+        )?;
+        Ok(resolved)
+    }
+
+    fn write_entry(&self, path: &Path, entry: &CacheEntry) -> Result<()> {
+        fs::create_dir_all(&self.dir).context(error::CreateDirSnafu { path: &self.dir })?;
+        let data = serde_json::to_vec(entry).context(error::SerializeSnafu)?;
+
+        // Each process writes its own temporary file, so that concurrent writers do not mix
+        // their output before the rename.
+        let partial = path.with_extension(format!("json.{}.partial", process::id()));
+        fs::write(&partial, data).context(error::WriteSnafu { path: &partial })?;
+        fs::rename(&partial, path).context(error::RenameSnafu {
+            old_path: &partial,
+            new_path: path,
+        })
+    }
+}
+
+/// Reads a cached entry. Entries that are missing or cannot be parsed, for example because an
+/// older version of buildsys wrote them, are treated as stale.
+fn read_entry(path: &Path) -> Option<CacheEntry> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+fn mtime(path: &Path) -> Result<(i64, u32)> {
+    let metadata = fs::metadata(path).context(error::MetadataSnafu { path })?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    Ok((mtime.unix_seconds(), mtime.nanoseconds()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use filetime::set_file_mtime;
+    use std::cell::Cell;
+    use tempfile::TempDir;
+
+    fn resolved(package: &str) -> ResolvedPackage {
+        ResolvedPackage {
+            package_dependencies: vec![package.to_string()],
+            kit_dependencies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_get_or_resolve() {
+        let dir = TempDir::new().unwrap();
+        let manifest = dir.path().join("Cargo.toml");
+        let cargo_metadata = dir.path().join("cargo-metadata.json");
+        fs::write(&manifest, "[package]\nname = \"pkg-a\"\n").unwrap();
+        fs::write(&cargo_metadata, "{}").unwrap();
+        set_file_mtime(&manifest, FileTime::from_unix_time(1_000, 0)).unwrap();
+
+        let cache = ResolvedPackageCache::new(&dir.path().join("state"));
+        let calls = Cell::new(0);
+        let get = |package: &str| {
+            cache
+                .get_or_resolve("pkg-a", &manifest, &cargo_metadata, || {
+                    calls.set(calls.get() + 1);
+                    Ok(resolved(package))
+                })
+                .unwrap()
+        };
+
+        // The first lookup resolves, and the second one hits the cache.
+        assert_eq!(get("pkg-b"), resolved("pkg-b"));
+        assert_eq!(get("pkg-c"), resolved("pkg-b"));
+        assert_eq!(calls.get(), 1);
+
+        // Editing the manifest makes the entry stale.
+        fs::write(
+            &manifest,
+            "[package]\nname = \"pkg-a\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        set_file_mtime(&manifest, FileTime::from_unix_time(2_000, 0)).unwrap();
+        assert_eq!(get("pkg-c"), resolved("pkg-c"));
+        assert_eq!(get("pkg-d"), resolved("pkg-c"));
+        assert_eq!(calls.get(), 2);
+
+        // No temporary files are left behind.
+        let entries = fs::read_dir(dir.path().join("state").join(CACHE_DIR))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, ["pkg-a.json"]);
+    }
+
+    #[test]
+    fn test_get_or_resolve_corrupt_entry() {
+        let dir = TempDir::new().unwrap();
+        let manifest = dir.path().join("Cargo.toml");
+        fs::write(&manifest, "[package]\nname = \"pkg-a\"\n").unwrap();
+
+        let cache = ResolvedPackageCache::new(dir.path());
+        fs::create_dir_all(dir.path().join(CACHE_DIR)).unwrap();
+        fs::write(dir.path().join(CACHE_DIR).join("pkg-a.json"), "{").unwrap();
+
+        let resolved_package = cache
+            .get_or_resolve("pkg-a", &manifest, &manifest, || Ok(resolved("pkg-b")))
+            .unwrap();
+        assert_eq!(resolved_package, resolved("pkg-b"));
+    }
+}
@@ -0,0 +1,42 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
+    CreateDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to resolve package dependencies: {source}"))]
+    Graph {
+        #[snafu(source(from(buildsys::manifest::Error, Box::new)))]
+        source: Box<buildsys::manifest::Error>,
+    },
+
+    #[snafu(display("Failed to get metadata for '{}': {}", path.display(), source))]
+    Metadata {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to rename '{}' to '{}': {}", old_path.display(), new_path.display(), source))]
+    Rename {
+        old_path: PathBuf,
+        new_path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to serialize resolved package dependencies: {source}"))]
+    Serialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
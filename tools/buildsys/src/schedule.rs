@@ -0,0 +1,284 @@
+/*!
+This module orders a set of package builds by their dependencies, so that they can be built with
+a single command. Packages that do not depend on each other are built in parallel, up to a limit.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::manifest::Manifest;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Read the manifest in each package directory, and find the dependencies between the packages.
+/// Dependencies on packages outside the set are left out, since they are not built here.
+pub(crate) fn package_dependencies(
+    package_dirs: &[PathBuf],
+    cargo_metadata_path: &Path,
+) -> Result<BTreeMap<String, (PathBuf, BTreeSet<String>)>> {
+    let mut packages: BTreeMap<String, (PathBuf, BTreeSet<String>)> = BTreeMap::new();
+    for dir in package_dirs {
+        let path = dir.join("Cargo.toml");
+        let manifest = Manifest::new(&path, cargo_metadata_path)
+            .context(error::ManifestSnafu { path: &path })?;
+        let dependencies = manifest
+            .package_dependencies()
+            .context(error::ManifestSnafu { path: &path })?;
+        let package = manifest.info().package_name().to_string();
+        ensure!(
+            !packages.contains_key(&package),
+            error::DuplicatePackageSnafu { package }
+        );
+        packages.insert(package, (dir.clone(), dependencies.into_iter().collect()));
+    }
+
+    let names = packages.keys().cloned().collect::<BTreeSet<_>>();
+    for (_, dependencies) in packages.values_mut() {
+        dependencies.retain(|d| names.contains(d));
+    }
+    Ok(packages)
+}
+
+/// The order to build a set of packages in. Packages in each batch only depend on packages in
+/// earlier batches, so the packages within a batch can be built at the same time.
+#[derive(Debug)]
+pub(crate) struct BuildPlan {
+    batches: Vec<Vec<String>>,
+    dependencies: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl BuildPlan {
+    /// Group the packages into batches, each as early as its dependencies allow.
+    pub(crate) fn new(dependencies: BTreeMap<String, BTreeSet<String>>) -> Result<Self> {
+        let mut remaining = dependencies.clone();
+        let mut planned = BTreeSet::new();
+        let mut batches = Vec::new();
+        while !remaining.is_empty() {
+            let batch = remaining
+                .iter()
+                .filter(|(_, deps)| {
+                    deps.iter()
+                        .all(|d| planned.contains(d) || !dependencies.contains_key(d))
+                })
+                .map(|(package, _)| package.clone())
+                .collect::<Vec<_>>();
+            ensure!(
+                !batch.is_empty(),
+                error::DependencyCycleSnafu {
+                    packages: remaining.into_keys().collect::<Vec<_>>()
+                }
+            );
+            for package in &batch {
+                remaining.remove(package);
+            }
+            planned.extend(batch.iter().cloned());
+            batches.push(batch);
+        }
+        Ok(Self {
+            batches,
+            dependencies,
+        })
+    }
+
+    pub(crate) fn batches(&self) -> &[Vec<String>] {
+        &self.batches
+    }
+
+    /// Build each batch in turn, running up to `jobs` builds at once. A package whose dependencies
+    /// did not build is skipped, but packages that do not depend on a failure are still built.
+    pub(crate) fn run<F>(&self, jobs: usize, build: F) -> BuildReport
+    where
+        F: Fn(&str) -> bool + Sync,
+    {
+        let mut outcomes = BTreeMap::new();
+        for batch in &self.batches {
+            let (ready, blocked): (Vec<_>, Vec<_>) = batch.iter().partition(|package| {
+                self.dependencies[package.as_str()]
+                    .iter()
+                    .all(|d| outcomes.get(d) == Some(&BuildOutcome::Built))
+            });
+            for package in blocked {
+                outcomes.insert(package.clone(), BuildOutcome::Skipped);
+            }
+
+            let next = AtomicUsize::new(0);
+            let workers = jobs.clamp(1, ready.len().max(1));
+            let results = thread::scope(|s| {
+                let handles = (0..workers)
+                    .map(|_| {
+                        s.spawn(|| {
+                            let mut results = Vec::new();
+                            while let Some(package) = ready.get(next.fetch_add(1, Ordering::SeqCst))
+                            {
+                                let outcome = if build(package) {
+                                    BuildOutcome::Built
+                                } else {
+                                    BuildOutcome::Failed
+                                };
+                                results.push(((*package).clone(), outcome));
+                            }
+                            results
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .flat_map(|h| h.join().unwrap_or_default())
+                    .collect::<Vec<_>>()
+            });
+            outcomes.extend(results);
+        }
+
+        let results = self
+            .batches
+            .iter()
+            .flatten()
+            .map(|package| (package.clone(), outcomes[package]))
+            .collect();
+        BuildReport { results }
+    }
+}
+
+impl Display for BuildPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, batch) in self.batches.iter().enumerate() {
+            writeln!(f, "batch {}: {}", i + 1, batch.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// What happened to a package in a set of builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BuildOutcome {
+    Built,
+    Failed,
+    /// Not attempted, because a dependency was not built.
+    Skipped,
+}
+
+impl Display for BuildOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildOutcome::Built => write!(f, "built"),
+            BuildOutcome::Failed => write!(f, "failed"),
+            BuildOutcome::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// The outcome of each package build, in the order of the plan.
+#[derive(Debug)]
+pub(crate) struct BuildReport {
+    results: Vec<(String, BuildOutcome)>,
+}
+
+impl BuildReport {
+    /// The number of packages that failed or were skipped.
+    pub(crate) fn unbuilt(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, outcome)| *outcome != BuildOutcome::Built)
+            .count()
+    }
+}
+
+impl Display for BuildReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (package, outcome) in &self.results {
+            writeln!(f, "{package}: {outcome}")?;
+        }
+        Ok(())
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    /// glibc <- libfoo <- foo, glibc <- bar, and an unrelated baz.
+    fn dependencies() -> BTreeMap<String, BTreeSet<String>> {
+        [
+            ("glibc", vec![]),
+            ("libfoo", vec!["glibc"]),
+            ("foo", vec!["libfoo", "glibc"]),
+            ("bar", vec!["glibc"]),
+            ("baz", vec![]),
+        ]
+        .into_iter()
+        .map(|(package, deps)| {
+            (
+                package.to_string(),
+                deps.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_build_plan_batches() {
+        let plan = BuildPlan::new(dependencies()).unwrap();
+        assert_eq!(
+            plan.batches(),
+            [vec!["baz", "glibc"], vec!["bar", "libfoo"], vec!["foo"]]
+        );
+        assert_eq!(
+            plan.to_string(),
+            "batch 1: baz, glibc\nbatch 2: bar, libfoo\nbatch 3: foo\n"
+        );
+    }
+
+    #[test]
+    fn test_build_plan_cycle() {
+        let mut dependencies = dependencies();
+        dependencies
+            .get_mut("glibc")
+            .unwrap()
+            .insert("foo".to_string());
+        let err = BuildPlan::new(dependencies).unwrap_err();
+        assert!(matches!(
+            err,
+            error::Error::DependencyCycle { packages } if packages == ["bar", "foo", "glibc", "libfoo"]
+        ));
+    }
+
+    #[test]
+    fn test_build_plan_runs_in_parallel() {
+        let plan = BuildPlan::new(dependencies()).unwrap();
+        // Both packages in the first batch must be running at once for either to finish.
+        let started = AtomicUsize::new(0);
+        let timed_out = AtomicBool::new(false);
+        let report = plan.run(2, |package| {
+            if ["baz", "glibc"].contains(&package) {
+                started.fetch_add(1, Ordering::SeqCst);
+                let waited = (0..500).any(|_| {
+                    thread::sleep(Duration::from_millis(10));
+                    started.load(Ordering::SeqCst) == 2
+                });
+                timed_out.fetch_or(!waited, Ordering::SeqCst);
+            }
+            true
+        });
+        assert!(!timed_out.load(Ordering::SeqCst));
+        assert_eq!(report.unbuilt(), 0);
+    }
+
+    #[test]
+    fn test_build_plan_failure_stops_dependents() {
+        let plan = BuildPlan::new(dependencies()).unwrap();
+        let report = plan.run(1, |package| package != "libfoo");
+        assert_eq!(report.unbuilt(), 2);
+        assert_eq!(
+            report.to_string(),
+            "baz: built\nglibc: built\nbar: built\nlibfoo: failed\nfoo: skipped\n"
+        );
+    }
+}
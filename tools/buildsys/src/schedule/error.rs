@@ -0,0 +1,21 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Package dependencies form a cycle among: {}", packages.join(", ")))]
+    DependencyCycle { packages: Vec<String> },
+
+    #[snafu(display("Package '{package}' is listed more than once"))]
+    DuplicatePackage { package: String },
+
+    #[snafu(display("Failed to read manifest for '{}': {source}", path.display()))]
+    Manifest {
+        path: PathBuf,
+        #[snafu(source(from(buildsys::manifest::Error, Box::new)))]
+        source: Box<buildsys::manifest::Error>,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,113 @@
+/*!
+This module computes the image sizes for a variant manifest, so that capacity can be planned and
+size growth checked without running a build.
+
+*/
+use buildsys::manifest::{ImageSize, ManifestInfo, SupportedArch};
+use serde::Serialize;
+use std::fmt::{self, Display};
+
+/// The sizes of the images built and published for a variant.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ImageSizes {
+    arch: SupportedArch,
+    os_image_size_gib: ImageSize,
+    data_image_size_gib: ImageSize,
+    os_image_publish_size_gib: i32,
+    /// There is no separate "data" image to publish with the `unified` partition plan.
+    data_image_publish_size_gib: Option<i32>,
+}
+
+impl ImageSizes {
+    pub(crate) fn new(manifest: &ManifestInfo, arch: SupportedArch) -> Self {
+        let image_layout = manifest.image_layout().cloned().unwrap_or_default();
+        let (os_image_publish_size_gib, data_image_publish_size_gib) =
+            image_layout.publish_image_sizes_gib();
+        Self {
+            arch,
+            os_image_size_gib: image_layout.os_image_size_gib,
+            data_image_size_gib: image_layout.data_image_size_gib,
+            os_image_publish_size_gib,
+            data_image_publish_size_gib: (data_image_publish_size_gib >= 0)
+                .then_some(data_image_publish_size_gib),
+        }
+    }
+}
+
+impl Display for ImageSizes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "arch: {}", self.arch)?;
+        writeln!(f, "os-image-size-gib: {}", self.os_image_size_gib)?;
+        writeln!(f, "data-image-size-gib: {}", self.data_image_size_gib)?;
+        writeln!(
+            f,
+            "os-image-publish-size-gib: {}",
+            self.os_image_publish_size_gib
+        )?;
+        match self.data_image_publish_size_gib {
+            Some(size) => writeln!(f, "data-image-publish-size-gib: {size}"),
+            None => writeln!(f, "data-image-publish-size-gib: none"),
+        }
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+        [package]
+        name = "aws-dev"
+
+        [package.metadata.build-variant.image-layout]
+        os-image-size-gib = 4
+        data-image-size-gib = 2
+        publish-image-size-hint-gib = 30
+    "#;
+
+    fn manifest_info(manifest: &str) -> ManifestInfo {
+        toml::from_str(manifest).unwrap()
+    }
+
+    #[test]
+    fn test_image_sizes() {
+        let manifest = manifest_info(MANIFEST);
+        let image_layout = manifest.image_layout().unwrap();
+        let (os_image_publish_size_gib, data_image_publish_size_gib) =
+            image_layout.publish_image_sizes_gib();
+
+        let sizes = ImageSizes::new(&manifest, SupportedArch::X86_64);
+        assert_eq!(sizes.os_image_publish_size_gib, os_image_publish_size_gib);
+        assert_eq!(
+            sizes.data_image_publish_size_gib,
+            Some(data_image_publish_size_gib)
+        );
+        assert_eq!(
+            sizes.to_string(),
+            "arch: x86_64\n\
+            os-image-size-gib: 4\n\
+            data-image-size-gib: 2\n\
+            os-image-publish-size-gib: 4\n\
+            data-image-publish-size-gib: 26\n"
+        );
+    }
+
+    #[test]
+    fn test_image_sizes_unified_json() {
+        let manifest = manifest_info(&format!("{MANIFEST}partition-plan = \"unified\"\n"));
+        let sizes = serde_json::to_value(ImageSizes::new(&manifest, SupportedArch::Aarch64));
+        assert_eq!(
+            sizes.unwrap(),
+            serde_json::json!({
+                "arch": "aarch64",
+                "os-image-size-gib": 4,
+                "data-image-size-gib": 2,
+                "os-image-publish-size-gib": 30,
+                "data-image-publish-size-gib": null,
+            })
+        );
+    }
+}
@@ -0,0 +1,273 @@
+/*!
+This module copies the project root into a snapshot for a build to read, in place of the live root.
+The build then sees the root as it was when the build started, even if files are edited while it
+runs.
+
+The snapshot is a full copy, so it takes as much space as the files it holds: disk space in the
+state directory, or memory if it is placed on a tmpfs such as `/dev/shm`. The RPMs and kits under
+`build` are usually most of that. A snapshot that would pass its size cap is refused rather than
+filling the disk or memory, and each snapshot is removed once its build is done.
+
+The exclusions in the Dockerfile's `.dockerignore` also apply to the snapshot, except for a
+catch-all `*`. That pattern limits the docker build context, which is what the bypass exists to get
+around, so negated patterns that carve exceptions out of it have no effect here either.
+*/
+pub(crate) mod error;
+use error::Result;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use snafu::ResultExt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A snapshot of the project root, taken for a build and removed when it is done.
+#[derive(Debug, Clone)]
+pub(crate) struct RootSnapshot {
+    root: PathBuf,
+    dir: PathBuf,
+    max_bytes: u64,
+    exclude: GlobSet,
+    skip: Vec<PathBuf>,
+}
+
+impl RootSnapshot {
+    /// Prepare to snapshot `root` into a directory under `dir`, refusing to copy more than
+    /// `max_bytes` of files. Snapshots in `dir` are never copied into another snapshot.
+    pub(crate) fn new(root: impl AsRef<Path>, dir: impl AsRef<Path>, max_bytes: u64) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        Self {
+            root: root.as_ref().to_path_buf(),
+            skip: vec![dir.clone()],
+            dir,
+            max_bytes,
+            exclude: GlobSet::empty(),
+        }
+    }
+
+    /// Leave out the paths that the `.dockerignore` file at `path` excludes, apart from its
+    /// catch-all `*`. A missing file excludes nothing.
+    pub(crate) fn with_dockerignore(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(self),
+            Err(source) => {
+                return Err(error::Error::Read {
+                    path: path.into(),
+                    source,
+                })
+            }
+        };
+
+        let mut exclude = GlobSetBuilder::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+            let pattern = line.trim_start_matches('/');
+            if pattern == "*" {
+                continue;
+            }
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .context(error::PatternSnafu { pattern, path })?;
+            exclude.add(glob);
+        }
+        self.exclude = exclude
+            .build()
+            .context(error::PatternSnafu { pattern: "", path })?;
+        Ok(self)
+    }
+
+    /// Leave out `path` and everything under it, such as the state directory that other builds
+    /// write to.
+    pub(crate) fn skipping(mut self, path: impl AsRef<Path>) -> Self {
+        self.skip.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The directory that the snapshot named `name` is copied to.
+    pub(crate) fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}-snapshot"))
+    }
+
+    /// Copy the root into the snapshot named `name`, replacing any left by an earlier build.
+    /// Symlinks are copied as links, and files that are neither regular files nor directories are
+    /// left out. Returns the number of bytes copied.
+    pub(crate) fn take(&self, name: &str) -> Result<u64> {
+        self.remove(name)?;
+        let snapshot = self.path(name);
+        let mut copied = 0;
+        let entries = WalkDir::new(&self.root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| !self.is_excluded(entry.path()));
+        for entry in entries {
+            let entry = entry.context(error::WalkSnafu { path: &self.root })?;
+            let relative = entry
+                .path()
+                .strip_prefix(&self.root)
+                .expect("walked paths are under the root");
+            let target = snapshot.join(relative);
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                fs::create_dir_all(&target).context(error::CreateSnafu { path: &target })?;
+            } else if file_type.is_symlink() {
+                let link =
+                    fs::read_link(entry.path()).context(error::ReadSnafu { path: entry.path() })?;
+                symlink(link, &target).context(error::CreateSnafu { path: &target })?;
+            } else if file_type.is_file() {
+                let len = entry
+                    .metadata()
+                    .context(error::WalkSnafu { path: entry.path() })?
+                    .len();
+                copied += len;
+                if copied > self.max_bytes {
+                    // Don't leave a partial snapshot taking up space.
+                    let _ = self.remove(name);
+                    return error::TooLargeSnafu {
+                        root: &self.root,
+                        max_bytes: self.max_bytes,
+                    }
+                    .fail();
+                }
+                fs::copy(entry.path(), &target).context(error::CopySnafu { path: entry.path() })?;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Remove the snapshot named `name`, if there is one.
+    pub(crate) fn remove(&self, name: &str) -> Result<()> {
+        let path = self.path(name);
+        match fs::remove_dir_all(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(error::Error::Remove { path, source: e })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        if self.skip.iter().any(|skip| path == skip) {
+            return true;
+        }
+        path.strip_prefix(&self.root)
+            .is_ok_and(|relative| self.exclude.is_match(relative))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pipesys::server::Server;
+    use pipesys::test_support::TestServer;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+
+    fn write(path: impl AsRef<Path>, contents: &str) {
+        let path = path.as_ref();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_contents() {
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        write(root.join("Twoliter.toml"), "schema-version = 1");
+        write(root.join("build/rpms/release/release.rpm"), "rpm");
+        write(root.join("sources/target/debug/app"), "binary");
+        write(root.join("build/state/marker"), "state");
+        write(
+            root.join("build.Dockerfile.dockerignore"),
+            "# Exclude everything.\n*\n!/build/tools\n\n*/target/*\n",
+        );
+        symlink("release.rpm", root.join("build/rpms/release/latest.rpm")).unwrap();
+
+        let snapshots = root.join("build/snapshots");
+        let snapshot = RootSnapshot::new(root, &snapshots, 1024)
+            .with_dockerignore(root.join("build.Dockerfile.dockerignore"))
+            .unwrap()
+            .skipping(root.join("build/state"));
+        // A snapshot left by an earlier build is replaced.
+        write(snapshot.path("build").join("stale"), "stale");
+        // Only the manifest, the RPM, and the .dockerignore itself are copied.
+        assert_eq!(snapshot.take("build").unwrap(), 18 + 3 + 50);
+
+        let copy = snapshot.path("build");
+        assert_eq!(copy, snapshots.join("build-snapshot"));
+        assert_eq!(
+            fs::read_to_string(copy.join("build/rpms/release/release.rpm")).unwrap(),
+            "rpm"
+        );
+        assert_eq!(
+            fs::read_link(copy.join("build/rpms/release/latest.rpm")).unwrap(),
+            Path::new("release.rpm")
+        );
+        assert!(copy.join("sources/target").is_dir());
+        assert!(!copy.join("sources/target/debug").exists());
+        assert!(!copy.join("build/state").exists());
+        assert!(!copy.join("build/snapshots").exists());
+        assert!(!copy.join("stale").exists());
+
+        snapshot.remove("build").unwrap();
+        assert!(!copy.exists());
+        snapshot.remove("build").unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_too_large() {
+        let root = TempDir::new().unwrap();
+        let snapshots = TempDir::new().unwrap();
+        write(root.path().join("a"), "12345");
+        write(root.path().join("b"), "67890");
+
+        let snapshot = RootSnapshot::new(root.path(), snapshots.path(), 8);
+        let err = snapshot.take("build").unwrap_err();
+        assert!(
+            matches!(err, error::Error::TooLarge { max_bytes: 8, .. }),
+            "{err}"
+        );
+        assert!(!snapshot.path("build").exists());
+        assert_eq!(
+            RootSnapshot::new(root.path(), snapshots.path(), 10)
+                .take("build")
+                .unwrap(),
+            10
+        );
+    }
+
+    #[test]
+    fn test_served_snapshot_unchanged() {
+        let root = TempDir::new().unwrap();
+        let snapshots = TempDir::new().unwrap();
+        write(root.path().join("packages/a/a.spec"), "Version: 1");
+        let snapshot = RootSnapshot::new(root.path(), snapshots.path(), 1024);
+        snapshot.take("build").unwrap();
+
+        // Serve the snapshot the way the bypass container serves the root, to this user.
+        let uid = fs::metadata(root.path()).unwrap().uid();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let server =
+            TestServer::start(|socket| Server::for_path(socket, uid, snapshot.path("build")))
+                .unwrap();
+        let fd = pipesys::client::fetch_owned_fd(server.socket()).unwrap();
+        let served = PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+
+        // Edits to the live root after the snapshot are not visible through the descriptor.
+        write(root.path().join("packages/a/a.spec"), "Version: 2");
+        write(root.path().join("packages/b/b.spec"), "Version: 1");
+        assert_eq!(
+            fs::read_to_string(served.join("packages/a/a.spec")).unwrap(),
+            "Version: 1"
+        );
+        assert!(!served.join("packages/b").exists());
+    }
+}
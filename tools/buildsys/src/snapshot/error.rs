@@ -0,0 +1,51 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to copy '{}' into the snapshot: {}", path.display(), source))]
+    Copy {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to create '{}' in the snapshot: {}", path.display(), source))]
+    Create {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Invalid pattern '{pattern}' in '{}': {source}", path.display()))]
+    Pattern {
+        pattern: String,
+        path: PathBuf,
+        source: globset::Error,
+    },
+
+    #[snafu(display("Failed to remove snapshot '{}': {}", path.display(), source))]
+    Remove {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "Snapshot of '{}' is larger than {max_bytes} bytes; raise --snapshot-max-bytes or leave out --snapshot-root",
+        root.display()
+    ))]
+    TooLarge { root: PathBuf, max_bytes: u64 },
+
+    #[snafu(display("Failed to walk '{}': {}", path.display(), source))]
+    Walk {
+        path: PathBuf,
+        source: walkdir::Error,
+    },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
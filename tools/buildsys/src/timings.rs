@@ -0,0 +1,116 @@
+/*!
+This module records how long each phase of a build took, so that a slow build can be traced to
+docker itself or to the steps that buildsys runs around it.
+
+The timings can be written as JSON, with every duration given in seconds:
+
+```json
+{
+  "total": 312.5,
+  "phases": { "cleanup": 0.4, "bypass-start": 1.2, "docker-build": 305.1, ... },
+  "attempts": [120.3, 184.8]
+}
+```
+
+*/
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// A part of a build that is timed on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BuildPhase {
+    /// Removing the outputs, image, and bypass container left by an earlier build, and the image
+    /// and bypass container of this build once it is done.
+    Cleanup,
+    /// Copying the project root into a snapshot for the build to read, if requested.
+    Snapshot,
+    /// Starting the bypass container and waiting for it to serve the project root.
+    BypassStart,
+    /// The docker build, including every retry.
+    DockerBuild,
+    /// Packaging the disks into an OVA, if requested, and moving artifacts to the output directory.
+    ArtifactCopy,
+    /// Computing checksums for the artifacts, if anything needs them.
+    Checksums,
+}
+
+/// How long each phase of a build took. Phases that did not run are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PhaseTimings {
+    phases: BTreeMap<BuildPhase, Duration>,
+    attempts: Vec<Duration>,
+}
+
+impl PhaseTimings {
+    /// Add the time since `started` to `phase`, and return it. A phase that runs more than once,
+    /// such as cleanup, accumulates its time.
+    pub(crate) fn record(&mut self, phase: BuildPhase, started: Instant) -> Duration {
+        let elapsed = started.elapsed();
+        *self.phases.entry(phase).or_default() += elapsed;
+        elapsed
+    }
+
+    /// Record how long each attempt at the docker build took, in order.
+    pub(crate) fn set_attempts(&mut self, attempts: Vec<Duration>) {
+        self.attempts = attempts;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get(&self, phase: BuildPhase) -> Option<Duration> {
+        self.phases.get(&phase).copied()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn attempts(&self) -> &[Duration] {
+        &self.attempts
+    }
+
+    /// Describe the timings as JSON, along with the total duration of the build.
+    pub(crate) fn to_json(&self, total: Duration) -> String {
+        let report = TimingsReport {
+            total: total.as_secs_f64(),
+            phases: self
+                .phases
+                .iter()
+                .map(|(phase, duration)| (*phase, duration.as_secs_f64()))
+                .collect(),
+            attempts: self.attempts.iter().map(Duration::as_secs_f64).collect(),
+        };
+        serde_json::to_string_pretty(&report).expect("timings serialize to JSON")
+    }
+}
+
+#[derive(Serialize)]
+struct TimingsReport {
+    total: f64,
+    phases: BTreeMap<BuildPhase, f64>,
+    attempts: Vec<f64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_phase_timings_json() {
+        let mut timings = PhaseTimings::default();
+        let started = Instant::now();
+        timings.record(BuildPhase::Cleanup, started);
+        timings.record(BuildPhase::DockerBuild, started);
+        let first = timings.get(BuildPhase::Cleanup).unwrap();
+        timings.record(BuildPhase::Cleanup, started);
+        assert!(timings.get(BuildPhase::Cleanup).unwrap() >= first);
+        assert_eq!(timings.get(BuildPhase::Checksums), None);
+        timings.set_attempts(vec![Duration::from_millis(1500), Duration::from_secs(2)]);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&timings.to_json(Duration::from_secs(4))).unwrap();
+        assert_eq!(json["total"], 4.0);
+        assert!(json["phases"]["cleanup"].is_f64());
+        assert!(json["phases"]["docker-build"].is_f64());
+        assert!(json["phases"].get("checksums").is_none());
+        assert_eq!(json["attempts"], serde_json::json!([1.5, 2.0]));
+    }
+}
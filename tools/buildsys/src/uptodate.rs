@@ -0,0 +1,117 @@
+/*!
+This module records what the last successful build of a variant was made from, so that a later
+build with the same inputs can be skipped while the artifacts it produced are still intact.
+
+The record is two files next to the build's marker directory: a digest of the build's inputs, and
+a reproducibility manifest with the checksums of its artifacts.
+*/
+pub(crate) mod error;
+use error::Result;
+
+use crate::repro::ArtifactHashes;
+use snafu::ResultExt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extensions for the files that record the inputs and artifacts of a variant's last successful
+/// build, next to its marker directory.
+const INPUTS_RECORD_EXTENSION: &str = ".buildsys_inputs";
+const CHECKSUMS_RECORD_EXTENSION: &str = ".buildsys_checksums";
+
+/// What the last successful build was made from, and the checksums of what it produced, so that a
+/// build with the same inputs can be skipped. The files sit next to the marker directory rather
+/// than in it, since everything a build leaves there is treated as an artifact.
+pub(crate) struct UpToDateRecord {
+    inputs: PathBuf,
+    checksums: PathBuf,
+}
+
+impl UpToDateRecord {
+    pub(crate) fn new(marker_dir: &Path) -> Self {
+        let with_extension = |extension| {
+            let mut path = marker_dir.as_os_str().to_owned();
+            path.push(extension);
+            PathBuf::from(path)
+        };
+        Self {
+            inputs: with_extension(INPUTS_RECORD_EXTENSION),
+            checksums: with_extension(CHECKSUMS_RECORD_EXTENSION),
+        }
+    }
+
+    /// Whether the last successful build had the same input digest, and every artifact it produced
+    /// is still in `output_dir` with the same contents.
+    pub(crate) fn matches(&self, digest: &str, output_dir: &Path, jobs: usize) -> bool {
+        let Ok(recorded) = fs::read_to_string(&self.inputs) else {
+            return false;
+        };
+        if recorded != digest {
+            return false;
+        }
+        let Ok(baseline) = ArtifactHashes::read(&self.checksums) else {
+            return false;
+        };
+        let artifacts = baseline.artifacts();
+        !artifacts.is_empty()
+            && ArtifactHashes::new(output_dir, &artifacts, jobs)
+                .is_ok_and(|current| current.differences(&baseline).is_empty())
+    }
+
+    /// Record a successful build. The digest is written last, so that the record only matches
+    /// once it is complete.
+    pub(crate) fn write(&self, digest: &str, hashes: &ArtifactHashes) -> Result<()> {
+        hashes.write(&self.checksums).context(error::ReproSnafu)?;
+        fs::write(&self.inputs, digest).context(error::FileWriteSnafu { path: &self.inputs })
+    }
+
+    /// Forget the last successful build, before its artifacts are removed or replaced.
+    pub(crate) fn remove(&self) -> Result<()> {
+        for path in [&self.inputs, &self.checksums] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).context(error::FileRemoveSnafu { path });
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_up_to_date_record() {
+        let state_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let marker_dir = state_dir.path().join("variant-x86_64-aws-dev");
+        fs::create_dir(&marker_dir).unwrap();
+        let image = output_dir.path().join("os.img");
+        fs::write(&image, "image").unwrap();
+        let hashes = ArtifactHashes::new(output_dir.path(), &["os.img"], 1).unwrap();
+
+        let record = UpToDateRecord::new(&marker_dir);
+        assert!(!record.matches("digest", output_dir.path(), 1));
+        record.write("digest", &hashes).unwrap();
+        assert!(record.matches("digest", output_dir.path(), 1));
+        assert!(!record.matches("other", output_dir.path(), 1));
+        // The record is kept out of the marker directory, where it would be taken for an artifact.
+        assert_eq!(fs::read_dir(&marker_dir).unwrap().count(), 0);
+
+        // Artifacts that were changed or removed since the last build must be built again.
+        fs::write(&image, "changed").unwrap();
+        assert!(!record.matches("digest", output_dir.path(), 1));
+        fs::remove_file(&image).unwrap();
+        assert!(!record.matches("digest", output_dir.path(), 1));
+
+        fs::write(&image, "image").unwrap();
+        assert!(record.matches("digest", output_dir.path(), 1));
+        record.remove().unwrap();
+        assert!(!record.matches("digest", output_dir.path(), 1));
+        record.remove().unwrap();
+    }
+}
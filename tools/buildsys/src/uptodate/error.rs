@@ -0,0 +1,23 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to remove up-to-date record '{}': {}", path.display(), source))]
+    FileRemove {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to write up-to-date record '{}': {}", path.display(), source))]
+    FileWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("{source}"))]
+    Repro { source: crate::repro::error::Error },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
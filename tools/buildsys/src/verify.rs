@@ -0,0 +1,424 @@
+/*!
+This module checks the partition table of a built "os" image against the layout that the variant
+manifest calls for, so that regressions in the image build scripts are caught before the image is
+published.
+
+The expected layout mirrors the `partyplanner` library used by the image build, and must be kept
+in sync with it. Only raw images are supported.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use buildsys::manifest::{ImageFeature, ImageLayout, ManifestInfo, PartitionPlan};
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+const MIB_SECTORS: u64 = 2048;
+
+// Fixed size partitions and reservations, in MiB.
+const GPT_MIB: u64 = 1;
+const BIOS_MIB: u64 = 4;
+const OVERHEAD_MIB: u64 = GPT_MIB * 2 + BIOS_MIB;
+const EFI_MIB: u64 = 5;
+const DATA_A_MIB: u64 = 1;
+
+// Partitions that scale with the size of the "os" image, in MiB per GiB.
+const BOOT_SCALE_FACTOR: u64 = 20;
+const ROOT_SCALE_FACTOR: u64 = 460;
+const HASH_SCALE_FACTOR: u64 = 5;
+const RESERVE_SCALE_FACTOR: u64 = 15;
+const PRIVATE_SCALE_FACTOR: u64 = 24;
+
+// Partition type GUIDs.
+const BIOS_BOOT_TYPE: &str = "21686148-6449-6e6f-744e-656564454649";
+const EFI_SYSTEM_TYPE: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+const EFI_BACKUP_TYPE: &str = "b39ce39c-0a00-b4ab-2d11-f18f8237a21c";
+const BOOT_TYPE: &str = "6b636168-7420-6568-2070-6c616e657421";
+const ROOT_TYPE: &str = "5526016a-1a97-4ea4-b39a-b7c8c6ca4502";
+const HASH_TYPE: &str = "598f10af-c955-4456-6a99-7720068a6cea";
+const RESERVED_TYPE: &str = "0c5d99a5-d331-4147-baef-08e2b855bdc9";
+const PRIVATE_TYPE: &str = "440408bb-eb0b-4328-a6e5-a29038fad706";
+const DATA_TYPE: &str = "626f7474-6c65-6474-6861-726d61726b73";
+
+/// A partition in a GPT partition table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Partition {
+    label: String,
+    type_guid: String,
+    first_lba: u64,
+    sectors: u64,
+}
+
+/// A partition that the layout calls for, and the role it plays in the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExpectedPartition {
+    role: String,
+    partition: Partition,
+}
+
+/// The partitions found in an image, and how they differ from the expected layout.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ImageVerification {
+    partitions: Vec<Partition>,
+    mismatches: Vec<String>,
+}
+
+impl ImageVerification {
+    /// Read the partition table from a raw "os" image, and compare it to the layout for the
+    /// variant.
+    pub(crate) fn new(image: &Path, manifest: &ManifestInfo) -> Result<Self> {
+        let image_layout = manifest.image_layout().cloned().unwrap_or_default();
+        let in_place_updates = manifest.enabled_image_features().map_or(true, |features| {
+            features.contains(&ImageFeature::InPlaceUpdates)
+        });
+
+        let partitions = read_partitions(image)?;
+        let expected = expected_partitions(&image_layout, in_place_updates);
+        let mismatches = compare_partitions(&expected, &partitions);
+        Ok(Self {
+            partitions,
+            mismatches,
+        })
+    }
+
+    pub(crate) fn mismatches(&self) -> &[String] {
+        &self.mismatches
+    }
+}
+
+impl Display for ImageVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "partitions:")?;
+        for (i, p) in self.partitions.iter().enumerate() {
+            writeln!(
+                f,
+                "  {}: '{}' start {} size {} type {}",
+                i + 1,
+                p.label,
+                p.first_lba,
+                p.sectors,
+                p.type_guid
+            )?;
+        }
+        if self.mismatches.is_empty() {
+            return writeln!(f, "No mismatches");
+        }
+        writeln!(f, "mismatches:")?;
+        for mismatch in &self.mismatches {
+            writeln!(f, "  {mismatch}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute the partitions on the "os" image for a layout, in the order they appear on disk.
+fn expected_partitions(
+    image_layout: &ImageLayout,
+    in_place_updates: bool,
+) -> Vec<ExpectedPartition> {
+    let os_image_gib = u64::from(image_layout.os_image_size_gib.gib());
+    let data_image_gib = u64::from(image_layout.data_image_size_gib.gib());
+
+    let boot_mib = os_image_gib * BOOT_SCALE_FACTOR;
+    let root_mib = os_image_gib * ROOT_SCALE_FACTOR;
+    let hash_mib = os_image_gib * HASH_SCALE_FACTOR;
+    let reserved_mib = os_image_gib * RESERVE_SCALE_FACTOR - EFI_MIB;
+    let private_mib = os_image_gib * PRIVATE_SCALE_FACTOR - OVERHEAD_MIB - DATA_A_MIB;
+
+    let mut expected = Vec::new();
+    // Skip the GPT label at the start of the disk.
+    let mut offset_mib = GPT_MIB;
+    let mut add = |role: &str, label: &str, type_guid: &str, size_mib: u64| {
+        expected.push(ExpectedPartition {
+            role: role.to_string(),
+            partition: Partition {
+                label: label.to_string(),
+                type_guid: type_guid.to_string(),
+                first_lba: offset_mib * MIB_SECTORS,
+                sectors: size_mib * MIB_SECTORS,
+            },
+        });
+        offset_mib += size_mib;
+    };
+
+    add("BIOS", "BIOS-BOOT", BIOS_BOOT_TYPE, BIOS_MIB);
+
+    // With in-place updates there are two banks of partitions, and otherwise there is a single
+    // bank where each partition is twice as large.
+    let (banks, scale): (&[&str], u64) = if in_place_updates {
+        (&["A", "B"], 1)
+    } else {
+        (&["A"], 2)
+    };
+    for bank in banks {
+        let (efi_label, efi_type) = match *bank {
+            "A" => ("EFI-SYSTEM", EFI_SYSTEM_TYPE),
+            _ => ("EFI-BACKUP", EFI_BACKUP_TYPE),
+        };
+        add(&format!("EFI-{bank}"), efi_label, efi_type, EFI_MIB * scale);
+        for (part, type_guid, size_mib) in [
+            ("BOOT", BOOT_TYPE, boot_mib),
+            ("ROOT", ROOT_TYPE, root_mib),
+            ("HASH", HASH_TYPE, hash_mib),
+            ("RESERVED", RESERVED_TYPE, reserved_mib),
+        ] {
+            add(
+                &format!("{part}-{bank}"),
+                &format!("BOTTLEROCKET-{part}-{bank}"),
+                type_guid,
+                size_mib * scale,
+            );
+        }
+    }
+
+    add("PRIVATE", "BOTTLEROCKET-PRIVATE", PRIVATE_TYPE, private_mib);
+
+    // The data partition is labeled during boot, so it has no label in the image.
+    match image_layout.partition_plan {
+        PartitionPlan::Split => add("DATA-A", "", DATA_TYPE, DATA_A_MIB),
+        PartitionPlan::Unified => add("DATA-A", "", DATA_TYPE, data_image_gib * 1024),
+    }
+
+    expected
+}
+
+/// Describe each way that the partitions in an image differ from the expected ones.
+fn compare_partitions(expected: &[ExpectedPartition], actual: &[Partition]) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    if expected.len() != actual.len() {
+        mismatches.push(format!(
+            "expected {} partitions, found {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+
+    for (i, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        let ExpectedPartition { role, partition } = expected;
+        let mut check = |field: &str, expected: &dyn Display, actual: &dyn Display| {
+            let (expected, actual) = (expected.to_string(), actual.to_string());
+            if expected != actual {
+                mismatches.push(format!(
+                    "partition {} ({role}): expected {field} '{expected}', found '{actual}'",
+                    i + 1
+                ));
+            }
+        };
+        check("label", &partition.label, &actual.label);
+        check("type", &partition.type_guid, &actual.type_guid);
+        check("start sector", &partition.first_lba, &actual.first_lba);
+        check("size in sectors", &partition.sectors, &actual.sectors);
+    }
+
+    mismatches
+}
+
+/// Read the partitions from the primary GPT partition table of a raw image, in the order they
+/// appear on disk.
+fn read_partitions(path: &Path) -> Result<Vec<Partition>> {
+    let mut file = File::open(path).context(error::ImageReadSnafu { path })?;
+    let mut header = [0u8; 92];
+    file.seek(SeekFrom::Start(SECTOR_SIZE))
+        .and_then(|_| file.read_exact(&mut header))
+        .context(error::ImageReadSnafu { path })?;
+    ensure!(
+        &header[0..8] == b"EFI PART",
+        error::MissingGptSnafu { path }
+    );
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap_or_default());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap_or_default());
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap_or_default());
+    ensure!(
+        entry_size >= 128,
+        error::EntrySizeSnafu { path, entry_size }
+    );
+
+    let mut entries = vec![0u8; entry_count as usize * entry_size as usize];
+    file.seek(SeekFrom::Start(entries_lba * SECTOR_SIZE))
+        .and_then(|_| file.read_exact(&mut entries))
+        .context(error::ImageReadSnafu { path })?;
+
+    let mut partitions = entries
+        .chunks_exact(entry_size as usize)
+        .filter(|entry| entry[0..16].iter().any(|b| *b != 0))
+        .map(|entry| {
+            let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap_or_default());
+            let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap_or_default());
+            let name = entry[56..128]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|c| *c != 0)
+                .collect::<Vec<_>>();
+            Partition {
+                label: String::from_utf16_lossy(&name),
+                type_guid: format_guid(&entry[0..16]),
+                first_lba,
+                sectors: last_lba.saturating_sub(first_lba) + 1,
+            }
+        })
+        .collect::<Vec<_>>();
+    partitions.sort_by_key(|p| p.first_lba);
+    Ok(partitions)
+}
+
+/// Format a GUID as stored on disk, where the first three fields are little-endian.
+fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        hex::encode(&bytes[8..10]),
+        hex::encode(&bytes[10..16])
+    )
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const MANIFEST: &str = r#"
+        [package]
+        name = "aws-dev"
+
+        [package.metadata.build-variant.image-layout]
+        os-image-size-gib = 2
+        data-image-size-gib = 1
+    "#;
+
+    fn manifest_info(manifest: &str) -> ManifestInfo {
+        toml::from_str(manifest).unwrap()
+    }
+
+    /// Convert a GUID to the mixed-endian form stored on disk.
+    fn guid_bytes(guid: &str) -> Vec<u8> {
+        let hex = hex::decode(guid.replace('-', "")).unwrap();
+        let mut bytes = Vec::new();
+        bytes.extend(hex[0..4].iter().rev());
+        bytes.extend(hex[4..6].iter().rev());
+        bytes.extend(hex[6..8].iter().rev());
+        bytes.extend(&hex[8..16]);
+        bytes
+    }
+
+    /// Write a synthetic image with just enough of a GPT partition table to describe the
+    /// partitions. The image is not padded out to its full size.
+    fn write_image(partitions: &[Partition]) -> NamedTempFile {
+        let mut header = vec![0u8; SECTOR_SIZE as usize];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+
+        let mut entries = vec![0u8; 128 * 128];
+        for (entry, p) in entries.chunks_exact_mut(128).zip(partitions) {
+            entry[0..16].copy_from_slice(&guid_bytes(&p.type_guid));
+            entry[32..40].copy_from_slice(&p.first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&(p.first_lba + p.sectors - 1).to_le_bytes());
+            for (i, c) in p.label.encode_utf16().enumerate() {
+                entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        let mut image = NamedTempFile::new().unwrap();
+        image.write_all(&[0u8; SECTOR_SIZE as usize]).unwrap();
+        image.write_all(&header).unwrap();
+        image.write_all(&entries).unwrap();
+        image
+    }
+
+    fn expected_for(manifest: &ManifestInfo) -> Vec<Partition> {
+        let image_layout = manifest.image_layout().cloned().unwrap();
+        expected_partitions(&image_layout, true)
+            .into_iter()
+            .map(|e| e.partition)
+            .collect()
+    }
+
+    #[test]
+    fn test_expected_partitions() {
+        let manifest = manifest_info(MANIFEST);
+        let expected = expected_for(&manifest);
+        let sizes_mib = expected
+            .iter()
+            .map(|p| (p.label.as_str(), p.first_lba / 2048, p.sectors / 2048))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sizes_mib,
+            [
+                ("BIOS-BOOT", 1, 4),
+                ("EFI-SYSTEM", 5, 5),
+                ("BOTTLEROCKET-BOOT-A", 10, 40),
+                ("BOTTLEROCKET-ROOT-A", 50, 920),
+                ("BOTTLEROCKET-HASH-A", 970, 10),
+                ("BOTTLEROCKET-RESERVED-A", 980, 25),
+                ("EFI-BACKUP", 1005, 5),
+                ("BOTTLEROCKET-BOOT-B", 1010, 40),
+                ("BOTTLEROCKET-ROOT-B", 1050, 920),
+                ("BOTTLEROCKET-HASH-B", 1970, 10),
+                ("BOTTLEROCKET-RESERVED-B", 1980, 25),
+                ("BOTTLEROCKET-PRIVATE", 2005, 41),
+                ("", 2046, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_matching_image() {
+        let manifest = manifest_info(MANIFEST);
+        let image = write_image(&expected_for(&manifest));
+        let verification = ImageVerification::new(image.path(), &manifest).unwrap();
+        assert!(verification.mismatches().is_empty());
+        assert_eq!(verification.partitions.len(), 13);
+        assert_eq!(verification.partitions[0].type_guid, BIOS_BOOT_TYPE);
+    }
+
+    #[test]
+    fn test_verify_mismatched_image() {
+        let manifest = manifest_info(MANIFEST);
+        let mut partitions = expected_for(&manifest);
+        partitions[3].sectors -= MIB_SECTORS;
+        partitions.pop();
+        let image = write_image(&partitions);
+
+        let verification = ImageVerification::new(image.path(), &manifest).unwrap();
+        assert_eq!(
+            verification.mismatches(),
+            [
+                "expected 13 partitions, found 12",
+                "partition 4 (ROOT-A): expected size in sectors '1884160', found '1882112'",
+            ]
+        );
+
+        // The layout without in-place updates has a single bank.
+        let manifest = manifest_info(&format!(
+            "{MANIFEST}\n[package.metadata.build-variant.image-features]\nin-place-updates = false\n"
+        ));
+        let verification = ImageVerification::new(image.path(), &manifest).unwrap();
+        assert!(verification.mismatches()[0].starts_with("expected 8 partitions"));
+    }
+
+    #[test]
+    fn test_verify_not_gpt() {
+        let mut image = NamedTempFile::new().unwrap();
+        image.write_all(&[0u8; 4096]).unwrap();
+        let manifest = manifest_info(MANIFEST);
+        assert!(matches!(
+            ImageVerification::new(image.path(), &manifest),
+            Err(error::Error::MissingGpt { .. })
+        ));
+    }
+}
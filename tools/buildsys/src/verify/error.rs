@@ -0,0 +1,23 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to read partition table from '{}': {}", path.display(), source))]
+    ImageRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("'{}' does not have a GPT partition table", path.display()))]
+    MissingGpt { path: PathBuf },
+
+    #[snafu(display(
+        "GPT partition table in '{}' has unsupported entry size {entry_size}",
+        path.display()
+    ))]
+    EntrySize { path: PathBuf, entry_size: u32 },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
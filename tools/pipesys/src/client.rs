@@ -0,0 +1,566 @@
+use crate::error::{self, Result};
+use crate::info::ServerInfo;
+use crate::server::{REFUSED_MESSAGE, REJECTED_MESSAGE, UNAVAILABLE_MESSAGE};
+use crate::socket::SocketAddress;
+use log::{debug, warn};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, F_DUPFD, F_GETFD};
+use nix::unistd::dup2;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::thread;
+use std::time::{Duration, Instant};
+use uds::{UnixSeqpacketConn, UnixSocketAddr};
+
+// Don't accept file descriptors 0, 1, or 2 since those correspond to the well-known stdin, stdout,
+// and stderr which could confuse the calling process or its children.
+const MIN_FD: i32 = 3;
+
+/// The longest message that is accepted along with a file descriptor, unless the caller asks for
+/// more. Servers send a short description of the descriptor, which must not be truncated.
+pub const DEFAULT_MESSAGE_LEN: usize = 4096;
+
+/// Room for more descriptors than a server should send, so that extra descriptors are counted
+/// and closed instead of silently dropped by the kernel. A server that sends even more still has
+/// the rest dropped, and `uds` does not report the `MSG_CTRUNC` flag that marks it, so such a
+/// reply looks like one with exactly this many descriptors.
+const MAX_FDS: usize = 8;
+
+/// How long to wait between attempts to connect to a server that is not listening yet. The wait
+/// doubles after each attempt, up to the maximum.
+const CONNECT_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_CONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Retrieve a file descriptor via an abstract socket. The descriptor is duplicated without the
+/// CLOEXEC flag, so that it is inherited by any program the caller executes.
+pub fn fetch_fd(socket: &str) -> Result<i32> {
+    strip_cloexec(fetch_owned_fd(socket)?)
+}
+
+/// Retrieve a file descriptor via an abstract socket, and place it at `target` without the CLOEXEC
+/// flag, for programs that expect to inherit descriptors at well-known numbers. The target must
+/// not already be open, so that nothing the caller holds is silently closed.
+pub fn fetch_fd_at(socket: &str, target: i32) -> Result<i32> {
+    ensure!(
+        target >= MIN_FD,
+        error::TargetFdReservedSnafu { fd: target }
+    );
+    ensure!(
+        fcntl(target, F_GETFD) == Err(Errno::EBADF),
+        error::TargetFdInUseSnafu { fd: target }
+    );
+    let fd = fetch_owned_fd(socket)?;
+    let raw_fd = fd.as_raw_fd();
+    dup2(raw_fd, target).context(error::DuplicateFdSnafu { fd: raw_fd })?;
+    debug!("duplicated file descriptor {raw_fd} to {target}");
+    Ok(target)
+}
+
+/// Retrieve a file descriptor via an abstract socket, and take ownership of it as received. It
+/// keeps the CLOEXEC flag, and is closed when dropped.
+pub fn fetch_owned_fd(socket: &str) -> Result<OwnedFd> {
+    Ok(fetch_fd_and_message(socket, DEFAULT_MESSAGE_LEN)?.0)
+}
+
+/// Retrieve a file descriptor via an abstract socket from a server that requires a token, and
+/// take ownership of it as received.
+pub fn fetch_owned_fd_with_token(socket: &str, token: &str) -> Result<OwnedFd> {
+    Ok(fetch(&abstract_socket(socket), Some(token), DEFAULT_MESSAGE_LEN)?.0)
+}
+
+/// Retrieve a file descriptor via an abstract socket, along with the message that the server sent
+/// with it. Messages longer than `max_message_len` bytes are rejected rather than truncated.
+pub fn fetch_fd_and_message(socket: &str, max_message_len: usize) -> Result<(OwnedFd, Vec<u8>)> {
+    fetch(&abstract_socket(socket), None, max_message_len)
+}
+
+/// Retrieve a file descriptor from the first of several abstract sockets that provides one, so
+/// that a backup server can take over if the primary one has died.
+pub fn fetch_fd_from_any<S: AsRef<str>>(sockets: &[S]) -> Result<i32> {
+    fetch_fd_from_any_with_token(sockets, None)
+}
+
+/// Retrieve a file descriptor from the first of several abstract sockets that provides one,
+/// sending `token` first to servers that require it.
+pub fn fetch_fd_from_any_with_token<S: AsRef<str>>(
+    sockets: &[S],
+    token: Option<&str>,
+) -> Result<i32> {
+    let addresses = sockets
+        .iter()
+        .map(|socket| abstract_socket(socket.as_ref()))
+        .collect::<Vec<_>>();
+    fetch_fd_from_any_address(&addresses, token)
+}
+
+/// Retrieve a file descriptor from the first of several sockets that provides one, which may be
+/// abstract sockets or socket files, sending `token` first to servers that require it.
+pub fn fetch_fd_from_any_address(addresses: &[SocketAddress], token: Option<&str>) -> Result<i32> {
+    let mut last_error = None;
+    for address in addresses {
+        match fetch(address, token, DEFAULT_MESSAGE_LEN).and_then(|(fd, _)| strip_cloexec(fd)) {
+            Ok(fd) => return Ok(fd),
+            Err(e) => {
+                warn!("{e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or(error::Error::NoSockets))
+}
+
+/// Retrieve a file descriptor via an abstract socket, retrying the connection until the server
+/// starts listening or the timeout expires.
+pub fn fetch_fd_with_timeout(socket: &str, timeout: Duration) -> Result<i32> {
+    let client = connect_with_timeout(socket, timeout)?;
+    strip_cloexec(receive(socket, &client, DEFAULT_MESSAGE_LEN)?.0)
+}
+
+/// Wait until a server is listening on an abstract socket, or the timeout expires. The server
+/// counts the connection as a client, but no file descriptor is fetched.
+pub fn wait_for(socket: &str, timeout: Duration) -> Result<()> {
+    connect_with_timeout(socket, timeout).map(drop)
+}
+
+/// Check whether a server is listening on an abstract socket, without fetching its descriptor.
+pub fn is_listening(socket: &str) -> bool {
+    socket_addr(&abstract_socket(socket))
+        .map(|addr| UnixSeqpacketConn::connect_unix_addr(&addr).is_ok())
+        .unwrap_or(false)
+}
+
+/// Ask a server what it is configured to serve, without fetching its descriptor. The server must
+/// have been started with `--serve-info`.
+pub fn fetch_info(socket: &str) -> Result<ServerInfo> {
+    let address = abstract_socket(socket).info();
+    let addr = socket_addr(&address)?;
+    let socket = address.to_string();
+    let client = UnixSeqpacketConn::connect_unix_addr(&addr)
+        .context(error::ConnectSnafu { socket: &socket })?;
+    let mut message = [0u8; DEFAULT_MESSAGE_LEN];
+    let len = client
+        .recv(&mut message)
+        .context(error::ReceiveSnafu { socket: &socket })?;
+    String::from_utf8_lossy(&message[..len]).parse()
+}
+
+/// Connect to an abstract socket, backing off between attempts until the server starts listening
+/// or the timeout expires.
+fn connect_with_timeout(socket: &str, timeout: Duration) -> Result<UnixSeqpacketConn> {
+    let addr = socket_addr(&abstract_socket(socket))?;
+    let deadline = Instant::now() + timeout;
+    let mut interval = CONNECT_INTERVAL;
+    loop {
+        match UnixSeqpacketConn::connect_unix_addr(&addr) {
+            Ok(client) => return Ok(client),
+            Err(_) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                ensure!(
+                    !remaining.is_zero(),
+                    error::TimeoutSnafu { socket, timeout }
+                );
+                thread::sleep(interval.min(remaining));
+                interval = (interval * 2).min(MAX_CONNECT_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Connect to a socket and receive a file descriptor, sending the token first if there is one.
+fn fetch(
+    address: &SocketAddress,
+    token: Option<&str>,
+    max_message_len: usize,
+) -> Result<(OwnedFd, Vec<u8>)> {
+    let addr = socket_addr(address)?;
+    let socket = &address.to_string();
+    let client =
+        UnixSeqpacketConn::connect_unix_addr(&addr).context(error::ConnectSnafu { socket })?;
+    if let Some(token) = token {
+        client
+            .send(token.as_bytes())
+            .context(error::SendTokenSnafu { socket })?;
+    }
+    receive(socket, &client, max_message_len)
+}
+
+fn abstract_socket(socket: &str) -> SocketAddress {
+    SocketAddress::Abstract(socket.to_string())
+}
+
+pub(crate) fn socket_addr(address: &SocketAddress) -> Result<UnixSocketAddr> {
+    match address {
+        SocketAddress::Abstract(name) => UnixSocketAddr::from_abstract(name.as_bytes()),
+        SocketAddress::Path(path) => UnixSocketAddr::from_path(path),
+    }
+    .context(error::SocketAddressSnafu {
+        socket: address.to_string(),
+    })
+}
+
+/// Receive a file descriptor and the message sent with it. The payload length is reported when
+/// the descriptor count is wrong, but truncation of the control message that carries the
+/// descriptors can't be detected, since `uds` does not expose `MSG_CTRUNC`.
+fn receive(
+    socket: &str,
+    client: &UnixSeqpacketConn,
+    max_message_len: usize,
+) -> Result<(OwnedFd, Vec<u8>)> {
+    // One extra byte shows whether the message fills the buffer exactly or was cut short.
+    let mut message = vec![0u8; max_message_len + 1];
+    let mut fd_buf = [-1; MAX_FDS];
+    let (len, truncated, fds) = client
+        .recv_fds(&mut message, &mut fd_buf)
+        .context(error::ReceiveSnafu { socket })?;
+
+    // Take ownership of every descriptor we received right away, so that none are leaked if they
+    // turn out to be unusable.
+    // SAFETY: the kernel just installed these descriptors for us, and nothing else refers to them.
+    let received = fd_buf[..fds]
+        .iter()
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) })
+        .collect::<Vec<_>>();
+
+    ensure!(
+        fds > 0 || message[..len] != *REFUSED_MESSAGE,
+        error::RefusedSnafu { socket }
+    );
+    ensure!(
+        fds > 0 || message[..len] != *UNAVAILABLE_MESSAGE,
+        error::UnavailableSnafu { socket }
+    );
+    ensure!(
+        fds > 0 || message[..len] != *REJECTED_MESSAGE,
+        error::TokenRejectedSnafu { socket }
+    );
+    ensure!(
+        fds == 1,
+        error::FdCountSnafu {
+            expected: 1usize,
+            received: fds,
+            payload_len: len,
+        }
+    );
+    ensure!(
+        !truncated && len <= max_message_len,
+        error::MessageTooLongSnafu {
+            socket,
+            max_len: max_message_len,
+        }
+    );
+
+    let fd = received
+        .into_iter()
+        .next()
+        .filter(|fd| fd.as_raw_fd() >= MIN_FD)
+        .context(error::InvalidFdSnafu { socket })?;
+    message.truncate(len);
+    Ok((fd, message))
+}
+
+/// Duplicate a file descriptor without the CLOEXEC flag set, and close the original.
+fn strip_cloexec(fd: OwnedFd) -> Result<i32> {
+    let raw_fd = fd.as_raw_fd();
+    let dupfd = fcntl(raw_fd, F_DUPFD(MIN_FD)).context(error::DuplicateFdSnafu { fd: raw_fd })?;
+    debug!("duplicated file descriptor {raw_fd} to {dupfd}");
+    Ok(dupfd)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+    use crate::server::Server;
+    use std::os::unix::fs::MetadataExt;
+    use std::process;
+    use uds::UnixSeqpacketListener;
+
+    fn test_socket(name: &str) -> String {
+        format!("pipesys-client-test-{}-{name}", process::id())
+    }
+
+    #[test]
+    fn test_connect_no_server() {
+        let socket = test_socket("none");
+        assert!(matches!(fetch_fd(&socket), Err(Error::Connect { .. })));
+    }
+
+    #[test]
+    fn test_is_listening() {
+        let socket = test_socket("listening");
+        assert!(!is_listening(&socket));
+        let _listener =
+            UnixSeqpacketListener::bind_unix_addr(&socket_addr(&abstract_socket(&socket)).unwrap())
+                .unwrap();
+        assert!(is_listening(&socket));
+    }
+
+    #[test]
+    fn test_timeout_no_server() {
+        let socket = test_socket("timeout");
+        assert!(matches!(
+            fetch_fd_with_timeout(&socket, Duration::from_millis(50)),
+            Err(Error::Timeout { .. })
+        ));
+    }
+
+    #[test]
+    fn test_wait_for() {
+        let socket = test_socket("wait");
+        let handle = thread::spawn({
+            let socket = socket.clone();
+            move || {
+                thread::sleep(Duration::from_millis(100));
+                let listener = UnixSeqpacketListener::bind_unix_addr(
+                    &socket_addr(&abstract_socket(&socket)).unwrap(),
+                )
+                .unwrap();
+                listener.accept_unix_addr().unwrap();
+            }
+        });
+        wait_for(&socket, Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_timeout() {
+        let socket = test_socket("wait-timeout");
+        let start = Instant::now();
+        assert!(matches!(
+            wait_for(&socket, Duration::from_millis(100)),
+            Err(Error::Timeout { .. })
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_fd_count_mismatch() {
+        let socket = test_socket("count");
+        let listener =
+            UnixSeqpacketListener::bind_unix_addr(&socket_addr(&abstract_socket(&socket)).unwrap())
+                .unwrap();
+        let handle = thread::spawn(move || {
+            let file = std::fs::File::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+            let (conn, _) = listener.accept_unix_addr().unwrap();
+            conn.send_fds(b"fds", &[]).unwrap();
+            let (conn, _) = listener.accept_unix_addr().unwrap();
+            conn.send_fds(b"two", &[file.as_raw_fd(), file.as_raw_fd()])
+                .unwrap();
+        });
+
+        let err = fetch_fd(&socket).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::FdCount {
+                    expected: 1,
+                    received: 0,
+                    payload_len: 3
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "Received 0 file descriptors with a 3-byte payload, expected 1"
+        );
+        assert!(matches!(
+            fetch_fd(&socket),
+            Err(Error::FdCount {
+                expected: 1,
+                received: 2,
+                ..
+            })
+        ));
+        handle.join().unwrap();
+    }
+
+    /// Serve one client with a file descriptor and the given message.
+    fn send_message(socket: &str, message: &'static [u8]) -> thread::JoinHandle<()> {
+        let listener =
+            UnixSeqpacketListener::bind_unix_addr(&socket_addr(&abstract_socket(socket)).unwrap())
+                .unwrap();
+        thread::spawn(move || {
+            let (conn, _) = listener.accept_unix_addr().unwrap();
+            let file = std::fs::File::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+            conn.send_fds(message, &[file.as_raw_fd()]).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_fetch_fd_and_message() {
+        let message = b"fifo-write path=/tmp/some/longer/path uid-map=0:1000:1";
+        let socket = test_socket("message");
+        let handle = send_message(&socket, message);
+        let (fd, received) = fetch_fd_and_message(&socket, DEFAULT_MESSAGE_LEN).unwrap();
+        handle.join().unwrap();
+        assert!(fd.as_raw_fd() >= MIN_FD);
+        assert_eq!(received, message);
+
+        // A message that exactly fills the buffer is still complete.
+        let socket = test_socket("message-exact");
+        let handle = send_message(&socket, message);
+        let (_, received) = fetch_fd_and_message(&socket, message.len()).unwrap();
+        handle.join().unwrap();
+        assert_eq!(received, message);
+    }
+
+    #[test]
+    fn test_fetch_fd_message_too_long() {
+        let socket = test_socket("message-long");
+        let handle = send_message(&socket, b"fifo-write");
+        assert!(matches!(
+            fetch_fd_and_message(&socket, 4),
+            Err(Error::MessageTooLong { max_len: 4, .. })
+        ));
+        handle.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_fd_failover() {
+        let primary = test_socket("primary");
+        let backup = test_socket("backup");
+        let server = Server::for_path(&backup, u32::MAX, env!("CARGO_MANIFEST_DIR"))
+            .with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        // Nothing listens on the primary socket, so the client falls through to the backup.
+        let sockets = [primary, backup.clone()];
+        let fd = tokio::task::spawn_blocking(move || {
+            // Wait for the backup server to start listening first.
+            fetch_fd_with_timeout(&backup, Duration::from_secs(5))
+                .map(nix::unistd::close)
+                .unwrap()
+                .unwrap();
+            fetch_fd_from_any(&sockets)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        handle.abort();
+
+        assert!(fd >= MIN_FD);
+        nix::unistd::close(fd).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_fd_from_any_failures() {
+        let sockets: [&str; 0] = [];
+        assert!(matches!(fetch_fd_from_any(&sockets), Err(Error::NoSockets)));
+
+        let sockets = [test_socket("dead-1"), test_socket("dead-2")];
+        assert!(matches!(
+            fetch_fd_from_any(&sockets),
+            Err(Error::Connect { socket, .. }) if socket == sockets[1]
+        ));
+    }
+
+    /// Count the descriptors open in this process that refer to the path.
+    fn open_fds_for(path: &std::path::Path) -> usize {
+        std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|e| std::fs::read_link(e.unwrap().path()).ok())
+            .filter(|target| target == path)
+            .count()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_fd_closes_originals() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let socket = test_socket("owned");
+        let server = Server::for_path(&socket, u32::MAX, &path).with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        tokio::task::spawn_blocking(move || {
+            // Wait for the server to start, then check how many descriptors it holds itself.
+            nix::unistd::close(fetch_fd_with_timeout(&socket, Duration::from_secs(5)).unwrap())
+                .unwrap();
+            let baseline = open_fds_for(&path);
+
+            // Without duplication, the received descriptor is the only new one, and dropping it
+            // closes it.
+            let owned = fetch_owned_fd(&socket).unwrap();
+            assert_eq!(open_fds_for(&path), baseline + 1);
+            drop(owned);
+            assert_eq!(open_fds_for(&path), baseline);
+
+            // With duplication, only the duplicate is left open.
+            let fd = fetch_fd(&socket).unwrap();
+            assert_eq!(open_fds_for(&path), baseline + 1);
+            nix::unistd::close(fd).unwrap();
+            assert_eq!(open_fds_for(&path), baseline);
+        })
+        .await
+        .unwrap();
+        handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_fd_at() {
+        let socket = test_socket("target");
+        let server = Server::for_path(&socket, u32::MAX, env!("CARGO_MANIFEST_DIR"))
+            .with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let output = tokio::task::spawn_blocking(move || {
+            nix::unistd::close(fetch_fd_with_timeout(&socket, Duration::from_secs(5)).unwrap())
+                .unwrap();
+            let fd = fetch_fd_at(&socket, 250).unwrap();
+            assert_eq!(fd, 250);
+
+            // The descriptor is inherited by a child at the requested number.
+            let output = process::Command::new("readlink")
+                .arg("/proc/self/fd/250")
+                .output()
+                .unwrap();
+            nix::unistd::close(fd).unwrap();
+            output
+        })
+        .await
+        .unwrap();
+        handle.abort();
+
+        // The link names the served directory, even if the manifest path goes through symlinks.
+        assert!(output.status.success());
+        let linked = std::fs::metadata(String::from_utf8(output.stdout).unwrap().trim()).unwrap();
+        let served = std::fs::metadata(env!("CARGO_MANIFEST_DIR")).unwrap();
+        assert_eq!((linked.dev(), linked.ino()), (served.dev(), served.ino()));
+    }
+
+    #[test]
+    fn test_fetch_fd_at_invalid_target() {
+        // Targets are checked before connecting, so no server is needed.
+        let socket = test_socket("target-invalid");
+        assert!(matches!(
+            fetch_fd_at(&socket, 1),
+            Err(Error::TargetFdReserved { fd: 1 })
+        ));
+
+        let file = std::fs::File::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let in_use = file.as_raw_fd();
+        assert!(matches!(
+            fetch_fd_at(&socket, in_use),
+            Err(Error::TargetFdInUse { fd }) if fd == in_use
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_fd() {
+        let socket = test_socket("fetch");
+        let server = Server::for_path(&socket, u32::MAX, env!("CARGO_MANIFEST_DIR"))
+            .with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let fd = tokio::task::spawn_blocking(move || {
+            fetch_fd_with_timeout(&socket, Duration::from_secs(5))
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        handle.abort();
+
+        assert!(fd >= MIN_FD);
+        nix::unistd::close(fd).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+use super::Args;
+use anyhow::Result;
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
+use std::io::Write;
+
+/// Print a shell completion script for pipesys.
+#[derive(Debug, Parser)]
+pub(crate) struct Completions {
+    /// The shell to generate completions for.
+    #[clap(value_enum)]
+    shell: Shell,
+}
+
+impl Completions {
+    pub(crate) fn execute(&self) -> Result<()> {
+        generate(self.shell, &mut std::io::stdout());
+        Ok(())
+    }
+}
+
+/// Write the completion script for `shell` covering every pipesys subcommand.
+fn generate(shell: Shell, out: &mut dyn Write) {
+    let mut command = Args::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::ValueEnum;
+
+    #[test]
+    fn test_generate() {
+        for shell in Shell::value_variants() {
+            let mut out = Vec::new();
+            generate(*shell, &mut out);
+            let script = String::from_utf8(out).unwrap();
+            assert!(script.contains("pipesys"), "{shell}");
+            assert!(script.contains("serve"), "{shell}");
+        }
+    }
+}
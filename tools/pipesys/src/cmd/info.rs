@@ -0,0 +1,18 @@
+use anyhow::Result;
+use clap::Parser;
+use pipesys::client::fetch_info;
+
+/// Ask a server what it is configured to serve.
+#[derive(Debug, Parser)]
+pub(crate) struct Info {
+    /// The abstract socket that the server listens on.
+    #[clap(long = "socket")]
+    socket: String,
+}
+
+impl Info {
+    pub(crate) fn execute(&self) -> Result<()> {
+        print!("{}", fetch_info(&self.socket)?);
+        Ok(())
+    }
+}
@@ -1,11 +1,12 @@
-use super::fetch_fd;
-
 use anyhow::{bail, Context, Result};
 use clap::Parser;
 use daemonize::{Daemonize, Outcome};
 use futures::{Future, StreamExt};
 use inotify::{Inotify, WatchMask};
 use log::{error, info, trace};
+use path_absolutize::Absolutize;
+use pipesys::client::fetch_fd_from_any_address;
+use pipesys::socket::SocketAddress;
 use std::path::{Path, PathBuf};
 use std::{env, process};
 use tokio::fs;
@@ -15,39 +16,59 @@ use tokio::fs;
 /// the symlink is removed.
 #[derive(Debug, Parser)]
 pub(crate) struct Link {
-    /// Fetch the file descriptor for a path from this abstract socket.
-    #[clap(long = "fd-socket")]
-    fd_socket: String,
+    /// Fetch the file descriptor for a path from this abstract socket. Repeat the option or
+    /// separate sockets with commas to list backups, which are tried in order if a socket fails.
+    #[clap(
+        long = "fd-socket",
+        value_delimiter = ',',
+        required_unless_present = "fd_socket_paths",
+        conflicts_with = "fd_socket_paths"
+    )]
+    fd_sockets: Vec<String>,
+
+    /// Fetch the file descriptor from a socket file at this path instead, for a server started
+    /// with `--socket-path`. Repeat the option to list backups, which are tried in order.
+    #[clap(long = "fd-socket-path")]
+    fd_socket_paths: Vec<PathBuf>,
 
     /// Create this target path as a symlink to the file descriptor.
     #[clap(long = "target")]
     target: PathBuf,
+
+    /// Resolve a relative target path against this directory. Defaults to the current directory.
+    #[clap(long = "base-dir")]
+    base_dir: Option<PathBuf>,
+
+    /// Send this token to the server before fetching the file descriptor, for servers started
+    /// with `--token`.
+    #[clap(long = "token")]
+    token: Option<String>,
 }
 
 impl Link {
     /// Retrieve the file descriptor, then spawn a background process to create the link so that it
     /// survives the return of the foreground process.
     pub(crate) async fn execute(&self) -> Result<()> {
+        let target = self.resolve_target()?;
+        let target = target.as_path();
+
         // Remove the existing symlink, if present.
-        if self.target.is_symlink() {
-            fs::remove_file(&self.target).await.with_context(|| {
-                format!("failed to clean up symlink for {}", self.target.display())
-            })?;
+        if target.is_symlink() {
+            fs::remove_file(target)
+                .await
+                .with_context(|| format!("failed to clean up symlink for {}", target.display()))?;
         }
 
         // If the target still exists, do not proceed.
-        if self.target.exists() {
-            bail!(
-                "found existing file or directory at {}",
-                self.target.display()
-            )
+        if target.exists() {
+            bail!("found existing file or directory at {}", target.display())
         }
 
         // Retrieve the path file descriptor.
-        let dir_fd = fetch_fd(&self.fd_socket)?;
+        let dir_fd = fetch_fd_from_any_address(&self.fd_addresses(), self.token.as_deref())?;
 
         // Create a log file for the background process.
-        let parent_dir = parent_dir(&self.target)?;
+        let parent_dir = parent_dir(target)?;
         let log_file = parent_dir.join("pipesys-link.log");
         let (stdout, stderr) = output_streams(&log_file).await.with_context(|| {
             format!("failed to create output streams for {}", log_file.display())
@@ -75,7 +96,7 @@ impl Link {
                         .expect("failed to build runtime");
 
                     rt.block_on(async {
-                        if let Err(e) = self.manage_symlink(dir_fd).await {
+                        if let Err(e) = manage_symlink(target, dir_fd).await {
                             error!("failed to manage symlink: {e}");
                             std::process::exit(1);
                         }
@@ -87,43 +108,74 @@ impl Link {
             });
         });
 
-        self.wait_for_symlink().await
+        wait_for_symlink(target).await
     }
 
-    /// The parent (foreground) process waits until the symlink is created.
-    async fn wait_for_symlink(&self) -> Result<()> {
-        let inotify = inotify_init(&self.target, WatchMask::CREATE)?;
-        inotify_wait(inotify, &self.target, &symlink_found).await
+    /// The sockets to fetch the file descriptor from, in order. Only one kind can be given.
+    fn fd_addresses(&self) -> Vec<SocketAddress> {
+        let sockets = self.fd_sockets.iter().cloned().map(SocketAddress::Abstract);
+        let paths = self
+            .fd_socket_paths
+            .iter()
+            .cloned()
+            .map(SocketAddress::Path);
+        sockets.chain(paths).collect()
     }
 
-    /// The child (background) process creates the symlink. Since it will be invalidated when the
-    /// process exits, wait until the external caller removes it before returning.
-    async fn manage_symlink(&self, dir_fd: i32) -> Result<()> {
-        let inotify = inotify_init(&self.target, WatchMask::DELETE)?;
+    /// Resolve the target path against the base directory, so that the result does not depend on
+    /// where pipesys happens to be invoked.
+    fn resolve_target(&self) -> Result<PathBuf> {
+        let base_dir = match &self.base_dir {
+            Some(base_dir) => base_dir.clone(),
+            None => env::current_dir().context("failed to get current directory")?,
+        };
+
+        if !base_dir.is_dir() {
+            bail!("base directory {} does not exist", base_dir.display())
+        }
+
+        let base_dir = base_dir
+            .absolutize()
+            .with_context(|| format!("failed to resolve base directory {}", base_dir.display()))?;
 
-        // Create the symlink.
-        let pid = process::id();
-        let source = format!("/proc/{pid}/fd/{dir_fd}");
-        fs::symlink(&source, &self.target)
-            .await
-            .with_context(|| format!("failed to symlink {source} to {}", self.target.display()))?;
-        info!("symlinked {} to {source}", self.target.display());
+        let target = self
+            .target
+            .absolutize_from(&base_dir)
+            .with_context(|| format!("failed to resolve target {}", self.target.display()))?;
 
-        inotify_wait(inotify, &self.target, &symlink_not_found).await
+        Ok(target.into_owned())
     }
 }
 
-/// Choose a plausible parent directory. For an absolute path, the parent should be available. For
-/// a relative path, assume the current process directory is intended.
+/// The parent (foreground) process waits until the symlink is created.
+async fn wait_for_symlink(target: &Path) -> Result<()> {
+    let inotify = inotify_init(target, WatchMask::CREATE)?;
+    inotify_wait(inotify, target, &symlink_found).await
+}
+
+/// The child (background) process creates the symlink. Since it will be invalidated when the
+/// process exits, wait until the external caller removes it before returning.
+async fn manage_symlink(target: &Path, dir_fd: i32) -> Result<()> {
+    let inotify = inotify_init(target, WatchMask::DELETE)?;
+
+    // Create the symlink.
+    let pid = process::id();
+    let source = format!("/proc/{pid}/fd/{dir_fd}");
+    fs::symlink(&source, target)
+        .await
+        .with_context(|| format!("failed to symlink {source} to {}", target.display()))?;
+    info!("symlinked {} to {source}", target.display());
+
+    inotify_wait(inotify, target, &symlink_not_found).await
+}
+
+/// Find the parent directory for a target. Targets are resolved to absolute paths before use, so
+/// the parent should be available.
 fn parent_dir(path: &Path) -> Result<PathBuf> {
-    if path.is_absolute() {
-        if let Some(parent) = path.parent() {
-            return Ok(parent.to_path_buf());
-        }
-    } else if let Ok(current) = env::current_dir() {
-        return Ok(current);
-    }
-    bail!("failed to find parent directory for {}", path.display());
+    path.parent()
+        .filter(|_| path.is_absolute())
+        .map(Path::to_path_buf)
+        .with_context(|| format!("failed to find parent directory for {}", path.display()))
 }
 
 /// Helper function to create stdout and stderr streams with the same file storage.
@@ -206,3 +258,82 @@ where
 
     bail!("failed to observe event for {}", path.display())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resolve_relative_target() {
+        let base_dir = TempDir::new().unwrap();
+        let link = Link::parse_from([
+            "link",
+            "--fd-socket=socket",
+            "--target=output/../bypass",
+            "--base-dir",
+            base_dir.path().to_str().unwrap(),
+        ]);
+
+        let target = link.resolve_target().unwrap();
+        assert_eq!(target, base_dir.path().join("bypass"));
+        assert_eq!(parent_dir(&target).unwrap(), base_dir.path());
+    }
+
+    #[test]
+    fn test_resolve_absolute_target() {
+        let base_dir = TempDir::new().unwrap();
+        let link = Link::parse_from([
+            "link",
+            "--fd-socket=socket",
+            "--target=/output",
+            "--base-dir",
+            base_dir.path().to_str().unwrap(),
+        ]);
+
+        assert_eq!(link.resolve_target().unwrap(), Path::new("/output"));
+    }
+
+    #[test]
+    fn test_resolve_missing_base_dir() {
+        let base_dir = TempDir::new().unwrap();
+        let missing = base_dir.path().join("missing");
+        let link = Link::parse_from([
+            "link",
+            "--fd-socket=socket",
+            "--target=output",
+            "--base-dir",
+            missing.to_str().unwrap(),
+        ]);
+
+        let err = link.resolve_target().unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn test_fd_socket_paths() {
+        let link = Link::parse_from([
+            "link",
+            "--fd-socket-path=/run/a.sock",
+            "--fd-socket-path=/run/b.sock",
+            "--target=output",
+        ]);
+        assert_eq!(
+            link.fd_addresses(),
+            [
+                SocketAddress::Path("/run/a.sock".into()),
+                SocketAddress::Path("/run/b.sock".into())
+            ]
+        );
+
+        // Only one kind of socket can be given.
+        assert!(Link::try_parse_from([
+            "link",
+            "--fd-socket=socket",
+            "--fd-socket-path=/run/a.sock",
+            "--target=output",
+        ])
+        .is_err());
+        assert!(Link::try_parse_from(["link", "--target=output"]).is_err());
+    }
+}
@@ -1,19 +1,21 @@
+mod completions;
+mod info;
 #[cfg_attr(target_os = "linux", path = "link.rs")]
 #[cfg_attr(not(target_os = "linux"), path = "non_linux_link.rs")]
 mod link;
+mod wait;
 
+use self::completions::Completions;
+use self::info::Info;
 use self::link::Link;
+use self::wait::Wait;
 use pipesys::server::Server as Serve;
 
-use anyhow::Result;
-#[cfg(target_os = "linux")]
-use anyhow::{ensure, Context};
+use anyhow::{Context, Result};
 use clap::Parser;
-#[cfg(target_os = "linux")]
-use log::debug;
 use log::LevelFilter;
-#[cfg(target_os = "linux")]
-use nix::fcntl::{fcntl, F_DUPFD};
+use std::future::Future;
+use tokio::signal::unix::{signal, SignalKind};
 
 const DEFAULT_LEVEL_FILTER: LevelFilter = LevelFilter::Info;
 
@@ -38,16 +40,36 @@ pub(crate) enum Subcommand {
 
     /// Link a directory file descriptor to the target path.
     Link(Link),
+
+    /// Print what a server started with `--serve-info` is serving, without fetching it.
+    Info(Info),
+
+    /// Wait until a server is listening on a socket.
+    Wait(Wait),
+
+    /// Print a shell completion script.
+    Completions(Completions),
 }
 
 /// Entrypoint for the `pipesys` command line program.
 pub(super) async fn run(args: Args) -> Result<()> {
     match args.subcommand {
-        Subcommand::Serve(serve_args) => serve_args.serve().await,
+        Subcommand::Serve(serve_args) => Ok(serve_args.serve_until(sigterm()?).await?),
         Subcommand::Link(link_args) => link_args.execute().await,
+        Subcommand::Info(info_args) => info_args.execute(),
+        Subcommand::Wait(wait_args) => wait_args.execute(),
+        Subcommand::Completions(completions_args) => completions_args.execute(),
     }
 }
 
+/// Completes when the process receives SIGTERM, so that the server can finish its sends and stop.
+fn sigterm() -> Result<impl Future<Output = ()>> {
+    let mut sigterm = signal(SignalKind::terminate()).context("failed to listen for SIGTERM")?;
+    Ok(async move {
+        sigterm.recv().await;
+    })
+}
+
 /// use `level` if present, or else use `RUST_LOG` if present, or else use a default.
 pub(super) fn init_logger(level: Option<LevelFilter>) {
     match (std::env::var(env_logger::DEFAULT_FILTER_ENV).ok(), level) {
@@ -66,51 +88,3 @@ pub(super) fn init_logger(level: Option<LevelFilter>) {
         }
     }
 }
-
-// Don't accept file descriptors 0, 1, or 2 since those correspond to the well-known stdin, stdout,
-// and stderr which could confuse the calling process or its children.
-#[cfg(target_os = "linux")]
-const MIN_FD: i32 = 3;
-
-/// Helper function to retrieve a file descriptor via an abstract socket.
-#[cfg(target_os = "linux")]
-fn fetch_fd(socket: &str) -> Result<i32> {
-    let addr = uds::UnixSocketAddr::from_abstract(socket.as_bytes())
-        .with_context(|| format!("failed to create socket {}", socket))?;
-    let client = uds::UnixSeqpacketConn::connect_unix_addr(&addr)
-        .with_context(|| format!("failed to connect to socket {}", socket))?;
-
-    let mut fd_buf = [-1; 1];
-    let (_, _, fds) = client
-        .recv_fds(&mut [0u8; 1], &mut fd_buf)
-        .with_context(|| format!("failed to receive file descriptor from socket {}", socket))?;
-
-    ensure!(
-        fds == 1,
-        format!("received {fds} file descriptors, expected 1")
-    );
-
-    let fd = fd_buf
-        .first()
-        .filter(|fd| **fd >= MIN_FD)
-        .with_context(|| {
-            format!(
-                "did not receive valid file descriptor from socket {}",
-                socket
-            )
-        })?;
-
-    let dupfd =
-        duplicate_fd(*fd).with_context(|| format!("failed to duplicate file descriptor {fd}"))?;
-    debug!("duplicated file descriptor {fd} to {dupfd}");
-
-    Ok(dupfd)
-}
-
-/// Duplicate file descriptors without the CLOEXEC flag set.
-#[cfg(target_os = "linux")]
-fn duplicate_fd(fd: i32) -> Result<i32> {
-    let newfd = fcntl(fd, F_DUPFD(MIN_FD))
-        .with_context(|| format!("failed to duplicate file descriptor {fd}"))?;
-    Ok(newfd)
-}
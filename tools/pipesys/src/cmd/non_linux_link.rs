@@ -7,13 +7,33 @@ use std::path::PathBuf;
 /// the symlink is removed.
 #[derive(Debug, Parser)]
 pub(crate) struct Link {
-    /// Fetch the file descriptor for a path from this abstract socket.
-    #[clap(long = "fd-socket")]
-    fd_socket: String,
+    /// Fetch the file descriptor for a path from this abstract socket. Repeat the option or
+    /// separate sockets with commas to list backups, which are tried in order if a socket fails.
+    #[clap(
+        long = "fd-socket",
+        value_delimiter = ',',
+        required_unless_present = "fd_socket_paths",
+        conflicts_with = "fd_socket_paths"
+    )]
+    fd_sockets: Vec<String>,
+
+    /// Fetch the file descriptor from a socket file at this path instead, for a server started
+    /// with `--socket-path`. Repeat the option to list backups, which are tried in order.
+    #[clap(long = "fd-socket-path")]
+    fd_socket_paths: Vec<PathBuf>,
 
     /// Create this target path as a symlink to the file descriptor.
     #[clap(long = "target")]
     target: PathBuf,
+
+    /// Resolve a relative target path against this directory. Defaults to the current directory.
+    #[clap(long = "base-dir")]
+    base_dir: Option<PathBuf>,
+
+    /// Send this token to the server before fetching the file descriptor, for servers started
+    /// with `--token`.
+    #[clap(long = "token")]
+    token: Option<String>,
 }
 
 impl Link {
@@ -0,0 +1,22 @@
+use anyhow::Result;
+use clap::Parser;
+use pipesys::client::wait_for;
+use std::time::Duration;
+
+/// Wait for a server to start listening, for scripts that start a client after the server.
+#[derive(Debug, Parser)]
+pub(crate) struct Wait {
+    /// The abstract socket that the server listens on.
+    #[clap(long = "socket")]
+    socket: String,
+
+    /// Give up after this many seconds.
+    #[clap(long = "timeout", default_value = "30")]
+    timeout: u64,
+}
+
+impl Wait {
+    pub(crate) fn execute(&self) -> Result<()> {
+        Ok(wait_for(&self.socket, Duration::from_secs(self.timeout))?)
+    }
+}
@@ -0,0 +1,199 @@
+use snafu::Snafu;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The ways that serving or fetching a file descriptor can fail.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+#[non_exhaustive]
+pub enum Error {
+    #[snafu(display("Failed to accept connection on socket {socket}: {source}"))]
+    Accept {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to bind to socket {socket}: {source}"))]
+    Bind {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to connect to socket {socket}: {source}"))]
+    Connect {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to duplicate file descriptor {fd}: {source}"))]
+    DuplicateFd { fd: i32, source: nix::Error },
+
+    #[snafu(display("Token must not be empty"))]
+    EmptyToken,
+
+    #[snafu(display(
+        "Received {received} file descriptors with a {payload_len}-byte payload, expected {expected}"
+    ))]
+    FdCount {
+        expected: usize,
+        received: usize,
+        payload_len: usize,
+    },
+
+    #[snafu(display("Failed to create FIFO {}: {source}", path.display()))]
+    FifoCreate { path: PathBuf, source: nix::Error },
+
+    #[snafu(display("Invalid FIFO '{spec}', expected <PATH>:read or <PATH>:write"))]
+    FifoSpec { spec: String },
+
+    #[snafu(display("Failed to query or update flags for {}: {source}", path.display()))]
+    Flags { path: PathBuf, source: nix::Error },
+
+    #[snafu(display("Invalid server info '{line}'"))]
+    InfoParse { line: String },
+
+    #[snafu(display("Did not receive a valid file descriptor from socket {socket}"))]
+    InvalidFd { socket: String },
+
+    #[snafu(display("Invalid number of seconds '{arg}': {source}"))]
+    InvalidSeconds {
+        arg: String,
+        source: std::num::ParseIntError,
+    },
+
+    #[snafu(display("File descriptor {fd} is not a listening socket"))]
+    NotListening { fd: i32 },
+
+    #[snafu(display("Message from socket {socket} is longer than {max_len} bytes"))]
+    MessageTooLong { socket: String, max_len: usize },
+
+    #[snafu(display("No path, FIFO, or listening socket to serve"))]
+    MissingPath,
+
+    #[snafu(display("No sockets to fetch a file descriptor from"))]
+    NoSockets,
+
+    #[snafu(display("{} exists and is not a FIFO", path.display()))]
+    NotAFifo { path: PathBuf },
+
+    #[snafu(display(
+        "{} resolves to {}, which is not under any allowed root",
+        path.display(),
+        resolved.display()
+    ))]
+    NotAllowed { path: PathBuf, resolved: PathBuf },
+
+    #[snafu(display("{} was not opened read-only", path.display()))]
+    NotReadOnly { path: PathBuf },
+
+    #[snafu(display("Failed to open {}: {source}", path.display()))]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to obtain peer credentials on socket {socket}: {source}"))]
+    PeerCredentials {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to find where {} resolves to: {source}", path.display()))]
+    ResolvePath {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to receive file descriptor from socket {socket}: {source}"))]
+    Receive {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "Server on socket {socket} refused to send its file descriptor, which was already sent as many times as allowed"
+    ))]
+    Refused { socket: String },
+
+    #[snafu(display("Failed to send file descriptor over socket {socket}: {source}"))]
+    Send {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to send token over socket {socket}: {source}"))]
+    SendToken {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to create socket {socket}: {source}"))]
+    SocketAddress {
+        socket: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("'{}' exists and is not a socket", path.display()))]
+    NotSocket { path: PathBuf },
+
+    #[snafu(display("Failed to remove stale socket '{}': {}", path.display(), source))]
+    RemoveStaleSocket {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Socket {socket} is already in use by another server"))]
+    SocketInUse { socket: String },
+
+    #[snafu(display("Failed to read metadata for {}: {source}", path.display()))]
+    Stat {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("File descriptor {fd} is already in use"))]
+    TargetFdInUse { fd: i32 },
+
+    #[snafu(display("File descriptor {fd} is reserved for stdin, stdout, or stderr"))]
+    TargetFdReserved { fd: i32 },
+
+    #[snafu(display("Timed out after {timeout:?} waiting for socket {socket}"))]
+    Timeout { socket: String, timeout: Duration },
+
+    #[snafu(display(
+        "Server on socket {socket} rejected the token, or did not receive it in time"
+    ))]
+    TokenRejected { socket: String },
+
+    #[snafu(display(
+        "Cannot send {count} file descriptors over socket {socket}, the kernel limit is {max}"
+    ))]
+    TooManyFds {
+        socket: String,
+        count: usize,
+        max: usize,
+    },
+
+    #[snafu(display(
+        "Server on socket {socket} could not open what it serves for this client, see its log"
+    ))]
+    Unavailable { socket: String },
+
+    #[snafu(display("Invalid UID map '{spec}', expected <INSIDE>:<OUTSIDE>:<COUNT>"))]
+    UidMapSpec { spec: String },
+
+    #[snafu(display("{} is not a directory or regular file", path.display()))]
+    UnsupportedFileType { path: PathBuf },
+
+    #[snafu(display("Peer with PID {pid:?}, UID {uid} and GID {gid:?} is not authorized"))]
+    Unauthorized {
+        pid: Option<u32>,
+        uid: u32,
+        gid: Option<u32>,
+    },
+
+    #[snafu(display("Client on socket {socket} did not send the expected token"))]
+    WrongToken { socket: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,186 @@
+use crate::error::{self, Error, Result};
+use crate::server::UidMap;
+use snafu::{ensure, OptionExt};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// What a server is configured to serve, as reported to `pipesys info`. Fetching it does not
+/// send a file descriptor, so it does not affect the clients of the server.
+///
+/// The info is sent as text, with one `<KEY>: <VALUE>` line per item.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The paths that the server sends file descriptors for.
+    pub paths: Vec<ServedPath>,
+    /// The UID that clients must have, if any.
+    pub client_uid: Option<u32>,
+    /// The effective GID that clients must have, if any.
+    pub client_gid: Option<u32>,
+    /// The mappings that client UIDs are translated through before they are compared.
+    pub uid_maps: Vec<UidMap>,
+}
+
+/// A path that a server sends file descriptors for, and what kind of file it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServedPath {
+    pub path: PathBuf,
+    pub kind: PathKind,
+}
+
+/// The kind of file behind a served path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathKind {
+    File,
+    Directory,
+    FifoRead,
+    FifoWrite,
+    /// A listening socket, which has no path of its own. The path is the server's descriptor.
+    ListeningSocket,
+    /// The path could not be examined, for instance because it was removed.
+    Unknown,
+}
+
+impl PathKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            PathKind::File => "file",
+            PathKind::Directory => "directory",
+            PathKind::FifoRead => "fifo-read",
+            PathKind::FifoWrite => "fifo-write",
+            PathKind::ListeningSocket => "listening-socket",
+            PathKind::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for PathKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PathKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            PathKind::File,
+            PathKind::Directory,
+            PathKind::FifoRead,
+            PathKind::FifoWrite,
+            PathKind::ListeningSocket,
+            PathKind::Unknown,
+        ]
+        .into_iter()
+        .find(|kind| kind.as_str() == s)
+        .context(error::InfoParseSnafu { line: s })
+    }
+}
+
+impl fmt::Display for ServerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ServedPath { path, kind } in &self.paths {
+            writeln!(f, "path: {kind} {}", path.display())?;
+        }
+        if let Some(client_uid) = self.client_uid {
+            writeln!(f, "client-uid: {client_uid}")?;
+        }
+        if let Some(client_gid) = self.client_gid {
+            writeln!(f, "client-gid: {client_gid}")?;
+        }
+        for uid_map in &self.uid_maps {
+            writeln!(f, "uid-map: {uid_map}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ServerInfo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut paths = Vec::new();
+        let mut client_uid = None;
+        let mut client_gid = None;
+        let mut uid_maps = Vec::new();
+        for line in s.lines() {
+            let parse_error = || error::InfoParseSnafu { line };
+            let (key, value) = line.split_once(": ").with_context(parse_error)?;
+            match key {
+                "path" => {
+                    let (kind, path) = value.split_once(' ').with_context(parse_error)?;
+                    paths.push(ServedPath {
+                        path: path.into(),
+                        kind: kind.parse()?,
+                    });
+                }
+                "client-uid" => client_uid = Some(value.parse().ok().with_context(parse_error)?),
+                "client-gid" => client_gid = Some(value.parse().ok().with_context(parse_error)?),
+                "uid-map" => uid_maps.push(value.parse()?),
+                _ => return parse_error().fail(),
+            }
+        }
+        // A server always expects a UID, a GID, or both.
+        ensure!(
+            client_uid.is_some() || client_gid.is_some(),
+            error::InfoParseSnafu { line: s }
+        );
+        Ok(Self {
+            paths,
+            client_uid,
+            client_gid,
+            uid_maps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_server_info_round_trip() {
+        let info = ServerInfo {
+            paths: vec![ServedPath {
+                path: "/tmp/dir with spaces".into(),
+                kind: PathKind::Directory,
+            }],
+            client_uid: Some(0),
+            client_gid: None,
+            uid_maps: vec![UidMap::new(0, 1000, 1)],
+        };
+        let text = info.to_string();
+        assert_eq!(
+            text,
+            "path: directory /tmp/dir with spaces\nclient-uid: 0\nuid-map: 0:1000:1\n"
+        );
+        assert_eq!(text.parse::<ServerInfo>().unwrap(), info);
+
+        let info = ServerInfo {
+            client_uid: None,
+            client_gid: Some(100),
+            ..info
+        };
+        let text = info.to_string();
+        assert!(text.contains("\nclient-gid: 100\n"), "{text}");
+        assert!(!text.contains("client-uid"), "{text}");
+        assert_eq!(text.parse::<ServerInfo>().unwrap(), info);
+    }
+
+    #[test]
+    fn test_server_info_invalid() {
+        for text in [
+            "path: socket /tmp/x\nclient-uid: 0\n",
+            "client-uid: root\n",
+            "client-gid: wheel\n",
+            "clients: 0\n",
+            "path: file /tmp/x\n",
+        ] {
+            assert!(
+                matches!(text.parse::<ServerInfo>(), Err(Error::InfoParse { .. })),
+                "{text}"
+            );
+        }
+    }
+}
@@ -1,3 +1,13 @@
+#[cfg_attr(target_os = "linux", path = "client.rs")]
+#[cfg_attr(not(target_os = "linux"), path = "non_linux_client.rs")]
+pub mod client;
+pub mod error;
+pub mod info;
 #[cfg_attr(target_os = "linux", path = "server.rs")]
 #[cfg_attr(not(target_os = "linux"), path = "non_linux_server.rs")]
 pub mod server;
+pub mod socket;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
+pub use error::{Error, Result};
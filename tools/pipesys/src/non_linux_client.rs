@@ -0,0 +1,67 @@
+use crate::error::Result;
+use crate::info::ServerInfo;
+use crate::socket::SocketAddress;
+use std::os::fd::OwnedFd;
+use std::time::Duration;
+
+pub const DEFAULT_MESSAGE_LEN: usize = 4096;
+
+/// Fail loudly on non-Linux.
+pub fn fetch_fd(_: &str) -> Result<i32> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_fd_at(_: &str, _: i32) -> Result<i32> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_owned_fd(_: &str) -> Result<OwnedFd> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_owned_fd_with_token(_: &str, _: &str) -> Result<OwnedFd> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_fd_and_message(_: &str, _: usize) -> Result<(OwnedFd, Vec<u8>)> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_fd_from_any<S: AsRef<str>>(_: &[S]) -> Result<i32> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_fd_from_any_with_token<S: AsRef<str>>(_: &[S], _: Option<&str>) -> Result<i32> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_fd_from_any_address(_: &[SocketAddress], _: Option<&str>) -> Result<i32> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_fd_with_timeout(_: &str, _: Duration) -> Result<i32> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn wait_for(_: &str, _: Duration) -> Result<()> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn is_listening(_: &str) -> bool {
+    unimplemented!("pipesys is not supported on this operating system");
+}
+
+/// Fail loudly on non-Linux.
+pub fn fetch_info(_: &str) -> Result<ServerInfo> {
+    unimplemented!("pipesys is not supported on this operating system");
+}
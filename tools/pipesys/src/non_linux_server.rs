@@ -1,21 +1,219 @@
-use anyhow::Result;
+use crate::error::{self, Error, Result};
 use clap::Parser;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fmt;
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-/// Serve the file descriptor for a path over an abstract UNIX domain socket.
+/// Serve the file descriptor for a path over a UNIX domain socket, abstract unless a socket file is
+/// given.
 #[derive(Clone, Debug, Parser)]
 pub struct Server {
     /// Listen on this abstract socket.
-    #[clap(long = "socket")]
-    socket: String,
+    #[clap(
+        long = "socket",
+        required_unless_present = "socket_path",
+        conflicts_with = "socket_path"
+    )]
+    socket: Option<String>,
+
+    /// Listen on a socket file at this path instead of an abstract socket, for clients that don't
+    /// share the server's network namespace, such as builds in a container that doesn't share the
+    /// host's. A socket file left by a server that is no longer running is replaced.
+    #[clap(long = "socket-path")]
+    socket_path: Option<PathBuf>,
 
     /// Expect clients with this UID.
-    #[clap(long = "client-uid")]
-    client_uid: u32,
+    #[clap(long = "client-uid", required_unless_present = "client_gid")]
+    client_uid: Option<u32>,
+
+    /// Expect clients with this effective GID, such as a group shared by builds that run as
+    /// different users. If a UID is also given, clients must have both. The GID is compared as the
+    /// server sees it, without translation through `--uid-map`.
+    #[clap(long = "client-gid")]
+    client_gid: Option<u32>,
 
     /// Send file descriptor for this path.
-    #[clap(long = "path")]
+    #[clap(
+        long = "path",
+        required_unless_present_any = ["fifo", "listen_fd"],
+        conflicts_with = "fifo"
+    )]
+    path: Option<PathBuf>,
+
+    /// Create a FIFO at this path if needed, and send each client a file descriptor of its own for
+    /// one end of it, once the other end has been opened. Specified as `<PATH>:read` or
+    /// `<PATH>:write`.
+    #[clap(long = "fifo")]
+    fifo: Option<Fifo>,
+
+    /// Send this inherited file descriptor, which must be a socket that is already listening.
+    /// Use this to let a build accept connections on a socket that the host created, such as one
+    /// that exposes a service during the build. The socket's flags other than close-on-exec are
+    /// shared with every copy of the descriptor, and are left as the host set them.
+    #[clap(long = "listen-fd", conflicts_with_all = ["path", "fifo"])]
+    listen_fd: Option<i32>,
+
+    /// Open a file at `path` for reading and writing, so that clients can write through the
+    /// descriptor. A directory can't be opened for writing, so it is opened with `O_DIRECTORY` as
+    /// usual, and clients can write under it as far as its permissions and mount allow. By
+    /// default, files are opened read-only.
+    #[clap(long = "writable", conflicts_with_all = ["fifo", "listen_fd"])]
+    writable: bool,
+
+    /// Only serve paths that resolve to a location under this directory, after following
+    /// symlinks. May be repeated to allow several directories. By default, any path is served.
+    #[clap(long = "allowed-root")]
+    allowed_roots: Vec<PathBuf>,
+
+    /// Translate client UIDs through this mapping before comparing them to `client_uid`, given as
+    /// `<INSIDE>:<OUTSIDE>:<COUNT>` like a line of `/proc/<pid>/uid_map`. Use this when clients run
+    /// in a user namespace that the server does not, such as builds under rootless docker or
+    /// podman, where UID 0 in the container is the invoking user's UID on the host; for example,
+    /// `--client-uid 0 --uid-map 0:1000:1`. Clients outside every mapping are rejected.
+    #[clap(long = "uid-map")]
+    uid_maps: Vec<UidMap>,
+
+    /// Stop serving after this many seconds without a new connection. By default, the server runs
+    /// until it is killed.
+    #[clap(long = "idle-timeout", value_parser = parse_seconds)]
+    idle_timeout: Option<Duration>,
+
+    /// When the server stops, wait up to this many seconds for file descriptors that are still
+    /// being sent to clients.
+    #[clap(long = "drain-timeout", value_parser = parse_seconds, default_value = "5")]
+    drain_timeout: Duration,
+
+    /// Open the path again for each client, so that clients see the current file or directory
+    /// if it was replaced after the server started. Clients that connect while the path is
+    /// missing get an error, and the server keeps running.
+    #[clap(long = "keep-alive")]
+    keep_alive: bool,
+
+    /// Log the credentials of each client that connects, including the PID exactly as the kernel
+    /// reported it. PIDs are only meaningful in the server's own PID namespace.
+    #[clap(long = "log-peers")]
+    log_peers: bool,
+
+    /// Also describe what is served on the socket `<SOCKET>-info`, for `pipesys info`. Clients of
+    /// that socket get the path, its type, and the expected client UID, but no file descriptor,
+    /// and they do not count as connections for the idle timeout.
+    #[clap(long = "serve-info")]
+    serve_info: bool,
+
+    /// Send the file descriptor to at most this many clients, and refuse any after that. Use this
+    /// for a descriptor that should only be handed out once, such as a secret. Other servers are
+    /// not affected. Every client that connects counts, including `pipesys wait`. By default,
+    /// there is no limit.
+    #[clap(long = "max-uses")]
+    max_uses: Option<usize>,
+
+    /// Stop serving once this file exists, so that the build can end the server when it is done
+    /// with the file descriptor. The server checks for the file a few times a second, and stops
+    /// right away if it already exists. Whichever comes first of this and the idle timeout stops
+    /// the server.
+    #[clap(long = "stop-file")]
+    stop_file: Option<PathBuf>,
+
+    /// Only serve clients that send this token when they connect, in addition to having the
+    /// expected UID. Use this when builds that share a UID on the same host should not be able to
+    /// fetch each other's file descriptors. Clients pass the same value to `pipesys link --token`.
+    #[clap(long = "token")]
+    token: Option<String>,
+}
+
+/// The credentials of a client process, as reported by the kernel when it connected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// The process ID of the client in the server's PID namespace, if it is visible there.
+    pub pid: Option<u32>,
+    /// The process ID exactly as the kernel reported it, if any.
+    pub raw_pid: Option<u32>,
+    /// The effective user ID of the client.
+    pub uid: u32,
+    /// The effective group ID of the client, if known.
+    pub gid: Option<u32>,
+}
+
+/// A named pipe, and the end of it to serve to clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fifo {
     path: PathBuf,
+    end: FifoEnd,
+}
+
+/// One end of a named pipe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FifoEnd {
+    Read,
+    Write,
+}
+
+impl FromStr for Fifo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, end) = s
+            .rsplit_once(':')
+            .filter(|(path, _)| !path.is_empty())
+            .context(error::FifoSpecSnafu { spec: s })?;
+        let end = match end {
+            "read" | "r" => FifoEnd::Read,
+            "write" | "w" => FifoEnd::Write,
+            _ => return error::FifoSpecSnafu { spec: s }.fail(),
+        };
+        Ok(Self {
+            path: path.into(),
+            end,
+        })
+    }
+}
+
+/// A range of user IDs in the namespace where clients run, and the IDs they correspond to in the
+/// server's namespace, in the same form as `/proc/<pid>/uid_map`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UidMap {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+impl UidMap {
+    pub fn new(inside: u32, outside: u32, count: u32) -> Self {
+        Self {
+            inside,
+            outside,
+            count,
+        }
+    }
+}
+
+impl fmt::Display for UidMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.inside, self.outside, self.count)
+    }
+}
+
+impl FromStr for UidMap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields = s
+            .split(':')
+            .map(|f| f.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()
+            .context(error::UidMapSpecSnafu { spec: s })?;
+        let [inside, outside, count] = fields[..] else {
+            return error::UidMapSpecSnafu { spec: s }.fail();
+        };
+        ensure!(
+            count > 0 && inside.checked_add(count - 1).is_some(),
+            error::UidMapSpecSnafu { spec: s }
+        );
+        Ok(Self::new(inside, outside, count))
+    }
 }
 
 impl Server {
@@ -27,7 +225,113 @@ impl Server {
         unimplemented!("pipesys is not supported on this operating system");
     }
 
+    pub fn for_path_on_socket_path<S, P>(_: S, _: u32, _: P) -> Self
+    where
+        S: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn for_writable_paths<S, P>(_: S, _: u32, _: P) -> Self
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn for_fifo<S, P>(_: S, _: u32, _: P, _: FifoEnd) -> Self
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn for_listening_socket<S>(_: S, _: u32, _: i32) -> Self
+    where
+        S: AsRef<str>,
+    {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_authorizer<F>(self, _: F) -> Self
+    where
+        F: Fn(&PeerCredentials) -> bool + Send + Sync + 'static,
+    {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_allowed_root<P: AsRef<Path>>(self, _: P) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_client_gid(self, _: u32) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_uid_map(self, _: UidMap) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_idle_timeout(self, _: Duration) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_drain_timeout(self, _: Duration) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_keep_alive(self, _: bool) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_log_peers(self, _: bool) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_serve_info(self, _: bool) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_max_uses(self, _: usize) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_stop_file<P: AsRef<Path>>(self, _: P) -> Self {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn with_token<T: AsRef<str>>(self, _: T) -> Result<Self> {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
     pub async fn serve(&self) -> Result<()> {
         unimplemented!("pipesys is not supported on this operating system");
     }
+
+    pub async fn serve_until<F>(&self, _: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub fn bind(&self) -> Result<Listener> {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+
+    pub async fn serve_listener(&self, _: Listener) -> Result<()> {
+        unimplemented!("pipesys is not supported on this operating system");
+    }
+}
+
+/// A socket that a server has bound, but is not serving yet.
+pub struct Listener;
+
+/// Parse a number of seconds from the command line.
+fn parse_seconds(arg: &str) -> Result<Duration> {
+    let seconds = arg.parse().context(error::InvalidSecondsSnafu { arg })?;
+    Ok(Duration::from_secs(seconds))
 }
@@ -1,25 +1,350 @@
-use anyhow::{Context, Result};
+use crate::client::socket_addr;
+use crate::error::{self, Error, Result};
+use crate::info::{PathKind, ServedPath, ServerInfo};
+use crate::socket::SocketAddress;
 use clap::Parser;
-use log::warn;
-use std::fs::OpenOptions;
-use std::os::fd::AsRawFd;
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::socket::{getsockopt, sockopt};
+use nix::sys::stat::Mode;
+use nix::sys::statvfs::{fstatvfs, FsFlags};
+use nix::unistd::mkfifo;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
-use uds::{tokio::UnixSeqpacketListener, UnixSocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
+use uds::tokio::{UnixSeqpacketConn, UnixSeqpacketListener};
+use uds::UnixSocketAddr;
 
-/// Serve the file descriptor for a path over an abstract UNIX domain socket.
+/// The most file descriptors that the kernel passes in one `SCM_RIGHTS` message, from
+/// `SCM_MAX_FD` in the kernel source. Sending more fails with `EINVAL`.
+const SCM_MAX_FD: usize = 253;
+
+/// The message sent without a file descriptor to clients that are over the server's limit.
+pub(crate) const REFUSED_MESSAGE: &[u8] = b"refused";
+
+/// The message sent without a file descriptor to clients when the path could not be opened again
+/// for them, such as when it was removed after the server started.
+pub(crate) const UNAVAILABLE_MESSAGE: &[u8] = b"unavailable";
+
+/// The message sent without a file descriptor to clients that did not send the server's token.
+pub(crate) const REJECTED_MESSAGE: &[u8] = b"rejected";
+
+/// How long to wait for a client to send the token, when the server requires one.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check whether the stop file exists.
+const STOP_FILE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for unfinished sends when the server stops, unless configured otherwise.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the file descriptor for a path over a UNIX domain socket, abstract unless a socket file is
+/// given.
 #[derive(Clone, Debug, Parser)]
 pub struct Server {
     /// Listen on this abstract socket.
-    #[clap(long = "socket")]
-    socket: String,
+    #[clap(
+        long = "socket",
+        required_unless_present = "socket_path",
+        conflicts_with = "socket_path"
+    )]
+    socket: Option<String>,
+
+    /// Listen on a socket file at this path instead of an abstract socket, for clients that don't
+    /// share the server's network namespace, such as builds in a container that doesn't share the
+    /// host's. A socket file left by a server that is no longer running is replaced.
+    #[clap(long = "socket-path")]
+    socket_path: Option<PathBuf>,
 
     /// Expect clients with this UID.
-    #[clap(long = "client-uid")]
-    client_uid: u32,
+    #[clap(long = "client-uid", required_unless_present = "client_gid")]
+    client_uid: Option<u32>,
+
+    /// Expect clients with this effective GID, such as a group shared by builds that run as
+    /// different users. If a UID is also given, clients must have both. The GID is compared as the
+    /// server sees it, without translation through `--uid-map`.
+    #[clap(long = "client-gid")]
+    client_gid: Option<u32>,
 
     /// Send file descriptor for this path.
-    #[clap(long = "path")]
+    #[clap(
+        long = "path",
+        required_unless_present_any = ["fifo", "listen_fd"],
+        conflicts_with = "fifo"
+    )]
+    path: Option<PathBuf>,
+
+    /// Create a FIFO at this path if needed, and send each client a file descriptor of its own for
+    /// one end of it, once the other end has been opened. Specified as `<PATH>:read` or
+    /// `<PATH>:write`.
+    #[clap(long = "fifo")]
+    fifo: Option<Fifo>,
+
+    /// Send this inherited file descriptor, which must be a socket that is already listening.
+    /// Use this to let a build accept connections on a socket that the host created, such as one
+    /// that exposes a service during the build. The socket's flags other than close-on-exec are
+    /// shared with every copy of the descriptor, and are left as the host set them.
+    #[clap(long = "listen-fd", conflicts_with_all = ["path", "fifo"])]
+    listen_fd: Option<RawFd>,
+
+    /// Open a file at `path` for reading and writing, so that clients can write through the
+    /// descriptor. A directory can't be opened for writing, so it is opened with `O_DIRECTORY` as
+    /// usual, and clients can write under it as far as its permissions and mount allow. By
+    /// default, files are opened read-only.
+    #[clap(long = "writable", conflicts_with_all = ["fifo", "listen_fd"])]
+    writable: bool,
+
+    /// Only serve paths that resolve to a location under this directory, after following
+    /// symlinks. May be repeated to allow several directories. By default, any path is served.
+    #[clap(long = "allowed-root")]
+    allowed_roots: Vec<PathBuf>,
+
+    /// Translate client UIDs through this mapping before comparing them to `client_uid`, given as
+    /// `<INSIDE>:<OUTSIDE>:<COUNT>` like a line of `/proc/<pid>/uid_map`. Use this when clients run
+    /// in a user namespace that the server does not, such as builds under rootless docker or
+    /// podman, where UID 0 in the container is the invoking user's UID on the host; for example,
+    /// `--client-uid 0 --uid-map 0:1000:1`. Clients outside every mapping are rejected.
+    #[clap(long = "uid-map")]
+    uid_maps: Vec<UidMap>,
+
+    /// Stop serving after this many seconds without a new connection. By default, the server runs
+    /// until it is killed.
+    #[clap(long = "idle-timeout", value_parser = parse_seconds)]
+    idle_timeout: Option<Duration>,
+
+    /// When the server stops, wait up to this many seconds for file descriptors that are still
+    /// being sent to clients.
+    #[clap(long = "drain-timeout", value_parser = parse_seconds, default_value = "5")]
+    drain_timeout: Duration,
+
+    /// Open the path again for each client, so that clients see the current file or directory
+    /// if it was replaced after the server started. Clients that connect while the path is
+    /// missing get an error, and the server keeps running.
+    #[clap(long = "keep-alive")]
+    keep_alive: bool,
+
+    /// Log the credentials of each client that connects, including the PID exactly as the kernel
+    /// reported it. PIDs are only meaningful in the server's own PID namespace.
+    #[clap(long = "log-peers")]
+    log_peers: bool,
+
+    /// Also describe what is served on the socket `<SOCKET>-info`, for `pipesys info`. Clients of
+    /// that socket get the path, its type, and the expected client UID, but no file descriptor,
+    /// and they do not count as connections for the idle timeout.
+    #[clap(long = "serve-info")]
+    serve_info: bool,
+
+    /// Send the file descriptor to at most this many clients, and refuse any after that. Use this
+    /// for a descriptor that should only be handed out once, such as a secret. Other servers are
+    /// not affected. Every client that connects counts, including `pipesys wait`. By default,
+    /// there is no limit.
+    #[clap(long = "max-uses")]
+    max_uses: Option<usize>,
+
+    /// Stop serving once this file exists, so that the build can end the server when it is done
+    /// with the file descriptor. The server checks for the file a few times a second, and stops
+    /// right away if it already exists. Whichever comes first of this and the idle timeout stops
+    /// the server.
+    #[clap(long = "stop-file")]
+    stop_file: Option<PathBuf>,
+
+    /// Only serve clients that send this token when they connect, in addition to having the
+    /// expected UID. Use this when builds that share a UID on the same host should not be able to
+    /// fetch each other's file descriptors. Clients pass the same value to `pipesys link --token`.
+    #[clap(long = "token")]
+    token: Option<Token>,
+
+    /// Decide whether to serve a client, instead of comparing its UID and GID to `client_uid` and
+    /// `client_gid`.
+    #[clap(skip)]
+    authorizer: Option<Authorizer>,
+}
+
+/// The credentials of a client process, as reported by the kernel when it connected.
+///
+/// The kernel reports the client's PID as seen from the server's PID namespace. A client in a
+/// container has a different PID inside it, and a client that the server's namespace can't see,
+/// such as one on the host when the server runs in a container, is reported as PID 0. PIDs are
+/// therefore only useful for diagnostics, and clients are authorized by UID and GID instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// The process ID of the client in the server's PID namespace, if it is visible there.
+    pub pid: Option<u32>,
+    /// The process ID exactly as the kernel reported it, if any.
+    pub raw_pid: Option<u32>,
+    /// The effective user ID of the client.
+    pub uid: u32,
+    /// The effective group ID of the client, if known.
+    pub gid: Option<u32>,
+}
+
+impl PeerCredentials {
+    fn new(raw_pid: Option<u32>, uid: u32, gid: Option<u32>) -> Self {
+        Self {
+            pid: raw_pid.filter(|pid| *pid != 0),
+            raw_pid,
+            uid,
+            gid,
+        }
+    }
+}
+
+impl fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.pid, self.raw_pid) {
+            (Some(pid), _) => write!(f, "PID {pid} (in the server's PID namespace)")?,
+            (None, Some(raw_pid)) => write!(
+                f,
+                "PID unknown (reported as {raw_pid}, outside the server's PID namespace)"
+            )?,
+            (None, None) => write!(f, "PID unknown")?,
+        }
+        write!(f, ", UID {}", self.uid)?;
+        if let Some(gid) = self.gid {
+            write!(f, ", GID {gid}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A caller-provided policy for deciding which clients to serve.
+#[derive(Clone)]
+struct Authorizer(Arc<dyn Fn(&PeerCredentials) -> bool + Send + Sync>);
+
+/// A named pipe, and the end of it to serve to clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fifo {
     path: PathBuf,
+    end: FifoEnd,
+}
+
+/// One end of a named pipe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FifoEnd {
+    Read,
+    Write,
+}
+
+impl FromStr for Fifo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, end) = s
+            .rsplit_once(':')
+            .filter(|(path, _)| !path.is_empty())
+            .context(error::FifoSpecSnafu { spec: s })?;
+        let end = match end {
+            "read" | "r" => FifoEnd::Read,
+            "write" | "w" => FifoEnd::Write,
+            _ => return error::FifoSpecSnafu { spec: s }.fail(),
+        };
+        Ok(Self {
+            path: path.into(),
+            end,
+        })
+    }
+}
+
+impl fmt::Debug for Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authorizer")
+    }
+}
+
+/// A secret that clients must send before they are served. It is left out of debug output, so
+/// that it does not end up in logs.
+#[derive(Clone, PartialEq, Eq)]
+struct Token(String);
+
+impl Token {
+    /// Compare the token that a client sent, in time that does not depend on where the first
+    /// difference is.
+    fn matches(&self, sent: &[u8]) -> bool {
+        let expected = self.0.as_bytes();
+        expected.len() == sent.len()
+            && expected
+                .iter()
+                .zip(sent)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl FromStr for Token {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ensure!(!s.is_empty(), error::EmptyTokenSnafu);
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+/// A range of user IDs in the namespace where clients run, and the IDs they correspond to in the
+/// server's namespace, in the same form as `/proc/<pid>/uid_map`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UidMap {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+impl UidMap {
+    pub fn new(inside: u32, outside: u32, count: u32) -> Self {
+        Self {
+            inside,
+            outside,
+            count,
+        }
+    }
+
+    /// Translate a UID seen by the server into the client's namespace, if it is in this range.
+    fn to_inside(self, uid: u32) -> Option<u32> {
+        let offset = uid.checked_sub(self.outside)?;
+        (offset < self.count).then(|| self.inside + offset)
+    }
+}
+
+impl fmt::Display for UidMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.inside, self.outside, self.count)
+    }
+}
+
+impl FromStr for UidMap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields = s
+            .split(':')
+            .map(|f| f.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()
+            .context(error::UidMapSpecSnafu { spec: s })?;
+        let [inside, outside, count] = fields[..] else {
+            return error::UidMapSpecSnafu { spec: s }.fail();
+        };
+        ensure!(
+            count > 0 && inside.checked_add(count - 1).is_some(),
+            error::UidMapSpecSnafu { spec: s }
+        );
+        Ok(Self::new(inside, outside, count))
+    }
 }
 
 impl Server {
@@ -29,55 +354,1692 @@ impl Server {
         P: AsRef<Path>,
     {
         let socket = socket.as_ref().to_string();
-        let path = path.as_ref().into();
+        let path = Some(path.as_ref().into());
 
         Self {
-            socket,
-            client_uid,
+            socket: Some(socket),
+            socket_path: None,
+            client_uid: Some(client_uid),
+            client_gid: None,
             path,
+            fifo: None,
+            listen_fd: None,
+            writable: false,
+            allowed_roots: Vec::new(),
+            uid_maps: Vec::new(),
+            idle_timeout: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            keep_alive: false,
+            log_peers: false,
+            serve_info: false,
+            max_uses: None,
+            stop_file: None,
+            token: None,
+            authorizer: None,
         }
     }
 
+    /// Serve the file descriptor for `path` on a socket file at `socket_path`, instead of an
+    /// abstract socket.
+    pub fn for_path_on_socket_path<S, P>(socket_path: S, client_uid: u32, path: P) -> Self
+    where
+        S: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        Self {
+            socket: None,
+            socket_path: Some(socket_path.as_ref().into()),
+            ..Self::for_path("", client_uid, path)
+        }
+    }
+
+    /// Serve a file descriptor for `path` that clients can write through. A regular file is
+    /// opened for reading and writing; a directory is opened as usual.
+    pub fn for_writable_paths<S, P>(socket: S, client_uid: u32, path: P) -> Self
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        Self {
+            writable: true,
+            ..Self::for_path(socket, client_uid, path)
+        }
+    }
+
+    /// Serve one end of a FIFO, which is created at `path` if it does not already exist.
+    pub fn for_fifo<S, P>(socket: S, client_uid: u32, path: P, end: FifoEnd) -> Self
+    where
+        S: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        Self {
+            path: None,
+            fifo: Some(Fifo {
+                path: path.as_ref().into(),
+                end,
+            }),
+            ..Self::for_path(socket, client_uid, "")
+        }
+    }
+
+    /// Serve a listening socket that the caller owns, such as one inherited from its parent. The
+    /// server sends a duplicate, so `fd` must stay open while the server runs. Clients that fetch
+    /// it with `fetch_fd` can accept connections on it, including after they exec.
+    pub fn for_listening_socket<S>(socket: S, client_uid: u32, fd: RawFd) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Self {
+            path: None,
+            listen_fd: Some(fd),
+            ..Self::for_path(socket, client_uid, "")
+        }
+    }
+
+    /// Only serve paths that resolve to a location under this directory. Each call allows another
+    /// directory.
+    pub fn with_allowed_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.allowed_roots.push(root.as_ref().into());
+        self
+    }
+
+    /// Expect clients with this effective GID, in addition to the UID.
+    pub fn with_client_gid(mut self, gid: u32) -> Self {
+        self.client_gid = Some(gid);
+        self
+    }
+
+    /// Translate client UIDs through this mapping before comparing them to the expected UID.
+    pub fn with_uid_map(mut self, uid_map: UidMap) -> Self {
+        self.uid_maps.push(uid_map);
+        self
+    }
+
+    /// Stop serving once no client has connected for this long.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// When the server stops, wait this long for file descriptors that are still being sent.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Open the path again for each client, instead of once when the server starts.
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Log the credentials of each client that connects.
+    pub fn with_log_peers(mut self, log_peers: bool) -> Self {
+        self.log_peers = log_peers;
+        self
+    }
+
+    /// Also describe what is served on the info socket.
+    pub fn with_serve_info(mut self, serve_info: bool) -> Self {
+        self.serve_info = serve_info;
+        self
+    }
+
+    /// Send the file descriptor to at most `max_uses` clients, and refuse any after that.
+    pub fn with_max_uses(mut self, max_uses: usize) -> Self {
+        self.max_uses = Some(max_uses);
+        self
+    }
+
+    /// Stop serving once a file exists at `path`.
+    pub fn with_stop_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.stop_file = Some(path.as_ref().into());
+        self
+    }
+
+    /// Only serve clients that send `token` when they connect, in addition to passing the UID
+    /// check or the authorizer.
+    pub fn with_token<T: AsRef<str>>(mut self, token: T) -> Result<Self> {
+        self.token = Some(token.as_ref().parse()?);
+        Ok(self)
+    }
+
+    /// Use the provided function to decide whether to serve a client. This replaces the check
+    /// against the expected client UID. The PID in the credentials depends on the PID namespace
+    /// that the server runs in, so the decision should not rest on it.
+    pub fn with_authorizer<F>(mut self, authorizer: F) -> Self
+    where
+        F: Fn(&PeerCredentials) -> bool + Send + Sync + 'static,
+    {
+        self.authorizer = Some(Authorizer(Arc::new(authorizer)));
+        self
+    }
+
+    fn authorize(&self, peer_creds: &PeerCredentials) -> Result<()> {
+        let authorized = match &self.authorizer {
+            Some(Authorizer(authorizer)) => authorizer(peer_creds),
+            None => {
+                self.client_uid
+                    .map_or(true, |uid| self.client_uid(peer_creds.uid) == Some(uid))
+                    && self
+                        .client_gid
+                        .map_or(true, |gid| peer_creds.gid == Some(gid))
+            }
+        };
+        ensure!(
+            authorized,
+            error::UnauthorizedSnafu {
+                pid: peer_creds.pid,
+                uid: peer_creds.uid,
+                gid: peer_creds.gid,
+            }
+        );
+        Ok(())
+    }
+
+    /// Find the UID of a client in its own namespace, given the UID that the server sees.
+    fn client_uid(&self, uid: u32) -> Option<u32> {
+        if self.uid_maps.is_empty() {
+            return Some(uid);
+        }
+        self.uid_maps.iter().find_map(|m| m.to_inside(uid))
+    }
+
     pub async fn serve(&self) -> Result<()> {
-        let addr = UnixSocketAddr::from_abstract(self.socket.as_bytes())
-            .with_context(|| format!("failed to create socket {}", self.socket))?;
-        let mut listener = UnixSeqpacketListener::bind_addr(&addr)
-            .with_context(|| format!("failed to bind to socket {}", self.socket))?;
+        self.serve_until(std::future::pending()).await
+    }
 
-        let f = OpenOptions::new()
-            .create(false)
-            .read(true)
-            .write(false)
-            .open(&self.path)
-            .with_context(|| format!("could not open {}", self.path.display()))?;
+    /// Serve clients until `shutdown` completes. The server then stops accepting connections,
+    /// but finishes sending file descriptors to clients it has already accepted, waiting up to
+    /// the drain timeout for them.
+    pub async fn serve_until<F>(&self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let listener = self.bind()?;
+        self.serve_on(listener, shutdown).await
+    }
+
+    /// Bind the server's socket without serving it yet, so that the caller can find out whether
+    /// the socket name is already taken before it depends on it. This must be called from within a
+    /// Tokio runtime.
+    pub fn bind(&self) -> Result<Listener> {
+        Ok(Listener(bind(&self.address())?))
+    }
+
+    /// The socket that the server listens on.
+    fn address(&self) -> SocketAddress {
+        match (&self.socket_path, &self.socket) {
+            (Some(path), _) => SocketAddress::Path(path.clone()),
+            (None, socket) => SocketAddress::Abstract(socket.clone().unwrap_or_default()),
+        }
+    }
+
+    /// Serve clients on a socket from `bind` until the task is cancelled.
+    pub async fn serve_listener(&self, listener: Listener) -> Result<()> {
+        self.serve_on(listener, std::future::pending()).await
+    }
 
-        let fd = f.as_raw_fd();
+    async fn serve_on<F>(&self, listener: Listener, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let socket = &self.address().to_string();
+        let Listener(mut listener) = listener;
+
+        let mut file = Arc::new(self.open_path()?);
+        let message = self.message();
+        let fifo = self.fifo.clone();
+
+        // The info socket is served by a separate task, which stops when this function returns.
+        let _info_task = if self.serve_info {
+            Some(AbortOnDrop(self.spawn_info_server()?))
+        } else {
+            None
+        };
 
+        let mut sends = JoinSet::new();
+        let mut token_checks = JoinSet::new();
+        let mut served = 0;
+        let stop_file = async {
+            match &self.stop_file {
+                Some(path) => {
+                    wait_for_file(path).await;
+                    info!(
+                        "stop file '{}' exists, stopping server on socket {socket}",
+                        path.display()
+                    );
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(shutdown, stop_file);
+
+        let idle_deadline = || self.idle_timeout.map(|t| Instant::now() + t);
+        let mut deadline = idle_deadline();
         loop {
-            let (mut conn, _) = listener.accept().await.with_context(|| {
-                format!("failed to accept connection on socket {}", self.socket)
-            })?;
-
-            let peer_creds = conn.initial_peer_credentials().with_context(|| {
-                format!(
-                    "failed to obtain peer credentials on socket {}",
-                    self.socket
-                )
-            })?;
-
-            let peer_uid = peer_creds.euid();
-            if peer_uid != self.client_uid {
-                warn!("ignoring connection from peer with UID {}", peer_uid);
+            let accept = async {
+                match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, listener.accept())
+                        .await
+                        .ok(),
+                    None => Some(listener.accept().await),
+                }
+            };
+            let accepted = tokio::select! {
+                biased;
+                () = &mut shutdown => {
+                    info!("shutting down server on socket {socket}");
+                    break;
+                }
+                () = &mut stop_file => break,
+                Some(sent) = sends.join_next(), if !sends.is_empty() => {
+                    log_send(sent);
+                    continue;
+                }
+                Some(checked) = token_checks.join_next(), if !token_checks.is_empty() => {
+                    match checked {
+                        Ok(Ok(conn)) => Accepted::TokenChecked(conn),
+                        Ok(Err(e)) => {
+                            warn!("ignoring connection: {e}");
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("token check did not finish: {e}");
+                            continue;
+                        }
+                    }
+                }
+                accepted = accept => Accepted::New(accepted),
+            };
+
+            let mut conn = match accepted {
+                Accepted::TokenChecked(conn) => conn,
+                Accepted::New(None) => {
+                    info!(
+                        "no connections on socket {} for {:?}, stopping",
+                        socket,
+                        self.idle_timeout.unwrap_or_default()
+                    );
+                    break;
+                }
+                Accepted::New(Some(accepted)) => {
+                    deadline = idle_deadline();
+                    let (conn, _) = match accepted.context(error::AcceptSnafu { socket }) {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            self.drain(&mut sends).await;
+                            return Err(e);
+                        }
+                    };
+
+                    let peer_creds = conn
+                        .initial_peer_credentials()
+                        .context(error::PeerCredentialsSnafu { socket })?;
+
+                    let peer_creds = PeerCredentials::new(
+                        peer_creds.pid().map(u32::from),
+                        peer_creds.euid(),
+                        peer_creds.egid(),
+                    );
+                    if self.log_peers {
+                        info!("client connected on socket {socket}: {peer_creds}");
+                    }
+
+                    if let Err(e) = self.authorize(&peer_creds) {
+                        warn!("ignoring connection: {e}");
+                        continue;
+                    }
+
+                    // The token is read in its own task, so that a client that is slow to send it
+                    // does not hold up the others. The client comes back through the loop once
+                    // its token has been checked.
+                    if let Some(token) = &self.token {
+                        token_checks.spawn(check_token(conn, token.clone(), socket.clone()));
+                        continue;
+                    }
+                    conn
+                }
+            };
+
+            if self.max_uses.is_some_and(|max| served >= max) {
+                warn!("refusing client on socket {socket}: the file descriptor was already sent {served} times");
+                let socket = socket.clone();
+                sends.spawn(async move {
+                    conn.send(REFUSED_MESSAGE)
+                        .await
+                        .context(error::SendSnafu { socket })
+                });
                 continue;
             }
 
-            let socket = self.socket.clone();
-            let fds = vec![fd];
-            tokio::spawn(async move {
-                conn.send_fds(b"fds", &fds)
+            // A path that can't be opened again only fails this client. It may be back by the
+            // time the next one connects.
+            if self.keep_alive {
+                match self.open_path() {
+                    Ok(f) => file = Arc::new(f),
+                    Err(e) => {
+                        warn!("not serving client on socket {socket}: {e}");
+                        let socket = socket.clone();
+                        sends.spawn(async move {
+                            conn.send(UNAVAILABLE_MESSAGE)
+                                .await
+                                .context(error::SendSnafu { socket })
+                        });
+                        continue;
+                    }
+                }
+            }
+            served += 1;
+
+            // The task holds a reference to the file so that the descriptor stays open until it
+            // has been sent, even if the path is opened again for the next client.
+            let socket = socket.clone();
+            let file = Arc::clone(&file);
+            let fifo = fifo.clone();
+            sends.spawn(async move {
+                // Each client of a FIFO gets an end of its own, and the server's copy is closed
+                // once it has been sent, so that only the client holds that end open.
+                let file = match fifo {
+                    Some(Fifo { path, end }) => Arc::new(open_fifo_end(file, path, end).await?),
+                    None => file,
+                };
+                let fds = [file.as_raw_fd()];
+                check_fd_count(&socket, &fds)?;
+                conn.send_fds(message, &fds)
                     .await
-                    .with_context(|| format!("failed to send file descriptors over {}", socket))
+                    .context(error::SendSnafu { socket })
             });
         }
+
+        self.drain(&mut sends).await;
+        Ok(())
+    }
+
+    /// Wait for the file descriptors that are still being sent, up to the drain timeout. Sends
+    /// that have not finished by then are cancelled.
+    async fn drain(&self, sends: &mut JoinSet<Result<usize>>) {
+        let drained = tokio::time::timeout(self.drain_timeout, async {
+            while let Some(sent) = sends.join_next().await {
+                log_send(sent);
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "cancelling {} unfinished sends on socket {} after {:?}",
+                sends.len(),
+                self.address(),
+                self.drain_timeout
+            );
+            sends.abort_all();
+        }
+    }
+
+    /// Bind the info socket, and answer each authorized client with a description of what the
+    /// server is configured to serve.
+    fn spawn_info_server(&self) -> Result<tokio::task::JoinHandle<()>> {
+        let address = self.address().info();
+        let mut listener = bind(&address)?;
+        let socket = address.to_string();
+
+        let server = self.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                let mut conn = match listener.accept().await {
+                    Ok((conn, _)) => conn,
+                    Err(e) => {
+                        warn!("failed to accept connection on socket {socket}: {e}");
+                        continue;
+                    }
+                };
+                let authorized = conn
+                    .initial_peer_credentials()
+                    .context(error::PeerCredentialsSnafu { socket: &socket })
+                    .and_then(|creds| {
+                        server.authorize(&PeerCredentials::new(
+                            creds.pid().map(u32::from),
+                            creds.euid(),
+                            creds.egid(),
+                        ))
+                    });
+                if let Err(e) = authorized {
+                    warn!("ignoring info request: {e}");
+                    continue;
+                }
+                if let Err(e) = conn.send(server.info().to_string().as_bytes()).await {
+                    warn!("failed to send info over socket {socket}: {e}");
+                }
+            }
+        }))
+    }
+
+    /// Describe what the server is configured to serve.
+    pub fn info(&self) -> ServerInfo {
+        let served = match (&self.fifo, &self.path) {
+            (Some(Fifo { path, end }), _) => Some(ServedPath {
+                path: path.clone(),
+                kind: match end {
+                    FifoEnd::Read => PathKind::FifoRead,
+                    FifoEnd::Write => PathKind::FifoWrite,
+                },
+            }),
+            (None, Some(path)) => Some(ServedPath {
+                path: path.clone(),
+                kind: match std::fs::metadata(path) {
+                    Ok(metadata) if metadata.is_dir() => PathKind::Directory,
+                    Ok(metadata) if metadata.is_file() => PathKind::File,
+                    _ => PathKind::Unknown,
+                },
+            }),
+            (None, None) => self.listen_fd.map(|fd| ServedPath {
+                path: format!("/proc/self/fd/{fd}").into(),
+                kind: PathKind::ListeningSocket,
+            }),
+        };
+        ServerInfo {
+            paths: served.into_iter().collect(),
+            client_uid: self.client_uid,
+            client_gid: self.client_gid,
+            uid_maps: self.uid_maps.clone(),
+        }
+    }
+
+    /// The message sent along with the file descriptor, which tells the client what it is.
+    fn message(&self) -> &'static [u8] {
+        match &self.fifo {
+            Some(Fifo {
+                end: FifoEnd::Read, ..
+            }) => b"fifo-read",
+            Some(Fifo {
+                end: FifoEnd::Write,
+                ..
+            }) => b"fifo-write",
+            None if self.listen_fd.is_some() => b"listening-socket",
+            None => b"fds",
+        }
+    }
+
+    fn open_path(&self) -> Result<File> {
+        // A socket has no path, so the allowed roots do not apply to it.
+        if let Some(fd) = self.listen_fd {
+            return open_listening_socket(fd);
+        }
+
+        match (&self.fifo, &self.path) {
+            (Some(fifo), _) => {
+                // Check where the FIFO will be created before creating it, and then check the
+                // FIFO itself, in case it already existed as a symlink.
+                let parent = match fifo.path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                let dir = File::open(parent).context(error::OpenSnafu { path: parent })?;
+                self.check_allowed(&dir, parent)?;
+                let file = open_fifo(fifo)?;
+                self.check_allowed(&file, &fifo.path)?;
+                Ok(file)
+            }
+            (None, Some(path)) => {
+                let file = if self.writable {
+                    open_writable(path)?
+                } else {
+                    OpenOptions::new()
+                        .create(false)
+                        .read(true)
+                        .write(false)
+                        .open(path)
+                        .context(error::OpenSnafu { path })?
+                };
+                self.check_allowed(&file, path)?;
+                let identity = check_served_file(&file, path, self.writable)?;
+                let access = if self.writable {
+                    "read-write"
+                } else {
+                    "read-only"
+                };
+                info!("serving {} {access} ({identity})", path.display());
+                Ok(file)
+            }
+            (None, None) => error::MissingPathSnafu.fail(),
+        }
+    }
+
+    /// Check that an open file is under one of the allowed roots, if any were given. The location
+    /// of the open file is checked, rather than the path, so that symlinks and `..` components
+    /// cannot lead outside the roots, even if they change after the check.
+    fn check_allowed(&self, file: &File, path: &Path) -> Result<()> {
+        if self.allowed_roots.is_empty() {
+            return Ok(());
+        }
+
+        let resolved = open_file_path(file, path)?;
+        for root in &self.allowed_roots {
+            let dir = File::open(root).context(error::OpenSnafu { path: root })?;
+            if resolved.starts_with(open_file_path(&dir, root)?) {
+                return Ok(());
+            }
+        }
+        error::NotAllowedSnafu { path, resolved }.fail()
+    }
+}
+
+/// Find where an open file is, with every symlink resolved, from the kernel's record of it.
+fn open_file_path(file: &File, path: &Path) -> Result<PathBuf> {
+    std::fs::read_link(fd_path(file)).context(error::ResolvePathSnafu { path })
+}
+
+/// Bind a socket. A name that another process already holds is reported separately, since the
+/// caller may be able to pick another name. A socket file that no server is listening on any
+/// more is removed and bound again.
+fn bind(address: &SocketAddress) -> Result<UnixSeqpacketListener> {
+    let socket = &address.to_string();
+    let addr = socket_addr(address)?;
+    match UnixSeqpacketListener::bind_addr(&addr) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if let SocketAddress::Path(path) = address {
+                if remove_stale_socket(path)? {
+                    return UnixSeqpacketListener::bind_addr(&addr)
+                        .context(error::BindSnafu { socket });
+                }
+            }
+            error::SocketInUseSnafu { socket }.fail()
+        }
+        result => result.context(error::BindSnafu { socket }),
+    }
+}
+
+/// Remove the socket file at `path` if no server accepts connections on it, and return whether
+/// it was removed. Anything other than a socket is left alone.
+fn remove_stale_socket(path: &Path) -> Result<bool> {
+    let metadata = std::fs::symlink_metadata(path).context(error::OpenSnafu { path })?;
+    ensure!(
+        metadata.file_type().is_socket(),
+        error::NotSocketSnafu { path }
+    );
+    match uds::UnixSeqpacketConn::connect(path) {
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            info!("removing stale socket {}", path.display());
+            std::fs::remove_file(path).context(error::RemoveStaleSocketSnafu { path })?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Check that the kernel will accept this many file descriptors in one message, so that a send
+/// that is too large fails with a clear error instead of `EINVAL`.
+fn check_fd_count(socket: &str, fds: &[RawFd]) -> Result<()> {
+    ensure!(
+        fds.len() <= SCM_MAX_FD,
+        error::TooManyFdsSnafu {
+            socket,
+            count: fds.len(),
+            max: SCM_MAX_FD,
+        }
+    );
+    Ok(())
+}
+
+/// A client connection that the serve loop picked up, either from the listener or after its token
+/// was checked.
+enum Accepted {
+    New(Option<std::io::Result<(UnixSeqpacketConn, UnixSocketAddr)>>),
+    TokenChecked(UnixSeqpacketConn),
+}
+
+/// Wait for a client to send the server's token. A client that sends the wrong token, or none in
+/// time, is told that it was rejected, so that it fails right away instead of waiting for a file
+/// descriptor.
+async fn check_token(
+    mut conn: UnixSeqpacketConn,
+    token: Token,
+    socket: String,
+) -> Result<UnixSeqpacketConn> {
+    // One extra byte shows whether the client sent a longer token.
+    let mut sent = vec![0u8; token.0.len() + 1];
+    let matched = match tokio::time::timeout(TOKEN_TIMEOUT, conn.recv(&mut sent)).await {
+        Ok(Ok(len)) => token.matches(&sent[..len]),
+        Ok(Err(_)) | Err(_) => false,
+    };
+    if !matched {
+        // The client is going away either way, so a failure to tell it is not worth reporting.
+        let _ = conn.send(REJECTED_MESSAGE).await;
+        return error::WrongTokenSnafu { socket }.fail();
+    }
+    Ok(conn)
+}
+
+/// Log a send that failed or was cancelled. The client sees the failure as a closed connection.
+fn log_send(sent: std::result::Result<Result<usize>, JoinError>) {
+    match sent {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => warn!("{e}"),
+        Err(e) => warn!("send task did not finish: {e}"),
+    }
+}
+
+/// A socket that a server has bound, but is not serving yet.
+pub struct Listener(UnixSeqpacketListener);
+
+/// Aborts a task when dropped, so that it does not outlive its owner.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Where an open file lives, so that the descriptor sent to clients can be traced back to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FileIdentity {
+    device: u64,
+    inode: u64,
+    read_only_mount: bool,
+}
+
+impl fmt::Display for FileIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mount = if self.read_only_mount {
+            "read-only"
+        } else {
+            "writable"
+        };
+        write!(
+            f,
+            "device {:#x}, inode {}, {mount} mount",
+            self.device, self.inode
+        )
+    }
+}
+
+/// Open a regular file for reading and writing, or a directory for reading, since a directory
+/// can't be opened for writing. Nothing is created if the path does not exist.
+fn open_writable(path: &Path) -> Result<File> {
+    match OpenOptions::new()
+        .create(false)
+        .read(true)
+        .write(true)
+        .open(path)
+    {
+        Err(e) if e.raw_os_error() == Some(Errno::EISDIR as i32) => OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_DIRECTORY.bits())
+            .open(path),
+        result => result,
+    }
+    .context(error::OpenSnafu { path })
+}
+
+/// Check that a file opened for a path is safe to share with clients. It must be a directory or a
+/// regular file, and unless the server was asked to serve it writable, it must not have been
+/// opened for writing.
+fn check_served_file(file: &File, path: &Path, writable: bool) -> Result<FileIdentity> {
+    let metadata = file.metadata().context(error::StatSnafu { path })?;
+    let file_type = metadata.file_type();
+    ensure!(
+        file_type.is_dir() || file_type.is_file(),
+        error::UnsupportedFileTypeSnafu { path }
+    );
+
+    let flags = fcntl(file.as_raw_fd(), FcntlArg::F_GETFL).context(error::FlagsSnafu { path })?;
+    let access_mode = OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE;
+    ensure!(
+        writable || access_mode == OFlag::O_RDONLY,
+        error::NotReadOnlySnafu { path }
+    );
+
+    // A writable mount is allowed, since clients may be expected to write through a directory
+    // descriptor, but it's worth noting in the log.
+    let read_only_mount = fstatvfs(file)
+        .context(error::FlagsSnafu { path })?
+        .flags()
+        .contains(FsFlags::ST_RDONLY);
+
+    Ok(FileIdentity {
+        device: metadata.dev(),
+        inode: metadata.ino(),
+        read_only_mount,
+    })
+}
+
+/// Create the FIFO if it does not exist, and open it with `O_PATH`, which refers to the FIFO
+/// without opening either end. Clients are sent their ends by `open_fifo_end`.
+fn open_fifo(fifo: &Fifo) -> Result<File> {
+    let path = &fifo.path;
+    match mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR) {
+        Ok(()) => {}
+        Err(Errno::EEXIST) => {
+            let metadata = std::fs::metadata(path).context(error::StatSnafu { path })?;
+            ensure!(
+                metadata.file_type().is_fifo(),
+                error::NotAFifoSnafu { path }
+            );
+        }
+        Err(e) => return Err(e).context(error::FifoCreateSnafu { path }),
+    }
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_PATH.bits())
+        .open(path)
+        .context(error::OpenSnafu { path })
+}
+
+/// Open one end of a FIFO for a client, through the descriptor from `open_fifo`.
+///
+/// The open blocks until the other end is opened, just as it would for the client, so that a
+/// reader does not see EOF before any writer has attached, and a writer does not see `EPIPE`
+/// before any reader has. It runs on a blocking thread so that other clients are served in the
+/// meantime.
+async fn open_fifo_end(fifo: Arc<File>, path: PathBuf, end: FifoEnd) -> Result<File> {
+    let mut release = ReleaseFifo {
+        fifo: Arc::clone(&fifo),
+        end,
+        waiting: true,
+    };
+    let opened = tokio::task::spawn_blocking(move || {
+        let mut options = OpenOptions::new();
+        match end {
+            FifoEnd::Read => options.read(true),
+            FifoEnd::Write => options.write(true),
+        };
+        options.open(fd_path(&fifo))
+    })
+    .await
+    .map_err(io::Error::from)
+    .and_then(|opened| opened)
+    .context(error::OpenSnafu { path });
+    release.waiting = false;
+    opened
+}
+
+/// Releases a thread that is still waiting in `open_fifo_end` when its send is cancelled, by
+/// opening the other end of the FIFO for a moment. The waiting open already counts as a reader or
+/// a writer, so a non-blocking open of the other end succeeds.
+struct ReleaseFifo {
+    fifo: Arc<File>,
+    end: FifoEnd,
+    waiting: bool,
+}
+
+impl Drop for ReleaseFifo {
+    fn drop(&mut self) {
+        if !self.waiting {
+            return;
+        }
+        let mut options = OpenOptions::new();
+        match self.end {
+            FifoEnd::Read => options.write(true),
+            FifoEnd::Write => options.read(true),
+        };
+        let _ = options
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(fd_path(&self.fifo));
+    }
+}
+
+/// The `/proc` path for an open file, which opens the same file again, even when the descriptor
+/// was opened with `O_PATH`.
+fn fd_path(file: &File) -> PathBuf {
+    format!("/proc/self/fd/{}", file.as_raw_fd()).into()
+}
+
+/// Duplicate an inherited descriptor for a listening socket, so that the server has its own copy
+/// to send. The copy is close-on-exec, so that it does not leak into programs the server runs.
+fn open_listening_socket(fd: RawFd) -> Result<File> {
+    let dupfd = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0)).context(error::DuplicateFdSnafu { fd })?;
+    // SAFETY: `fcntl` just created this descriptor, and nothing else refers to it.
+    let file = unsafe { File::from_raw_fd(dupfd) };
+    ensure!(
+        getsockopt(&file, sockopt::AcceptConn).unwrap_or(false),
+        error::NotListeningSnafu { fd }
+    );
+    info!("serving listening socket from descriptor {fd}");
+    Ok(file)
+}
+
+/// Completes once a file exists at `path`.
+async fn wait_for_file(path: &Path) {
+    let mut interval = tokio::time::interval(STOP_FILE_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if tokio::fs::try_exists(path).await.unwrap_or(false) {
+            return;
+        }
+    }
+}
+
+/// Parse a number of seconds from the command line.
+fn parse_seconds(arg: &str) -> Result<Duration> {
+    let seconds = arg.parse().context(error::InvalidSecondsSnafu { arg })?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{fetch_fd_with_timeout, fetch_owned_fd_with_token, wait_for};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+    use std::process;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use uds::UnixSeqpacketConn;
+
+    /// Connect to the server and return the number of file descriptors received.
+    fn fetch_fds(socket: &str) -> usize {
+        let addr = UnixSocketAddr::from_abstract(socket.as_bytes()).unwrap();
+        for _ in 0..100 {
+            if let Ok(client) = UnixSeqpacketConn::connect_unix_addr(&addr) {
+                let mut fd_buf = [-1; 1];
+                let (_, _, fds) = client.recv_fds(&mut [0u8; 3], &mut fd_buf).unwrap();
+                return fds;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("failed to connect to socket {socket}");
+    }
+
+    async fn serve_and_fetch(server: Server) -> usize {
+        let socket = server.address().to_string();
+        let handle = tokio::spawn(async move { server.serve().await });
+        let fds = tokio::task::spawn_blocking(move || fetch_fds(&socket))
+            .await
+            .unwrap();
+        handle.abort();
+        fds
+    }
+
+    fn test_server(name: &str) -> Server {
+        let socket = format!("pipesys-test-{}-{name}", process::id());
+        Server::for_path(socket, u32::MAX, env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_authorizer_rejects_pid() {
+        let pid = process::id();
+        let server = test_server("reject").with_authorizer(move |peer| peer.pid != Some(pid));
+        assert_eq!(serve_and_fetch(server).await, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_authorizer_accepts_pid() {
+        let pid = process::id();
+        let server = test_server("accept").with_authorizer(move |peer| peer.pid == Some(pid));
+        assert_eq!(serve_and_fetch(server).await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_timeout() {
+        let server = test_server("idle")
+            .with_authorizer(|_| true)
+            .with_idle_timeout(Duration::from_millis(500))
+            .with_keep_alive(true);
+        let socket = server.address().to_string();
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        // Sequential clients are all served, since each one arrives before the timeout.
+        let fds = tokio::task::spawn_blocking(move || {
+            (0..3).map(|_| fetch_fds(&socket)).collect::<Vec<_>>()
+        })
+        .await
+        .unwrap();
+        assert_eq!(fds, [1, 1, 1]);
+
+        // Once the clients stop, the server exits on its own.
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("server did not stop after idle timeout")
+            .unwrap()
+            .unwrap();
+    }
+
+    /// Connect to the server and return the file descriptor received, along with the message.
+    fn fetch_file(socket: &str) -> (File, Vec<u8>) {
+        let addr = UnixSocketAddr::from_abstract(socket.as_bytes()).unwrap();
+        for _ in 0..100 {
+            if let Ok(client) = UnixSeqpacketConn::connect_unix_addr(&addr) {
+                let mut message = [0u8; 16];
+                let mut fd_buf = [-1; 1];
+                let (len, _, fds) = client.recv_fds(&mut message, &mut fd_buf).unwrap();
+                assert_eq!(fds, 1);
+                let file = unsafe { File::from_raw_fd(fd_buf[0]) };
+                return (file, message[..len].to_vec());
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("failed to connect to socket {socket}");
+    }
+
+    /// Run `f` on a thread of its own, so that a test can stop waiting for it if it hangs.
+    fn on_thread<T, F>(f: F) -> mpsc::Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(f());
+        });
+        rx
+    }
+
+    /// Open a FIFO once the server has created it. This blocks until the other end is open too.
+    fn open_fifo_path(path: &Path, options: &OpenOptions) -> File {
+        while !path.exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        options.open(path).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fifo_read_before_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pipe");
+        let socket = format!("pipesys-test-{}-fifo-read", process::id());
+        let server =
+            Server::for_fifo(&socket, u32::MAX, &path, FifoEnd::Read).with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        // The client asks for the read end before any writer exists.
+        let reader = on_thread(move || {
+            let (mut reader, message) = fetch_file(&socket);
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            (data, message)
+        });
+        std::thread::sleep(Duration::from_millis(100));
+
+        // It still sees what the writer writes, and then EOF once the writer closes.
+        let mut writer = open_fifo_path(&path, OpenOptions::new().write(true));
+        writer.write_all(b"x").unwrap();
+        drop(writer);
+        let (data, message) = reader.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.abort();
+
+        assert_eq!(data, b"x");
+        assert_eq!(message, b"fifo-read");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fifo_write_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pipe");
+        let socket = format!("pipesys-test-{}-fifo-write", process::id());
+        let server =
+            Server::for_fifo(&socket, u32::MAX, &path, FifoEnd::Write).with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let client_socket = socket.clone();
+        let writer = on_thread(move || {
+            let (mut writer, message) = fetch_file(&client_socket);
+            let flags = fcntl(writer.as_raw_fd(), FcntlArg::F_GETFL).unwrap();
+            writer.write_all(b"x").unwrap();
+            (OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE, message)
+        });
+
+        // The reader sees EOF once the client closes its write end, since the server does not
+        // keep a copy open.
+        let mut reader = open_fifo_path(&path, OpenOptions::new().read(true));
+        let read = on_thread(move || {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            data
+        });
+        assert_eq!(read.recv_timeout(Duration::from_secs(5)).unwrap(), b"x");
+        let (mode, message) = writer.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(mode, OFlag::O_WRONLY);
+        assert_eq!(message, b"fifo-write");
+
+        // Once the reader has gone, a write fails with EPIPE.
+        let (closed_tx, closed_rx) = mpsc::channel();
+        let writer = on_thread(move || {
+            let (mut writer, _) = fetch_file(&socket);
+            closed_rx.recv().unwrap();
+            writer.write_all(b"x").unwrap_err().kind()
+        });
+        drop(open_fifo_path(&path, OpenOptions::new().read(true)));
+        closed_tx.send(()).unwrap();
+        let error = writer.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.abort();
+
+        assert_eq!(error, io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_parse_fifo() {
+        let fifo: Fifo = "/tmp/a:b/pipe:write".parse().unwrap();
+        assert_eq!(fifo.path, PathBuf::from("/tmp/a:b/pipe"));
+        assert_eq!(fifo.end, FifoEnd::Write);
+        assert_eq!("pipe:r".parse::<Fifo>().unwrap().end, FifoEnd::Read);
+        for spec in ["pipe", "pipe:both", ":read"] {
+            assert!(matches!(spec.parse::<Fifo>(), Err(Error::FifoSpec { .. })));
+        }
+    }
+
+    #[test]
+    fn test_fifo_not_a_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "").unwrap();
+        let fifo = Fifo {
+            path,
+            end: FifoEnd::Read,
+        };
+        assert!(matches!(open_fifo(&fifo), Err(Error::NotAFifo { .. })));
+    }
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("30").unwrap(), Duration::from_secs(30));
+        assert!(matches!(
+            parse_seconds("soon"),
+            Err(Error::InvalidSeconds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_default_authorization() {
+        let server = Server::for_path("socket", 1000, "/");
+        let mut peer = PeerCredentials {
+            pid: Some(1),
+            raw_pid: Some(1),
+            uid: 1000,
+            gid: Some(1000),
+        };
+        assert!(server.authorize(&peer).is_ok());
+        peer.uid = 0;
+        assert!(matches!(
+            server.authorize(&peer),
+            Err(Error::Unauthorized { uid: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_gid_authorization() {
+        let mut peer = PeerCredentials::new(Some(1), 1000, Some(100));
+
+        // A GID alone admits any user in the group.
+        let server =
+            Server::try_parse_from(["server", "--socket=s", "--client-gid=100", "--path=/"])
+                .unwrap();
+        assert!(server.authorize(&peer).is_ok());
+        peer.uid = 1001;
+        assert!(server.authorize(&peer).is_ok());
+
+        // With a UID as well, both have to match.
+        let server = Server::for_path("socket", 1000, "/").with_client_gid(100);
+        assert!(server.authorize(&peer).is_err());
+        peer.uid = 1000;
+        assert!(server.authorize(&peer).is_ok());
+        peer.gid = Some(101);
+        let err = server.authorize(&peer).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Peer with PID Some(1), UID 1000 and GID Some(101) is not authorized"
+        );
+        peer.gid = None;
+        assert!(server.authorize(&peer).is_err());
+
+        // One of them is required.
+        assert!(Server::try_parse_from(["server", "--socket=s", "--path=/"]).is_err());
+    }
+
+    #[test]
+    fn test_peer_credentials_pid_namespace() {
+        // A client the server can see reports its PID in the server's namespace.
+        let peer = PeerCredentials::new(Some(4321), 1000, Some(1000));
+        assert_eq!(peer.pid, Some(4321));
+        assert_eq!(
+            peer.to_string(),
+            "PID 4321 (in the server's PID namespace), UID 1000, GID 1000"
+        );
+
+        // A client outside the server's PID namespace is reported as PID 0, which is not a real
+        // process, but the raw value is kept.
+        let peer = PeerCredentials::new(Some(0), 1000, Some(1000));
+        assert_eq!(peer.pid, None);
+        assert_eq!(peer.raw_pid, Some(0));
+        assert_eq!(
+            peer.to_string(),
+            "PID unknown (reported as 0, outside the server's PID namespace), UID 1000, GID 1000"
+        );
+
+        // Either way, only the UID decides whether the client is served.
+        let server = Server::for_path("socket", 1000, "/");
+        assert!(server.authorize(&peer).is_ok());
+        let peer = PeerCredentials::new(Some(0), 0, Some(0));
+        assert!(matches!(
+            server.authorize(&peer),
+            Err(Error::Unauthorized {
+                pid: None,
+                uid: 0,
+                gid: Some(0)
+            })
+        ));
+    }
+
+    #[test]
+    fn test_check_read_only() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let metadata = std::fs::metadata(path).unwrap();
+        let file = File::open(path).unwrap();
+
+        let identity = check_served_file(&file, path, false).unwrap();
+        assert_eq!(identity.device, metadata.dev());
+        assert_eq!(identity.inode, metadata.ino());
+
+        // The log line identifies the file that was served.
+        let logged = identity.to_string();
+        assert!(logged.contains(&format!("device {:#x}", metadata.dev())));
+        assert!(logged.contains(&format!("inode {}", metadata.ino())));
+    }
+
+    #[test]
+    fn test_open_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "").unwrap();
+
+        // A regular file is opened for reading and writing, and a directory for reading only.
+        let file = open_writable(&path).unwrap();
+        check_served_file(&file, &path, true).unwrap();
+        (&file).write_all(b"written").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "written");
+        let dir_file = open_writable(dir.path()).unwrap();
+        check_served_file(&dir_file, dir.path(), true).unwrap();
+        check_served_file(&dir_file, dir.path(), false).unwrap();
+
+        // Nothing is created for a missing path.
+        let missing = dir.path().join("missing");
+        assert!(matches!(open_writable(&missing), Err(Error::Open { .. })));
+        assert!(!missing.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve_writable_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output");
+        fs::write(&path, "").unwrap();
+        let socket = format!("pipesys-test-{}-writable", process::id());
+        let server = Server::for_writable_paths(&socket, u32::MAX, &path).with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let fd = tokio::task::spawn_blocking(move || {
+            fetch_fd_with_timeout(&socket, Duration::from_secs(5)).unwrap()
+        })
+        .await
+        .unwrap();
+        handle.abort();
+
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(b"staged").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "staged");
+    }
+
+    #[test]
+    fn test_check_read_only_rejects_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let file = File::create(&path).unwrap();
+        assert!(matches!(
+            check_served_file(&file, &path, false),
+            Err(Error::NotReadOnly { .. })
+        ));
+
+        let fifo = Fifo {
+            path: dir.path().join("pipe"),
+            end: FifoEnd::Read,
+        };
+        let file = open_fifo(&fifo).unwrap();
+        assert!(matches!(
+            check_served_file(&file, &fifo.path, false),
+            Err(Error::UnsupportedFileType { .. })
+        ));
+    }
+
+    #[test]
+    fn test_uid_map_authorization() {
+        let server = Server::for_path("socket", 0, "/").with_uid_map("0:1000:1".parse().unwrap());
+        let mut peer = PeerCredentials {
+            pid: Some(1),
+            raw_pid: Some(1),
+            uid: 1000,
+            gid: Some(1000),
+        };
+
+        // Root in the client's namespace is the invoking user outside it.
+        assert!(server.authorize(&peer).is_ok());
+
+        // Real root, and other users outside the mapping, are not.
+        peer.uid = 0;
+        assert!(server.authorize(&peer).is_err());
+        peer.uid = 1001;
+        assert!(server.authorize(&peer).is_err());
+
+        // Subordinate ranges are translated by offset.
+        let server = Server::for_path("socket", 5, "/")
+            .with_uid_map("0:1000:1".parse().unwrap())
+            .with_uid_map("1:100000:65536".parse().unwrap());
+        peer.uid = 100004;
+        assert!(server.authorize(&peer).is_ok());
+        peer.uid = 100005;
+        assert!(server.authorize(&peer).is_err());
+    }
+
+    #[test]
+    fn test_parse_uid_map() {
+        assert_eq!(
+            "1:100000:65536".parse::<UidMap>().unwrap(),
+            UidMap::new(1, 100000, 65536)
+        );
+        for spec in [
+            "0:1000",
+            "0:1000:0",
+            "a:b:c",
+            "0:1000:1:1",
+            "4294967295:0:2",
+        ] {
+            assert!(
+                matches!(spec.parse::<UidMap>(), Err(Error::UidMapSpec { .. })),
+                "{spec}"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve_info() {
+        let server = test_server("info")
+            .with_authorizer(|_| true)
+            .with_serve_info(true);
+        let socket = server.address().to_string();
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let info = tokio::task::spawn_blocking(move || {
+            let info_addr =
+                UnixSocketAddr::from_abstract(format!("{socket}-info").as_bytes()).unwrap();
+            for _ in 0..100 {
+                if let Ok(client) = UnixSeqpacketConn::connect_unix_addr(&info_addr) {
+                    let mut message = [0u8; 4096];
+                    let len = client.recv(&mut message).unwrap();
+                    let info = String::from_utf8(message[..len].to_vec()).unwrap();
+                    // Asking for info does not use up the socket that serves the descriptor.
+                    assert_eq!(fetch_fds(&socket), 1);
+                    return info.parse::<ServerInfo>().unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            panic!("failed to connect to info socket");
+        })
+        .await
+        .unwrap();
+        handle.abort();
+
+        assert_eq!(
+            info.paths,
+            [ServedPath {
+                path: env!("CARGO_MANIFEST_DIR").into(),
+                kind: PathKind::Directory,
+            }]
+        );
+        assert_eq!(info.client_uid, Some(u32::MAX));
+    }
+
+    // The server and the send tasks share one thread, so the send for the accepted client has not
+    // started when the shutdown is seen. Without draining, it would be cancelled.
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_shutdown_drains_sends() {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let tx = std::sync::Mutex::new(Some(tx));
+        let server = test_server("drain").with_authorizer(move |_| {
+            // Shut down as soon as the first client is accepted.
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(());
+            }
+            true
+        });
+        let socket = server.address().to_string();
+        let handle = tokio::spawn(async move {
+            server
+                .serve_until(async {
+                    let _ = rx.await;
+                })
+                .await
+        });
+
+        let fds = tokio::task::spawn_blocking(move || fetch_fds(&socket))
+            .await
+            .unwrap();
+        assert_eq!(fds, 1);
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("server did not stop after shutdown")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve_on_socket_path() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let socket_path = dir.path().join("pipesys.sock");
+        // A socket file left by a server that has exited is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+        let server = Server::for_path_on_socket_path(&socket_path, u32::MAX, dir.path())
+            .with_authorizer(|_| true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let addresses = [SocketAddress::Path(socket_path)];
+        let fd = tokio::task::spawn_blocking(move || {
+            for _ in 0..100 {
+                if let Ok(fd) = crate::client::fetch_fd_from_any_address(&addresses, None) {
+                    return fd;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            panic!("failed to fetch from socket {}", addresses[0]);
+        })
+        .await
+        .unwrap();
+        handle.abort();
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let served = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap();
+        assert_eq!(served, dir.path());
+    }
+
+    #[tokio::test]
+    async fn test_socket_path_not_socket() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let socket_path = dir.path().join("pipesys.sock");
+        fs::write(&socket_path, "not a socket").unwrap();
+        let server = Server::for_path_on_socket_path(&socket_path, u32::MAX, dir.path());
+        assert!(matches!(
+            server.serve().await,
+            Err(Error::NotSocket { path }) if path == socket_path
+        ));
+        assert!(socket_path.is_file());
+    }
+
+    #[test]
+    fn test_socket_and_socket_path_conflict() {
+        let base = ["server", "--client-uid=0", "--path=/"];
+        assert!(Server::try_parse_from(base.iter().chain(&["--socket=a"])).is_ok());
+        assert!(Server::try_parse_from(base.iter().chain(&["--socket-path=/tmp/a"])).is_ok());
+        assert!(Server::try_parse_from(base).is_err());
+        assert!(
+            Server::try_parse_from(base.iter().chain(&["--socket=a", "--socket-path=/tmp/a"]))
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_in_use() {
+        let server = test_server("in-use");
+        let socket = server.address().to_string();
+        let addr = UnixSocketAddr::from_abstract(socket.as_bytes()).unwrap();
+        let _listener = UnixSeqpacketListener::bind_addr(&addr).unwrap();
+        assert!(matches!(
+            server.serve().await,
+            Err(Error::SocketInUse { socket: in_use }) if in_use == socket
+        ));
+    }
+
+    #[test]
+    fn test_allowed_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let socket = format!("pipesys-test-{}-allowed", process::id());
+
+        let server = Server::for_path(&socket, u32::MAX, root.join("sub")).with_allowed_root(&root);
+        server.open_path().unwrap();
+
+        let server = Server::for_fifo(&socket, u32::MAX, root.join("fifo"), FifoEnd::Read)
+            .with_allowed_root(&root);
+        server.open_path().unwrap();
+    }
+
+    #[test]
+    fn test_allowed_root_escape() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        // A sibling whose name starts with the root's name is still outside it.
+        std::fs::create_dir_all(dir.path().join("root-sibling")).unwrap();
+        let socket = format!("pipesys-test-{}-escape", process::id());
+
+        for path in [
+            root.join("escape"),
+            root.join("../outside"),
+            dir.path().join("root-sibling"),
+        ] {
+            let server = Server::for_path(&socket, u32::MAX, &path).with_allowed_root(&root);
+            assert!(
+                matches!(server.open_path(), Err(Error::NotAllowed { .. })),
+                "{}",
+                path.display()
+            );
+        }
+
+        let server = Server::for_fifo(&socket, u32::MAX, root.join("escape/fifo"), FifoEnd::Read)
+            .with_allowed_root(&root);
+        assert!(matches!(server.open_path(), Err(Error::NotAllowed { .. })));
+        assert!(!outside.join("fifo").exists());
+    }
+
+    #[test]
+    fn test_check_fd_count() {
+        let fds = vec![0; SCM_MAX_FD + 1];
+        check_fd_count("socket", &fds[..SCM_MAX_FD]).unwrap();
+        assert!(matches!(
+            check_fd_count("socket", &fds),
+            Err(Error::TooManyFds {
+                count: 254,
+                max: 253,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_listening_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("service.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let socket = format!("pipesys-test-{}-listen", process::id());
+        let server = Server::for_listening_socket(&socket, u32::MAX, listener.as_raw_fd())
+            .with_authorizer(|_| true);
+        assert_eq!(server.info().paths[0].kind, PathKind::ListeningSocket);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let (received, message) = tokio::task::spawn_blocking(move || fetch_file(&socket))
+            .await
+            .unwrap();
+        handle.abort();
+        assert_eq!(message, b"listening-socket");
+
+        // The client can accept connections on the socket it received.
+        let received = UnixListener::from(OwnedFd::from(received));
+        let client = std::thread::spawn(move || {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(b"hello").unwrap();
+        });
+        let (mut stream, _) = received.accept().unwrap();
+        let mut greeting = String::new();
+        stream.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "hello");
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_listening_socket_not_listening() {
+        let dir = tempfile::tempdir().unwrap();
+        let datagram = UnixDatagram::bind(dir.path().join("datagram.sock")).unwrap();
+        let server = Server::for_listening_socket("unused", 0, datagram.as_raw_fd());
+        assert!(matches!(
+            server.open_path(),
+            Err(Error::NotListening { fd }) if fd == datagram.as_raw_fd()
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_uses() {
+        let once = test_server("once")
+            .with_authorizer(|_| true)
+            .with_max_uses(1);
+        let always = test_server("always").with_authorizer(|_| true);
+        let (once_socket, always_socket) =
+            (once.address().to_string(), always.address().to_string());
+        let handles = [
+            tokio::spawn(async move { once.serve().await }),
+            tokio::spawn(async move { always.serve().await }),
+        ];
+
+        // Waiting for the server with a bare connection would count as a use, so the first fetch
+        // from each server waits for it instead.
+        let fetch = |socket: &str| {
+            fetch_fd_with_timeout(socket, Duration::from_secs(5))
+                .map(|fd| drop(unsafe { OwnedFd::from_raw_fd(fd) }))
+        };
+        let results = tokio::task::spawn_blocking(move || {
+            [
+                fetch(&once_socket),
+                fetch(&once_socket),
+                fetch(&always_socket),
+                fetch(&always_socket),
+            ]
+        })
+        .await
+        .unwrap();
+        for handle in handles {
+            handle.abort();
+        }
+
+        // The once-only descriptor is refused the second time, but the other is still served.
+        let [first, second, other_first, other_second] = results;
+        assert!(first.is_ok());
+        assert!(matches!(second, Err(Error::Refused { socket }) if socket.ends_with("-once")));
+        assert!(other_first.is_ok());
+        assert!(other_second.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_token() {
+        let server = test_server("token")
+            .with_authorizer(|_| true)
+            .with_max_uses(1)
+            .with_token("build-a-secret")
+            .unwrap();
+        let socket = server.address().to_string();
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let results = tokio::task::spawn_blocking(move || {
+            // A bare connection sends no token, so it is rejected and does not count as a use.
+            wait_for(&socket, Duration::from_secs(5)).unwrap();
+            [
+                "build-b-secret",
+                "build-a-secret-extra",
+                "build-a",
+                "build-a-secret",
+            ]
+            .map(|token| fetch_owned_fd_with_token(&socket, token).map(drop))
+        })
+        .await
+        .unwrap();
+        handle.abort();
+
+        let [other, longer, prefix, correct] = results;
+        for wrong in [other, longer, prefix] {
+            assert!(
+                matches!(wrong, Err(Error::TokenRejected { .. })),
+                "{wrong:?}"
+            );
+        }
+        assert!(correct.is_ok());
+    }
+
+    #[test]
+    fn test_token_matches() {
+        let token: Token = "secret".parse().unwrap();
+        assert!(token.matches(b"secret"));
+        assert!(!token.matches(b"secreT"));
+        assert!(!token.matches(b"secrets"));
+        assert!(!token.matches(b""));
+        assert!(matches!("".parse::<Token>(), Err(Error::EmptyToken)));
+        assert_eq!(format!("{token:?}"), "Token(..)");
+    }
+
+    #[tokio::test]
+    async fn test_stop_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let stop_file = dir.path().join("done");
+        let server = Server::for_path(
+            format!("pipesys-test-{}-stop-file", process::id()),
+            u32::MAX,
+            dir.path(),
+        )
+        .with_stop_file(&stop_file);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        tokio::time::sleep(STOP_FILE_POLL_INTERVAL * 3).await;
+        assert!(!handle.is_finished());
+
+        fs::write(&stop_file, "").unwrap();
+        let served = tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("server did not stop after the stop file was created")
+            .unwrap();
+        assert!(served.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_keep_alive_missing_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("served");
+        fs::create_dir(&path).unwrap();
+        let socket = format!("pipesys-test-{}-churn", process::id());
+        let server = Server::for_path(&socket, u32::MAX, &path)
+            .with_authorizer(|_| true)
+            .with_keep_alive(true);
+        let handle = tokio::spawn(async move { server.serve().await });
+
+        let fetch = |socket: &str| {
+            fetch_fd_with_timeout(socket, Duration::from_secs(5))
+                .map(|fd| drop(unsafe { OwnedFd::from_raw_fd(fd) }))
+        };
+        let results = tokio::task::spawn_blocking(move || {
+            let before = fetch(&socket);
+            fs::remove_dir(&path).unwrap();
+            let removed = fetch(&socket);
+            fs::create_dir(&path).unwrap();
+            let restored = fetch(&socket);
+            [before, removed, restored]
+        })
+        .await
+        .unwrap();
+
+        // The client that connected while the path was missing gets an error, but the server
+        // keeps running for the next one.
+        let [before, removed, restored] = results;
+        assert!(before.is_ok());
+        assert!(matches!(removed, Err(Error::Unavailable { .. })));
+        assert!(restored.is_ok());
+        assert!(!handle.is_finished());
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_missing_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = format!("pipesys-test-{}-missing", process::id());
+        let server = Server::for_path(socket, u32::MAX, dir.path().join("missing"));
+        assert!(matches!(server.serve().await, Err(Error::Open { .. })));
     }
 }
@@ -0,0 +1,34 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Where a server listens for clients. Abstract sockets are the default, and reach every process
+/// in the same network namespace. A socket file reaches processes that don't share one, such as
+/// a build in a container and a server on the host, as long as both can see the path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocketAddress {
+    Abstract(String),
+    Path(PathBuf),
+}
+
+impl SocketAddress {
+    /// The socket where a server describes what it serves, if it was asked to.
+    pub(crate) fn info(&self) -> Self {
+        match self {
+            Self::Abstract(name) => Self::Abstract(format!("{name}-info")),
+            Self::Path(path) => {
+                let mut path = path.clone().into_os_string();
+                path.push("-info");
+                Self::Path(path.into())
+            }
+        }
+    }
+}
+
+impl fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Abstract(name) => f.write_str(name),
+            Self::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+/*!
+Helpers for exercising the client against a real server in the same process, without spawning the
+pipesys binary. The server runs as a task on the caller's Tokio runtime and listens on an abstract
+socket with a name that no other test server uses.
+
+This module is built for the crate's own tests, and for other crates when the `test-support`
+feature is enabled.
+*/
+
+use crate::server::Server;
+use crate::Result;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+/// A server running in a background task. The task is aborted when this is dropped.
+pub struct TestServer {
+    socket: String,
+    task: JoinHandle<Result<()>>,
+}
+
+impl TestServer {
+    /// Start the server that `build` returns for a new socket name. The socket is bound before
+    /// this returns, so clients can connect right away. This must be called from within a Tokio
+    /// runtime.
+    pub fn start<F>(build: F) -> Result<Self>
+    where
+        F: FnOnce(&str) -> Server,
+    {
+        let socket = random_socket();
+        let server = build(&socket);
+        let listener = server.bind()?;
+        let task = tokio::spawn(async move { server.serve_listener(listener).await });
+        Ok(Self { socket, task })
+    }
+
+    /// The name of the abstract socket that the server listens on.
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
+
+    /// Whether the server has stopped, for example because it reached its use limit.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Pick a socket name that is unique to this process and call, and unlikely to be in use by a
+/// concurrent test run.
+fn random_socket() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    format!(
+        "pipesys-test-{}-{}-{nanos:x}",
+        process::id(),
+        NEXT_SOCKET.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use crate::client::fetch_owned_fd;
+    use crate::server::FifoEnd;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_two_pipes_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = [dir.path().join("pipe-a"), dir.path().join("pipe-b")];
+        let servers = paths
+            .iter()
+            .map(|path| {
+                TestServer::start(|socket| {
+                    Server::for_fifo(socket, u32::MAX, path, FifoEnd::Read)
+                        .with_authorizer(|_| true)
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_ne!(servers[0].socket(), servers[1].socket());
+
+        let sockets = servers
+            .iter()
+            .map(|s| s.socket().to_string())
+            .collect::<Vec<_>>();
+        let received = tokio::task::spawn_blocking(move || {
+            sockets
+                .iter()
+                .zip(&paths)
+                .zip([b'a', b'b'])
+                .map(|((socket, path), byte)| {
+                    // The read end is sent once a writer opens the FIFO, so the client waits for
+                    // it on a thread of its own.
+                    let socket = socket.clone();
+                    let reader = std::thread::spawn(move || {
+                        let mut reader = File::from(fetch_owned_fd(&socket).unwrap());
+                        let mut buf = [0u8; 1];
+                        reader.read_exact(&mut buf).unwrap();
+                        buf[0]
+                    });
+                    // The server creates the FIFO when it starts serving.
+                    while !path.exists() {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    let mut writer = OpenOptions::new().write(true).open(path).unwrap();
+                    writer.write_all(&[byte]).unwrap();
+                    reader.join().unwrap()
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(received, b"ab");
+        assert!(servers.iter().all(|s| !s.is_finished()));
+    }
+}